@@ -8,7 +8,6 @@ use esp_hal::interrupt::software::SoftwareInterruptControl;
 use esp_hal::timer::timg::TimerGroup;
 use esp_println::println;
 use esp_radio::ieee802154::Ieee802154;
-use zigbee::aps::aib;
 use zigbee::nwk::nib::CapabilityInformation;
 use zigbee::nwk::nlme::Nlme;
 use zigbee::nwk::nlme::management::NlmeJoinStatus;
@@ -38,9 +37,6 @@ async fn main(_spawner: embassy_executor::Spawner) -> ! {
 
     esp_alloc::heap_allocator!(size: 24 * 1024);
 
-    zigbee::nwk::nib::init(zigbee::nwk::nib::NibStorage::default());
-    zigbee::aps::aib::init(zigbee::aps::aib::AibStorage::default());
-
     let ieee802154 = Ieee802154::new(peripherals.IEEE802154);
     let mac = EspMlme::new(ieee802154, Default::default());
     let nwk = Nlme::new(mac);
@@ -74,7 +70,8 @@ async fn main(_spawner: embassy_executor::Spawner) -> ! {
             let network_key = nib.security_material_set().first().unwrap().key;
             println!("Network key installed: key={:02x?}", network_key);
 
-            let link_key = aib::get_ref()
+            let link_key = bdb
+                .aib()
                 .device_key_pair_set()
                 .first()
                 .unwrap()
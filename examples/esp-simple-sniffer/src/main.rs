@@ -10,7 +10,9 @@ use esp_hal::esp_riscv_rt::entry;
 use esp_radio::ieee802154::{Config, Ieee802154, ReceivedFrame};
 use esp_println::println;
 use ieee802154::mac::Address;
+use zigbee::aps::aib::Aib;
 use zigbee::nwk::frame::Frame as NwkFrame;
+use zigbee::nwk::nib::Nib;
 use zigbee::security::SecurityContext;
 
 esp_bootloader_esp_idf::esp_app_desc!();
@@ -20,8 +22,8 @@ fn main() -> ! {
     let peripherals = esp_hal::init(esp_hal::Config::default());
     esp_alloc::heap_allocator!(size: 72 * 1024);
 
-    zigbee::nwk::nib::init(zigbee::nwk::nib::NibStorage::default());
-    zigbee::aps::aib::init(zigbee::aps::aib::AibStorage::default());
+    let nib = Nib::default();
+    let aib = Aib::default();
     let mut ieee802154 = Ieee802154::new(peripherals.IEEE802154);
 
     ieee802154.set_config(Config {
@@ -38,7 +40,9 @@ fn main() -> ! {
 
     loop {
         if let Some(Ok(frame)) = ieee802154.received() {
-            print_ieee802154_mac_frame(&frame).and_then(print_zigbee_nwk_frame);
+            print_ieee802154_mac_frame(&frame).and_then(|payload| {
+                print_zigbee_nwk_frame(payload, SecurityContext::new(&nib, &aib))
+            });
         }
     }
 }
@@ -61,12 +65,12 @@ fn print_ieee802154_mac_frame(frame: &ReceivedFrame) -> Option<&[u8]> {
     }
 }
 
-fn print_zigbee_nwk_frame(payload: &[u8]) -> Option<&[u8]> {
+fn print_zigbee_nwk_frame<'a>(payload: &'a [u8], cx: SecurityContext<'a>) -> Option<&'a [u8]> {
     let mut buf = [0u8; 256];
     hex::encode_to_slice(payload, &mut buf[0..(payload.len() * 2)]).unwrap();
     let s = unsafe { str::from_utf8_unchecked(&buf) };
 
-    let (nwk_frame, _) = NwkFrame::try_read(payload, SecurityContext::get()).unwrap();
+    let (nwk_frame, _) = NwkFrame::try_read(payload, cx).unwrap();
     match nwk_frame {
         NwkFrame::Data(nwk_data_frame) => {
             //println!(
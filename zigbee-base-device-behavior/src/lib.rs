@@ -8,6 +8,9 @@
 #![no_std]
 #![allow(unused)]
 
+#[cfg(test)]
+extern crate std;
+
 use thiserror::Error;
 
 pub mod types;
@@ -22,29 +25,37 @@ use types::BdbCommissioningStatus;
 use types::CommissioningMode;
 use zigbee::Config;
 use zigbee::LogicalType;
-use zigbee::aps::aib;
+use zigbee::apl::endpoint::Endpoint;
+use zigbee::aps::aib::Aib;
+use zigbee::aps::aib::AibStorage;
 use zigbee::aps::aib::DeviceKeyPairDescriptor;
 use zigbee::aps::aib::KeyAttribute;
 use zigbee::aps::aib::LinkKeyType;
+use zigbee::aps::apsme::basemgt::ApsmeGetConfirm;
+use zigbee::aps::apsme::basemgt::ApsmeSetConfirm;
+use zigbee::aps::apsme::basemgt::ApsmeSetRequest;
 use zigbee::aps::frame::command::Command;
 use zigbee::aps::frame::command::ConfirmKey;
 use zigbee::aps::frame::command::RequestKey;
 use zigbee::aps::frame::command::TransportKey;
 use zigbee::aps::frame::command::VerifyKey;
-use zigbee::nwk::nib;
 use zigbee::nwk::nib::CapabilityInformation;
 use zigbee::nwk::nib::Nib;
 use zigbee::nwk::nib::NibStorage;
 use zigbee::nwk::nlme::NetworkError;
 use zigbee::nwk::nlme::Nlme;
+use zigbee::nwk::nlme::management::NlmeGetConfirm;
 use zigbee::nwk::nlme::management::NlmeJoinConfirm;
 use zigbee::nwk::nlme::management::NlmeJoinRequest;
 use zigbee::nwk::nlme::management::NlmeJoinStatus;
 use zigbee::nwk::nlme::management::NlmeNetworkFormationRequest;
 use zigbee::nwk::nlme::management::NlmePermitJoiningRequest;
+use zigbee::nwk::nlme::management::NlmeSetConfirm;
+use zigbee::nwk::nlme::management::NlmeSetRequest;
 use zigbee::nwk::nlme::management::RejoinNetwork;
 use zigbee::security::primitives::HmacAes128Mmo;
 use zigbee::zdo::ZigbeeDevice;
+use zigbee::zdo::mgmt_permit_joining::MgmtPermitJoiningReq;
 use zigbee::zdp::device_annce::DeviceAnnce;
 use zigbee_mac::mlme::Mlme;
 use zigbee_types::ByteArray;
@@ -77,9 +88,34 @@ impl<M: Mlme> BaseDeviceBehavior<M> {
         }
     }
 
-    /// Returns a reference to the global NIB singleton.
-    pub fn nib(&self) -> &'static Nib<NibStorage> {
-        nib::get_ref()
+    /// Returns the NIB of this node.
+    pub fn nib(&self) -> &Nib<NibStorage> {
+        self.nlme.nib()
+    }
+
+    /// Returns the AIB of this node.
+    pub fn aib(&self) -> &Aib<AibStorage> {
+        self.nlme.aib()
+    }
+
+    /// Read a NIB attribute by its identifier (NLME-GET).
+    pub fn nlme_get(&self, attribute: u8) -> NlmeGetConfirm {
+        self.nlme.get(attribute)
+    }
+
+    /// Write a NIB attribute by its identifier (NLME-SET).
+    pub fn nlme_set(&mut self, request: NlmeSetRequest) -> NlmeSetConfirm {
+        self.nlme.set(request)
+    }
+
+    /// Read an AIB attribute by its identifier (APSME-GET).
+    pub fn apsme_get(&self, attribute: u8) -> ApsmeGetConfirm {
+        self.device.apsme_get(&self.nlme, attribute)
+    }
+
+    /// Write an AIB attribute by its identifier (APSME-SET).
+    pub fn apsme_set(&mut self, request: ApsmeSetRequest) -> ApsmeSetConfirm {
+        self.device.apsme_set(&self.nlme, request)
     }
 
     /// Initialization procedure (BDB §7.1).
@@ -113,12 +149,16 @@ impl<M: Mlme> BaseDeviceBehavior<M> {
         self.bdb_commissioning_status = BdbCommissioningStatus::InProgress;
 
         // §8.2 step 1
-        self.nlme.network_discovery(channels, scan_duration).await?;
+        self.nlme
+            .network_discovery(channels.clone(), scan_duration)
+            .await?;
 
         // §8.2 step 5
         let request = NlmeJoinRequest {
             extended_pan_id,
             rejoin_network: RejoinNetwork::Association,
+            scan_channels: channels,
+            scan_duration,
             capability_information,
             security_enabled: false,
         };
@@ -131,6 +171,13 @@ impl<M: Mlme> BaseDeviceBehavior<M> {
         // §8.2 step 9
         self.device.poll_transport_key(&mut self.nlme).await?;
 
+        // negotiate the timeout with the parent, older parents do not answer
+        if self.is_end_device()
+            && let Err(e) = self.nlme.end_device_timeout_request().await
+        {
+            log::debug!("[BDB] end device timeout negotiation failed: {e}");
+        }
+
         // §8.2 step 11
         self.device_annce(capability_information).await?;
 
@@ -142,12 +189,69 @@ impl<M: Mlme> BaseDeviceBehavior<M> {
         Ok(confirm)
     }
 
+    /// Network steering procedure for a node already on a network
+    /// (BDB §8.1).
+    ///
+    /// Opens the network for new devices for at least
+    /// bdbcMinCommissioningTime seconds.
+    pub async fn network_steering_on_network(&mut self) -> Result<(), NetworkError> {
+        // §8.1 step 1
+        if !self.bdb_node_is_on_a_network {
+            self.bdb_commissioning_status = BdbCommissioningStatus::NotOnANetwork;
+            return Ok(());
+        }
+        self.bdb_commissioning_status = BdbCommissioningStatus::InProgress;
+
+        // §8.1 step 4
+        let request = MgmtPermitJoiningReq {
+            permit_duration: BDBC_MIN_COMMISSIONING_TIME,
+            tc_significance: 0x01,
+        };
+        self.device
+            .mgmt_permit_joining_req(&mut self.nlme, request)
+            .await?;
+
+        // §8.1 step 5
+        if !self.is_end_device() {
+            let request = NlmePermitJoiningRequest {
+                permit_duration: BDBC_MIN_COMMISSIONING_TIME,
+            };
+            let confirm = self.nlme.permit_joining(request).await;
+            log::debug!("[BDB] permit joining: {:?}", confirm.status);
+        }
+
+        self.bdb_commissioning_status = BdbCommissioningStatus::Success;
+        Ok(())
+    }
+
+    /// Receive and dispatch inbound frames forever.
+    ///
+    /// Once the node is on a network this runs next to the application,
+    /// which receives its APS data through `endpoints`.
+    pub async fn run(&mut self, endpoints: &mut [&mut dyn Endpoint]) -> ! {
+        self.device.run(&mut self.nlme, endpoints).await
+    }
+
+    /// Poll the parent of a sleepy end device when due.
+    ///
+    /// Returns the milliseconds the radio may sleep before the next call,
+    /// `None` if the receiver has to stay on.
+    pub async fn poll(
+        &mut self,
+        endpoints: &mut [&mut dyn Endpoint],
+        elapsed_ms: u32,
+    ) -> Result<Option<u32>, NetworkError> {
+        self.device
+            .poll(&mut self.nlme, endpoints, elapsed_ms)
+            .await
+    }
+
     /// Broadcast a ZDO Device_annce (§2.4.3.1.11, BDB §8.2 step 11).
     async fn device_annce(
         &mut self,
         capability_information: CapabilityInformation,
     ) -> Result<(), NetworkError> {
-        let nib = nib::get_ref();
+        let nib = self.nlme.nib();
         let annce = DeviceAnnce {
             nwk_addr: ShortAddress(nib.network_address()),
             ieee_addr: nib.ieee_address(),
@@ -163,7 +267,7 @@ impl<M: Mlme> BaseDeviceBehavior<M> {
     /// VERIFY-KEY → CONFIRM-KEY.
     async fn tc_link_key_exchange(&mut self) -> Result<(), NetworkError> {
         let tc_short = ShortAddress(0x0000);
-        let tc_ieee = aib::get_ref().trust_center_address();
+        let tc_ieee = self.nlme.aib().trust_center_address();
 
         log::debug!("[BDB] start TC link key exchange, TC={tc_ieee:?}");
 
@@ -202,7 +306,7 @@ impl<M: Mlme> BaseDeviceBehavior<M> {
         };
 
         // §10.2.5 step 9
-        let aib = aib::get_ref();
+        let aib = self.nlme.aib();
         let mut key_set = aib.device_key_pair_set();
         if let Some(entry) = key_set.iter_mut().find(|k| k.device_address == tc_ieee) {
             entry.link_key = new_key;
@@ -227,7 +331,7 @@ impl<M: Mlme> BaseDeviceBehavior<M> {
             NetworkError::SecurityError(zigbee::security::SecurityError::Unspecified)
         })?;
         // §4.4.10.7.3
-        let device_addr = nib::get_ref().ieee_address();
+        let device_addr = self.nlme.nib().ieee_address();
 
         let mut attempts = 0u8;
         loop {
@@ -254,6 +358,7 @@ impl<M: Mlme> BaseDeviceBehavior<M> {
                 Ok(Command::ConfirmKey(confirm)) if confirm.status == 0x00 => {
                     log::debug!("[BDB] TC link key verified successfully");
                     // mark key as verified
+                    let aib = self.nlme.aib();
                     let mut key_set = aib.device_key_pair_set();
                     if let Some(entry) = key_set.iter_mut().find(|k| k.device_address == tc_ieee) {
                         entry.key_attributes = KeyAttribute::VerifiedKey;
@@ -288,3 +393,186 @@ pub enum BdbError {
     #[error("no open network discovered to join")]
     NoNetwork,
 }
+
+#[cfg(test)]
+mod tests {
+    use core::future::Future;
+
+    use zigbee::apl::descriptors::node_descriptor::LogicalType;
+    use zigbee_mac::Address;
+    use zigbee_mac::AssociationStatus;
+    use zigbee_mac::MacShortAddress;
+    use zigbee_mac::PanId;
+    use zigbee_mac::mlme::AssociationResponse;
+    use zigbee_mac::mlme::MacError;
+    use zigbee_mac::mlme::MacIndication;
+    use zigbee_mac::mlme::ScanResult;
+    use zigbee_mac::mlme::ScanType;
+    use zigbee_mac::mlme::StartRequest;
+    use zigbee_mac::mlme::ZigbeeBeacon;
+
+    use super::*;
+
+    const PAN_ID: u16 = 0x1234;
+
+    // Minimal async block_on — the mock futures resolve immediately so a
+    // single poll is sufficient.
+    #[allow(clippy::panic)]
+    fn block_on<F: Future>(f: F) -> F::Output {
+        use core::pin::pin;
+        use core::task::Context;
+        use core::task::Poll;
+        use core::task::RawWaker;
+        use core::task::RawWakerVTable;
+        use core::task::Waker;
+
+        fn noop(_: *const ()) {}
+        fn clone(p: *const ()) -> RawWaker {
+            RawWaker::new(p, &VTABLE)
+        }
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+
+        let waker = unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &VTABLE)) };
+        let mut cx = Context::from_waker(&waker);
+        let mut f = pin!(f);
+
+        match f.as_mut().poll(&mut cx) {
+            Poll::Ready(val) => val,
+            Poll::Pending => panic!("block_on: future returned Pending"),
+        }
+    }
+
+    mockall::mock! {
+        Mlme {}
+        impl Mlme for Mlme {
+            async fn scan_network(
+                &mut self,
+                ty: ScanType,
+                channels: core::ops::Range<u8>,
+                duration: u8,
+            ) -> Result<ScanResult, MacError>;
+            async fn associate(
+                &mut self,
+                channel: u8,
+                dest: Address,
+                capabilities: zigbee_mac::CapabilityInformation,
+            ) -> Result<AssociationResponse, MacError>;
+            async fn poll_data(
+                &mut self,
+                coord_address: Address,
+                buf: &mut [u8],
+            ) -> Result<(usize, u8, bool), MacError>;
+            async fn transmit_data(
+                &mut self,
+                dest: Address,
+                payload: &[u8],
+            ) -> Result<(), MacError>;
+            async fn transmit_indirect(
+                &mut self,
+                dest: Address,
+                payload: &[u8],
+                frame_pending: bool,
+            ) -> Result<(), MacError>;
+            fn set_frame_pending(&mut self, dest: Address, pending: bool);
+            async fn start(&mut self, request: StartRequest) -> Result<(), MacError>;
+            fn set_association_permit(&mut self, permit: bool);
+            fn set_channel(&mut self, channel: u8);
+            fn set_pan_id(&mut self, pan_id: PanId);
+            fn set_short_address(&mut self, short_address: ShortAddress);
+            fn set_beacon_payload(&mut self, payload: ZigbeeBeacon);
+            async fn receive(&mut self, buf: &mut [u8]) -> Result<MacIndication, MacError>;
+            async fn associate_response(
+                &mut self,
+                device_address: IeeeAddress,
+                association_address: ShortAddress,
+                status: AssociationStatus,
+            ) -> Result<(), MacError>;
+            async fn orphan_response(
+                &mut self,
+                orphan_address: IeeeAddress,
+                short_address: ShortAddress,
+                associated_member: bool,
+            ) -> Result<(), MacError>;
+            fn extended_address(&self) -> IeeeAddress;
+        }
+    }
+
+    /// A router joined to [`PAN_ID`] as 0x1111.
+    fn make_router(mut mac: MockMlme) -> BaseDeviceBehavior<MockMlme> {
+        mac.expect_extended_address()
+            .return_const(IeeeAddress(0x0011_2233_4455_6677));
+        let nlme = Nlme::new(mac);
+        nlme.nib().set_network_address(0x1111);
+        nlme.nib().set_panid(PAN_ID);
+        let cap = nlme.nib().capability_information().set_device_type(true);
+        nlme.nib().set_capability_information(cap);
+        let config = Config {
+            device_type: LogicalType::Router,
+            ..Config::default()
+        };
+        let mut bdb = BaseDeviceBehavior::new(nlme, config);
+        bdb.bdb_node_is_on_a_network = true;
+        bdb
+    }
+
+    // BDB 8.1
+    #[test]
+    fn network_steering_on_network_opens_the_network() {
+        // given
+        let sent = std::sync::Arc::new(std::sync::Mutex::new(std::vec::Vec::new()));
+        let recorded = sent.clone();
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data().returning(move |dest, payload| {
+            recorded.lock().unwrap().push((dest, payload.to_vec()));
+            Ok(())
+        });
+        mac.expect_set_association_permit()
+            .withf(|permit| *permit)
+            .times(1)
+            .return_const(());
+        let mut bdb = make_router(mac);
+
+        // when
+        block_on(bdb.network_steering_on_network()).unwrap();
+
+        // then
+        assert_eq!(
+            bdb.bdb_commissioning_status,
+            BdbCommissioningStatus::Success
+        );
+        assert!(bdb.nlme.is_joining_permitted());
+        let [(dest, frame)] = &sent.lock().unwrap()[..] else {
+            unreachable!("expected one broadcast");
+        };
+        assert_eq!(
+            *dest,
+            Address::Short(PanId(PAN_ID), MacShortAddress(0xffff))
+        );
+        // NWK destination: all routers and the coordinator
+        assert_eq!(frame[2..4], [0xfc, 0xff]);
+        // APS header: broadcast data frame for the ZDP Mgmt_Permit_Joining_req
+        // from and to the ZDO endpoint
+        let aps = &frame[8..];
+        assert_eq!(aps[..7], [0x08, 0x00, 0x36, 0x00, 0x00, 0x00, 0x00]);
+        // ZDP: PermitDuration = bdbcMinCommissioningTime, TC_Significance
+        assert_eq!(aps[aps.len() - 2..], [BDBC_MIN_COMMISSIONING_TIME, 0x01]);
+    }
+
+    // BDB 8.1
+    #[test]
+    fn network_steering_requires_a_network() {
+        // given
+        let mut bdb = make_router(MockMlme::new());
+        bdb.bdb_node_is_on_a_network = false;
+
+        // when
+        block_on(bdb.network_steering_on_network()).unwrap();
+
+        // then
+        assert_eq!(
+            bdb.bdb_commissioning_status,
+            BdbCommissioningStatus::NotOnANetwork
+        );
+        assert!(!bdb.nlme.is_joining_permitted());
+    }
+}
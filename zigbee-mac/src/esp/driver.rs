@@ -1,6 +1,8 @@
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::signal::Signal;
+use embassy_time::Timer;
 use esp_hal::efuse;
+use esp_hal::peripherals::IEEE802154;
 use esp_radio::ieee802154::Config;
 use esp_radio::ieee802154::Error as Ieee802154Error;
 use esp_radio::ieee802154::Ieee802154;
@@ -9,6 +11,24 @@ use esp_radio::ieee802154::ReceivedFrame;
 static TX_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 static RX_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
+/// Opcodes of the radio's command register used for energy detection,
+/// which `esp-radio` doesn't expose.
+const CMD_RX_START: u8 = 0x42;
+const CMD_ED_START: u8 = 0x44;
+const CMD_STOP: u8 = 0x45;
+
+/// Report the peak instead of the average energy of a measurement.
+const ED_SAMPLE_MODE_MAX: u8 = 0;
+/// Sample mode `esp-radio` uses for clear channel assessment.
+const ED_SAMPLE_MODE_AVG: u8 = 1;
+
+/// Symbol period at 2.4 GHz (O-QPSK).
+const SYMBOL_PERIOD_US: u32 = 16;
+
+/// Time allowed for the radio to settle before the measurement result is
+/// read.
+const ED_SETTLE_US: u64 = 128;
+
 /// Derive an EUI-64 extended address from a 6-byte EUI-48 MAC.
 ///
 /// Inserts `0xFF, 0xFE` after the OUI (first 3 bytes) per the
@@ -62,6 +82,11 @@ impl<'a> Ieee802154Driver<'a> {
         self.config.short_addr
     }
 
+    /// The current logical channel.
+    pub fn channel(&self) -> u8 {
+        self.config.channel
+    }
+
     pub fn update_driver_config(&mut self, update_fn: impl Fn(&mut Config)) {
         update_fn(&mut self.config);
         self.config.rx_when_idle = true;
@@ -97,6 +122,40 @@ impl<'a> Ieee802154Driver<'a> {
         RX_SIGNAL.wait().await;
     }
 
+    /// Measure the peak energy on the current channel over `duration_us`
+    /// with the radio's energy detection, in dBm.
+    ///
+    /// `esp-radio` has no energy detection API, so receiving is stopped and
+    /// the measurement is run through the radio registers directly. The
+    /// interrupt handler of `esp-radio` ignores the ED done event, the
+    /// result is read once the duration has passed. Receiving resumes
+    /// afterwards.
+    pub async fn energy_detect(&mut self, duration_us: u32) -> i8 {
+        let regs = IEEE802154::regs();
+        // SAFETY: the opcodes, sample modes and durations written are valid
+        // for the registers, and `&mut self` keeps the driver from using the
+        // radio while the measurement runs
+        unsafe {
+            regs.command().modify(|_, w| w.opcode().bits(CMD_STOP));
+            regs.ed_scan_cfg()
+                .modify(|_, w| w.ed_sample_mode().bits(ED_SAMPLE_MODE_MAX));
+            regs.ed_scan_duration()
+                .modify(|_, w| w.ed_scan_duration().bits(duration_us / SYMBOL_PERIOD_US));
+            regs.command().modify(|_, w| w.opcode().bits(CMD_ED_START));
+        }
+
+        Timer::after_micros(u64::from(duration_us) + ED_SETTLE_US).await;
+
+        let rss = regs.ed_scan_cfg().read().ed_rss().bits().cast_signed();
+        // SAFETY: as above
+        unsafe {
+            regs.ed_scan_cfg()
+                .modify(|_, w| w.ed_sample_mode().bits(ED_SAMPLE_MODE_AVG));
+            regs.command().modify(|_, w| w.opcode().bits(CMD_RX_START));
+        }
+        rss
+    }
+
     /// Enter RX mode explicitly. Needed after channel changes or
     /// initial startup before the first TX triggers `rx_when_idle`.
     pub fn start_receive(&mut self) {
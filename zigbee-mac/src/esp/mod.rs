@@ -8,28 +8,42 @@ use esp_radio::ieee802154::Frame;
 use esp_radio::ieee802154::Ieee802154;
 use esp_radio::ieee802154::ReceivedFrame;
 use ieee802154::mac::Address;
+use ieee802154::mac::ExtendedAddress;
 use ieee802154::mac::FrameContent;
 use ieee802154::mac::FrameType;
 use ieee802154::mac::FrameVersion;
 use ieee802154::mac::Header;
+use ieee802154::mac::beacon::Beacon;
+use ieee802154::mac::beacon::GuaranteedTimeSlotInformation;
+use ieee802154::mac::beacon::PendingAddress;
+use ieee802154::mac::beacon::SuperframeSpecification;
+use ieee802154::mac::command::AssociationStatus;
 use ieee802154::mac::command::CapabilityInformation;
 use ieee802154::mac::command::Command;
+use ieee802154::mac::command::CoordinatorRealignmentData;
 use ieee802154::mac::security::SecurityContext;
 
 use crate::esp::driver::Ieee802154Driver;
 use crate::mlme::A_BASE_SUPER_FRAME_DURATION;
 use crate::mlme::A_RESPONSE_WAIT_TIME;
 use crate::mlme::AssociationResponse;
+use crate::mlme::EnergyDetect;
 use crate::mlme::MAX_IEEE802154_CHANNELS;
 use crate::mlme::MacError;
+use crate::mlme::MacIndication;
 use crate::mlme::Mlme;
 use crate::mlme::PanDescriptor;
 use crate::mlme::PanDescriptorList;
 use crate::mlme::ScanResult;
 use crate::mlme::ScanType;
+use crate::mlme::StartRequest;
+use crate::mlme::ZigbeeBeacon;
 
 mod driver;
 
+/// Maximum number of association responses waiting for a data request.
+const MAX_PENDING_ASSOCIATIONS: usize = 4;
+
 /// Wait for the first frame matching `$pat` within `$timeout_us` microseconds,
 /// skipping non-matching frames. Evaluates `$body` on match. Returns
 /// `Err(MacError::NoData)` if the timeout expires before a match.
@@ -58,6 +72,31 @@ macro_rules! recv_frame {
 pub struct EspMlme<'a> {
     driver: Ieee802154Driver<'a>,
     seq_number: u8,
+    /// Superframe started with MLME-START, answered on beacon requests.
+    beacon: Option<BeaconState>,
+    /// Association responses sent once the device polls for them.
+    pending_associations: Vec<PendingAssociation>,
+    /// Devices with frames queued for indirect transmission.
+    ///
+    /// The radio acknowledges every data request with the frame pending
+    /// bit set, a device without queued frames gets an empty data frame
+    /// instead (IEEE 802.15.4 §7.5.6.3).
+    pending_addresses: Vec<Address>,
+}
+
+/// Association response queued by MLME-ASSOCIATE.response.
+struct PendingAssociation {
+    device_address: ExtendedAddress,
+    association_address: u16,
+    status: AssociationStatus,
+}
+
+/// Parameters of the superframe this device is advertising.
+struct BeaconState {
+    pan_id: u16,
+    short_address: u16,
+    superframe_spec: SuperframeSpecification,
+    payload: ZigbeeBeacon,
 }
 
 impl<'a> EspMlme<'a> {
@@ -65,6 +104,9 @@ impl<'a> EspMlme<'a> {
         Self {
             driver: Ieee802154Driver::new(ieee802154, config),
             seq_number: 0,
+            beacon: None,
+            pending_associations: Vec::new(),
+            pending_addresses: Vec::new(),
         }
     }
 }
@@ -81,15 +123,183 @@ impl EspMlme<'_> {
     }
 
     /// Wait for the next frame from the hardware RX queue (indefinite).
+    ///
+    /// Beacon requests are answered here while a superframe is started
+    /// and are not passed on to the caller.
     async fn next_frame(&mut self) -> Result<ReceivedFrame, MacError> {
         loop {
             if let Some(result) = self.driver.poll_received() {
-                return result.map_err(MacError::RadioError);
+                let frame = result.map_err(MacError::RadioError)?;
+                if self.beacon.is_some()
+                    && matches!(
+                        frame.frame.content,
+                        FrameContent::Command(Command::BeaconRequest)
+                    )
+                {
+                    self.transmit_beacon().await?;
+                    continue;
+                }
+                return Ok(frame);
             }
             self.driver.wait_rx_available().await;
         }
     }
 
+    /// Transmit a beacon frame (IEEE 802.15.4 §7.2.2.1) for the superframe
+    /// started with MLME-START.
+    async fn transmit_beacon(&mut self) -> Result<(), MacError> {
+        let Some(beacon) = &self.beacon else {
+            return Ok(());
+        };
+        let source = Address::Short(
+            ieee802154::mac::PanId(beacon.pan_id),
+            ieee802154::mac::ShortAddress(beacon.short_address),
+        );
+        let superframe_spec = beacon.superframe_spec;
+        let payload = beacon.payload;
+
+        let seq = self.sequence_number();
+        let frame_header = Header {
+            frame_type: FrameType::Beacon,
+            frame_pending: false,
+            ack_request: false,
+            pan_id_compress: false,
+            seq_no_suppress: false,
+            ie_present: false,
+            version: FrameVersion::Ieee802154_2003,
+            seq,
+            destination: None,
+            source: Some(source),
+            auxiliary_security_header: None,
+        };
+        let frame_content = FrameContent::Beacon(Beacon {
+            superframe_spec,
+            guaranteed_time_slot_info: GuaranteedTimeSlotInformation::new(),
+            pending_address: PendingAddress::new(),
+        });
+
+        let mut buf = [0u8; 40];
+        let offset = &mut 0;
+        buf.write_with(
+            offset,
+            frame_header,
+            &Some(&mut SecurityContext::no_security()),
+        )?;
+        buf.write_with(offset, frame_content, ())?;
+        buf.write_with(offset, payload, ())?;
+        // 2-byte FCS placeholder, computed by the hardware
+        let total_len = *offset + 2;
+
+        self.driver.transmit(&buf[..total_len]).await?;
+        log::debug!("[MLME] tx beacon, len={total_len}");
+
+        Ok(())
+    }
+
+    /// Transmit the queued association response (IEEE 802.15.4 §7.3.2) for
+    /// `device_address`, if any.
+    async fn transmit_association_response(
+        &mut self,
+        device_address: ExtendedAddress,
+    ) -> Result<(), MacError> {
+        let Some(index) = self
+            .pending_associations
+            .iter()
+            .position(|p| p.device_address == device_address)
+        else {
+            return Ok(());
+        };
+        let pending = self.pending_associations.remove(index);
+        let pan_id = ieee802154::mac::PanId(self.beacon.as_ref().map_or(0xffff, |b| b.pan_id));
+
+        let seq = self.sequence_number();
+        let frame_header = Header {
+            frame_type: FrameType::MacCommand,
+            frame_pending: false,
+            ack_request: true,
+            pan_id_compress: true,
+            seq_no_suppress: false,
+            ie_present: false,
+            version: FrameVersion::Ieee802154_2003,
+            seq,
+            destination: Some(Address::Extended(pan_id, device_address)),
+            source: Some(Address::Extended(pan_id, self.driver.ieee_address())),
+            auxiliary_security_header: None,
+        };
+        let frame_content = FrameContent::Command(Command::AssociationResponse(
+            ieee802154::mac::ShortAddress(pending.association_address),
+            pending.status,
+        ));
+
+        let mut buf = [0u8; 32];
+        let offset = &mut 0;
+        buf.write_with(
+            offset,
+            frame_header,
+            &Some(&mut SecurityContext::no_security()),
+        )?;
+        buf.write_with(offset, frame_content, ())?;
+        // 2-byte FCS placeholder, computed by the hardware
+        let total_len = *offset + 2;
+
+        self.driver.transmit(&buf[..total_len]).await?;
+        log::debug!(
+            "[MLME-ASSOCIATE] response sent, short_addr=0x{:04x}",
+            pending.association_address
+        );
+
+        Ok(())
+    }
+
+    /// Transmit a MAC data frame carrying `payload`, with the frame pending
+    /// bit set if more frames are queued for `dest`.
+    async fn transmit_data_frame(
+        &mut self,
+        dest: Address,
+        payload: &[u8],
+        frame_pending: bool,
+    ) -> Result<(), MacError> {
+        let seq = self.sequence_number();
+        let source = Some(match self.driver.short_address() {
+            Some(short) => Address::Short(dest.pan_id(), ieee802154::mac::ShortAddress(short)),
+            None => Address::Extended(dest.pan_id(), self.driver.ieee_address()),
+        });
+
+        let frame_header = Header {
+            frame_type: FrameType::Data,
+            frame_pending,
+            ack_request: true,
+            pan_id_compress: source.is_some(),
+            seq_no_suppress: false,
+            ie_present: false,
+            version: FrameVersion::Ieee802154_2003,
+            seq,
+            destination: Some(dest),
+            source,
+            auxiliary_security_header: None,
+        };
+
+        let mut frame_buf = [0u8; 127];
+        let offset = &mut 0;
+        frame_buf.write_with(
+            offset,
+            frame_header,
+            &Some(&mut SecurityContext::no_security()),
+        )?;
+        let hdr_len = *offset;
+        let payload_len = payload.len().min(frame_buf.len() - hdr_len - 2);
+        frame_buf[hdr_len..hdr_len + payload_len].copy_from_slice(&payload[..payload_len]);
+        // 2-byte FCS placeholder (IEEE 802.15.4 §7.2.1.8) — the hardware
+        // computes the actual CRC-16 over the frame and overwrites these
+        // bytes during transmission
+        let total_len = hdr_len + payload_len + 2;
+
+        self.driver.transmit(&frame_buf[..total_len]).await?;
+        log::debug!("[MLME] tx data, len={total_len}");
+
+        Ok(())
+    }
+
     fn beacon_request_frame(&mut self) -> [u8; 10] {
         let seq_number = self.sequence_number();
         [0x3, 0x8, seq_number, 0xff, 0xff, 0xff, 0xff, 0x7, 0x0, 0x0]
@@ -136,6 +346,162 @@ impl EspMlme<'_> {
         Ok(Some(pds))
     }
 
+    /// Send an orphan notification (IEEE 802.15.4 §7.3.6) on `channel` and
+    /// wait aResponseWaitTime for a coordinator realignment addressed to
+    /// this device.
+    async fn scan_channel_orphan(
+        &mut self,
+        channel: u8,
+    ) -> Result<CoordinatorRealignmentData, MacError> {
+        self.flush();
+        self.driver.update_driver_config(|config| {
+            config.channel = channel;
+            config.promiscuous = true;
+        });
+        self.driver.start_receive();
+
+        let broadcast = ieee802154::mac::PanId(0xffff);
+        let ieee_address = self.driver.ieee_address();
+        let seq = self.sequence_number();
+        let frame_header = Header {
+            frame_type: FrameType::MacCommand,
+            frame_pending: false,
+            ack_request: false,
+            pan_id_compress: true,
+            seq_no_suppress: false,
+            ie_present: false,
+            version: FrameVersion::Ieee802154_2003,
+            seq,
+            destination: Some(Address::Short(
+                broadcast,
+                ieee802154::mac::ShortAddress(0xffff),
+            )),
+            source: Some(Address::Extended(broadcast, ieee_address)),
+            auxiliary_security_header: None,
+        };
+
+        let mut buf = [0u8; 24];
+        let offset = &mut 0;
+        buf.write_with(
+            offset,
+            frame_header,
+            &Some(&mut SecurityContext::no_security()),
+        )?;
+        buf.write_with(
+            offset,
+            FrameContent::Command(Command::OrphanNotification),
+            (),
+        )?;
+        // 2-byte FCS placeholder, computed by the hardware
+        let total_len = *offset + 2;
+        self.driver.transmit(&buf[..total_len]).await?;
+        log::debug!("[MLME-SCAN] sent orphan notification on channel {channel}");
+
+        let timeout_us = (A_RESPONSE_WAIT_TIME as u64) * 16;
+        let deadline = Timer::after_micros(timeout_us);
+        let mut deadline = core::pin::pin!(deadline);
+        loop {
+            match embassy_futures::select::select(&mut deadline, self.next_frame()).await {
+                Either::First(_) => return Err(MacError::NoData),
+                Either::Second(Ok(ReceivedFrame {
+                    frame:
+                        Frame {
+                            header:
+                                Header {
+                                    destination: Some(Address::Extended(_, destination)),
+                                    ..
+                                },
+                            content:
+                                FrameContent::Command(Command::CoordinatorRealignment(realignment)),
+                            ..
+                        },
+                    ..
+                })) if destination == ieee_address => return Ok(realignment),
+                Either::Second(_) => continue,
+            }
+        }
+    }
+
+    /// Energy detect scan (IEEE 802.15.4 §7.5.2.1.1).
+    ///
+    /// The peak energy of each channel is measured with the energy
+    /// detection of the radio over the scan duration.
+    async fn scan_energy(
+        &mut self,
+        channels: core::ops::Range<u8>,
+        duration: u8,
+    ) -> Result<ScanResult, MacError> {
+        log::debug!("[MLME-SCAN] start energy detect scan");
+
+        let current_channel = self.driver.channel();
+        let mut energy_detect_list = Vec::new();
+        for c in channels {
+            if (c as usize) >= MAX_IEEE802154_CHANNELS {
+                continue;
+            }
+
+            self.driver
+                .update_driver_config(|config| config.channel = c);
+            let rssi = self
+                .driver
+                .energy_detect(calculate_scan_duration_max_us(duration))
+                .await;
+            let energy = rssi_to_energy(rssi);
+            log::debug!("[MLME-SCAN] energy on channel {c}: {energy}");
+            energy_detect_list.push(EnergyDetect { channel: c, energy });
+        }
+
+        self.driver
+            .update_driver_config(|config| config.channel = current_channel);
+
+        Ok(ScanResult {
+            scan_type: ScanType::Ed,
+            pan_descriptor: Vec::new(),
+            energy_detect_list,
+            coordinator_realignment: None,
+        })
+    }
+
+    /// Orphan scan (IEEE 802.15.4 §7.5.2.1.4), stopping at the first
+    /// channel on which the coordinator realigned this device.
+    async fn scan_orphan(
+        &mut self,
+        channels: core::ops::Range<u8>,
+    ) -> Result<ScanResult, MacError> {
+        log::debug!("[MLME-SCAN] start orphan scan");
+
+        for c in channels {
+            if (c as usize) >= MAX_IEEE802154_CHANNELS {
+                continue;
+            }
+
+            match self.scan_channel_orphan(c).await {
+                Ok(realignment) => {
+                    self.driver.update_driver_config(|config| {
+                        config.promiscuous = false;
+                        config.pan_id = Some(realignment.pan_id.0);
+                        config.short_addr = Some(realignment.device_address.0);
+                    });
+                    log::debug!("[MLME-SCAN] realigned on channel {c}");
+                    return Ok(ScanResult {
+                        scan_type: ScanType::Orphan,
+                        pan_descriptor: Vec::new(),
+                        energy_detect_list: Vec::new(),
+                        coordinator_realignment: Some(realignment),
+                    });
+                }
+                Err(MacError::NoData) => (),
+                Err(e) => {
+                    log::error!("[MLME-SCAN] error on channel {c}: {e}");
+                }
+            }
+        }
+
+        self.driver
+            .update_driver_config(|config| config.promiscuous = false);
+        Err(MacError::NoBeacon)
+    }
+
     fn parse_beacon(&self, received: ReceivedFrame) -> Option<PanDescriptor> {
         match received {
             ReceivedFrame {
@@ -164,19 +530,14 @@ impl EspMlme<'_> {
                     }
                 };
 
-                Some(PanDescriptor {
+                Some(PanDescriptor::new(
                     channel,
-                    coord_addr_mode: match source {
-                        Address::Short(_, _) => 0x2,
-                        Address::Extended(_, _) => 0x3,
-                    },
-                    coord_pan_id: source.pan_id().0.into(),
-                    coord_address: source,
-                    superframe_spec: beacon_content.superframe_spec,
-                    link_quality: lqi,
-                    security_use: hdr.has_security(),
+                    source,
+                    beacon_content.superframe_spec,
+                    lqi,
+                    hdr.has_security(),
                     zigbee_beacon,
-                })
+                ))
             }
             other => {
                 log::debug!("[MLME-SCAN] received non-beacon frame: {other:?}");
@@ -263,6 +624,13 @@ fn calculate_scan_duration_max_us(duration: u8) -> u32 {
     16 * A_BASE_SUPER_FRAME_DURATION * (2 * (duration as u32) + 1)
 }
 
+/// Map a received signal strength to an ED value (IEEE 802.15.4 §6.9.7):
+/// 0x00 at the receiver sensitivity of -85 dBm, 0xff at 40 dB above it.
+fn rssi_to_energy(rssi: i8) -> u8 {
+    let above_sensitivity = (i16::from(rssi) + 85).clamp(0, 40);
+    (above_sensitivity * 255 / 40) as u8
+}
+
 impl Mlme for EspMlme<'_> {
     async fn scan_network(
         &mut self,
@@ -270,8 +638,11 @@ impl Mlme for EspMlme<'_> {
         channels: core::ops::Range<u8>,
         duration: u8,
     ) -> Result<ScanResult, MacError> {
-        if !matches!(scan_type, ScanType::Active) {
-            return Err(MacError::InvalidScanParams);
+        match scan_type {
+            ScanType::Active => {}
+            ScanType::Ed => return self.scan_energy(channels, duration).await,
+            ScanType::Orphan => return self.scan_orphan(channels).await,
+            _ => return Err(MacError::InvalidScanParams),
         }
 
         log::debug!("[MLME-SCAN] start scan");
@@ -296,6 +667,8 @@ impl Mlme for EspMlme<'_> {
         Ok(ScanResult {
             scan_type,
             pan_descriptor,
+            energy_detect_list: Vec::new(),
+            coordinator_realignment: None,
         })
     }
 
@@ -374,7 +747,7 @@ impl Mlme for EspMlme<'_> {
         &mut self,
         coord_address: Address,
         buf: &mut [u8],
-    ) -> Result<(usize, u8), MacError> {
+    ) -> Result<(usize, u8, bool), MacError> {
         self.flush();
         let data_req = self.data_request_frame(coord_address)?;
         self.driver.transmit(&data_req).await?;
@@ -383,57 +756,261 @@ impl Mlme for EspMlme<'_> {
         let timeout_us = (A_RESPONSE_WAIT_TIME as u64) * 16;
         recv_frame!(self, timeout_us,
             ReceivedFrame {
-                frame: Frame { content: FrameContent::Data, payload, .. },
+                frame: Frame { header, content: FrameContent::Data, payload, .. },
                 lqi,
                 ..
             } => {
                 let len = payload.len().min(buf.len());
                 log::debug!("[MLME-POLL] rx data len={len}");
                 buf[..len].copy_from_slice(&payload[..len]);
-                (len, lqi)
+                (len, lqi, header.frame_pending)
             },
         )
     }
 
     async fn transmit_data(&mut self, dest: Address, payload: &[u8]) -> Result<(), MacError> {
-        let seq = self.sequence_number();
-        let source = Some(match self.driver.short_address() {
-            Some(short) => Address::Short(dest.pan_id(), ieee802154::mac::ShortAddress(short)),
-            None => Address::Extended(dest.pan_id(), self.driver.ieee_address()),
+        self.transmit_data_frame(dest, payload, false).await
+    }
+
+    async fn transmit_indirect(
+        &mut self,
+        dest: Address,
+        payload: &[u8],
+        frame_pending: bool,
+    ) -> Result<(), MacError> {
+        self.transmit_data_frame(dest, payload, frame_pending)
+            .await?;
+        self.set_frame_pending(dest, frame_pending);
+        Ok(())
+    }
+
+    fn set_frame_pending(&mut self, dest: Address, pending: bool) {
+        let queued = self.pending_addresses.contains(&dest);
+        if pending && !queued {
+            self.pending_addresses.push(dest);
+        } else if !pending && queued {
+            self.pending_addresses.retain(|address| *address != dest);
+        }
+    }
+
+    async fn start(&mut self, request: StartRequest) -> Result<(), MacError> {
+        let channel = request.channel;
+        let pan_id = request.pan_id.0;
+        let short_address = request.short_address.0;
+        let pan_coordinator = request.pan_coordinator;
+        self.driver.update_driver_config(|config| {
+            *config = Default::default();
+            config.channel = channel;
+            config.pan_id = Some(pan_id);
+            config.short_addr = Some(short_address);
+            config.auto_ack_tx = true;
+            config.auto_ack_rx = true;
+            config.coordinator = pan_coordinator;
+        });
+        self.flush();
+        self.driver.start_receive();
+
+        self.beacon = Some(BeaconState {
+            pan_id,
+            short_address,
+            superframe_spec: SuperframeSpecification {
+                beacon_order: request.beacon_order,
+                superframe_order: request.superframe_order,
+                final_cap_slot: 15,
+                battery_life_extension: request.battery_life_extension,
+                pan_coordinator,
+                association_permit: false,
+            },
+            payload: request.beacon_payload,
+        });
+        log::debug!("[MLME-START] started PAN 0x{pan_id:04x} on channel {channel}");
+
+        Ok(())
+    }
+
+    fn set_association_permit(&mut self, permit: bool) {
+        if let Some(beacon) = &mut self.beacon {
+            beacon.superframe_spec.association_permit = permit;
+        }
+    }
+
+    fn set_channel(&mut self, channel: u8) {
+        self.driver
+            .update_driver_config(|config| config.channel = channel);
+    }
+
+    fn set_pan_id(&mut self, pan_id: ieee802154::mac::PanId) {
+        self.driver
+            .update_driver_config(|config| config.pan_id = Some(pan_id.0));
+        if let Some(beacon) = &mut self.beacon {
+            beacon.pan_id = pan_id.0;
+        }
+    }
+
+    fn set_short_address(&mut self, short_address: zigbee_types::ShortAddress) {
+        self.driver
+            .update_driver_config(|config| config.short_addr = Some(short_address.0));
+        if let Some(beacon) = &mut self.beacon {
+            beacon.short_address = short_address.0;
+        }
+    }
+
+    fn set_beacon_payload(&mut self, payload: ZigbeeBeacon) {
+        if let Some(beacon) = &mut self.beacon {
+            beacon.payload = payload;
+        }
+    }
+
+    async fn receive(&mut self, buf: &mut [u8]) -> Result<MacIndication, MacError> {
+        loop {
+            let received = self.next_frame().await?;
+            if matches!(received.frame.content, FrameContent::Beacon(_)) {
+                if let Some(pan_descriptor) = self.parse_beacon(received) {
+                    return Ok(MacIndication::Beacon { pan_descriptor });
+                }
+                continue;
+            }
+            let ReceivedFrame { frame, lqi, .. } = received;
+            match (frame.content, frame.header.source, frame.header.destination) {
+                (FrameContent::Data, Some(source), Some(destination)) => {
+                    let len = frame.payload.len().min(buf.len());
+                    buf[..len].copy_from_slice(&frame.payload[..len]);
+                    log::debug!("[MCPS-DATA] rx data len={len}");
+                    return Ok(MacIndication::Data {
+                        source,
+                        destination,
+                        len,
+                        lqi,
+                    });
+                }
+                (
+                    FrameContent::Command(Command::AssociationRequest(capability_information)),
+                    Some(Address::Extended(_, device_address)),
+                    _,
+                ) => {
+                    log::debug!("[MLME-ASSOCIATE] indication from {device_address:?}");
+                    return Ok(MacIndication::Associate {
+                        device_address: zigbee_types::IeeeAddress(device_address.0),
+                        capability_information,
+                    });
+                }
+                (
+                    FrameContent::Command(Command::OrphanNotification),
+                    Some(Address::Extended(_, orphan_address)),
+                    _,
+                ) => {
+                    log::debug!("[MLME-ORPHAN] indication from {orphan_address:?}");
+                    return Ok(MacIndication::Orphan {
+                        orphan_address: zigbee_types::IeeeAddress(orphan_address.0),
+                    });
+                }
+                (
+                    FrameContent::Command(Command::DataRequest),
+                    Some(Address::Extended(_, device_address)),
+                    _,
+                ) => {
+                    self.transmit_association_response(device_address).await?;
+                }
+                (FrameContent::Command(Command::DataRequest), Some(source), _) => {
+                    log::trace!("[MLME-POLL] indication from {source:?}");
+                    if !self.pending_addresses.contains(&source) {
+                        // the acknowledgement had the frame pending bit set
+                        self.transmit_data_frame(source, &[], false).await?;
+                    }
+                    return Ok(MacIndication::Poll { source });
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    async fn associate_response(
+        &mut self,
+        device_address: zigbee_types::IeeeAddress,
+        association_address: zigbee_types::ShortAddress,
+        status: AssociationStatus,
+    ) -> Result<(), MacError> {
+        if self.beacon.is_none() {
+            return Err(MacError::NotStarted);
+        }
+
+        let device_address = ExtendedAddress(device_address.0);
+        self.pending_associations
+            .retain(|p| p.device_address != device_address);
+        if self.pending_associations.len() >= MAX_PENDING_ASSOCIATIONS {
+            // drop the oldest response, its device has most likely given up
+            self.pending_associations.remove(0);
+        }
+        self.pending_associations.push(PendingAssociation {
+            device_address,
+            association_address: association_address.0,
+            status,
         });
 
+        Ok(())
+    }
+
+    async fn orphan_response(
+        &mut self,
+        orphan_address: zigbee_types::IeeeAddress,
+        short_address: zigbee_types::ShortAddress,
+        associated_member: bool,
+    ) -> Result<(), MacError> {
+        let Some(beacon) = &self.beacon else {
+            return Err(MacError::NotStarted);
+        };
+        if !associated_member {
+            return Ok(());
+        }
+        let pan_id = ieee802154::mac::PanId(beacon.pan_id);
+        let realignment = CoordinatorRealignmentData {
+            pan_id,
+            coordinator_address: ieee802154::mac::ShortAddress(beacon.short_address),
+            channel: self.driver.channel(),
+            device_address: ieee802154::mac::ShortAddress(short_address.0),
+            channel_page: None,
+        };
+
+        let seq = self.sequence_number();
         let frame_header = Header {
-            frame_type: FrameType::Data,
+            frame_type: FrameType::MacCommand,
             frame_pending: false,
             ack_request: true,
-            pan_id_compress: source.is_some(),
+            pan_id_compress: false,
             seq_no_suppress: false,
             ie_present: false,
             version: FrameVersion::Ieee802154_2003,
             seq,
-            destination: Some(dest),
-            source,
+            destination: Some(Address::Extended(
+                ieee802154::mac::PanId(0xffff),
+                ExtendedAddress(orphan_address.0),
+            )),
+            source: Some(Address::Extended(pan_id, self.driver.ieee_address())),
             auxiliary_security_header: None,
         };
+        let frame_content = FrameContent::Command(Command::CoordinatorRealignment(realignment));
 
-        let mut frame_buf = [0u8; 127];
+        let mut buf = [0u8; 40];
         let offset = &mut 0;
-        frame_buf.write_with(
+        buf.write_with(
             offset,
             frame_header,
             &Some(&mut SecurityContext::no_security()),
         )?;
-        let hdr_len = *offset;
-        let payload_len = payload.len().min(frame_buf.len() - hdr_len - 2);
-        frame_buf[hdr_len..hdr_len + payload_len].copy_from_slice(&payload[..payload_len]);
-        // 2-byte FCS placeholder (IEEE 802.15.4 §7.2.1.8) — the hardware
-        // computes the actual CRC-16 over the frame and overwrites these
-        // bytes during transmission
-        let total_len = hdr_len + payload_len + 2;
+        buf.write_with(offset, frame_content, ())?;
+        // 2-byte FCS placeholder, computed by the hardware
+        let total_len = *offset + 2;
 
-        self.driver.transmit(&frame_buf[..total_len]).await?;
-        log::debug!("[MLME] tx data, len={total_len}");
+        self.driver.transmit(&buf[..total_len]).await?;
+        log::debug!(
+            "[MLME-ORPHAN] realignment sent, short_addr=0x{:04x}",
+            short_address.0
+        );
 
         Ok(())
     }
+
+    fn extended_address(&self) -> zigbee_types::IeeeAddress {
+        zigbee_types::IeeeAddress(self.driver.ieee_address().0)
+    }
 }
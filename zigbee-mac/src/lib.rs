@@ -15,5 +15,7 @@ pub use ieee802154::mac::PanId;
 pub use ieee802154::mac::ShortAddress as MacShortAddress;
 pub use ieee802154::mac::beacon::BeaconOrder;
 pub use ieee802154::mac::beacon::SuperframeOrder;
+pub use ieee802154::mac::beacon::SuperframeSpecification;
 pub use ieee802154::mac::command::AssociationStatus;
 pub use ieee802154::mac::command::CapabilityInformation;
+pub use ieee802154::mac::command::CoordinatorRealignmentData;
@@ -1,7 +1,11 @@
 use ieee802154::mac::Address;
+use ieee802154::mac::PanId;
+use ieee802154::mac::beacon::BeaconOrder;
+use ieee802154::mac::beacon::SuperframeOrder;
 use ieee802154::mac::beacon::SuperframeSpecification;
 use ieee802154::mac::command::AssociationStatus;
 use ieee802154::mac::command::CapabilityInformation;
+use ieee802154::mac::command::CoordinatorRealignmentData;
 use thiserror::Error;
 use zigbee_macros::impl_byte;
 use zigbee_types::ByteArray;
@@ -22,6 +26,15 @@ pub const A_BASE_SUPER_FRAME_DURATION: u32 = A_BASE_SLOT_DURATION * A_NUM_SUPER_
 pub const A_RESPONSE_WAIT_TIME: u32 = 32 * A_BASE_SUPER_FRAME_DURATION;
 
 pub trait Mlme {
+    /// MLME-SCAN.request (IEEE 802.15.4 §7.1.11.1).
+    ///
+    /// An energy detect scan measures the peak energy on each channel
+    /// over the scan duration and reports it in the energy detect list.
+    ///
+    /// An orphan scan sends an orphan notification on each channel and
+    /// stops at the first coordinator realignment addressed to this
+    /// device, adopting its PAN identifier and short address. It fails
+    /// with `MacError::NoBeacon` when no coordinator answered.
     async fn scan_network(
         &mut self,
         ty: ScanType,
@@ -42,14 +55,15 @@ pub trait Mlme {
     /// receive mode to capture both the ACK (with frame-pending check)
     /// and the subsequent data frame in a single uninterrupted session.
     ///
-    /// Returns `Ok((bytes_written, lqi))` on success, or
-    /// `Err(MacError::NoData)` if the ACK has frame-pending clear or
+    /// Returns `Ok((bytes_written, lqi, frame_pending))` on success, where
+    /// `frame_pending` is the frame pending bit of the received data frame,
+    /// or `Err(MacError::NoData)` if the ACK has frame-pending clear or
     /// no data frame arrives within the timeout.
     async fn poll_data(
         &mut self,
         coord_address: Address,
         buf: &mut [u8],
-    ) -> Result<(usize, u8), MacError>;
+    ) -> Result<(usize, u8, bool), MacError>;
 
     /// Transmit a MAC data frame carrying the given NWK-layer payload.
     ///
@@ -57,6 +71,98 @@ pub trait Mlme {
     /// sequence number, addressing) and appends `payload` as the MAC
     /// service data unit.
     async fn transmit_data(&mut self, dest: Address, payload: &[u8]) -> Result<(), MacError>;
+
+    /// Transmit a MAC data frame to a device which polled for it with a
+    /// data request (indirect transmission, IEEE 802.15.4 §7.5.6.3).
+    ///
+    /// `frame_pending` is set in the frame control field when more frames
+    /// are queued for the device, which then polls again right away.
+    async fn transmit_indirect(
+        &mut self,
+        dest: Address,
+        payload: &[u8],
+        frame_pending: bool,
+    ) -> Result<(), MacError>;
+
+    /// Add or remove `dest` in the table of devices with frames pending for
+    /// indirect transmission (IEEE 802.15.4 §7.5.6.3).
+    ///
+    /// The acknowledgement to a data request of a device in the table has
+    /// the frame pending bit set, the device then waits for its frame.
+    fn set_frame_pending(&mut self, dest: Address, pending: bool);
+
+    /// MLME-START.request (IEEE 802.15.4 §7.1.14.1).
+    ///
+    /// Configures the PAN identifier, logical channel and short address
+    /// and starts answering beacon requests with `beacon_payload`. With
+    /// `pan_coordinator` set the device becomes the coordinator of a new
+    /// PAN.
+    async fn start(&mut self, request: StartRequest) -> Result<(), MacError>;
+
+    /// Set `macAssociationPermit` (IEEE 802.15.4 §7.4.2), advertised in the
+    /// superframe specification of outgoing beacons.
+    fn set_association_permit(&mut self, permit: bool);
+
+    /// Set `phyCurrentChannel` (IEEE 802.15.4 §6.4.2).
+    fn set_channel(&mut self, channel: u8);
+
+    /// Set `macPANId` (IEEE 802.15.4 §7.4.2).
+    fn set_pan_id(&mut self, pan_id: PanId);
+
+    /// Set `macShortAddress` (IEEE 802.15.4 §7.4.2).
+    fn set_short_address(&mut self, short_address: ShortAddress);
+
+    /// Set `macBeaconPayload` (IEEE 802.15.4 §7.4.2) of the superframe
+    /// started with [`Mlme::start`].
+    fn set_beacon_payload(&mut self, payload: ZigbeeBeacon);
+
+    /// Wait for the next inbound frame (MCPS-DATA.indication or an
+    /// MLME indication, IEEE 802.15.4 §7.1).
+    ///
+    /// The MSDU of a data frame is copied to the start of `buf`.
+    async fn receive(&mut self, buf: &mut [u8]) -> Result<MacIndication, MacError>;
+
+    /// MLME-ASSOCIATE.response (IEEE 802.15.4 §7.1.3.3).
+    ///
+    /// Queues the association response command for `device_address`; it
+    /// is sent indirectly once the device polls with a data request.
+    async fn associate_response(
+        &mut self,
+        device_address: IeeeAddress,
+        association_address: ShortAddress,
+        status: AssociationStatus,
+    ) -> Result<(), MacError>;
+
+    /// MLME-ORPHAN.response (IEEE 802.15.4 §7.1.8.2).
+    ///
+    /// With `associated_member` set a coordinator realignment command
+    /// carrying `short_address` is sent to the orphaned device, otherwise
+    /// the notification is ignored.
+    async fn orphan_response(
+        &mut self,
+        orphan_address: IeeeAddress,
+        short_address: ShortAddress,
+        associated_member: bool,
+    ) -> Result<(), MacError>;
+
+    /// The IEEE 802.15.4 extended address of this device
+    /// (`aExtendedAddress`, IEEE 802.15.4 §7.4.1).
+    fn extended_address(&self) -> IeeeAddress;
+}
+
+/// Parameters of MLME-START.request (IEEE 802.15.4 §7.1.14.1.1).
+#[derive(Debug, Clone)]
+pub struct StartRequest {
+    pub pan_id: PanId,
+    pub channel: u8,
+    /// Short address to use as `macShortAddress` on the new superframe.
+    pub short_address: ShortAddress,
+    pub pan_coordinator: bool,
+    pub beacon_order: BeaconOrder,
+    pub superframe_order: SuperframeOrder,
+    pub battery_life_extension: bool,
+    /// Payload appended to every beacon frame (`macBeaconPayload`).
+    pub beacon_payload: ZigbeeBeacon,
 }
 
 #[derive(Debug)]
@@ -66,6 +172,32 @@ pub struct AssociationResponse {
     pub status: AssociationStatus,
 }
 
+/// Inbound frame reported by [`Mlme::receive`].
+#[derive(Debug)]
+pub enum MacIndication {
+    /// MCPS-DATA.indication (IEEE 802.15.4 §7.1.1.3).
+    Data {
+        source: Address,
+        destination: Address,
+        /// Length of the MSDU copied to the receive buffer.
+        len: usize,
+        lqi: u8,
+    },
+    /// MLME-ASSOCIATE.indication (IEEE 802.15.4 §7.1.3.2).
+    Associate {
+        device_address: IeeeAddress,
+        capability_information: CapabilityInformation,
+    },
+    /// MLME-ORPHAN.indication (IEEE 802.15.4 §7.1.8.1).
+    Orphan { orphan_address: IeeeAddress },
+    /// MLME-POLL.indication, a data request command was received from
+    /// `source`.
+    Poll { source: Address },
+    /// MLME-BEACON-NOTIFY.indication (IEEE 802.15.4 §7.1.5.1), a beacon was
+    /// received outside of a scan.
+    Beacon { pan_descriptor: PanDescriptor },
+}
+
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ScanType {
@@ -89,6 +221,8 @@ pub enum MacError {
     NoData,
     #[error("no acknowledgment received")]
     NoAck,
+    #[error("no superframe started")]
+    NotStarted,
     #[cfg(feature = "esp32c6")]
     #[error("radio error")]
     RadioError(#[from] esp_radio::ieee802154::Error),
@@ -109,8 +243,26 @@ pub type PanDescriptorList = heapless::Vec<PanDescriptor, MAX_PAN_DESCRIPTOR_SIZ
 pub struct ScanResult {
     pub scan_type: ScanType,
     pub pan_descriptor: PanDescriptorList,
+    /// Energy measured on each channel during an energy detect scan.
+    pub energy_detect_list: EnergyDetectList,
+    /// Coordinator realignment received during an orphan scan.
+    pub coordinator_realignment: Option<CoordinatorRealignmentData>,
 }
 
+/// Peak energy measured on a channel during an energy detect scan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnergyDetect {
+    pub channel: u8,
+    /// ED value (IEEE 802.15.4 §6.9.7), 0x00 at the receiver sensitivity
+    /// up to 0xff for 40 dB above it.
+    pub energy: u8,
+}
+
+#[cfg(feature = "alloc")]
+pub type EnergyDetectList = alloc::vec::Vec<EnergyDetect>;
+#[cfg(not(feature = "alloc"))]
+pub type EnergyDetectList = heapless::Vec<EnergyDetect, MAX_IEEE802154_CHANNELS>;
+
 #[non_exhaustive]
 #[derive(Debug)]
 pub struct PanDescriptor {
@@ -128,8 +280,34 @@ pub struct PanDescriptor {
     pub zigbee_beacon: ZigbeeBeacon,
 }
 
+impl PanDescriptor {
+    /// Describe a beacon received from `coord_address` on `channel`.
+    pub fn new(
+        channel: u8,
+        coord_address: Address,
+        superframe_spec: SuperframeSpecification,
+        link_quality: u8,
+        security_use: bool,
+        zigbee_beacon: ZigbeeBeacon,
+    ) -> Self {
+        Self {
+            channel,
+            coord_addr_mode: match coord_address {
+                Address::Short(_, _) => 0x2,
+                Address::Extended(_, _) => 0x3,
+            },
+            coord_pan_id: coord_address.pan_id().0.into(),
+            coord_address,
+            superframe_spec,
+            link_quality,
+            security_use,
+            zigbee_beacon,
+        }
+    }
+}
+
 impl_byte! {
-    #[derive(Debug)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct ZigbeeBeacon {
         pub protocol_id: u8,
         pub stack_profile: StackProfile,
@@ -14,52 +14,6 @@ macro_rules! construct_ib {
     ) => {
         pub type ${ concat($ib_name, Storage) } = ::zigbee_types::storage::InMemoryStorage<{ ${ concat($ib_name, Id) }::BUFFER_SIZE }>;
 
-        static mut IB: Option<$ib_name<${ concat($ib_name, Storage) }>> = None;
-
-        /// Initializes the IB.
-        pub fn init(storage: ${ concat($ib_name, Storage) }) {
-            // SAFETY: NIB can only be initialized once
-            unsafe {
-                if IB.is_some() {
-                    panic!(concat!(stringify!($ib_name), " already initialized"));
-                }
-                let ib = $ib_name::new(storage);
-                ib.init();
-                IB = Some(ib);
-            }
-        }
-
-        /// Returns a reference to the IB.
-        pub fn get_ref() -> &'static $ib_name<${ concat($ib_name, Storage) }> {
-            // SAFETY: IB is mutated only in init once
-            unsafe { IB.as_ref().expect(concat!(stringify!($ib_name), " not initialized")) }
-        }
-
-        /// Initialize the IB if not already initialized, otherwise do nothing.
-        #[cfg(test)]
-        pub fn try_init(storage: ${ concat($ib_name, Storage) }) {
-            // SAFETY: only called at test setup before concurrent access
-            unsafe {
-                if IB.is_none() {
-                    let ib = $ib_name::new(storage);
-                    ib.init();
-                    IB = Some(ib);
-                }
-            }
-        }
-
-        /// Re-write default values to the existing IB storage.
-        #[cfg(test)]
-        pub fn reset() {
-            // SAFETY: only called at test setup; the singleton reference
-            // is not reallocated, just its storage contents are overwritten
-            unsafe {
-                if let Some(ref ib) = IB {
-                    ib.init();
-                }
-            }
-        }
-
         #[repr(usize)]
         #[allow(non_camel_case_types)]
         #[derive(Copy, Clone, Eq, PartialEq)]
@@ -110,6 +64,15 @@ macro_rules! construct_ib {
             storage: ::spin::Mutex<C>,
         }
 
+        impl Default for $ib_name<${ concat($ib_name, Storage) }> {
+            /// Creates an IB in memory holding the default values.
+            fn default() -> Self {
+                let ib = Self::new(Default::default());
+                ib.init();
+                ib
+            }
+        }
+
         #[allow(clippy::cast_possible_truncation)]
         impl<C: ::embedded_storage::Storage> $ib_name<C> {
             pub fn new(storage: C) -> Self {
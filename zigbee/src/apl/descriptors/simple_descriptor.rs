@@ -70,7 +70,7 @@ mod tests {
         for i in 0..APPLICATION_INPUT_CLUSTER_COUNT as usize {
             assert_eq!(
                 simple_descriptor.application_input_cluster_list[i],
-                (i + 1) as u8
+                u8::try_from(i + 1).unwrap()
             );
         }
         assert_eq!(
@@ -81,7 +81,7 @@ mod tests {
         for i in 0..APPLICATION_OUTPUT_CLUSTER_COUNT as usize {
             assert_eq!(
                 simple_descriptor.application_output_cluster_list[i],
-                (i + 2) as u8
+                u8::try_from(i + 2).unwrap()
             );
         }
     }
@@ -118,7 +118,7 @@ mod tests {
         for i in 0..APPLICATION_INPUT_CLUSTER_COUNT as usize {
             assert_eq!(
                 simple_descriptor.application_input_cluster_list[i],
-                (i + 1) as u8
+                u8::try_from(i + 1).unwrap()
             );
         }
         assert_eq!(simple_descriptor.application_output_cluster_count, 0);
@@ -159,7 +159,7 @@ mod tests {
         for i in 0..APPLICATION_OUTPUT_CLUSTER_COUNT as usize {
             assert_eq!(
                 simple_descriptor.application_output_cluster_list[i],
-                (i + 2) as u8
+                u8::try_from(i + 2).unwrap()
             );
         }
     }
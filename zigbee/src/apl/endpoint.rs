@@ -0,0 +1,31 @@
+//! Application Endpoints
+//!
+//! See Section 2.3.1
+//!
+//! Application objects are hosted on endpoints 1 - 240 of a node. The APS
+//! hands received data to the endpoint addressed by the frame.
+
+use crate::aps::apsde::ApsdeSapIndication;
+use crate::nwk::nlme::NwkIndication;
+
+/// Lowest endpoint available to application objects.
+pub const MIN_APPLICATION_ENDPOINT: u8 = 0x01;
+/// Highest endpoint available to application objects.
+pub const MAX_APPLICATION_ENDPOINT: u8 = 0xf0;
+/// Endpoint addressing all application endpoints of a node.
+pub const BROADCAST_ENDPOINT: u8 = 0xff;
+
+/// An application object hosted on an endpoint.
+pub trait Endpoint {
+    /// Endpoint number of the application object.
+    fn endpoint(&self) -> u8;
+
+    /// Data received for this endpoint (APSDE-DATA.indication).
+    fn data_indication(&mut self, indication: &ApsdeSapIndication);
+
+    /// Network management event of the node, an NLME-JOIN,
+    /// NLME-LEAVE or NLME-NWK-STATUS.indication.
+    ///
+    /// Every endpoint is told, the default ignores the event.
+    fn nlme_indication(&mut self, _indication: &NwkIndication) {}
+}
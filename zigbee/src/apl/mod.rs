@@ -6,3 +6,4 @@
 //! objects are hosted on ZigBee devices.
 
 pub mod descriptors;
+pub mod endpoint;
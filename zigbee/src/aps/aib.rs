@@ -10,6 +10,25 @@ const MAX_APS_GROUP_TABLE: usize = 2; // TODO
 const MAX_APS_MAX_WINDOW_SIZE: usize = 2; // TODO
 const MAX_APS_DEVICE_KEY_PAIR_SET: usize = 2; // TODO
 
+/// AIB attribute identifiers (Table 2-24 and Table 4-36).
+pub mod attribute_id {
+    pub const DEVICE_KEY_PAIR_SET: u8 = 0xaa;
+    pub const TRUST_CENTER_ADDRESS: u8 = 0xab;
+    pub const SECURITY_TIMEOUT_PERIOD: u8 = 0xac;
+    pub const BINDING_TABLE: u8 = 0xc1;
+    pub const DESIGNATED_COORDINATOR: u8 = 0xc2;
+    pub const CHANNEL_MASK_LIST: u8 = 0xc3;
+    pub const USE_EXTENDED_PAN_ID: u8 = 0xc4;
+    pub const GROUP_TABLE: u8 = 0xc5;
+    pub const NON_MEMBER_RADIUS: u8 = 0xc6;
+    pub const USE_INSECURE_JOIN: u8 = 0xc8;
+    pub const INTERFRAME_DELAY: u8 = 0xc9;
+    pub const LAST_CHANNEL_ENERGY: u8 = 0xca;
+    pub const LAST_CHANNEL_FAILURE_RATE: u8 = 0xcb;
+    pub const CHANNEL_TIMER: u8 = 0xcc;
+    pub const MAX_WINDOW_SIZE: u8 = 0xcd;
+}
+
 construct_ib! {
     /// 2.2.7.2 - AIB (APS Information Base Attributes)
     pub struct Aib {
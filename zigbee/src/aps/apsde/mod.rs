@@ -13,11 +13,17 @@
 //! * Fragmentation
 #![allow(dead_code)]
 
+use heapless::Vec;
+
 use super::types::Address;
 use super::types::DstAddrMode;
 use super::types::SrcAddrMode;
 use super::types::TxOptions;
 use crate::aps::types;
+use crate::nwk::nlde::MAX_NSDU_LENGTH;
+
+/// Longest ASDU passed to an endpoint.
+pub const MAX_ASDU_LENGTH: usize = MAX_NSDU_LENGTH;
 
 /// Application support sub-layer data entity – service access point
 ///
@@ -131,20 +137,20 @@ pub enum SecurityStatus {
     SecuredLinkKey,
 }
 
-// 2.2.4.1.3
+/// APSDE-DATA.indication (§2.2.4.1.3).
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct ApsdeSapIndication {
-    dst_addr_mode: DstAddrMode,
-    dst_address: u8,
-    dst_endpoint: u8,
-    src_addr_mode: SrcAddrMode,
-    src_address: u64,
-    src_endpoint: types::SrcEndpoint,
-    profile_id: u16,
-    cluster_id: u16,
-    asdulength: u8,
-    status: ApsdeSapIndicationStatus,
-    security_status: SecurityStatus,
-    link_quality: u8,
-    rx_time: u8,
+    pub dst_addr_mode: DstAddrMode,
+    pub dst_address: Address,
+    pub dst_endpoint: u8,
+    pub src_addr_mode: SrcAddrMode,
+    pub src_address: Address,
+    pub src_endpoint: u8,
+    pub profile_id: u16,
+    pub cluster_id: u16,
+    pub asdu: Vec<u8, MAX_ASDU_LENGTH>,
+    pub status: ApsdeSapIndicationStatus,
+    pub security_status: SecurityStatus,
+    pub link_quality: u8,
+    pub rx_time: u8,
 }
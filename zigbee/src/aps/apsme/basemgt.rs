@@ -8,6 +8,7 @@
 #![allow(missing_docs)]
 use crate::aps::types::Address;
 use crate::aps::types::{self};
+use crate::nwk::nlme::management::AttributeValue;
 
 type DstAddrMode = u8;
 /// 2.2.4.3.1 - APSME-BIND.request
@@ -75,9 +76,12 @@ pub struct ApsmeUnbindConfirm {
 /// 2.2.4.4.2 - APSME-GET.confirm
 #[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct ApsmeGetConfirm {
-    pub(crate) status: ApsmeGetConfirmStatus,
-    pub(crate) attribute: u8,
-    pub(crate) attribute_length: u8,
+    pub status: ApsmeGetConfirmStatus,
+    /// Identifier of the attribute, see [`crate::aps::aib::attribute_id`].
+    pub attribute: u8,
+    /// Value of the attribute if the status is
+    /// [`ApsmeGetConfirmStatus::Success`].
+    pub value: Option<AttributeValue>,
 }
 
 #[derive(Debug, Clone, Default, PartialEq, Eq)]
@@ -87,6 +91,14 @@ pub enum ApsmeGetConfirmStatus {
     UnsupportedAttribute,
 }
 
+/// 2.2.4.4.3 - APSME-SET.request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApsmeSetRequest {
+    /// Identifier of the attribute, see [`crate::aps::aib::attribute_id`].
+    pub attribute: u8,
+    pub value: AttributeValue,
+}
+
 #[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub enum ApsmeSetConfirmStatus {
     #[default]
@@ -98,8 +110,8 @@ pub enum ApsmeSetConfirmStatus {
 /// 2.2.4.4.4 - APSME-SET.confirm
 #[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct ApsmeSetConfirm {
-    pub(crate) status: ApsmeSetConfirmStatus,
-    pub(crate) identifier: u8,
+    pub status: ApsmeSetConfirmStatus,
+    pub attribute: u8,
 }
 
 /// 2.2.4.5.1 - APSME-ADD-GROUP.request
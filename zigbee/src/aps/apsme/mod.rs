@@ -24,13 +24,19 @@ use basemgt::ApsmeRemoveAllGroupsRequest;
 use basemgt::ApsmeRemoveGroupConfirm;
 use basemgt::ApsmeRemoveGroupRequest;
 use basemgt::ApsmeSetConfirm;
+use basemgt::ApsmeSetConfirmStatus;
+use basemgt::ApsmeSetRequest;
 use basemgt::ApsmeUnbindConfirm;
 use basemgt::ApsmeUnbindRequest;
 use basemgt::ApsmeUnbindRequestStatus;
 use byte::BytesExt;
+use byte::TryRead;
 use zigbee_types::IeeeAddress;
 use zigbee_types::ShortAddress;
 
+use super::aib::Aib;
+use super::aib::AibStorage;
+use super::aib::attribute_id;
 use super::binding::ApsBindingTable;
 use super::frame::CommandFrame;
 use super::frame::Frame;
@@ -43,11 +49,30 @@ use super::types::Address;
 use super::types::TxOptions;
 use crate::nwk::nlme::NetworkError;
 use crate::nwk::nlme::Nlme;
+use crate::nwk::nlme::management::AttributeValue;
 use crate::security::SecurityContext;
+use crate::security::frame::AuxFrameHeader;
+use crate::security::frame::KeyIdentifier;
 
 pub mod basemgt;
 pub mod groupmgt;
 
+/// Maximum number of acknowledged transmissions waiting for their APS
+/// acknowledgement.
+const MAX_PENDING_ACKS: usize = 4;
+
+/// An APS frame passed up by the APSME along with how it was secured.
+pub(crate) struct ApsFrameIndication<'a> {
+    /// The parsed frame, decrypted when it was APS secured.
+    pub frame: Frame<'a>,
+    /// Whether the APS security flag was set.
+    pub secured: bool,
+    /// The key a secured frame was protected with.
+    pub key_identifier: Option<KeyIdentifier>,
+    /// Extended source address from the auxiliary header of a secured frame.
+    pub source: Option<IeeeAddress>,
+}
+
 /// Application support sub-layer management service - service access point
 ///
 /// 2.2.4.2
@@ -61,6 +86,10 @@ pub trait ApsmeSap {
     /// 2.2.4.3.3 - request to unbind two devices, or to unbind a device from a
     /// group
     fn unbind_request(&mut self, request: ApsmeUnbindRequest) -> ApsmeUnbindConfirm;
+    /// 2.2.4.4.1 - read an attribute of `aib` by its identifier
+    fn get(&self, aib: &Aib<AibStorage>, attribute: u8) -> ApsmeGetConfirm;
+    /// 2.2.4.4.3 - write an attribute of `aib` by its identifier
+    fn set(&mut self, aib: &Aib<AibStorage>, request: ApsmeSetRequest) -> ApsmeSetConfirm;
     /// 2.2.4.5.1 - APSME-ADD-GROUP.request
     fn add_group(&self, request: ApsmeAddGroupRequest) -> ApsmeAddGroupConfirm;
     /// 2.2.4.5.3 - APSME-REMOVE-GROUP.request
@@ -79,6 +108,9 @@ pub(crate) struct Apsme {
     pub(crate) joined_network: Option<Address>,
     /// apsCounter AIB attribute (§4.4.11)
     pub(crate) aps_counter: u8,
+    /// APS counters of the acknowledged transmissions waiting for their
+    /// acknowledgement, the device fast polls meanwhile.
+    pending_acks: heapless::Vec<u8, MAX_PENDING_ACKS>,
 }
 
 impl Apsme {
@@ -88,6 +120,7 @@ impl Apsme {
             binding_table: ApsBindingTable::new(),
             joined_network: None,
             aps_counter: 0,
+            pending_acks: heapless::Vec::new(),
         }
     }
 
@@ -128,7 +161,7 @@ impl Apsme {
         let mut buf = [0u8; 128];
         let len = if aps_secure {
             let aps_frame = Frame::ApsCommand(CommandFrame { header, command });
-            let cx = SecurityContext::get();
+            let cx = nlme.security_context();
             cx.encrypt_aps_frame_in_place(aps_frame, &mut buf, dest_ieee, TxOptions::default())?
         } else {
             let offset = &mut 0;
@@ -149,20 +182,107 @@ impl Apsme {
     ) -> Result<Command, NetworkError> {
         let mut buf = [0u8; 128];
         let mut nwk_data = nlme.poll_nwk_data(&mut buf, retries).await?;
+        let header = &nwk_data.header;
+        let nwk_source = header
+            .frame_control
+            .security_flag()
+            .then_some(header.source);
 
         // SAFETY: we can safely take a &mut since it references the buf above
         let aps_buf = unsafe { nwk_data.payload_as_mut() };
-        let cx = SecurityContext::get();
-        let aps_frame = cx.decrypt_aps_frame_in_place(aps_buf)?;
-
-        let Frame::ApsCommand(CommandFrame { command, .. }) = aps_frame else {
+        let ApsFrameIndication {
+            frame,
+            secured,
+            source,
+            ..
+        } = self.frame_indication(nlme, aps_buf)?;
+
+        let Frame::ApsCommand(CommandFrame { command, .. }) = frame else {
             return Err(NetworkError::ParseError);
         };
+        self.command_indication(nlme, &command, secured, source, nwk_source)?;
 
         Ok(command)
     }
 
+    /// Parse the APS frame in the NSDU `buf`, decrypting it in place when
+    /// the APS security flag is set (§4.4.1.2).
+    pub(crate) fn frame_indication<'a, M: zigbee_mac::mlme::Mlme>(
+        &self,
+        nlme: &Nlme<M>,
+        buf: &'a mut [u8],
+    ) -> Result<ApsFrameIndication<'a>, NetworkError> {
+        let (header, len) = Header::try_read(buf, ())?;
+        if header.frame_control.frame_type() == FrameType::InterPan {
+            return Err(NetworkError::ParseError);
+        }
+        if header.frame_control.security_flag() {
+            let (aux_header, _) = AuxFrameHeader::try_read(&buf[len..], ())?;
+            let cx = nlme.security_context();
+            return Ok(ApsFrameIndication {
+                frame: cx.decrypt_aps_frame_in_place(buf)?,
+                secured: true,
+                key_identifier: Some(aux_header.security_control.key_identifier()),
+                source: aux_header.source_address,
+            });
+        }
+        Ok(ApsFrameIndication {
+            frame: Frame::from_payload(header, &buf[len..])?,
+            secured: false,
+            key_identifier: None,
+            source: None,
+        })
+    }
+
+    /// Process the security services of a received APS command (§4.4).
+    ///
+    /// A Switch-Key command activates the network key it names. It is only
+    /// accepted from the Trust Center, either APS secured or NWK secured
+    /// (§4.4.9). `nwk_source` is the NWK originator of a NWK secured frame.
+    pub(crate) fn command_indication<M: zigbee_mac::mlme::Mlme>(
+        &self,
+        nlme: &Nlme<M>,
+        command: &Command,
+        secured: bool,
+        source: Option<IeeeAddress>,
+        nwk_source: Option<ShortAddress>,
+    ) -> Result<(), NetworkError> {
+        if let Command::SwitchKey(switch_key) = command {
+            let trust_center_address = nlme.aib().trust_center_address();
+            let from_trust_center = if secured {
+                source == Some(trust_center_address)
+            } else {
+                nwk_source
+                    == Some(Self::trust_center_network_address(
+                        nlme,
+                        trust_center_address,
+                    ))
+            };
+            if !from_trust_center {
+                log::debug!("[APSME] dropping Switch-Key not secured by the Trust Center");
+                return Ok(());
+            }
+            let cx = nlme.security_context();
+            cx.switch_network_key(switch_key.sequence_number)?;
+        }
+        Ok(())
+    }
+
+    /// Network address of the Trust Center, from nwkAddressMap or the
+    /// coordinator of a centralized security network (§4.6.1.1).
+    fn trust_center_network_address<M: zigbee_mac::mlme::Mlme>(
+        nlme: &Nlme<M>,
+        trust_center_address: IeeeAddress,
+    ) -> ShortAddress {
+        nlme.lookup_network_address(trust_center_address)
+            .unwrap_or(ShortAddress(0x0000))
+    }
+
     /// Send a unicast APS data frame to a specific destination (§2.2.5.1).
+    ///
+    /// With `ack_request` the destination acknowledges the frame, the
+    /// device polls at the short poll interval until the acknowledgement
+    /// arrives, see [`Apsme::ack_indication`].
     pub(crate) async fn unicast_data<M: zigbee_mac::mlme::Mlme>(
         &mut self,
         nlme: &mut Nlme<M>,
@@ -171,13 +291,15 @@ impl Apsme {
         cluster_id: u16,
         profile_id: u16,
         src_endpoint: u8,
+        ack_request: bool,
         payload: &[u8],
     ) -> Result<(), NetworkError> {
         self.aps_counter = self.aps_counter.wrapping_add(1);
 
         let frame_control = FrameControl::default()
             .set_frame_type(FrameType::Data)
-            .set_delivery_mode(DeliveryMode::Unicast);
+            .set_delivery_mode(DeliveryMode::Unicast)
+            .set_ack_request(ack_request);
 
         let header = Header {
             frame_control,
@@ -199,7 +321,34 @@ impl Apsme {
         buf[hdr_len..hdr_len + payload_len].copy_from_slice(&payload[..payload_len]);
 
         nlme.send_data(destination, false, &buf[..hdr_len + payload_len])
-            .await
+            .await?;
+
+        if ack_request {
+            if self.pending_acks.push(self.aps_counter).is_ok() {
+                nlme.start_fast_poll();
+            } else {
+                log::debug!("[APSME] too many frames waiting for an acknowledgement");
+            }
+        }
+        Ok(())
+    }
+
+    /// APS acknowledgement `header` received (§2.2.8.4.2), ends the fast
+    /// polling for the acknowledged transmission.
+    pub(crate) fn ack_indication<M: zigbee_mac::mlme::Mlme>(
+        &mut self,
+        nlme: &mut Nlme<M>,
+        header: &Header,
+    ) {
+        let Some(index) = self
+            .pending_acks
+            .iter()
+            .position(|counter| *counter == header.counter)
+        else {
+            return;
+        };
+        self.pending_acks.swap_remove(index);
+        nlme.stop_fast_poll();
     }
 
     /// Broadcast an APS data frame (§2.2.5.1).
@@ -304,6 +453,93 @@ impl ApsmeSap for Apsme {
         }
     }
 
+    /// 2.2.4.4.1 - APSME-GET.request
+    ///
+    /// Only attributes with a scalar value are accessible by identifier, the
+    /// tables of the AIB are reported as unsupported.
+    fn get(&self, aib: &Aib<AibStorage>, attribute: u8) -> ApsmeGetConfirm {
+        use AttributeValue::Bool;
+        use AttributeValue::U8;
+        use AttributeValue::U16;
+        use AttributeValue::U64;
+
+        let value = match attribute {
+            attribute_id::TRUST_CENTER_ADDRESS => Some(U64(aib.trust_center_address().0)),
+            attribute_id::SECURITY_TIMEOUT_PERIOD => Some(U16(aib.security_timeout_period())),
+            attribute_id::DESIGNATED_COORDINATOR => Some(Bool(aib.designated_coordinator())),
+            attribute_id::USE_EXTENDED_PAN_ID => Some(U64(aib.use_extended_pan_id().0)),
+            attribute_id::NON_MEMBER_RADIUS => Some(U8(aib.non_member_radius())),
+            attribute_id::USE_INSECURE_JOIN => Some(Bool(aib.use_insecure_join())),
+            attribute_id::INTERFRAME_DELAY => Some(U8(aib.interframe_delay())),
+            attribute_id::LAST_CHANNEL_ENERGY => Some(U8(aib.last_channel_energy())),
+            attribute_id::LAST_CHANNEL_FAILURE_RATE => Some(U8(aib.last_channel_failure_rate())),
+            attribute_id::CHANNEL_TIMER => Some(U8(aib.channel_timer())),
+            _ => None,
+        };
+
+        ApsmeGetConfirm {
+            status: if value.is_some() {
+                ApsmeGetConfirmStatus::Success
+            } else {
+                ApsmeGetConfirmStatus::UnsupportedAttribute
+            },
+            attribute,
+            value,
+        }
+    }
+
+    /// 2.2.4.4.3 - APSME-SET.request
+    ///
+    /// A value of the wrong type or out of the range of Table 2-24 is
+    /// rejected with `InvalidParameter`.
+    fn set(&mut self, aib: &Aib<AibStorage>, request: ApsmeSetRequest) -> ApsmeSetConfirm {
+        let value = request.value;
+        let result = match request.attribute {
+            attribute_id::TRUST_CENTER_ADDRESS => value
+                .as_u64()
+                .map(|v| aib.set_trust_center_address(IeeeAddress(v))),
+            attribute_id::SECURITY_TIMEOUT_PERIOD => {
+                value.as_u16().map(|v| aib.set_security_timeout_period(v))
+            }
+            attribute_id::DESIGNATED_COORDINATOR => {
+                value.as_bool().map(|v| aib.set_designated_coordinator(v))
+            }
+            attribute_id::USE_EXTENDED_PAN_ID => value
+                .as_u64()
+                .map(|v| aib.set_use_extended_pan_id(IeeeAddress(v))),
+            attribute_id::NON_MEMBER_RADIUS => value
+                .as_u8()
+                .filter(|v| *v <= 0x07)
+                .map(|v| aib.set_non_member_radius(v)),
+            attribute_id::USE_INSECURE_JOIN => {
+                value.as_bool().map(|v| aib.set_use_insecure_join(v))
+            }
+            attribute_id::INTERFRAME_DELAY => value.as_u8().map(|v| aib.set_interframe_delay(v)),
+            attribute_id::LAST_CHANNEL_ENERGY => {
+                value.as_u8().map(|v| aib.set_last_channel_energy(v))
+            }
+            attribute_id::LAST_CHANNEL_FAILURE_RATE => value
+                .as_u8()
+                .filter(|v| *v <= 100)
+                .map(|v| aib.set_last_channel_failure_rate(v)),
+            attribute_id::CHANNEL_TIMER => value.as_u8().map(|v| aib.set_channel_timer(v)),
+            _ => {
+                return ApsmeSetConfirm {
+                    status: ApsmeSetConfirmStatus::UnsupportedAttribute,
+                    attribute: request.attribute,
+                };
+            }
+        };
+
+        ApsmeSetConfirm {
+            status: match result {
+                Some(()) => ApsmeSetConfirmStatus::Success,
+                None => ApsmeSetConfirmStatus::InvalidParameter,
+            },
+            attribute: request.attribute,
+        }
+    }
+
     /// 2.2.4.5.1 - APSME-ADD-GROUP.request
     fn add_group(&self, _request: ApsmeAddGroupRequest) -> ApsmeAddGroupConfirm {
         ApsmeAddGroupConfirm {}
@@ -326,9 +562,15 @@ impl ApsmeSap for Apsme {
 #[cfg(test)]
 mod tests {
     use basemgt::ApsmeBindRequestStatus;
+    use zigbee_types::ByteArray;
 
     use super::*;
+    use crate::aps::frame::command::SwitchKey;
     use crate::aps::types::SrcEndpoint;
+    use crate::nwk::nlme::tests::MockMlme;
+    use crate::nwk::nlme::tests::block_on;
+    use crate::nwk::nlme::tests::make_end_device;
+    use crate::nwk::nlme::tests::make_nlme;
 
     // 2.2.4.3.1
     #[test]
@@ -426,4 +668,199 @@ mod tests {
         // then
         assert_eq!(result.status, ApsmeBindRequestStatus::Success);
     }
+
+    // 2.2.4.4.3
+    #[test]
+    fn set_request_writes_aib_attribute() {
+        // given
+        let aib = Aib::default();
+        let mut apsme = Apsme::new();
+
+        // when
+        let result = apsme.set(
+            &aib,
+            ApsmeSetRequest {
+                attribute: attribute_id::NON_MEMBER_RADIUS,
+                value: AttributeValue::U8(0x05),
+            },
+        );
+
+        // then
+        assert_eq!(result.status, ApsmeSetConfirmStatus::Success);
+        let result = apsme.get(&aib, attribute_id::NON_MEMBER_RADIUS);
+        assert_eq!(result.status, ApsmeGetConfirmStatus::Success);
+        assert_eq!(result.value, Some(AttributeValue::U8(0x05)));
+    }
+
+    // 2.2.4.4.3
+    #[test]
+    fn set_request_with_invalid_value_should_fail() {
+        // given
+        let aib = Aib::default();
+        let mut apsme = Apsme::new();
+
+        // when
+        let out_of_range = apsme.set(
+            &aib,
+            ApsmeSetRequest {
+                attribute: attribute_id::NON_MEMBER_RADIUS,
+                value: AttributeValue::U8(0x08),
+            },
+        );
+        let unsupported = apsme.set(
+            &aib,
+            ApsmeSetRequest {
+                attribute: attribute_id::BINDING_TABLE,
+                value: AttributeValue::U8(0x00),
+            },
+        );
+
+        // then
+        assert_eq!(out_of_range.status, ApsmeSetConfirmStatus::InvalidParameter);
+        assert_eq!(
+            unsupported.status,
+            ApsmeSetConfirmStatus::UnsupportedAttribute
+        );
+        assert_eq!(
+            apsme.get(&aib, attribute_id::BINDING_TABLE).status,
+            ApsmeGetConfirmStatus::UnsupportedAttribute
+        );
+    }
+
+    // 4.4.9
+    #[test]
+    fn switch_key_is_only_accepted_from_the_trust_center() {
+        // given
+        let nlme = make_nlme(MockMlme::new());
+        let cx = nlme.security_context();
+        cx.install_network_key(0, ByteArray([0x11; 16]), 0x01);
+        cx.install_network_key(1, ByteArray([0x22; 16]), 0x01);
+        nlme.aib().set_trust_center_address(IeeeAddress(0xaa));
+        let apsme = Apsme::new();
+        let command = Command::SwitchKey(SwitchKey { sequence_number: 1 });
+
+        // when
+        let unsecured =
+            apsme.command_indication(&nlme, &command, false, Some(IeeeAddress(0xaa)), None);
+        let other_source =
+            apsme.command_indication(&nlme, &command, true, Some(IeeeAddress(0xbb)), None);
+
+        // then
+        assert!(unsecured.is_ok());
+        assert!(other_source.is_ok());
+        assert_eq!(nlme.nib().active_key_seq_number(), 0);
+
+        // when
+        let result = apsme.command_indication(&nlme, &command, true, Some(IeeeAddress(0xaa)), None);
+
+        // then
+        assert!(result.is_ok());
+        assert_eq!(nlme.nib().active_key_seq_number(), 1);
+    }
+
+    // 4.4.9
+    #[test]
+    fn nwk_secured_switch_key_is_accepted_from_the_trust_center() {
+        // given
+        let nlme = make_nlme(MockMlme::new());
+        let cx = nlme.security_context();
+        cx.install_network_key(0, ByteArray([0x11; 16]), 0x01);
+        cx.install_network_key(1, ByteArray([0x22; 16]), 0x01);
+        nlme.aib().set_trust_center_address(IeeeAddress(0xaa));
+        nlme.update_address_map(IeeeAddress(0xaa), ShortAddress(0x2222));
+        let apsme = Apsme::new();
+        let command = Command::SwitchKey(SwitchKey { sequence_number: 1 });
+
+        // when
+        let other_source =
+            apsme.command_indication(&nlme, &command, false, None, Some(ShortAddress(0x3333)));
+
+        // then
+        assert!(other_source.is_ok());
+        assert_eq!(nlme.nib().active_key_seq_number(), 0);
+
+        // when
+        let result =
+            apsme.command_indication(&nlme, &command, false, None, Some(ShortAddress(0x2222)));
+
+        // then
+        assert!(result.is_ok());
+        assert_eq!(nlme.nib().active_key_seq_number(), 1);
+    }
+
+    fn ack_header(counter: u8) -> Header {
+        Header {
+            frame_control: FrameControl::default().set_frame_type(FrameType::Acknowledgement),
+            destination_endpoint: Some(1),
+            group_address: None,
+            cluster_id: Some(0x0006),
+            profile_id: Some(0x0104),
+            source_endpoint: Some(1),
+            counter,
+            extended_header: None,
+        }
+    }
+
+    // 2.2.8.4.2
+    #[test]
+    fn acknowledged_unicast_fast_polls_until_acknowledged() {
+        // given
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data().times(1).returning(|_, _| Ok(()));
+        let mut nlme = make_end_device(mac);
+        let mut apsme = Apsme::new();
+
+        // when
+        block_on(apsme.unicast_data(
+            &mut nlme,
+            ShortAddress(0x0000),
+            1,
+            0x0006,
+            0x0104,
+            1,
+            true,
+            &[0x01],
+        ))
+        .unwrap();
+
+        // then
+        assert!(nlme.is_fast_polling());
+
+        // when
+        apsme.ack_indication(&mut nlme, &ack_header(apsme.aps_counter.wrapping_add(1)));
+
+        // then
+        assert!(nlme.is_fast_polling());
+
+        // when
+        apsme.ack_indication(&mut nlme, &ack_header(apsme.aps_counter));
+
+        // then
+        assert!(!nlme.is_fast_polling());
+    }
+
+    #[test]
+    fn unacknowledged_unicast_does_not_fast_poll() {
+        // given
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data().times(1).returning(|_, _| Ok(()));
+        let mut nlme = make_end_device(mac);
+        let mut apsme = Apsme::new();
+
+        // when
+        block_on(apsme.unicast_data(
+            &mut nlme,
+            ShortAddress(0x0000),
+            1,
+            0x0006,
+            0x0104,
+            1,
+            false,
+            &[0x01],
+        ))
+        .unwrap();
+
+        // then
+        assert!(!nlme.is_fast_polling());
+    }
 }
@@ -2,12 +2,16 @@ use zigbee_macros::impl_byte;
 
 mod confirm_key;
 mod request_key;
+mod switch_key;
 mod transport_key;
+mod update_device;
 mod verify_key;
 
 pub use confirm_key::*;
 pub use request_key::*;
+pub use switch_key::*;
 pub use transport_key::*;
+pub use update_device::*;
 pub use verify_key::*;
 
 impl_byte! {
@@ -17,8 +21,12 @@ impl_byte! {
     pub enum Command {
         #[tag_value = 0x05]
         TransportKey(TransportKey),
+        #[tag_value = 0x06]
+        UpdateDevice(UpdateDevice),
         #[tag_value = 0x08]
         RequestKey(RequestKey),
+        #[tag_value = 0x09]
+        SwitchKey(SwitchKey),
         #[tag_value = 0x0f]
         VerifyKey(VerifyKey),
         #[tag_value = 0x10]
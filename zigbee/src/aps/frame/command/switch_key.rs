@@ -0,0 +1,35 @@
+use zigbee_macros::impl_byte;
+
+impl_byte! {
+    /// Switch-Key Command Frame (§4.4.10.4, Table 4-27, command id 0x09)
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct SwitchKey {
+        /// Sequence number of the network key to make active
+        pub sequence_number: u8,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byte::TryRead;
+    use byte::TryWrite;
+
+    use crate::aps::frame::command::Command;
+    use crate::aps::frame::command::SwitchKey;
+
+    #[test]
+    fn round_trip_switch_key() {
+        let frame_buf = [
+            0x09, // command id: SwitchKey
+            0x02, // key sequence number
+        ];
+
+        let (cmd, _) = Command::try_read(&frame_buf, ()).unwrap();
+        assert_eq!(cmd, Command::SwitchKey(SwitchKey { sequence_number: 2 }));
+
+        let mut got_buf = [0u8; _];
+        cmd.try_write(&mut got_buf, ()).unwrap();
+
+        assert_eq!(frame_buf, got_buf);
+    }
+}
@@ -0,0 +1,61 @@
+use zigbee_macros::impl_byte;
+use zigbee_types::IeeeAddress;
+use zigbee_types::ShortAddress;
+
+impl_byte! {
+    /// Update-Device Command Frame (§4.4.10.2, Table 4-27, command id 0x06)
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct UpdateDevice {
+        /// IEEE address of the device which joined, rejoined or left
+        pub device_address: IeeeAddress,
+        /// Network address of the device
+        pub device_short_address: ShortAddress,
+        /// See [`update_device_status`]
+        pub status: u8,
+    }
+}
+
+/// Update-Device status values (§4.4.10.2.3).
+pub mod update_device_status {
+    pub const STANDARD_DEVICE_SECURED_REJOIN: u8 = 0x00;
+    pub const STANDARD_DEVICE_UNSECURED_JOIN: u8 = 0x01;
+    pub const DEVICE_LEFT: u8 = 0x02;
+    pub const STANDARD_DEVICE_TRUST_CENTER_REJOIN: u8 = 0x03;
+}
+
+#[cfg(test)]
+mod tests {
+    use byte::TryRead;
+    use byte::TryWrite;
+    use zigbee_types::IeeeAddress;
+    use zigbee_types::ShortAddress;
+
+    use super::update_device_status;
+    use crate::aps::frame::command::Command;
+    use crate::aps::frame::command::UpdateDevice;
+
+    #[test]
+    fn round_trip_update_device() {
+        let frame_buf = [
+            0x06, // command id: UpdateDevice
+            0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01, // device address
+            0x42, 0x42, // device short address
+            0x01, // status: standard device unsecured join
+        ];
+
+        let (cmd, _) = Command::try_read(&frame_buf, ()).unwrap();
+        assert_eq!(
+            cmd,
+            Command::UpdateDevice(UpdateDevice {
+                device_address: IeeeAddress(0x0102_0304_0506_0708),
+                device_short_address: ShortAddress(0x4242),
+                status: update_device_status::STANDARD_DEVICE_UNSECURED_JOIN,
+            })
+        );
+
+        let mut got_buf = [0u8; _];
+        cmd.try_write(&mut got_buf, ()).unwrap();
+
+        assert_eq!(frame_buf, got_buf);
+    }
+}
@@ -6,7 +6,8 @@
 //! `Network layer` and the `Application layer`.
 
 pub(crate) mod error;
-pub(crate) mod types;
+/// Parameter types of the APS service primitives.
+pub mod types;
 
 /// The APS data entity provides the data transmission service between two or
 /// more application entities located on the same network.
@@ -4,6 +4,24 @@ impl_byte! {
     /// End Device Timeout Response Command Frame
     #[derive(Debug, Clone)]
     pub struct EndDeviceTimeoutResponse {
-        pub timeout: u8,
+        pub status: u8,
+        pub parent_information: u8,
     }
 }
+
+/// Values of [`EndDeviceTimeoutResponse::status`] (Section 3.4.12.3.1).
+pub mod status {
+    pub const SUCCESS: u8 = 0x00;
+    pub const INCORRECT_VALUE: u8 = 0x01;
+}
+
+/// Bits of [`EndDeviceTimeoutResponse::parent_information`]
+/// (Section 3.4.12.3.2).
+pub mod parent_information {
+    /// The parent keeps children alive on MAC data polls.
+    pub const MAC_DATA_POLL_KEEPALIVE: u8 = 0b0000_0001;
+    /// The parent keeps children alive on end device timeout requests.
+    pub const END_DEVICE_TIMEOUT_REQUEST_KEEPALIVE: u8 = 0b0000_0010;
+    /// The parent supports power negotiation.
+    pub const POWER_NEGOTIATION: u8 = 0b0000_0100;
+}
@@ -58,16 +58,18 @@ impl CommandOptions {
     }
 }
 
+// §3.4.8.3.1: entry count in bits 0-4, first frame in bit 5, last frame in
+// bit 6
 mod offset {
     pub const ENTRY_COUNT: u8 = 0;
-    pub const FIRST_FRAME: u8 = 4;
-    pub const LAST_FRAME: u8 = 5;
+    pub const FIRST_FRAME: u8 = 5;
+    pub const LAST_FRAME: u8 = 6;
 }
 
 mod mask {
-    pub const ENTRY_COUNT: u8 = 0b0000_1111;
-    pub const FIRST_FRAME: u8 = 0b0001_0000;
-    pub const LAST_FRAME: u8 = 0b0010_0000;
+    pub const ENTRY_COUNT: u8 = 0b0001_1111;
+    pub const FIRST_FRAME: u8 = 0b0010_0000;
+    pub const LAST_FRAME: u8 = 0b0100_0000;
 }
 
 impl_byte! {
@@ -79,3 +81,67 @@ impl_byte! {
         pub link_status: u8,
     }
 }
+
+impl LinkStatusEntry {
+    /// Entry for `neighbor_address` with the given link costs (1 - 7).
+    pub fn new(neighbor_address: ShortAddress, incoming_cost: u8, outgoing_cost: u8) -> Self {
+        Self {
+            neighbor_address,
+            link_status: (incoming_cost & 0b111) | ((outgoing_cost & 0b111) << 4),
+        }
+    }
+
+    /// Incoming cost
+    ///
+    /// Estimated cost of the link from the neighbor to the sender.
+    pub fn incoming_cost(&self) -> u8 {
+        self.link_status & 0b111
+    }
+
+    /// Outgoing cost
+    ///
+    /// Cost of the link from the sender to the neighbor, as last reported
+    /// by the neighbor.
+    pub fn outgoing_cost(&self) -> u8 {
+        (self.link_status >> 4) & 0b111
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byte::TryRead;
+    use byte::TryWrite;
+
+    use super::*;
+
+    #[test]
+    fn write_link_status() {
+        let entries = [
+            LinkStatusEntry::new(ShortAddress(0x1234), 1, 3),
+            LinkStatusEntry::new(ShortAddress(0x5678), 7, 7),
+        ];
+        let status = LinkStatus {
+            command_options: CommandOptions(0)
+                .set_entry_count(2)
+                .set_first_frame(true)
+                .set_last_frame(true),
+            entries: TypeArrayRef(&entries),
+        };
+        let mut buf = [0u8; 16];
+
+        let len = status.try_write(&mut buf, ()).unwrap();
+
+        assert_eq!(&buf[..len], [0x62, 0x34, 0x12, 0x31, 0x78, 0x56, 0x77]);
+    }
+
+    #[test]
+    fn parse_link_status_options() {
+        let raw = [0x5f];
+
+        let (options, _) = CommandOptions::try_read(&raw, ()).unwrap();
+
+        assert_eq!(options.entry_count(), 31);
+        assert!(!options.first_frame());
+        assert!(options.last_frame());
+    }
+}
@@ -23,3 +23,8 @@ impl_byte! {
         pub device_type: u8,
     }
 }
+
+/// Report command identifiers, 0x01 - 0x07 are reserved.
+pub mod report_type {
+    pub const PAN_IDENTIFIER_CONFLICT: u8 = 0x00;
+}
@@ -1,15 +1,46 @@
 use zigbee_macros::impl_byte;
 use zigbee_types::ShortAddress;
-use zigbee_types::TypeArrayCtx;
-use zigbee_types::TypeArrayRef;
 
 impl_byte! {
     /// Route Record Command Frame
     #[derive(Debug, Clone)]
     pub struct RouteRecord<'a> {
         pub relay_count: u8,
-        #[ctx = TypeArrayCtx::Len(usize::from(relay_count))]
+        /// Little endian addresses of the relays, the first one is the
+        /// closest to the originator.
+        #[ctx = byte::ctx::Bytes::Len(usize::from(relay_count) * 2)]
         #[ctx_write = ()]
-        pub relay_list: TypeArrayRef<'a, ShortAddress>,
+        pub relay_list: &'a [u8],
+    }
+}
+
+impl RouteRecord<'_> {
+    /// Relay addresses of [`RouteRecord::relay_list`].
+    pub fn relays(&self) -> impl Iterator<Item = ShortAddress> + '_ {
+        self.relay_list
+            .chunks_exact(2)
+            .map(|r| ShortAddress(u16::from_le_bytes([r[0], r[1]])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byte::TryRead;
+
+    use super::*;
+
+    #[test]
+    fn parse_route_record() {
+        let raw = [0x02, 0x34, 0x12, 0x78, 0x56];
+
+        let (record, len) = RouteRecord::try_read(&raw, ()).unwrap();
+
+        assert_eq!(len, 5);
+        assert_eq!(record.relay_count, 2);
+        assert!(
+            record
+                .relays()
+                .eq([ShortAddress(0x1234), ShortAddress(0x5678)])
+        );
     }
 }
@@ -75,6 +75,16 @@ impl CommandOptions {
     }
 }
 
+/// Values of [`CommandOptions::many_to_one`] (Section 3.4.1.3.1.1).
+pub mod many_to_one {
+    /// Not a many-to-one route request.
+    pub const NONE: u8 = 0;
+    /// The concentrator stores route records, devices send route records.
+    pub const ROUTE_RECORD_TABLE: u8 = 1;
+    /// The concentrator does not store route records.
+    pub const NO_ROUTE_CACHE: u8 = 2;
+}
+
 mod offset {
     pub const MANY_TO_ONE: u8 = 3;
     pub const DEST_IEEE: u8 = 5;
@@ -44,6 +44,67 @@ impl_byte! {
     pub struct MulticastControl(u8);
 }
 
+impl MulticastControl {
+    /// Value of the non-member radius which is never decremented.
+    pub const INFINITE_NON_MEMBER_RADIUS: u8 = 0x07;
+
+    pub const fn new(
+        mode: MulticastMode,
+        non_member_radius: u8,
+        max_non_member_radius: u8,
+    ) -> Self {
+        Self(
+            (mode as u8)
+                | ((non_member_radius << 2) & 0x1c)
+                | ((max_non_member_radius << 5) & 0xe0),
+        )
+    }
+
+    /// See Section 3.3.1.8.1.
+    pub fn mode(&self) -> MulticastMode {
+        match self.0 & 0x03 {
+            0x01 => MulticastMode::Member,
+            _ => MulticastMode::NonMember,
+        }
+    }
+
+    /// Sets the multicast mode
+    #[must_use]
+    pub fn set_mode(self, mode: MulticastMode) -> Self {
+        Self((self.0 & !0x03) | mode as u8)
+    }
+
+    /// Hops a non-member mode frame may still travel outside the group.
+    ///
+    /// See Section 3.3.1.8.2.
+    pub fn non_member_radius(&self) -> u8 {
+        (self.0 & 0x1c) >> 2
+    }
+
+    /// Sets the non-member radius
+    #[must_use]
+    pub fn set_non_member_radius(self, value: u8) -> Self {
+        Self((self.0 & !0x1c) | ((value << 2) & 0x1c))
+    }
+
+    /// See Section 3.3.1.8.3.
+    pub fn max_non_member_radius(&self) -> u8 {
+        (self.0 & 0xe0) >> 5
+    }
+}
+
+/// Multicast Mode
+///
+/// See Section 3.3.1.8.1.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MulticastMode {
+    /// Sent by a device outside the group, relayed towards the group.
+    NonMember = 0x00,
+    /// Sent or relayed by a member of the group.
+    Member = 0x01,
+}
+
 impl_byte! {
     /// Source Route Subframe
     #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -63,12 +124,21 @@ impl_byte! {
         /// the originator.
         ///
         /// See Section 3.3.1.9.2.
-        #[ctx = byte::ctx::Bytes::Len(relay_count as usize)]
+        #[ctx = byte::ctx::Bytes::Len(relay_count as usize * 2)]
         #[ctx_write = ()]
         pub relay_list: &'a [u8],
     }
 }
 
+impl SourceRouteSubframe<'_> {
+    /// Relay addresses of [`SourceRouteSubframe::relay_list`].
+    pub fn relays(&self) -> impl Iterator<Item = ShortAddress> + '_ {
+        self.relay_list
+            .chunks_exact(2)
+            .map(|r| ShortAddress(u16::from_le_bytes([r[0], r[1]])))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use byte::TryRead;
@@ -91,4 +161,63 @@ mod tests {
         assert_eq!(header.radius, 8);
         assert_eq!(header.sequence_number, 191);
     }
+
+    #[test]
+    fn parse_multicast_control() {
+        let raw = [
+            0x00,
+            0x01, // frame control: data, multicast
+            0x01,
+            0x00, // group
+            0x34,
+            0x12,        // source
+            0x05,        // radius
+            0x01,        // seq number
+            0b0110_1000, // non-member mode, radius 2, max radius 3
+        ];
+
+        let (header, len) = Header::try_read(&raw, ()).unwrap();
+
+        assert_eq!(len, raw.len());
+        let control = header.multicast_control.unwrap();
+        assert_eq!(control.mode(), MulticastMode::NonMember);
+        assert_eq!(control.non_member_radius(), 2);
+        assert_eq!(control.max_non_member_radius(), 3);
+        assert_eq!(
+            control,
+            MulticastControl::new(MulticastMode::NonMember, 2, 3)
+        );
+
+        let control = control
+            .set_mode(MulticastMode::Member)
+            .set_non_member_radius(1);
+        assert_eq!(control.mode(), MulticastMode::Member);
+        assert_eq!(control.non_member_radius(), 1);
+        assert_eq!(control.max_non_member_radius(), 3);
+    }
+
+    #[test]
+    fn parse_source_route_subframe() {
+        let raw = [
+            0x08, 0x04, // frame control: data, source route
+            0x34, 0x12, // destination
+            0x00, 0x00, // source
+            0x05, // radius
+            0x01, // seq number
+            0x02, // relay count
+            0x01, // relay index
+            0x22, 0x22, 0x33, 0x33, // relay list
+        ];
+
+        let (header, len) = Header::try_read(&raw, ()).unwrap();
+
+        assert_eq!(len, raw.len());
+        let subframe = header.source_route_subframe.unwrap();
+        assert_eq!(subframe.relay_index, 1);
+        assert!(
+            subframe
+                .relays()
+                .eq([ShortAddress(0x2222), ShortAddress(0x3333)])
+        );
+    }
 }
@@ -49,6 +49,24 @@ impl<'a> Frame<'a> {
             FrameType::InterPan => Ok(Frame::InterPan(header)),
         }
     }
+
+    /// NWK header of the frame.
+    pub fn header(&self) -> &Header<'a> {
+        match self {
+            Frame::Data(frame) => &frame.header,
+            Frame::NwkCommand(frame) => &frame.header,
+            Frame::Reserved(header) | Frame::InterPan(header) => header,
+        }
+    }
+
+    /// Mutable NWK header of the frame.
+    pub fn header_mut(&mut self) -> &mut Header<'a> {
+        match self {
+            Frame::Data(frame) => &mut frame.header,
+            Frame::NwkCommand(frame) => &mut frame.header,
+            Frame::Reserved(header) | Frame::InterPan(header) => header,
+        }
+    }
 }
 
 impl<'a> TryRead<'a, SecurityContext<'a>> for Frame<'a> {
@@ -5,4 +5,5 @@
 //! service interface to the application layer.
 pub mod frame;
 pub mod nib;
+pub mod nlde;
 pub mod nlme;
@@ -13,6 +13,7 @@ use embedded_storage::Storage;
 use heapless::Vec;
 use heapless::index_map::FnvIndexMap;
 use spin::Mutex;
+use zigbee_mac::mlme::A_BASE_SUPER_FRAME_DURATION;
 use zigbee_macros::construct_ib;
 use zigbee_macros::impl_byte;
 use zigbee_types::ByteArray;
@@ -41,6 +42,21 @@ impl_byte! {
 
 pub const NWK_COORDINATOR_ADDRESS: u16 = 0x0000;
 
+/// Highest PAN identifier a Zigbee network may use (§3.6.1.1).
+pub(crate) const MAX_PAN_ID: u16 = 0x3fff;
+
+/// Broadcast addresses (Table 3-54).
+pub mod broadcast_address {
+    /// All devices in the PAN.
+    pub const ALL_DEVICES: u16 = 0xffff;
+    /// All devices with macRxOnWhenIdle set.
+    pub const RX_ON_WHEN_IDLE: u16 = 0xfffd;
+    /// All routers and the coordinator.
+    pub const ROUTERS: u16 = 0xfffc;
+    /// Lowest broadcast address, 0xfff8 - 0xfffb are reserved.
+    pub const MIN: u16 = 0xfff8;
+}
+
 /// Neighbor table relationship values (Table 3-63).
 pub mod relationship {
     /// The neighbor is the parent of this device.
@@ -57,38 +73,121 @@ pub mod relationship {
     pub const UNAUTHENTICATED_CHILD: u8 = 0x05;
 }
 
+/// Address allocation schemes for nwkAddrAlloc (Table 3-58).
+pub mod addr_alloc {
+    /// Distributed (tree) address assignment.
+    pub const DISTRIBUTED: u8 = 0x00;
+    /// Stochastic address assignment.
+    pub const STOCHASTIC: u8 = 0x02;
+}
+
+/// NIB attribute identifiers (Table 3-58 and Table 4-2).
+pub mod attribute_id {
+    pub const PAN_ID: u8 = 0x80;
+    pub const SEQUENCE_NUMBER: u8 = 0x81;
+    pub const PASSIVE_ACK_TIMEOUT: u8 = 0x82;
+    pub const MAX_BROADCAST_RETRIES: u8 = 0x83;
+    pub const MAX_CHILDREN: u8 = 0x84;
+    pub const MAX_DEPTH: u8 = 0x85;
+    pub const MAX_ROUTERS: u8 = 0x86;
+    pub const NEIGHBOR_TABLE: u8 = 0x87;
+    pub const NETWORK_BROADCAST_DELIVERY_TIME: u8 = 0x88;
+    pub const REPORT_CONSTANT_COST: u8 = 0x89;
+    pub const ROUTE_TABLE: u8 = 0x8b;
+    pub const TIME_STAMP: u8 = 0x8c;
+    pub const TX_TOTAL: u8 = 0x8d;
+    pub const SYM_LINK: u8 = 0x8e;
+    pub const CAPABILITY_INFORMATION: u8 = 0x8f;
+    pub const ADDR_ALLOC: u8 = 0x90;
+    pub const USE_TREE_ROUTING: u8 = 0x91;
+    pub const MANAGER_ADDR: u8 = 0x92;
+    pub const MAX_SOURCE_ROUTE: u8 = 0x93;
+    pub const UPDATE_ID: u8 = 0x94;
+    pub const TRANSACTION_PERSISTENCE_TIME: u8 = 0x95;
+    pub const NETWORK_ADDRESS: u8 = 0x96;
+    pub const STACK_PROFILE: u8 = 0x97;
+    pub const BROADCAST_TRANSACTION_TABLE: u8 = 0x98;
+    pub const GROUP_ID_TABLE: u8 = 0x99;
+    pub const EXTENDED_PAN_ID: u8 = 0x9a;
+    pub const USE_MULTICAST: u8 = 0x9b;
+    pub const ROUTE_RECORD_TABLE: u8 = 0x9c;
+    pub const IS_CONCENTRATOR: u8 = 0x9d;
+    pub const CONCENTRATOR_RADIUS: u8 = 0x9e;
+    pub const CONCENTRATOR_DISCOVERY_TIME: u8 = 0x9f;
+    pub const SECURITY_LEVEL: u8 = 0xa0;
+    pub const SECURITY_MATERIAL_SET: u8 = 0xa1;
+    pub const ACTIVE_KEY_SEQ_NUMBER: u8 = 0xa2;
+    pub const ALL_FRESH: u8 = 0xa3;
+    pub const LINK_STATUS_PERIOD: u8 = 0xa6;
+    pub const ROUTER_AGE_LIMIT: u8 = 0xa7;
+    pub const UNIQUE_ADDR: u8 = 0xa8;
+    pub const ADDRESS_MAP: u8 = 0xa9;
+}
+
 /// See Section 3.5.1.
-const NWKC_COORDINATOR_CAPABLE: bool = true;
+pub(crate) const NWKC_COORDINATOR_CAPABLE: bool = true;
 const NWKC_DEFAULT_SECURITY_LEVEL: u8 = 0x00; // defined in stack profile
 const NWKC_MIN_HEADER_OVERHEAD: u8 = 0x08;
-const NWKC_PROTOCOL_VERSION: u8 = 0x02;
+pub(crate) const NWKC_PROTOCOL_VERSION: u8 = 0x02;
 const NWKC_WAIT_BEFORE_VALIDATION: u32 = 0x9c40;
-const NWKC_ROUTE_DISCOVERY_TIME: u32 = 0x4c4b4;
-const NWKC_MAX_BROADCAST_JITTER: u32 = 0x7d0;
-const NWKC_INITIAL_RREQ_RETRIES: u8 = 0x03;
-const NWKC_RREQ_RETRIES: u8 = 0x02;
-const NWKC_RREQ_RETRY_INTERVAL: u32 = 0x1f02;
-const NWKC_MIN_RREQ_JITTER: u32 = 0x3f;
-const NWKC_MAX_RREQ_JITTER: u32 = 0xfa0;
+pub(crate) const NWKC_ROUTE_DISCOVERY_TIME: u32 = 0x4c4b4;
+pub(crate) const NWKC_MAX_BROADCAST_JITTER: u32 = 0x7d0;
+pub(crate) const NWKC_INITIAL_RREQ_RETRIES: u8 = 0x03;
+pub(crate) const NWKC_RREQ_RETRIES: u8 = 0x02;
+pub(crate) const NWKC_RREQ_RETRY_INTERVAL: u32 = 0x1f02;
+pub(crate) const NWKC_MIN_RREQ_JITTER: u32 = 0x3f;
+pub(crate) const NWKC_MAX_RREQ_JITTER: u32 = 0xfa0;
 const NWKC_MAC_FRAME_OVERHEAD: u8 = 0x0b;
 
+/// Convert a duration in OctetDurations to milliseconds.
+///
+/// One octet takes 32 µs on the 2.4 GHz PHY.
+pub(crate) const fn octets_to_ms(octets: u32) -> u32 {
+    octets * 32 / 1000
+}
+
+/// Convert a duration in superframe periods of a non-beacon network
+/// (aBaseSuperframeDuration symbols) to milliseconds.
+///
+/// One symbol takes 16 µs on the 2.4 GHz PHY.
+pub(crate) const fn superframes_to_ms(superframes: u32) -> u32 {
+    superframes * A_BASE_SUPER_FRAME_DURATION * 16 / 1000
+}
+
 // implementation specific
 
 // 1 for end device
-const MAX_NEIGBOUR_TABLE: usize = 16;
+pub(crate) const MAX_NEIGHBOR_TABLE: usize = 16;
 // 0 for end devices
 const MAX_ROUTE_TABLE: usize = 8;
 const MAX_BROADCAST_TRANSACTION_TABLE: usize = 4;
 const MAX_GROUP_ID_TABLE: usize = 4;
 // 0 for end devices
 const MAX_ROUTE_RECORD_TABLE: usize = 8;
+/// Relays stored per route record, bounds nwkMaxSourceRoute.
+pub(crate) const MAX_ROUTE_RECORD_RELAYS: usize = 16;
 const MAX_NWK_ADDRESS_MAP: usize = 16;
 const MAX_MAC_INTERFACE_TABLE: usize = 1;
-const MAX_SECURITY_KEYS: usize = 1;
+// active and alternate network key
+pub(crate) const MAX_SECURITY_KEYS: usize = 2;
 
 /// Maximum acceptable link cost for parent selection (§3.6.1.4.1.1).
 pub const MAX_PARENT_LINK_COST: u8 = 3;
 
+/// Highest valid value of the requested timeout enumeration (Table 3-54).
+pub(crate) const MAX_END_DEVICE_TIMEOUT: u8 = 14;
+
+/// Duration in seconds of a requested timeout enumeration value, as used by
+/// nwkEndDeviceTimeoutDefault and the end device timeout request
+/// (Table 3-54): 10 seconds for 0, otherwise 2^`value` minutes.
+pub(crate) const fn end_device_timeout_seconds(value: u8) -> Option<u32> {
+    match value {
+        0 => Some(10),
+        1..=MAX_END_DEVICE_TIMEOUT => Some(60 << value),
+        _ => None,
+    }
+}
+
 /// Compute the link cost from an LQI value (§3.6.3.1).
 ///
 /// The link cost is a value in the range 1–7 representing the estimated
@@ -122,20 +221,20 @@ construct_ib! {
     pub struct Nib {
         /// Sequence number
         sequence_number: u8, // random value, read only
-        passive_ack_timeout: u32, // stack profile
+        passive_ack_timeout: u32 = 0x3d09, // stack profile, OctetDurations
         max_broadcast_retries: u8 = 0x03,
-        max_children: u8, // stack profile
-        max_depth: u8, // stack profile, read only
-        max_routers: u8, // stack profile
-        neighbor_table: StorageVec<NwkNeighbor, MAX_NEIGBOUR_TABLE>,
-        network_broadcast_delivery_time: u32, // stack profile
+        max_children: u8 = 0x14, // stack profile
+        max_depth: u8 = 0x0f, // stack profile, read only
+        max_routers: u8 = 0x06, // stack profile
+        neighbor_table: StorageVec<NwkNeighbor, MAX_NEIGHBOR_TABLE>,
+        network_broadcast_delivery_time: u32 = 0x44aa2, // stack profile, OctetDurations
         report_constant_cost: u8 = 0x00, // 0x00 - 0x01
         route_table: StorageVec<NwkRoute, MAX_ROUTE_TABLE>,
         #[ctx = ()]
         #[ctx_write = ()]
         sym_link: bool = false, // bool
         capability_information: CapabilityInformation = CapabilityInformation(0x00), // read only
-        addr_alloc: u8 = 0x0, // 0x00 - 0x02
+        addr_alloc: u8 = addr_alloc::STOCHASTIC, // 0x00 - 0x02
         #[ctx = ()]
         #[ctx_write = ()]
         use_tree_routing: bool = true,
@@ -205,6 +304,13 @@ impl CapabilityInformation {
         self.0 & (1 << 1) != 0
     }
 
+    /// Sets bit 1 — Device type.
+    #[must_use]
+    pub fn set_device_type(mut self, router: bool) -> Self {
+        self.0 = (self.0 & !(1 << 1)) | (u8::from(router) << 1);
+        self
+    }
+
     /// Bit 2 — Power source: 1 = mains-powered, 0 = other.
     pub fn power_source(&self) -> bool {
         self.0 & (1 << 2) != 0
@@ -215,6 +321,13 @@ impl CapabilityInformation {
         self.0 & (1 << 3) != 0
     }
 
+    /// Sets bit 3 — Receiver on when idle.
+    #[must_use]
+    pub fn set_receiver_on_when_idle(mut self, value: bool) -> Self {
+        self.0 = (self.0 & !(1 << 3)) | (u8::from(value) << 3);
+        self
+    }
+
     /// Bit 6 — Security capability (always 0 in Zigbee).
     pub fn security_capability(&self) -> bool {
         self.0 & (1 << 6) != 0
@@ -236,9 +349,11 @@ impl_byte! {
         #[ctx = ()]
         pub rx_on_when_idle: bool,
         pub end_device_configuration: u16,
-        // unused
-        //timeout_counter: u32,
-        //device_timeout: u32,
+        /// Seconds until an end device child times out.
+        pub timeout_counter: u32,
+        /// Timeout of an end device child in seconds, see
+        /// [`end_device_timeout_seconds`].
+        pub device_timeout: u32,
         pub relationship: u8,
         pub transmit_failure: u8,
         pub lqi: u8,
@@ -282,7 +397,7 @@ impl_byte! {
 }
 
 /// See Table 3-67.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub(crate) enum RouteStatus {
     Active,
@@ -300,10 +415,26 @@ impl_byte! {
         pub destination_address: ShortAddress,
         pub next_hop_address: ShortAddress,
         status: u8,
+        /// Milliseconds since the route was last used, implementation
+        /// specific.
+        pub age: u32,
     }
 }
 
 impl NwkRoute {
+    pub(crate) fn new(
+        destination_address: ShortAddress,
+        next_hop_address: ShortAddress,
+        status: RouteStatus,
+    ) -> Self {
+        Self {
+            destination_address,
+            next_hop_address,
+            status: status as u8,
+            age: 0,
+        }
+    }
+
     pub(crate) fn status(&self) -> RouteStatus {
         let status = self.status & 0b111;
         if status > 0x4 {
@@ -314,24 +445,40 @@ impl NwkRoute {
         }
     }
 
+    pub(crate) fn set_status(&mut self, status: RouteStatus) {
+        self.status = (self.status & !0b111) | status as u8;
+    }
+
     /// A flag indicating that the destination indicated by this address does
     /// not store source routes.
     pub(crate) fn no_route_cache(&self) -> bool {
         (self.status >> 3) & 0b1 != 0
     }
 
+    pub(crate) fn set_no_route_cache(&mut self, value: bool) {
+        self.status = (self.status & !(1 << 3)) | (u8::from(value) << 3);
+    }
+
     /// A flag indicating that the destination is a concentrator that issued a
     /// many-to-one route request.
     pub(crate) fn many_to_one(&self) -> bool {
         (self.status >> 4) & 0b1 != 0
     }
 
+    pub(crate) fn set_many_to_one(&mut self, value: bool) {
+        self.status = (self.status & !(1 << 4)) | (u8::from(value) << 4);
+    }
+
     /// A flag indicating that a route record command frame should be sent to
     /// the destination prior to the next data packet.
     pub(crate) fn route_record_required(&self) -> bool {
         (self.status >> 5) & 0b1 != 0
     }
 
+    pub(crate) fn set_route_record_required(&mut self, value: bool) {
+        self.status = (self.status & !(1 << 5)) | (u8::from(value) << 5);
+    }
+
     /// A flag indicating that the destination address is a Group ID.
     pub(crate) fn group_id(&self) -> bool {
         (self.status >> 6) & 0b1 != 0
@@ -344,6 +491,7 @@ impl_byte! {
     pub struct TransactionRecord {
         pub source_address: ShortAddress,
         pub sequence_number: u8,
+        /// Seconds until the entry expires.
         pub expiration_time: u8,
     }
 }
@@ -354,7 +502,7 @@ impl_byte! {
     pub struct RouteRecord {
         pub network_address: ShortAddress,
         pub relay_count: u16,
-        pub path: StorageVec<ShortAddress, 16>,
+        pub path: StorageVec<ShortAddress, MAX_ROUTE_RECORD_RELAYS>,
     }
 }
 
@@ -375,7 +523,7 @@ impl_byte! {
     pub struct NetworkSecurityMaterialDescriptor {
         pub key_seq_number: u8,
         pub outgoing_frame_counter: u32,
-        pub incoming_frame_counter_set: StorageVec<IncomingFrameCounterDescriptor, MAX_NEIGBOUR_TABLE>,
+        pub incoming_frame_counter_set: StorageVec<IncomingFrameCounterDescriptor, MAX_NEIGHBOR_TABLE>,
         pub key: ByteArray<16>,
         pub network_key_type: u8,
     }
@@ -399,7 +547,7 @@ mod tests {
         let nib = Nib::new(NibStorage::default());
         nib.init();
 
-        let mut set = StorageVec::<NetworkSecurityMaterialDescriptor, 1>::new();
+        let mut set = StorageVec::<NetworkSecurityMaterialDescriptor, MAX_SECURITY_KEYS>::new();
         set.push(NetworkSecurityMaterialDescriptor {
             key_seq_number: 0,
             outgoing_frame_counter: 0,
@@ -414,14 +562,19 @@ mod tests {
 
     #[test]
     fn nib_default() {
-        init(NibStorage::default());
-        let nib = get_ref();
+        let nib = Nib::new(NibStorage::default());
+        nib.init();
 
+        assert_eq!(nib.passive_ack_timeout(), 0x3d09);
         assert_eq!(nib.max_broadcast_retries(), 0x03);
+        assert_eq!(nib.network_broadcast_delivery_time(), 0x44aa2);
+        assert_eq!(nib.max_children(), 0x14);
+        assert_eq!(nib.max_depth(), 0x0f);
+        assert_eq!(nib.max_routers(), 0x06);
         assert_eq!(nib.report_constant_cost(), 0x00);
         assert!(!nib.sym_link());
         assert_eq!(nib.capability_information(), CapabilityInformation(0x00));
-        assert_eq!(nib.addr_alloc(), 0x0);
+        assert_eq!(nib.addr_alloc(), addr_alloc::STOCHASTIC);
         assert!(nib.use_tree_routing());
         assert_eq!(nib.manager_addr(), 0x0000);
         assert_eq!(nib.max_source_route(), 0x0c);
@@ -0,0 +1,47 @@
+//! Network Layer Data Entity
+//!
+//! The NLDE passes NWK data frames for this device to the next higher layer
+//! (§3.2.1).
+
+use heapless::Vec;
+use zigbee_types::ShortAddress;
+
+/// Longest NSDU passed up to the next higher layer.
+pub const MAX_NSDU_LENGTH: usize = 128;
+
+/// Destination of a received NWK data frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NldeDestination {
+    /// Group ID of a multicast frame.
+    Group(u16),
+    /// Network address of this device or a broadcast address.
+    Network(ShortAddress),
+}
+
+/// NLDE-DATA.indication (§3.2.1.3).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NldeDataIndication {
+    pub destination: NldeDestination,
+    /// Network address of the originator.
+    pub source: ShortAddress,
+    pub nsdu: Vec<u8, MAX_NSDU_LENGTH>,
+    pub link_quality: u8,
+    /// Whether the frame was secured with the network key.
+    pub security_use: bool,
+}
+
+/// NLDE-DATA.confirm (§3.2.1.2) of a frame which could not be delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NldeDataConfirm {
+    /// Final destination of the frame.
+    pub destination: ShortAddress,
+    pub status: NldeDataStatus,
+}
+
+/// Status of a NLDE-DATA.confirm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NldeDataStatus {
+    /// The frame buffered for a sleepy end device child was not polled
+    /// within nwkTransactionPersistenceTime.
+    TransactionExpired,
+}
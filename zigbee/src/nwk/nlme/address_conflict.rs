@@ -0,0 +1,474 @@
+//! Network address conflict detection and resolution
+//!
+//! With stochastic address assignment two devices may end up with the same
+//! network address. Conflicts are detected from frames carrying both the
+//! network and the IEEE address of a device and reported with a broadcast
+//! network status. Routers pick a new address themselves, parents assign a
+//! new one to their end device children (§3.6.1.9).
+
+use zigbee_mac::mlme::Mlme;
+use zigbee_types::IeeeAddress;
+use zigbee_types::ShortAddress;
+
+use super::NetworkError;
+use super::Nlme;
+use super::association::MAX_ASSIGNABLE_ADDRESS;
+use super::management::NlmeNwkStatusIndication;
+use super::rejoin::rejoin_status;
+use crate::nwk::frame::CommandFrame;
+use crate::nwk::frame::Frame as NwkFrame;
+use crate::nwk::frame::command::Command as NwkCommand;
+use crate::nwk::frame::command::network_status::NetworkStatus;
+use crate::nwk::frame::command::network_status::NetworkStatusCode;
+use crate::nwk::frame::command::rejoin_response::RejoinResponse;
+use crate::nwk::frame::header::Header as NwkHeader;
+use crate::nwk::nib::NWK_COORDINATOR_ADDRESS;
+use crate::nwk::nib::addr_alloc;
+use crate::nwk::nib::broadcast_address;
+
+impl<M> Nlme<M>
+where
+    M: Mlme,
+{
+    /// Whether this device looks for address conflicts (§3.6.1.9.1).
+    fn address_conflict_detection(&self) -> bool {
+        let nib = self.nib();
+        nib.network_address() != 0xffff
+            && (nib.addr_alloc() == addr_alloc::STOCHASTIC || !nib.unique_addr())
+    }
+
+    /// Check the pairing of `network_address` and `ieee_address` learned
+    /// from a received frame against this device and nwkAddressMap
+    /// (§3.6.1.9.2).
+    ///
+    /// Pairings without conflict are recorded in nwkAddressMap. Returns the
+    /// NLME-NWK-STATUS.indication when this device changed its address.
+    pub(super) async fn check_address_conflict(
+        &mut self,
+        network_address: ShortAddress,
+        ieee_address: IeeeAddress,
+    ) -> Result<Option<NlmeNwkStatusIndication>, NetworkError> {
+        if !self.address_conflict_detection() || network_address.0 > MAX_ASSIGNABLE_ADDRESS {
+            return Ok(None);
+        }
+        let own_ieee = self.nib().ieee_address();
+        let conflict = if network_address.0 == self.nib().network_address() {
+            ieee_address != own_ieee
+        } else {
+            self.nib().address_map().iter().any(|entry| {
+                entry.network_address == network_address && entry.ieee_address != ieee_address
+            })
+        };
+        if !conflict {
+            if ieee_address != own_ieee {
+                self.update_address_map(ieee_address, network_address);
+            }
+            return Ok(None);
+        }
+
+        log::warn!(
+            "[NLME] address conflict on 0x{:04x} with {ieee_address:?}",
+            network_address.0
+        );
+        self.broadcast_address_conflict(network_address).await?;
+        self.resolve_address_conflict(network_address).await
+    }
+
+    /// Handle a network status reporting a conflict on `network_address`
+    /// (§3.6.1.9.3).
+    pub(super) async fn address_conflict_indication(
+        &mut self,
+        network_address: ShortAddress,
+    ) -> Result<Option<NlmeNwkStatusIndication>, NetworkError> {
+        if !self.address_conflict_detection() {
+            return Ok(None);
+        }
+        self.resolve_address_conflict(network_address).await
+    }
+
+    /// Device announcement received by the next higher layer, checked for
+    /// a conflicting address (§2.5.5.5.3.3, §3.6.1.9.2).
+    ///
+    /// Returns the NLME-NWK-STATUS.indication when this device changed its
+    /// address and has to announce itself again.
+    pub async fn device_annce_indication(
+        &mut self,
+        network_address: ShortAddress,
+        ieee_address: IeeeAddress,
+    ) -> Result<Option<NlmeNwkStatusIndication>, NetworkError> {
+        self.check_address_conflict(network_address, ieee_address)
+            .await
+    }
+
+    /// Check the source addresses carried by the NWK header of `frame`.
+    ///
+    /// Rejoin requests are skipped, the parent assigns a new address in its
+    /// rejoin response if needed.
+    pub(super) async fn frame_address_conflict(
+        &mut self,
+        frame: &NwkFrame<'_>,
+    ) -> Result<Option<NlmeNwkStatusIndication>, NetworkError> {
+        if let NwkFrame::NwkCommand(CommandFrame {
+            command: NwkCommand::RejoinRequest(_),
+            ..
+        }) = frame
+        {
+            return Ok(None);
+        }
+        let header = frame.header();
+        let Some(source_ieee) = header.source_ieee else {
+            return Ok(None);
+        };
+        self.check_address_conflict(header.source, source_ieee)
+            .await
+    }
+
+    /// Report the conflict on `network_address` to all rx-on-when-idle
+    /// devices.
+    async fn broadcast_address_conflict(
+        &mut self,
+        network_address: ShortAddress,
+    ) -> Result<(), NetworkError> {
+        let secure = self.nwk_security_enabled();
+        let radius = self.nib().max_depth().saturating_mul(2);
+        let header = self.nwk_command_header(
+            ShortAddress(broadcast_address::RX_ON_WHEN_IDLE),
+            radius,
+            secure,
+        );
+        let status = NetworkStatus {
+            status_code: NetworkStatusCode::AddressConflict,
+            destination_address: network_address,
+        };
+        let len = self.build_nwk_command_frame(header, NwkCommand::NetworkStatus(status))?;
+        self.originate_broadcast(len).await
+    }
+
+    /// Move this device or an end device child off `network_address`.
+    async fn resolve_address_conflict(
+        &mut self,
+        network_address: ShortAddress,
+    ) -> Result<Option<NlmeNwkStatusIndication>, NetworkError> {
+        let own_address = self.nib().network_address();
+        if network_address.0 == own_address {
+            // end devices get a new address from their parent, the
+            // coordinator keeps its address
+            if !self.is_router() || own_address == NWK_COORDINATOR_ADDRESS {
+                return Ok(None);
+            }
+            let Some(new_address) = self.allocate_address() else {
+                return Ok(None);
+            };
+            log::debug!(
+                "[NLME] changing address 0x{own_address:04x} -> 0x{:04x}",
+                new_address.0
+            );
+            self.nib().set_network_address(new_address.0);
+            self.mac.set_short_address(new_address);
+            self.update_beacon_payload();
+            return Ok(Some(NlmeNwkStatusIndication {
+                network_address: new_address,
+                status: NetworkStatusCode::NetworkAddressUpdate,
+            }));
+        }
+
+        if self.is_end_device_child(network_address) {
+            self.reassign_child_address(network_address).await?;
+        }
+        Ok(None)
+    }
+
+    /// Give the end device child at `network_address` a new address with an
+    /// unsolicited rejoin response (§3.6.1.9.3).
+    async fn reassign_child_address(
+        &mut self,
+        network_address: ShortAddress,
+    ) -> Result<(), NetworkError> {
+        let Some(device_address) = self
+            .nib()
+            .address_map()
+            .iter()
+            .find(|entry| entry.network_address == network_address)
+            .map(|entry| entry.ieee_address)
+        else {
+            return Ok(());
+        };
+        let Some(new_address) = self.allocate_address() else {
+            return Ok(());
+        };
+        log::debug!(
+            "[NLME] moving child {device_address:?} 0x{:04x} -> 0x{:04x}",
+            network_address.0,
+            new_address.0
+        );
+
+        let mut table = self.nib().neighbor_table();
+        for neighbor in table
+            .iter_mut()
+            .filter(|n| n.network_address == network_address)
+        {
+            neighbor.network_address = new_address;
+        }
+        self.nib().set_neighbor_table(table);
+        self.update_address_map(device_address, new_address);
+
+        let secure = self.nwk_security_enabled();
+        self.send_rejoin_response(
+            network_address,
+            device_address,
+            RejoinResponse {
+                network_address: new_address,
+                status: rejoin_status::SUCCESS,
+            },
+            secure,
+        )
+        .await
+    }
+
+    /// Handle a rejoin response outside of a rejoin, the parent assigning a
+    /// new address after a conflict (§3.6.1.9.3).
+    ///
+    /// Returns the NLME-NWK-STATUS.indication for the next higher layer to
+    /// announce the new address.
+    pub(super) fn rejoin_response_indication(
+        &mut self,
+        header: &NwkHeader<'_>,
+        response: &RejoinResponse,
+    ) -> Option<NlmeNwkStatusIndication> {
+        if header.source != self.parent_network_address().ok()?
+            || header.destination_ieee != Some(self.nib().ieee_address())
+            || response.status != rejoin_status::SUCCESS
+            || response.network_address.0 > MAX_ASSIGNABLE_ADDRESS
+        {
+            return None;
+        }
+        log::debug!(
+            "[NLME] parent assigned address 0x{:04x}",
+            response.network_address.0
+        );
+        self.nib().set_network_address(response.network_address.0);
+        self.mac.set_short_address(response.network_address);
+        Some(NlmeNwkStatusIndication {
+            network_address: response.network_address,
+            status: NetworkStatusCode::NetworkAddressUpdate,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use zigbee_mac::mlme::MacIndication;
+
+    use super::*;
+    use crate::nwk::frame::command::route_record::RouteRecord;
+    use crate::nwk::nib::DeviceType;
+    use crate::nwk::nib::relationship;
+    use crate::nwk::nlme::NwkIndication;
+    use crate::nwk::nlme::tests::MockMlme;
+    use crate::nwk::nlme::tests::OWN;
+    use crate::nwk::nlme::tests::PAN_ID;
+    use crate::nwk::nlme::tests::block_on;
+    use crate::nwk::nlme::tests::mac_short;
+    use crate::nwk::nlme::tests::make_neighbor;
+    use crate::nwk::nlme::tests::make_nlme;
+    use crate::nwk::nlme::tests::make_router;
+    use crate::nwk::nlme::tests::parse_command;
+
+    const CHILD: u16 = 0x4444;
+    const PARENT: u16 = NWK_COORDINATOR_ADDRESS;
+    const OWN_IEEE: IeeeAddress = IeeeAddress(0x0011_2233_4455_6677);
+    const CHILD_IEEE: IeeeAddress = IeeeAddress(0x0000_0000_0000_4444);
+    const OTHER_IEEE: IeeeAddress = IeeeAddress(0x0000_0000_0000_9999);
+
+    fn make_parent(mac: MockMlme) -> Nlme<MockMlme> {
+        let nlme = make_router(mac);
+        nlme.nib().set_ieee_address(OWN_IEEE);
+        let mut child = make_neighbor(PAN_ID, CHILD, 0, 0xff, 1);
+        child.device_type = DeviceType::EndDevice;
+        child.relationship = relationship::CHILD;
+        let mut table = nlme.nib().neighbor_table();
+        table.push(child).unwrap();
+        nlme.nib().set_neighbor_table(table);
+        nlme.update_address_map(CHILD_IEEE, ShortAddress(CHILD));
+        nlme
+    }
+
+    /// Receive a route record from `source` carrying `source_ieee`.
+    fn expect_frame(nlme: &mut Nlme<MockMlme>, source: u16, source_ieee: IeeeAddress) {
+        let mut header = nlme.nwk_command_header(ShortAddress(OWN), 1, false);
+        header.source = ShortAddress(source);
+        header.frame_control = header.frame_control.set_source_ieee_flag(true);
+        header.source_ieee = Some(source_ieee);
+        let command = NwkCommand::RouteRecord(RouteRecord {
+            relay_count: 0,
+            relay_list: &[],
+        });
+        let len = nlme.build_nwk_command_frame(header, command).unwrap();
+        let frame = nlme.buf[..len].to_vec();
+        nlme.mac.expect_receive().times(1).returning(move |buf| {
+            buf[..frame.len()].copy_from_slice(&frame);
+            Ok(MacIndication::Data {
+                source: mac_short(source),
+                destination: mac_short(OWN),
+                len: frame.len(),
+                lqi: 0xff,
+            })
+        });
+    }
+
+    fn expect_conflict_broadcast(mac: &mut MockMlme, address: u16) {
+        mac.expect_transmit_data()
+            .withf(move |dest, payload| {
+                let (header, NwkCommand::NetworkStatus(status)) = parse_command(payload) else {
+                    return false;
+                };
+                *dest == mac_short(0xffff)
+                    && header.destination == ShortAddress(broadcast_address::RX_ON_WHEN_IDLE)
+                    && status.status_code == NetworkStatusCode::AddressConflict
+                    && status.destination_address == ShortAddress(address)
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+    }
+
+    fn expect_address_change(mac: &mut MockMlme) {
+        mac.expect_set_short_address()
+            .withf(|address| address.0 != OWN && address.0 <= MAX_ASSIGNABLE_ADDRESS)
+            .times(1)
+            .return_const(());
+        mac.expect_set_beacon_payload().return_const(());
+    }
+
+    #[test]
+    fn router_changes_conflicting_own_address() {
+        let mut mac = MockMlme::new();
+        expect_conflict_broadcast(&mut mac, OWN);
+        expect_address_change(&mut mac);
+        let mut nlme = make_parent(mac);
+        expect_frame(&mut nlme, OWN, OTHER_IEEE);
+
+        let Some(NwkIndication::NwkStatus(indication)) = block_on(nlme.receive()).unwrap() else {
+            unreachable!("expected a network status indication");
+        };
+        assert_eq!(indication.status, NetworkStatusCode::NetworkAddressUpdate);
+        assert_ne!(indication.network_address, ShortAddress(OWN));
+        assert_eq!(nlme.nib().network_address(), indication.network_address.0);
+    }
+
+    #[test]
+    fn new_pairing_is_recorded() {
+        let mut nlme = make_parent(MockMlme::new());
+        expect_frame(&mut nlme, 0x2222, OTHER_IEEE);
+
+        block_on(nlme.receive()).unwrap();
+        assert_eq!(
+            nlme.lookup_network_address(OTHER_IEEE),
+            Some(ShortAddress(0x2222))
+        );
+    }
+
+    #[test]
+    fn conflicting_child_gets_new_address() {
+        let mut mac = MockMlme::new();
+        expect_conflict_broadcast(&mut mac, CHILD);
+        mac.expect_transmit_indirect()
+            .withf(|dest, payload, _| {
+                let (header, NwkCommand::RejoinResponse(response)) = parse_command(payload) else {
+                    return false;
+                };
+                *dest == mac_short(CHILD)
+                    && header.destination == ShortAddress(CHILD)
+                    && header.destination_ieee == Some(CHILD_IEEE)
+                    && response.status == rejoin_status::SUCCESS
+                    && response.network_address != ShortAddress(CHILD)
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        mac.expect_set_frame_pending()
+            .withf(|dest, _| *dest == mac_short(CHILD))
+            .times(2)
+            .return_const(());
+        let mut nlme = make_parent(mac);
+
+        let indication = block_on(nlme.device_annce_indication(ShortAddress(CHILD), OTHER_IEEE));
+        assert!(indication.unwrap().is_none());
+        // the sleepy child polls under its old address
+        block_on(nlme.indirect_poll_indication(mac_short(CHILD)));
+        let new_address = nlme.lookup_network_address(CHILD_IEEE).unwrap();
+        assert_ne!(new_address, ShortAddress(CHILD));
+        assert!(nlme.is_end_device_child(new_address));
+        assert!(!nlme.is_end_device_child(ShortAddress(CHILD)));
+    }
+
+    #[test]
+    fn network_status_conflict_changes_own_address() {
+        let mut mac = MockMlme::new();
+        expect_address_change(&mut mac);
+        let mut nlme = make_parent(mac);
+
+        let indication = block_on(nlme.address_conflict_indication(ShortAddress(OWN)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(indication.status, NetworkStatusCode::NetworkAddressUpdate);
+        assert_ne!(nlme.nib().network_address(), OWN);
+    }
+
+    #[test]
+    fn coordinator_keeps_its_address() {
+        let mut mac = MockMlme::new();
+        expect_conflict_broadcast(&mut mac, PARENT);
+        let mut nlme = make_parent(mac);
+        nlme.nib().set_network_address(PARENT);
+
+        let indication = block_on(nlme.device_annce_indication(ShortAddress(PARENT), OTHER_IEEE));
+        assert!(indication.unwrap().is_none());
+        assert_eq!(nlme.nib().network_address(), PARENT);
+    }
+
+    #[test]
+    fn no_detection_with_unique_distributed_addresses() {
+        let mut nlme = make_parent(MockMlme::new());
+        nlme.nib().set_addr_alloc(addr_alloc::DISTRIBUTED);
+
+        let indication = block_on(nlme.device_annce_indication(ShortAddress(OWN), OTHER_IEEE));
+        assert!(indication.unwrap().is_none());
+        assert_eq!(nlme.nib().network_address(), OWN);
+    }
+
+    #[test]
+    fn end_device_takes_address_from_parent() {
+        let mut mac = MockMlme::new();
+        mac.expect_set_short_address()
+            .withf(|address| address.0 == 0x5555)
+            .times(1)
+            .return_const(());
+        let mut nlme = make_nlme(mac);
+        nlme.nib().set_network_address(OWN);
+        nlme.nib().set_panid(PAN_ID);
+        nlme.nib().set_ieee_address(OWN_IEEE);
+        let mut parent = make_neighbor(PAN_ID, PARENT, 0, 0xff, 0);
+        parent.relationship = relationship::PARENT;
+        let mut table = nlme.nib().neighbor_table();
+        table.push(parent).unwrap();
+        nlme.nib().set_neighbor_table(table);
+
+        let mut header = nlme.nwk_command_header(ShortAddress(OWN), 1, false);
+        header.source = ShortAddress(PARENT);
+        header.destination_ieee = Some(OWN_IEEE);
+        let response = RejoinResponse {
+            network_address: ShortAddress(0x5555),
+            status: rejoin_status::SUCCESS,
+        };
+
+        let indication = nlme.rejoin_response_indication(&header, &response).unwrap();
+        assert_eq!(indication.network_address, ShortAddress(0x5555));
+        assert_eq!(nlme.nib().network_address(), 0x5555);
+
+        // only the parent may assign addresses
+        header.source = ShortAddress(0x2222);
+        assert!(
+            nlme.rejoin_response_indication(&header, &response)
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,570 @@
+//! Parent side of the join procedure
+//!
+//! Admits devices associating through this router or coordinator and
+//! assigns their network addresses (§3.6.1.4.1.2, §3.6.1.7).
+
+use zigbee_mac::Address;
+use zigbee_mac::AssociationStatus;
+use zigbee_mac::mlme::Mlme;
+use zigbee_types::IeeeAddress;
+use zigbee_types::ShortAddress;
+
+use super::NetworkError;
+use super::Nlme;
+use super::management::NlmeJoinIndication;
+use super::management::RejoinNetwork;
+use crate::nwk::nib::AddressMap;
+use crate::nwk::nib::CapabilityInformation;
+use crate::nwk::nib::DeviceType;
+use crate::nwk::nib::NwkNeighbor;
+use crate::nwk::nib::addr_alloc;
+use crate::nwk::nib::end_device_timeout_seconds;
+use crate::nwk::nib::relationship;
+
+/// Highest network address which may be assigned to a device.
+pub(super) const MAX_ASSIGNABLE_ADDRESS: u16 = 0xfff7;
+
+impl<M> Nlme<M>
+where
+    M: Mlme,
+{
+    /// Handle an MLME-ASSOCIATE.indication (§3.6.1.4.1.2).
+    ///
+    /// Admits the device as a child if joining is permitted and there is
+    /// capacity left, and issues the matching MLME-ASSOCIATE.response.
+    /// Returns the NLME-JOIN.indication for the next higher layer when the
+    /// device was admitted.
+    pub async fn associate_indication(
+        &mut self,
+        device_address: IeeeAddress,
+        capability_information: zigbee_mac::CapabilityInformation,
+    ) -> Result<Option<NlmeJoinIndication>, NetworkError> {
+        let capability_information = Self::build_nwk_capabilities(&capability_information);
+        let join_as_router = capability_information.device_type();
+
+        let (status, network_address) =
+            match self.admit_child(device_address, capability_information) {
+                Ok(network_address) => (AssociationStatus::Successful, network_address),
+                Err(status) => (status, ShortAddress(0xffff)),
+            };
+        log::debug!(
+            "[NLME] association from {device_address:?} (router: {join_as_router}): {status:?}"
+        );
+
+        self.mac
+            .associate_response(device_address, network_address, status)
+            .await?;
+
+        if status != AssociationStatus::Successful {
+            return Ok(None);
+        }
+
+        // capacity may have changed
+        self.update_beacon_payload();
+
+        Ok(Some(NlmeJoinIndication {
+            network_address,
+            extended_address: device_address,
+            capability_information,
+            rejoin_network: RejoinNetwork::Association,
+            secure_rejoin: false,
+        }))
+    }
+
+    /// Check the join permission and capacity, assign a network address and
+    /// add the device to the neighbor table.
+    fn admit_child(
+        &mut self,
+        device_address: IeeeAddress,
+        capability_information: CapabilityInformation,
+    ) -> Result<ShortAddress, AssociationStatus> {
+        if !self.is_joining_permitted() {
+            return Err(AssociationStatus::AccessDenied);
+        }
+
+        // A child associating again keeps its address (§3.6.1.4.1.2).
+        let previous_address = self.child_address(device_address);
+        if !self.has_child_slot(previous_address, capability_information.device_type()) {
+            return Err(AssociationStatus::NetworkAtCapacity);
+        }
+        if let Some(network_address) = previous_address {
+            self.remove_neighbor(network_address);
+        }
+
+        let network_address = match previous_address {
+            Some(network_address) => network_address,
+            None => self
+                .allocate_address()
+                .ok_or(AssociationStatus::NetworkAtCapacity)?,
+        };
+
+        // Children of a secured network stay unauthenticated until the
+        // Trust Center has delivered the network key (§4.6.3.2).
+        let relationship = if self.nib().security_material_set().is_empty() {
+            relationship::CHILD
+        } else {
+            relationship::UNAUTHENTICATED_CHILD
+        };
+        self.add_child(
+            device_address,
+            network_address,
+            capability_information,
+            relationship,
+        )?;
+
+        Ok(network_address)
+    }
+
+    /// Whether a device may become a child, `previous_address` being its
+    /// address if it already is one.
+    ///
+    /// A child keeps its slot unless it changes its device type.
+    pub(super) fn has_child_slot(
+        &self,
+        previous_address: Option<ShortAddress>,
+        join_as_router: bool,
+    ) -> bool {
+        let keeps_slot = previous_address.is_some_and(|network_address| {
+            self.is_end_device_child(network_address) != join_as_router
+        });
+        keeps_slot || self.has_child_capacity(join_as_router)
+    }
+
+    /// Whether another router or end device may join as a child.
+    pub(super) fn has_child_capacity(&self, join_as_router: bool) -> bool {
+        if join_as_router {
+            self.router_capacity()
+        } else {
+            self.end_device_capacity()
+        }
+    }
+
+    /// Add `device_address` to the neighbor table as a child and record its
+    /// network address in nwkAddressMap.
+    pub(super) fn add_child(
+        &self,
+        device_address: IeeeAddress,
+        network_address: ShortAddress,
+        capability_information: CapabilityInformation,
+        relationship: u8,
+    ) -> Result<(), AssociationStatus> {
+        // end devices start out with nwkEndDeviceTimeoutDefault until they
+        // request their own timeout (§3.6.10.3)
+        let device_timeout = if capability_information.device_type() {
+            0
+        } else {
+            end_device_timeout_seconds(self.nib().end_device_timeout_default()).unwrap_or(0)
+        };
+        let neighbor = NwkNeighbor {
+            network_address,
+            device_type: if capability_information.device_type() {
+                DeviceType::Router
+            } else {
+                DeviceType::EndDevice
+            },
+            rx_on_when_idle: capability_information.receiver_on_when_idle(),
+            end_device_configuration: 0,
+            timeout_counter: device_timeout,
+            device_timeout,
+            relationship,
+            transmit_failure: 0,
+            lqi: 0,
+            outgoing_cost: 0,
+            age: 0,
+            keepalive_received: false,
+            extended_pan_id: IeeeAddress(0),
+            logical_channel: 0,
+            depth: self.depth.saturating_add(1),
+            permit_joining: false,
+            potential_parent: 0,
+            router_capacity: false,
+            end_device_capacity: false,
+            update_id: 0,
+            pan_id: 0xffff,
+        };
+        let mut table = self.nib().neighbor_table();
+        if table.push(neighbor).is_err() {
+            return Err(AssociationStatus::NetworkAtCapacity);
+        }
+        self.nib().set_neighbor_table(table);
+        self.update_address_map(device_address, network_address);
+
+        Ok(())
+    }
+
+    /// Network address previously assigned to the child `device_address`.
+    pub(super) fn child_address(&self, device_address: IeeeAddress) -> Option<ShortAddress> {
+        let network_address = self.lookup_network_address(device_address)?;
+        self.nib()
+            .neighbor_table()
+            .iter()
+            .any(|n| {
+                n.network_address == network_address
+                    && (n.relationship == relationship::CHILD
+                        || n.relationship == relationship::UNAUTHENTICATED_CHILD)
+            })
+            .then_some(network_address)
+    }
+
+    /// Mark the unauthenticated child at `source` as a child once it sent a
+    /// frame secured with the network key (§4.6.3.2).
+    pub(super) fn authenticate_child(&self, source: Address) {
+        let Address::Short(_, source) = source else {
+            return;
+        };
+        let mut table = self.nib().neighbor_table();
+        let Some(neighbor) = table.iter_mut().find(|n| {
+            n.network_address.0 == source.0 && n.relationship == relationship::UNAUTHENTICATED_CHILD
+        }) else {
+            return;
+        };
+        neighbor.relationship = relationship::CHILD;
+        self.nib().set_neighbor_table(table);
+        log::debug!("[NLME] child 0x{:04x} authenticated", source.0);
+    }
+
+    /// Look up the network address of `ieee_address` in nwkAddressMap.
+    pub(crate) fn lookup_network_address(&self, ieee_address: IeeeAddress) -> Option<ShortAddress> {
+        self.nib()
+            .address_map()
+            .iter()
+            .find(|entry| entry.ieee_address == ieee_address)
+            .map(|entry| entry.network_address)
+    }
+
+    /// Insert or update the nwkAddressMap entry of `ieee_address`.
+    ///
+    /// When the map is full the oldest entry is evicted.
+    pub(crate) fn update_address_map(
+        &self,
+        ieee_address: IeeeAddress,
+        network_address: ShortAddress,
+    ) {
+        let mut map = self.nib().address_map();
+        if let Some(entry) = map.iter_mut().find(|e| e.ieee_address == ieee_address) {
+            entry.network_address = network_address;
+        } else {
+            if map.is_full() {
+                map.remove(0);
+            }
+            let _ = map.push(AddressMap {
+                ieee_address,
+                network_address,
+            });
+        }
+        self.nib().set_address_map(map);
+    }
+
+    /// Remove the neighbor table entry of `network_address`.
+    pub(crate) fn remove_neighbor(&self, network_address: ShortAddress) {
+        let mut table = self.nib().neighbor_table();
+        table.retain(|n| n.network_address != network_address);
+        self.nib().set_neighbor_table(table);
+    }
+
+    /// Assign a network address to a joining device according to
+    /// nwkAddrAlloc (§3.6.1.7).
+    pub(super) fn allocate_address(&mut self) -> Option<ShortAddress> {
+        if self.nib().addr_alloc() != addr_alloc::STOCHASTIC {
+            log::warn!("[NLME] only stochastic address assignment is supported");
+            return None;
+        }
+
+        // §3.6.1.7.2: pick a random address in 0x0001..=0xfff7 not in use
+        // by this device or any device it knows about.
+        loop {
+            let candidate = (self.next_random() & 0xffff) as u16;
+            if candidate == 0 || candidate > MAX_ASSIGNABLE_ADDRESS {
+                continue;
+            }
+            if !self.is_address_in_use(ShortAddress(candidate)) {
+                return Some(ShortAddress(candidate));
+            }
+        }
+    }
+
+    /// Whether `network_address` is used by this device or a known device.
+    pub(super) fn is_address_in_use(&self, network_address: ShortAddress) -> bool {
+        let nib = self.nib();
+        nib.network_address() == network_address.0
+            || nib
+                .neighbor_table()
+                .iter()
+                .any(|n| n.network_address == network_address)
+            || nib
+                .address_map()
+                .iter()
+                .any(|e| e.network_address == network_address)
+    }
+
+    /// Build the NWK layer `CapabilityInformation` bitmap (Table 3-62) from
+    /// the IEEE 802.15.4 MAC `CapabilityInformation`.
+    fn build_nwk_capabilities(cap: &zigbee_mac::CapabilityInformation) -> CapabilityInformation {
+        CapabilityInformation(
+            u8::from(cap.full_function_device) << 1
+                | u8::from(cap.mains_power) << 2
+                | u8::from(cap.idle_receive) << 3
+                | u8::from(cap.frame_protection) << 6
+                | u8::from(cap.allocate_address) << 7,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use zigbee_mac::mlme::MacIndication;
+
+    use super::*;
+    use crate::nwk::frame::command::Command as NwkCommand;
+    use crate::nwk::frame::command::network_status::NetworkStatus;
+    use crate::nwk::frame::command::network_status::NetworkStatusCode;
+    use crate::nwk::nib::NWK_COORDINATOR_ADDRESS;
+    use crate::nwk::nib::NetworkSecurityMaterialDescriptor;
+    use crate::nwk::nlme::NwkIndication;
+    use crate::nwk::nlme::management::NlmePermitJoiningRequest;
+    use crate::nwk::nlme::tests::MockMlme;
+    use crate::nwk::nlme::tests::block_on;
+    use crate::nwk::nlme::tests::expect_command_frame;
+    use crate::nwk::nlme::tests::install_network_key;
+    use crate::nwk::nlme::tests::make_nlme;
+
+    const CHILD: IeeeAddress = IeeeAddress(0x0102_0304_0506_0708);
+
+    fn end_device_capabilities() -> zigbee_mac::CapabilityInformation {
+        zigbee_mac::CapabilityInformation {
+            full_function_device: false,
+            mains_power: false,
+            idle_receive: false,
+            frame_protection: false,
+            allocate_address: true,
+        }
+    }
+
+    fn router_capabilities() -> zigbee_mac::CapabilityInformation {
+        zigbee_mac::CapabilityInformation {
+            full_function_device: true,
+            mains_power: true,
+            idle_receive: true,
+            frame_protection: false,
+            allocate_address: true,
+        }
+    }
+
+    /// A coordinator which has formed a network and permits joining.
+    fn make_coordinator(mut mac: MockMlme) -> Nlme<MockMlme> {
+        mac.expect_set_association_permit().return_const(());
+        mac.expect_set_beacon_payload().return_const(());
+        let mut nlme = make_nlme(mac);
+        nlme.nib().set_network_address(NWK_COORDINATOR_ADDRESS);
+        block_on(nlme.permit_joining(NlmePermitJoiningRequest {
+            permit_duration: 0xff,
+        }));
+        nlme
+    }
+
+    fn expect_response(mac: &mut MockMlme, expected: AssociationStatus) {
+        mac.expect_associate_response()
+            .withf(move |_, _, status| *status == expected)
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+    }
+
+    #[test]
+    fn associate_end_device_child() {
+        let mut mac = MockMlme::new();
+        mac.expect_associate_response()
+            .withf(|device, address, status| {
+                *device == CHILD
+                    && *status == AssociationStatus::Successful
+                    && (0x0001..=0xfff7).contains(&address.0)
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        let mut nlme = make_coordinator(mac);
+
+        let indication = block_on(nlme.associate_indication(CHILD, end_device_capabilities()))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(indication.extended_address, CHILD);
+        assert_eq!(indication.rejoin_network, RejoinNetwork::Association);
+        assert!(!indication.capability_information.device_type());
+        assert!(indication.capability_information.allocate_address());
+
+        let table = nlme.nib().neighbor_table();
+        assert_eq!(table.len(), 1);
+        assert_eq!(table[0].network_address, indication.network_address);
+        assert_eq!(table[0].relationship, relationship::CHILD);
+        assert!(matches!(table[0].device_type, DeviceType::EndDevice));
+        assert_eq!(table[0].depth, 1);
+        assert_eq!(
+            nlme.lookup_network_address(CHILD),
+            Some(indication.network_address)
+        );
+    }
+
+    #[test]
+    fn associate_denied_when_joining_not_permitted() {
+        let mut mac = MockMlme::new();
+        expect_response(&mut mac, AssociationStatus::AccessDenied);
+        let mut nlme = make_nlme(mac);
+        nlme.nib().set_network_address(NWK_COORDINATOR_ADDRESS);
+
+        let indication =
+            block_on(nlme.associate_indication(CHILD, end_device_capabilities())).unwrap();
+
+        assert!(indication.is_none());
+        assert!(nlme.nib().neighbor_table().is_empty());
+    }
+
+    #[test]
+    fn associate_router_rejected_when_max_routers_reached() {
+        let mut mac = MockMlme::new();
+        expect_response(&mut mac, AssociationStatus::Successful);
+        expect_response(&mut mac, AssociationStatus::NetworkAtCapacity);
+        let mut nlme = make_coordinator(mac);
+        nlme.nib().set_max_routers(1);
+
+        let first = block_on(nlme.associate_indication(CHILD, router_capabilities())).unwrap();
+        assert!(first.is_some());
+
+        let second =
+            block_on(nlme.associate_indication(IeeeAddress(0xAA), router_capabilities())).unwrap();
+        assert!(second.is_none());
+        assert_eq!(nlme.nib().neighbor_table().len(), 1);
+    }
+
+    #[test]
+    fn associate_rejected_when_max_children_reached() {
+        let mut mac = MockMlme::new();
+        expect_response(&mut mac, AssociationStatus::NetworkAtCapacity);
+        let mut nlme = make_coordinator(mac);
+        nlme.nib().set_max_children(0);
+
+        let indication =
+            block_on(nlme.associate_indication(CHILD, end_device_capabilities())).unwrap();
+        assert!(indication.is_none());
+    }
+
+    #[test]
+    fn associate_again_keeps_address() {
+        let mut mac = MockMlme::new();
+        mac.expect_associate_response()
+            .times(2)
+            .returning(|_, _, _| Ok(()));
+        let mut nlme = make_coordinator(mac);
+
+        let first = block_on(nlme.associate_indication(CHILD, end_device_capabilities()))
+            .unwrap()
+            .unwrap();
+        let second = block_on(nlme.associate_indication(CHILD, end_device_capabilities()))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(first.network_address, second.network_address);
+        assert_eq!(nlme.nib().neighbor_table().len(), 1);
+    }
+
+    #[test]
+    fn associate_on_secured_network_is_unauthenticated() {
+        let mut mac = MockMlme::new();
+        expect_response(&mut mac, AssociationStatus::Successful);
+        let mut nlme = make_coordinator(mac);
+        let mut set = nlme.nib().security_material_set();
+        set.push(NetworkSecurityMaterialDescriptor {
+            key_seq_number: 0,
+            outgoing_frame_counter: 0,
+            incoming_frame_counter_set: zigbee_types::StorageVec::default(),
+            key: zigbee_types::ByteArray([0x11; 16]),
+            network_key_type: 0x01,
+        })
+        .unwrap();
+        nlme.nib().set_security_material_set(set);
+
+        block_on(nlme.associate_indication(CHILD, end_device_capabilities())).unwrap();
+
+        let table = nlme.nib().neighbor_table();
+        assert_eq!(table[0].relationship, relationship::UNAUTHENTICATED_CHILD);
+    }
+
+    #[test]
+    fn associate_again_as_router_at_capacity_keeps_child() {
+        let mut mac = MockMlme::new();
+        expect_response(&mut mac, AssociationStatus::Successful);
+        expect_response(&mut mac, AssociationStatus::NetworkAtCapacity);
+        let mut nlme = make_coordinator(mac);
+        nlme.nib().set_max_routers(0);
+
+        let first = block_on(nlme.associate_indication(CHILD, end_device_capabilities()))
+            .unwrap()
+            .unwrap();
+        let second = block_on(nlme.associate_indication(CHILD, router_capabilities())).unwrap();
+
+        assert!(second.is_none());
+        assert_eq!(nlme.child_address(CHILD), Some(first.network_address));
+        assert!(nlme.is_end_device_child(first.network_address));
+    }
+
+    /// Associate `CHILD` with a secured coordinator and let it send a
+    /// network status command, secured with the network key or not.
+    fn child_sends_command(secure: bool) -> Nlme<MockMlme> {
+        let mut mac = MockMlme::new();
+        expect_response(&mut mac, AssociationStatus::Successful);
+        let mut nlme = make_coordinator(mac);
+        install_network_key(&nlme);
+        let indication = block_on(nlme.associate_indication(CHILD, end_device_capabilities()))
+            .unwrap()
+            .unwrap();
+        let status = NetworkStatus {
+            status_code: NetworkStatusCode::NoRouteAvailable,
+            destination_address: ShortAddress(0x2222),
+        };
+        expect_command_frame(
+            &mut nlme,
+            indication.network_address.0,
+            NwkCommand::NetworkStatus(status),
+            secure,
+        );
+        block_on(nlme.receive()).unwrap();
+        nlme
+    }
+
+    #[test]
+    fn secured_frame_authenticates_child() {
+        let nlme = child_sends_command(true);
+
+        let table = nlme.nib().neighbor_table();
+        assert_eq!(table[0].relationship, relationship::CHILD);
+    }
+
+    #[test]
+    fn unsecured_frame_keeps_child_unauthenticated() {
+        let nlme = child_sends_command(false);
+
+        let table = nlme.nib().neighbor_table();
+        assert_eq!(table[0].relationship, relationship::UNAUTHENTICATED_CHILD);
+    }
+
+    #[test]
+    fn receive_reports_join_indication() {
+        let mut mac = MockMlme::new();
+        mac.expect_receive().times(1).returning(|_| {
+            Ok(MacIndication::Associate {
+                device_address: CHILD,
+                capability_information: end_device_capabilities(),
+            })
+        });
+        expect_response(&mut mac, AssociationStatus::Successful);
+        let mut nlme = make_coordinator(mac);
+
+        let indication = block_on(nlme.receive()).unwrap();
+
+        assert!(matches!(
+            indication,
+            Some(NwkIndication::Join(NlmeJoinIndication {
+                extended_address: CHILD,
+                ..
+            }))
+        ));
+    }
+}
@@ -0,0 +1,372 @@
+//! NIB maintenance
+//!
+//! The next higher layer reads and writes NIB attributes by their identifier
+//! with NLME-GET and NLME-SET (§3.2.2.26 - §3.2.2.29). Only attributes with
+//! a scalar value are accessible this way, the tables of the NIB are
+//! available through the typed accessors of [`Nib`].
+
+use zigbee_mac::PanId;
+use zigbee_mac::mlme::Mlme;
+use zigbee_types::ShortAddress;
+
+use super::Nlme;
+use super::management::AttributeValue;
+use super::management::NlmeGetConfirm;
+use super::management::NlmeSetConfirm;
+use super::management::NlmeSetRequest;
+use super::management::NwkStatus;
+use crate::nwk::nib::Nib;
+use crate::nwk::nib::addr_alloc;
+use crate::nwk::nib::attribute_id;
+use crate::security::frame::SecurityLevel;
+
+/// Highest network address a device may use, higher addresses are
+/// broadcast addresses.
+const MAX_NETWORK_ADDRESS: u16 = 0xfff7;
+
+/// Upper bound of nwkPassiveAckTimeout, 10 s in OctetDurations.
+const MAX_PASSIVE_ACK_TIMEOUT: u32 = 312_500;
+
+/// Upper bound of nwkMaxBroadcastRetries.
+const MAX_BROADCAST_RETRIES: u8 = 0x05;
+
+impl<M> Nlme<M>
+where
+    M: Mlme,
+{
+    /// 3.2.2.26 - NLME-GET.request
+    ///
+    /// Read the NIB attribute `attribute`.
+    pub fn get(&self, attribute: u8) -> NlmeGetConfirm {
+        use AttributeValue::Bool;
+        use AttributeValue::U8;
+        use AttributeValue::U16;
+        use AttributeValue::U32;
+        use AttributeValue::U64;
+
+        let nib = self.nib();
+        let value = match attribute {
+            attribute_id::PAN_ID => Some(U16(nib.panid())),
+            attribute_id::SEQUENCE_NUMBER => Some(U8(nib.sequence_number())),
+            attribute_id::PASSIVE_ACK_TIMEOUT => Some(U32(nib.passive_ack_timeout())),
+            attribute_id::MAX_BROADCAST_RETRIES => Some(U8(nib.max_broadcast_retries())),
+            attribute_id::MAX_CHILDREN => Some(U8(nib.max_children())),
+            attribute_id::MAX_DEPTH => Some(U8(nib.max_depth())),
+            attribute_id::MAX_ROUTERS => Some(U8(nib.max_routers())),
+            attribute_id::NETWORK_BROADCAST_DELIVERY_TIME => {
+                Some(U32(nib.network_broadcast_delivery_time()))
+            }
+            attribute_id::REPORT_CONSTANT_COST => Some(U8(nib.report_constant_cost())),
+            attribute_id::TIME_STAMP => Some(Bool(nib.time_stamp())),
+            attribute_id::TX_TOTAL => Some(U16(nib.tx_total())),
+            attribute_id::SYM_LINK => Some(Bool(nib.sym_link())),
+            attribute_id::CAPABILITY_INFORMATION => Some(U8(nib.capability_information().0)),
+            attribute_id::ADDR_ALLOC => Some(U8(nib.addr_alloc())),
+            attribute_id::USE_TREE_ROUTING => Some(Bool(nib.use_tree_routing())),
+            attribute_id::MANAGER_ADDR => Some(U16(nib.manager_addr())),
+            attribute_id::MAX_SOURCE_ROUTE => Some(U8(nib.max_source_route())),
+            attribute_id::UPDATE_ID => Some(U8(nib.update_id())),
+            attribute_id::TRANSACTION_PERSISTENCE_TIME => {
+                Some(U16(nib.transaction_persistence_time()))
+            }
+            attribute_id::NETWORK_ADDRESS => Some(U16(nib.network_address())),
+            attribute_id::STACK_PROFILE => Some(U8(nib.stack_profile())),
+            attribute_id::EXTENDED_PAN_ID => Some(U64(nib.extended_panid())),
+            attribute_id::USE_MULTICAST => Some(Bool(nib.use_multicast())),
+            attribute_id::IS_CONCENTRATOR => Some(Bool(nib.is_concentrator())),
+            attribute_id::CONCENTRATOR_RADIUS => Some(U8(nib.concentrator_radius())),
+            attribute_id::CONCENTRATOR_DISCOVERY_TIME => {
+                Some(U8(nib.concentrator_discovery_time()))
+            }
+            attribute_id::SECURITY_LEVEL => Some(U8(nib.security_level().into_bits())),
+            attribute_id::ACTIVE_KEY_SEQ_NUMBER => Some(U8(nib.active_key_seq_number())),
+            attribute_id::ALL_FRESH => Some(Bool(nib.all_fresh())),
+            attribute_id::LINK_STATUS_PERIOD => Some(U8(nib.link_status_period())),
+            attribute_id::ROUTER_AGE_LIMIT => Some(U8(nib.router_age_limit())),
+            attribute_id::UNIQUE_ADDR => Some(Bool(nib.unique_addr())),
+            _ => None,
+        };
+
+        NlmeGetConfirm {
+            status: if value.is_some() {
+                NwkStatus::Success
+            } else {
+                NwkStatus::UnsupportedAttribute
+            },
+            attribute,
+            value,
+        }
+    }
+
+    /// 3.2.2.28 - NLME-SET.request
+    ///
+    /// Write a NIB attribute. A value of the wrong type or out of the range
+    /// of Table 3-58 is rejected with [`NwkStatus::InvalidParameter`].
+    pub fn set(&mut self, request: NlmeSetRequest) -> NlmeSetConfirm {
+        let status = self.set_attribute(request.attribute, request.value);
+        // keep the beacon payload in line with the attributes it advertises
+        if status == NwkStatus::Success
+            && matches!(
+                request.attribute,
+                attribute_id::MAX_CHILDREN
+                    | attribute_id::MAX_ROUTERS
+                    | attribute_id::STACK_PROFILE
+                    | attribute_id::EXTENDED_PAN_ID
+                    | attribute_id::UPDATE_ID
+            )
+        {
+            self.update_beacon_payload();
+        }
+        NlmeSetConfirm {
+            status,
+            attribute: request.attribute,
+        }
+    }
+
+    fn set_attribute(&mut self, attribute: u8, value: AttributeValue) -> NwkStatus {
+        let nib = &self.nib;
+        let result = match attribute {
+            attribute_id::SEQUENCE_NUMBER
+            | attribute_id::MAX_DEPTH
+            | attribute_id::CAPABILITY_INFORMATION => return NwkStatus::ReadOnly,
+            attribute_id::PAN_ID => value.as_u16().map(|v| {
+                nib.set_panid(v);
+                self.mac.set_pan_id(PanId(v));
+            }),
+            attribute_id::PASSIVE_ACK_TIMEOUT => value
+                .as_u32()
+                .filter(|v| *v <= MAX_PASSIVE_ACK_TIMEOUT)
+                .map(|v| nib.set_passive_ack_timeout(v)),
+            attribute_id::MAX_BROADCAST_RETRIES => value
+                .as_u8()
+                .filter(|v| *v <= MAX_BROADCAST_RETRIES)
+                .map(|v| nib.set_max_broadcast_retries(v)),
+            attribute_id::MAX_CHILDREN => value.as_u8().map(|v| nib.set_max_children(v)),
+            attribute_id::MAX_ROUTERS => value
+                .as_u8()
+                .filter(|v| *v >= 0x01)
+                .map(|v| nib.set_max_routers(v)),
+            attribute_id::NETWORK_BROADCAST_DELIVERY_TIME => value
+                .as_u32()
+                .map(|v| nib.set_network_broadcast_delivery_time(v)),
+            attribute_id::REPORT_CONSTANT_COST => value
+                .as_u8()
+                .filter(|v| *v <= 0x01)
+                .map(|v| nib.set_report_constant_cost(v)),
+            attribute_id::TIME_STAMP => value.as_bool().map(|v| nib.set_time_stamp(v)),
+            attribute_id::TX_TOTAL => value.as_u16().map(|v| nib.set_tx_total(v)),
+            attribute_id::SYM_LINK => value.as_bool().map(|v| nib.set_sym_link(v)),
+            attribute_id::ADDR_ALLOC => value
+                .as_u8()
+                .filter(|v| *v <= addr_alloc::STOCHASTIC)
+                .map(|v| nib.set_addr_alloc(v)),
+            attribute_id::USE_TREE_ROUTING => value.as_bool().map(|v| nib.set_use_tree_routing(v)),
+            attribute_id::MANAGER_ADDR => value
+                .as_u16()
+                .filter(|v| *v <= MAX_NETWORK_ADDRESS)
+                .map(|v| nib.set_manager_addr(v)),
+            attribute_id::MAX_SOURCE_ROUTE => value.as_u8().map(|v| nib.set_max_source_route(v)),
+            attribute_id::UPDATE_ID => value.as_u8().map(|v| nib.set_update_id(v)),
+            attribute_id::TRANSACTION_PERSISTENCE_TIME => value
+                .as_u16()
+                .map(|v| nib.set_transaction_persistence_time(v)),
+            attribute_id::NETWORK_ADDRESS => value
+                .as_u16()
+                .filter(|v| *v <= MAX_NETWORK_ADDRESS)
+                .map(|v| {
+                    nib.set_network_address(v);
+                    self.mac.set_short_address(ShortAddress(v));
+                }),
+            attribute_id::STACK_PROFILE => value
+                .as_u8()
+                .filter(|v| *v <= 0x0f)
+                .map(|v| nib.set_stack_profile(v)),
+            attribute_id::EXTENDED_PAN_ID => value
+                .as_u64()
+                .filter(|v| *v != u64::MAX)
+                .map(|v| nib.set_extended_panid(v)),
+            attribute_id::USE_MULTICAST => value.as_bool().map(|v| nib.set_use_multicast(v)),
+            attribute_id::IS_CONCENTRATOR => value.as_bool().map(|v| nib.set_is_concentrator(v)),
+            attribute_id::CONCENTRATOR_RADIUS => {
+                value.as_u8().map(|v| nib.set_concentrator_radius(v))
+            }
+            attribute_id::CONCENTRATOR_DISCOVERY_TIME => value
+                .as_u8()
+                .map(|v| nib.set_concentrator_discovery_time(v)),
+            attribute_id::SECURITY_LEVEL => value
+                .as_u8()
+                .filter(|v| *v <= 0x07)
+                .map(|v| nib.set_security_level(SecurityLevel::from_bits(v))),
+            // only a key of nwkSecurityMaterialSet can become active
+            attribute_id::ACTIVE_KEY_SEQ_NUMBER => value
+                .as_u8()
+                .filter(|v| {
+                    nib.security_material_set()
+                        .iter()
+                        .any(|key| key.key_seq_number == *v)
+                })
+                .map(|v| nib.set_active_key_seq_number(v)),
+            attribute_id::ALL_FRESH => value.as_bool().map(|v| nib.set_all_fresh(v)),
+            attribute_id::LINK_STATUS_PERIOD => {
+                value.as_u8().map(|v| nib.set_link_status_period(v))
+            }
+            attribute_id::ROUTER_AGE_LIMIT => value.as_u8().map(|v| nib.set_router_age_limit(v)),
+            attribute_id::UNIQUE_ADDR => value.as_bool().map(|v| nib.set_unique_addr(v)),
+            _ => return NwkStatus::UnsupportedAttribute,
+        };
+
+        match result {
+            Some(()) => NwkStatus::Success,
+            None => NwkStatus::InvalidParameter,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nwk::nlme::tests::MockMlme;
+    use crate::nwk::nlme::tests::install_network_key;
+    use crate::nwk::nlme::tests::make_nlme;
+
+    #[test]
+    fn get_returns_attribute_value() {
+        let nlme = make_nlme(MockMlme::new());
+        nlme.nib().set_max_children(0x10);
+
+        let confirm = nlme.get(attribute_id::MAX_CHILDREN);
+
+        assert_eq!(confirm.status, NwkStatus::Success);
+        assert_eq!(confirm.attribute, attribute_id::MAX_CHILDREN);
+        assert_eq!(confirm.value, Some(AttributeValue::U8(0x10)));
+    }
+
+    #[test]
+    fn unknown_attribute_is_unsupported() {
+        let mut nlme = make_nlme(MockMlme::new());
+
+        assert_eq!(nlme.get(0x8a).status, NwkStatus::UnsupportedAttribute);
+        assert_eq!(
+            nlme.get(attribute_id::NEIGHBOR_TABLE).status,
+            NwkStatus::UnsupportedAttribute
+        );
+        let confirm = nlme.set(NlmeSetRequest {
+            attribute: 0x8a,
+            value: AttributeValue::U8(3),
+        });
+        assert_eq!(confirm.status, NwkStatus::UnsupportedAttribute);
+    }
+
+    #[test]
+    fn set_writes_attribute() {
+        let mut mac = MockMlme::new();
+        mac.expect_set_short_address()
+            .withf(|address| *address == ShortAddress(0x1234))
+            .times(1)
+            .return_const(());
+        let mut nlme = make_nlme(mac);
+
+        let confirm = nlme.set(NlmeSetRequest {
+            attribute: attribute_id::NETWORK_ADDRESS,
+            value: AttributeValue::U16(0x1234),
+        });
+
+        assert_eq!(confirm.status, NwkStatus::Success);
+        assert_eq!(nlme.nib().network_address(), 0x1234);
+        assert_eq!(
+            nlme.get(attribute_id::NETWORK_ADDRESS).value,
+            Some(AttributeValue::U16(0x1234))
+        );
+    }
+
+    #[test]
+    fn set_rejects_invalid_values() {
+        let mut nlme = make_nlme(MockMlme::new());
+
+        let out_of_range = nlme.set(NlmeSetRequest {
+            attribute: attribute_id::REPORT_CONSTANT_COST,
+            value: AttributeValue::U8(2),
+        });
+        let wrong_type = nlme.set(NlmeSetRequest {
+            attribute: attribute_id::MAX_CHILDREN,
+            value: AttributeValue::U16(2),
+        });
+
+        assert_eq!(out_of_range.status, NwkStatus::InvalidParameter);
+        assert_eq!(wrong_type.status, NwkStatus::InvalidParameter);
+        assert_eq!(nlme.nib().report_constant_cost(), 0x00);
+    }
+
+    #[test]
+    fn set_enforces_table_3_58_ranges() {
+        let mut nlme = make_nlme(MockMlme::new());
+
+        let retries = nlme.set(NlmeSetRequest {
+            attribute: attribute_id::MAX_BROADCAST_RETRIES,
+            value: AttributeValue::U8(6),
+        });
+        let passive_ack = nlme.set(NlmeSetRequest {
+            attribute: attribute_id::PASSIVE_ACK_TIMEOUT,
+            value: AttributeValue::U32(MAX_PASSIVE_ACK_TIMEOUT + 1),
+        });
+        let routers = nlme.set(NlmeSetRequest {
+            attribute: attribute_id::MAX_ROUTERS,
+            value: AttributeValue::U8(0),
+        });
+
+        assert_eq!(retries.status, NwkStatus::InvalidParameter);
+        assert_eq!(passive_ack.status, NwkStatus::InvalidParameter);
+        assert_eq!(routers.status, NwkStatus::InvalidParameter);
+        assert_eq!(nlme.nib().max_broadcast_retries(), 0x03);
+        assert_eq!(nlme.nib().passive_ack_timeout(), 0x3d09);
+    }
+
+    #[test]
+    fn capacity_change_updates_beacon_payload() {
+        let mut mac = MockMlme::new();
+        mac.expect_set_beacon_payload()
+            .withf(|payload| !payload.stack_profile.end_device_capacity())
+            .times(1)
+            .return_const(());
+        let mut nlme = make_nlme(mac);
+
+        let confirm = nlme.set(NlmeSetRequest {
+            attribute: attribute_id::MAX_CHILDREN,
+            value: AttributeValue::U8(0),
+        });
+
+        assert_eq!(confirm.status, NwkStatus::Success);
+    }
+
+    #[test]
+    fn active_key_must_be_in_security_material_set() {
+        let mut nlme = make_nlme(MockMlme::new());
+        install_network_key(&nlme);
+
+        let unknown_key = nlme.set(NlmeSetRequest {
+            attribute: attribute_id::ACTIVE_KEY_SEQ_NUMBER,
+            value: AttributeValue::U8(1),
+        });
+        let installed_key = nlme.set(NlmeSetRequest {
+            attribute: attribute_id::ACTIVE_KEY_SEQ_NUMBER,
+            value: AttributeValue::U8(0),
+        });
+
+        assert_eq!(unknown_key.status, NwkStatus::InvalidParameter);
+        assert_eq!(installed_key.status, NwkStatus::Success);
+        assert_eq!(nlme.nib().active_key_seq_number(), 0);
+    }
+
+    #[test]
+    fn read_only_attribute_is_not_written() {
+        let mut nlme = make_nlme(MockMlme::new());
+
+        let confirm = nlme.set(NlmeSetRequest {
+            attribute: attribute_id::MAX_DEPTH,
+            value: AttributeValue::U8(2),
+        });
+
+        assert_eq!(confirm.status, NwkStatus::ReadOnly);
+        assert_eq!(nlme.nib().max_depth(), 0x0f);
+    }
+}
@@ -0,0 +1,555 @@
+//! Broadcast transmission
+//!
+//! Every device remembers the broadcasts it has seen in the broadcast
+//! transaction table to drop duplicates. Routers rebroadcast after a random
+//! jitter and repeat the broadcast until all neighboring routers were heard
+//! relaying it, the passive acknowledgement (§3.6.5).
+
+use heapless::Vec;
+use zigbee_mac::Address;
+use zigbee_mac::mlme::Mlme;
+use zigbee_types::ShortAddress;
+
+use super::NetworkError;
+use super::Nlme;
+use crate::nwk::frame::Frame as NwkFrame;
+use crate::nwk::nib::DeviceType;
+use crate::nwk::nib::NWKC_MAX_BROADCAST_JITTER;
+use crate::nwk::nib::TransactionRecord;
+use crate::nwk::nib::broadcast_address;
+use crate::nwk::nib::octets_to_ms;
+use crate::nwk::nib::relationship;
+
+// implementation specific
+
+const MAX_PENDING_BROADCASTS: usize = 2;
+const MAX_PENDING_BROADCAST_LEN: usize = 128;
+/// Neighbors tracked per broadcast for passive acknowledgement.
+const MAX_PASSIVE_ACKS: usize = 16;
+
+/// A broadcast waiting for its next transmission.
+struct PendingBroadcast {
+    source: ShortAddress,
+    sequence_number: u8,
+    /// Transmissions so far.
+    transmissions: u8,
+    /// Milliseconds until the next transmission.
+    due: u32,
+    /// Neighbors which were heard relaying the broadcast.
+    acks: Vec<ShortAddress, MAX_PASSIVE_ACKS>,
+    len: usize,
+    frame: [u8; MAX_PENDING_BROADCAST_LEN],
+}
+
+/// Broadcast state of a device.
+pub(super) struct Broadcasts {
+    pending: Vec<PendingBroadcast, MAX_PENDING_BROADCASTS>,
+    /// Milliseconds not yet accounted for in the expiration of the
+    /// broadcast transaction table.
+    expiry_elapsed_ms: u32,
+}
+
+impl Broadcasts {
+    pub(super) const fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+            expiry_elapsed_ms: 0,
+        }
+    }
+}
+
+impl<M> Nlme<M>
+where
+    M: Mlme,
+{
+    /// Whether `address` is one of the broadcast addresses 0xfff8 - 0xffff.
+    pub(super) const fn is_broadcast(address: ShortAddress) -> bool {
+        address.0 >= broadcast_address::MIN
+    }
+
+    /// Whether this device is addressed by the broadcast address
+    /// `destination` (Table 3-69).
+    fn is_broadcast_recipient(&self, destination: ShortAddress) -> bool {
+        match destination.0 {
+            broadcast_address::ALL_DEVICES => true,
+            broadcast_address::RX_ON_WHEN_IDLE => {
+                self.is_router() || self.nib().capability_information().receiver_on_when_idle()
+            }
+            broadcast_address::ROUTERS => self.is_router(),
+            _ => false,
+        }
+    }
+
+    /// Remember the broadcast `(source, sequence_number)` in the broadcast
+    /// transaction table.
+    ///
+    /// Returns `false` if the table is full.
+    fn add_transaction_record(&self, source: ShortAddress, sequence_number: u8) -> bool {
+        let delivery_ms = octets_to_ms(self.nib().network_broadcast_delivery_time());
+        let expiration_time = u8::try_from(delivery_ms.div_ceil(1000)).unwrap_or(u8::MAX);
+        let mut table = self.nib().broadcast_transaction_table();
+        if table
+            .push(TransactionRecord {
+                source_address: source,
+                sequence_number,
+                expiration_time,
+            })
+            .is_err()
+        {
+            return false;
+        }
+        self.nib().set_broadcast_transaction_table(table);
+        true
+    }
+
+    fn has_transaction_record(&self, source: ShortAddress, sequence_number: u8) -> bool {
+        self.nib()
+            .broadcast_transaction_table()
+            .iter()
+            .any(|r| r.source_address == source && r.sequence_number == sequence_number)
+    }
+
+    /// Handle a broadcast frame received from the neighbor `source`.
+    ///
+    /// Duplicates are dropped and count as passive acknowledgement, new
+    /// broadcasts are recorded and routers schedule a rebroadcast. Returns
+    /// whether the frame is for this device.
+    pub(super) fn broadcast_indication(
+        &mut self,
+        source: Address,
+        frame: &NwkFrame<'_>,
+    ) -> Result<bool, NetworkError> {
+        if !self.record_broadcast(source, frame) {
+            return Ok(false);
+        }
+        if self.is_router() {
+            self.relay_broadcast(source, frame.clone())?;
+        }
+        Ok(self.is_broadcast_recipient(frame.header().destination))
+    }
+
+    /// Record the broadcast `frame` received from the neighbor `source` in
+    /// the broadcast transaction table.
+    ///
+    /// Returns `false` for duplicates, which count as passive
+    /// acknowledgement, and if the table is full.
+    pub(super) fn record_broadcast(&mut self, source: Address, frame: &NwkFrame<'_>) -> bool {
+        let header = frame.header();
+        let (originator, sequence_number) = (header.source, header.sequence_number);
+        let own_address = ShortAddress(self.nib().network_address());
+        if originator == own_address || self.has_transaction_record(originator, sequence_number) {
+            if let Some(sender) = Self::broadcast_sender(source) {
+                self.passive_ack(originator, sequence_number, sender);
+            }
+            return false;
+        }
+        if !self.add_transaction_record(originator, sequence_number) {
+            log::debug!(
+                "[NLME] broadcast transaction table full, dropping broadcast from 0x{:04x}",
+                originator.0
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Schedule the rebroadcast of `frame` received from the neighbor
+    /// `source` after a random jitter, unless its radius is used up.
+    pub(super) fn relay_broadcast(
+        &mut self,
+        source: Address,
+        frame: NwkFrame<'_>,
+    ) -> Result<(), NetworkError> {
+        let header = frame.header();
+        let (originator, sequence_number) = (header.source, header.sequence_number);
+        if header.radius <= 1 {
+            return Ok(());
+        }
+        if self.broadcasts.pending.is_full() {
+            log::debug!("[NLME] too many pending broadcasts, not relaying");
+            return Ok(());
+        }
+        let len = self.build_relayed_frame(frame)?;
+        let due = self.broadcast_jitter();
+        let sender = Self::broadcast_sender(source);
+        self.queue_broadcast(originator, sequence_number, len, 0, due, sender);
+        Ok(())
+    }
+
+    const fn broadcast_sender(source: Address) -> Option<ShortAddress> {
+        match source {
+            Address::Short(_, sender) => Some(ShortAddress(sender.0)),
+            Address::Extended(..) => None,
+        }
+    }
+
+    /// Queue the broadcast in the first `len` bytes of `self.buf`, which was
+    /// sent `transmissions` times, for transmission in `due` milliseconds.
+    fn queue_broadcast(
+        &mut self,
+        source: ShortAddress,
+        sequence_number: u8,
+        len: usize,
+        transmissions: u8,
+        due: u32,
+        sender: Option<ShortAddress>,
+    ) {
+        if len > MAX_PENDING_BROADCAST_LEN {
+            log::debug!("[NLME] broadcast too long to be repeated");
+            return;
+        }
+        let mut broadcast = PendingBroadcast {
+            source,
+            sequence_number,
+            transmissions,
+            due,
+            acks: Vec::new(),
+            len,
+            frame: [0u8; MAX_PENDING_BROADCAST_LEN],
+        };
+        broadcast.frame[..len].copy_from_slice(&self.buf[..len]);
+        if let Some(sender) = sender {
+            let _ = broadcast.acks.push(sender);
+        }
+        if self.broadcasts.pending.push(broadcast).is_err() {
+            log::debug!("[NLME] too many pending broadcasts");
+        }
+    }
+
+    /// Broadcast the frame in the first `len` bytes of `self.buf` originated
+    /// by this device, routers repeat it until passively acknowledged.
+    pub(super) async fn originate_broadcast(&mut self, len: usize) -> Result<(), NetworkError> {
+        let source = ShortAddress(self.nib().network_address());
+        let sequence_number = self.nwk_seq;
+        if !self.add_transaction_record(source, sequence_number) {
+            log::debug!("[NLME] broadcast transaction table full");
+        }
+        self.transmit_nwk_frame(ShortAddress(broadcast_address::ALL_DEVICES), len)
+            .await?;
+        if self.is_router() {
+            let due = octets_to_ms(self.nib().passive_ack_timeout()).max(1);
+            self.queue_broadcast(source, sequence_number, len, 1, due, None);
+        }
+        Ok(())
+    }
+
+    /// Record that `sender` relayed the broadcast `(source, sequence_number)`.
+    fn passive_ack(&mut self, source: ShortAddress, sequence_number: u8, sender: ShortAddress) {
+        let Some(broadcast) = self
+            .broadcasts
+            .pending
+            .iter_mut()
+            .find(|b| b.source == source && b.sequence_number == sequence_number)
+        else {
+            return;
+        };
+        if !broadcast.acks.contains(&sender) {
+            let _ = broadcast.acks.push(sender);
+        }
+    }
+
+    /// Whether all neighboring routers relayed the broadcast.
+    fn passive_acks_complete(&self, acks: &[ShortAddress]) -> bool {
+        self.nib().neighbor_table().iter().all(|n| {
+            matches!(n.device_type, DeviceType::EndDevice)
+                || !matches!(
+                    n.relationship,
+                    relationship::PARENT | relationship::CHILD | relationship::SIBLING
+                )
+                || acks.contains(&n.network_address)
+        })
+    }
+
+    /// Advance the broadcast timers: transmit due broadcasts, repeat them
+    /// until passively acknowledged and expire the broadcast transaction
+    /// table.
+    pub(super) async fn broadcast_tick(&mut self, elapsed_ms: u32) {
+        let passive_ack_timeout = octets_to_ms(self.nib().passive_ack_timeout());
+        let max_transmissions = self.nib().max_broadcast_retries().saturating_add(1);
+        let dest = self.mac_address(ShortAddress(broadcast_address::ALL_DEVICES));
+        let mut idx = 0;
+        while idx < self.broadcasts.pending.len() {
+            let broadcast = &mut self.broadcasts.pending[idx];
+            broadcast.due = broadcast.due.saturating_sub(elapsed_ms);
+            if broadcast.due > 0 {
+                idx += 1;
+                continue;
+            }
+            let (transmissions, acks) = (broadcast.transmissions, broadcast.acks.clone());
+            if transmissions >= max_transmissions
+                || (transmissions > 0 && self.passive_acks_complete(&acks))
+            {
+                self.broadcasts.pending.swap_remove(idx);
+                continue;
+            }
+
+            let broadcast = &mut self.broadcasts.pending[idx];
+            broadcast.transmissions += 1;
+            broadcast.due = passive_ack_timeout.max(1);
+            if let Err(e) = self
+                .mac
+                .transmit_data(dest, &broadcast.frame[..broadcast.len])
+                .await
+            {
+                log::debug!("[NLME] broadcast failed: {e}");
+            }
+            idx += 1;
+        }
+
+        self.broadcasts.expiry_elapsed_ms += elapsed_ms;
+        let seconds = self.broadcasts.expiry_elapsed_ms / 1000;
+        if seconds == 0 {
+            return;
+        }
+        self.broadcasts.expiry_elapsed_ms %= 1000;
+        let mut table = self.nib().broadcast_transaction_table();
+        if table.is_empty() {
+            return;
+        }
+        let seconds = u8::try_from(seconds).unwrap_or(u8::MAX);
+        table.retain_mut(|r| {
+            r.expiration_time = r.expiration_time.saturating_sub(seconds);
+            r.expiration_time > 0
+        });
+        self.nib().set_broadcast_transaction_table(table);
+    }
+
+    /// Random delay of a broadcast of up to nwkcMaxBroadcastJitter.
+    pub(super) fn broadcast_jitter(&mut self) -> u32 {
+        self.next_random() % (octets_to_ms(NWKC_MAX_BROADCAST_JITTER) + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byte::BytesExt;
+    use byte::TryRead;
+
+    use super::*;
+    use crate::nwk::frame::header::Header as NwkHeader;
+    use crate::nwk::nlme::NwkIndication;
+    use crate::nwk::nlme::tests::MockMlme;
+    use crate::nwk::nlme::tests::OWN;
+    use crate::nwk::nlme::tests::PAN_ID;
+    use crate::nwk::nlme::tests::block_on;
+    use crate::nwk::nlme::tests::expect_receive;
+    use crate::nwk::nlme::tests::mac_short;
+    use crate::nwk::nlme::tests::make_neighbor;
+    use crate::nwk::nlme::tests::make_nlme;
+    use crate::nwk::nlme::tests::make_router;
+    use crate::security::SecurityContext;
+
+    const NEIGHBOR: u16 = 0x2222;
+    const OTHER_NEIGHBOR: u16 = 0x3333;
+    const ORIGINATOR: u16 = 0x4444;
+
+    fn make_relay(mac: MockMlme) -> Nlme<MockMlme> {
+        let mut nlme = make_router(mac);
+        // no link status during the tests
+        nlme.link_status_due = u32::MAX;
+        let mut table = nlme.nib().neighbor_table();
+        for address in [NEIGHBOR, OTHER_NEIGHBOR] {
+            let mut neighbor = make_neighbor(PAN_ID, address, 0, 0xff, 1);
+            neighbor.relationship = relationship::SIBLING;
+            table.push(neighbor).unwrap();
+        }
+        nlme.nib().set_neighbor_table(table);
+        nlme
+    }
+
+    fn broadcast_frame(destination: u16, radius: u8) -> std::vec::Vec<u8> {
+        let header = NwkHeader {
+            frame_control: crate::nwk::frame::frame_control::FrameControl(0)
+                .set_protocol_version(2),
+            destination: ShortAddress(destination),
+            source: ShortAddress(ORIGINATOR),
+            radius,
+            sequence_number: 7,
+            destination_ieee: None,
+            source_ieee: None,
+            multicast_control: None,
+            source_route_subframe: None,
+        };
+        let mut buf = [0u8; 32];
+        let offset = &mut 0;
+        buf.write_with(offset, header, ()).unwrap();
+        buf.write_with(offset, &[1u8, 2, 3][..], ()).unwrap();
+        buf[..*offset].to_vec()
+    }
+
+    fn is_relayed_broadcast(dest: &Address, payload: &[u8]) -> bool {
+        let (header, _) = NwkHeader::try_read(payload, ()).unwrap();
+        *dest == mac_short(broadcast_address::ALL_DEVICES)
+            && header.source == ShortAddress(ORIGINATOR)
+            && header.radius == 4
+    }
+
+    #[test]
+    fn router_rebroadcasts_until_passively_acknowledged() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(is_relayed_broadcast)
+            .times(2)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_relay(mac);
+        let passive_ack_timeout = octets_to_ms(nlme.nib().passive_ack_timeout());
+
+        expect_receive(
+            &mut nlme,
+            NEIGHBOR,
+            broadcast_address::ALL_DEVICES,
+            0xff,
+            broadcast_frame(0xffff, 5),
+        );
+        assert!(matches!(
+            block_on(nlme.receive()).unwrap(),
+            Some(NwkIndication::Data(_))
+        ));
+        // first transmission after the jitter, then a retry as
+        // OTHER_NEIGHBOR was not heard
+        block_on(nlme.tick(octets_to_ms(NWKC_MAX_BROADCAST_JITTER)));
+        block_on(nlme.tick(passive_ack_timeout));
+
+        expect_receive(
+            &mut nlme,
+            OTHER_NEIGHBOR,
+            broadcast_address::ALL_DEVICES,
+            0xff,
+            broadcast_frame(0xffff, 4),
+        );
+        assert!(block_on(nlme.receive()).unwrap().is_none());
+        block_on(nlme.tick(passive_ack_timeout));
+
+        assert!(nlme.broadcasts.pending.is_empty());
+    }
+
+    #[test]
+    fn rebroadcast_gives_up_after_max_retries() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(is_relayed_broadcast)
+            .times(4)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_relay(mac);
+        let passive_ack_timeout = octets_to_ms(nlme.nib().passive_ack_timeout());
+
+        expect_receive(
+            &mut nlme,
+            NEIGHBOR,
+            broadcast_address::ALL_DEVICES,
+            0xff,
+            broadcast_frame(0xffff, 5),
+        );
+        block_on(nlme.receive()).unwrap();
+        block_on(nlme.tick(octets_to_ms(NWKC_MAX_BROADCAST_JITTER)));
+        for _ in 0..4 {
+            block_on(nlme.tick(passive_ack_timeout));
+        }
+
+        assert!(nlme.broadcasts.pending.is_empty());
+    }
+
+    #[test]
+    fn duplicate_broadcast_is_dropped() {
+        let mut nlme = make_relay(MockMlme::new());
+        let mut raw = broadcast_frame(0xffff, 5);
+        let frame = nlme
+            .security_context()
+            .decrypt_nwk_frame_in_place(&mut raw)
+            .unwrap();
+
+        assert!(
+            nlme.broadcast_indication(mac_short(NEIGHBOR), &frame)
+                .unwrap()
+        );
+        assert!(
+            !nlme
+                .broadcast_indication(mac_short(OTHER_NEIGHBOR), &frame)
+                .unwrap()
+        );
+        assert_eq!(nlme.nib().broadcast_transaction_table().len(), 1);
+        assert_eq!(nlme.broadcasts.pending.len(), 1);
+        assert_eq!(
+            nlme.broadcasts.pending[0].acks,
+            [ShortAddress(NEIGHBOR), ShortAddress(OTHER_NEIGHBOR)]
+        );
+    }
+
+    #[test]
+    fn broadcast_address_filters() {
+        let nlme = make_nlme(MockMlme::new());
+        nlme.nib().set_network_address(OWN);
+
+        assert!(nlme.is_broadcast_recipient(ShortAddress(broadcast_address::ALL_DEVICES)));
+        assert!(!nlme.is_broadcast_recipient(ShortAddress(broadcast_address::RX_ON_WHEN_IDLE)));
+        assert!(!nlme.is_broadcast_recipient(ShortAddress(broadcast_address::ROUTERS)));
+        assert!(!nlme.is_broadcast_recipient(ShortAddress(0xfffe)));
+
+        // receiver on when idle
+        let cap = nlme
+            .nib()
+            .capability_information()
+            .set_receiver_on_when_idle(true);
+        nlme.nib().set_capability_information(cap);
+        assert!(nlme.is_broadcast_recipient(ShortAddress(broadcast_address::RX_ON_WHEN_IDLE)));
+        assert!(!nlme.is_broadcast_recipient(ShortAddress(broadcast_address::ROUTERS)));
+
+        nlme.nib()
+            .set_capability_information(cap.set_device_type(true));
+        assert!(nlme.is_broadcast_recipient(ShortAddress(broadcast_address::ROUTERS)));
+    }
+
+    #[test]
+    fn end_device_does_not_relay_broadcasts() {
+        let mut nlme = make_nlme(MockMlme::new());
+        nlme.nib().set_network_address(OWN);
+        let mut raw = broadcast_frame(0xffff, 5);
+        let frame = nlme
+            .security_context()
+            .decrypt_nwk_frame_in_place(&mut raw)
+            .unwrap();
+
+        assert!(
+            nlme.broadcast_indication(mac_short(NEIGHBOR), &frame)
+                .unwrap()
+        );
+        assert!(nlme.broadcasts.pending.is_empty());
+    }
+
+    #[test]
+    fn transaction_records_expire() {
+        let mut nlme = make_relay(MockMlme::new());
+        assert!(nlme.add_transaction_record(ShortAddress(ORIGINATOR), 1));
+
+        let delivery_ms = octets_to_ms(nlme.nib().network_broadcast_delivery_time());
+        block_on(nlme.tick(delivery_ms - 1000));
+        assert!(nlme.has_transaction_record(ShortAddress(ORIGINATOR), 1));
+        block_on(nlme.tick(1000));
+        assert!(!nlme.has_transaction_record(ShortAddress(ORIGINATOR), 1));
+    }
+
+    #[test]
+    fn originator_repeats_broadcast() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, _| *dest == mac_short(broadcast_address::ALL_DEVICES))
+            .times(2)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_relay(mac);
+        let passive_ack_timeout = octets_to_ms(nlme.nib().passive_ack_timeout());
+
+        block_on(nlme.broadcast_data(
+            ShortAddress(broadcast_address::RX_ON_WHEN_IDLE),
+            false,
+            &[1, 2, 3],
+        ))
+        .unwrap();
+        block_on(nlme.tick(passive_ack_timeout));
+
+        for neighbor in [NEIGHBOR, OTHER_NEIGHBOR] {
+            nlme.passive_ack(ShortAddress(OWN), nlme.nwk_seq, ShortAddress(neighbor));
+        }
+        block_on(nlme.tick(passive_ack_timeout));
+        assert!(nlme.broadcasts.pending.is_empty());
+    }
+}
@@ -0,0 +1,641 @@
+//! End device timeout
+//!
+//! End devices negotiate their timeout with the parent after joining and
+//! keep their neighbor table entry on the parent alive with MAC data polls
+//! or end device timeout requests. Parents remove end device children which
+//! stayed silent for longer than their timeout and ask them to leave
+//! (§3.6.10).
+
+use heapless::Vec;
+use zigbee_mac::Address;
+use zigbee_mac::mlme::MacError;
+use zigbee_mac::mlme::Mlme;
+use zigbee_types::ShortAddress;
+
+use super::NetworkError;
+use super::Nlme;
+use crate::nwk::frame::CommandFrame as NwkCommandFrame;
+use crate::nwk::frame::Frame as NwkFrame;
+use crate::nwk::frame::command::Command as NwkCommand;
+use crate::nwk::frame::command::end_device_timeout_request::EndDeviceTimeoutRequest;
+use crate::nwk::frame::command::end_device_timeout_response::EndDeviceTimeoutResponse;
+use crate::nwk::frame::command::end_device_timeout_response::parent_information;
+use crate::nwk::frame::command::end_device_timeout_response::status;
+use crate::nwk::frame::command::leave::CommandOptions as LeaveOptions;
+use crate::nwk::frame::header::Header as NwkHeader;
+use crate::nwk::nib::DeviceType;
+use crate::nwk::nib::MAX_NEIGHBOR_TABLE;
+use crate::nwk::nib::NwkNeighbor;
+use crate::nwk::nib::end_device_timeout_seconds;
+use crate::nwk::nib::relationship;
+use crate::security::SecurityContext;
+
+/// Number of data polls for the end device timeout response.
+const END_DEVICE_TIMEOUT_RESPONSE_POLLS: u8 = 3;
+
+/// Keepalives sent per timeout period, so that a lost keepalive does not
+/// expire the entry on the parent.
+const KEEPALIVES_PER_TIMEOUT: u32 = 4;
+
+/// Keepalive methods supported by this device as a parent.
+const PARENT_INFORMATION: u8 = parent_information::MAC_DATA_POLL_KEEPALIVE
+    | parent_information::END_DEVICE_TIMEOUT_REQUEST_KEEPALIVE;
+
+/// Whether `neighbor` is an end device child of this device.
+pub(super) fn is_end_device_child(neighbor: &NwkNeighbor) -> bool {
+    matches!(neighbor.device_type, DeviceType::EndDevice)
+        && matches!(
+            neighbor.relationship,
+            relationship::CHILD | relationship::UNAUTHENTICATED_CHILD
+        )
+}
+
+impl<M> Nlme<M>
+where
+    M: Mlme,
+{
+    /// Request nwkEndDeviceTimeoutDefault as timeout from the parent and
+    /// poll for the response (§3.6.10.2).
+    ///
+    /// Must be called by end devices after joining. Parents which do not
+    /// support the negotiation never answer, the device is then kept alive
+    /// by its data polls alone.
+    pub async fn end_device_timeout_request(&mut self) -> Result<(), NetworkError> {
+        if self.is_router() {
+            return Ok(());
+        }
+        self.send_end_device_timeout_request().await?;
+
+        let mut buf = [0u8; 128];
+        for _ in 0..END_DEVICE_TIMEOUT_RESPONSE_POLLS {
+            let (frame, lqi) = match self.poll_nwk_data_request(&mut buf).await {
+                Ok(polled) => polled,
+                Err(NetworkError::MacError(MacError::NoData)) => continue,
+                Err(NetworkError::MacError(e)) => return Err(e.into()),
+                Err(e) => {
+                    log::debug!("[NLME] dropping polled frame: {e}");
+                    continue;
+                }
+            };
+            if let NwkFrame::NwkCommand(NwkCommandFrame {
+                command: NwkCommand::EndDeviceTimeoutResponse(response),
+                ..
+            }) = frame
+            {
+                self.end_device_timeout_response_indication(&response);
+                return Ok(());
+            }
+            // e.g. a frame the parent buffered before the response
+            if let Some(indication) = self.polled_frame_indication(lqi, frame).await? {
+                log::debug!("[NLME] {indication:?}");
+            }
+        }
+
+        Err(MacError::NoData.into())
+    }
+
+    /// Send an end device timeout request to the parent, returns the
+    /// address of the parent.
+    async fn send_end_device_timeout_request(&mut self) -> Result<ShortAddress, NetworkError> {
+        let parent = self.parent_network_address()?;
+        let secure = self.nwk_security_enabled();
+        let header = self.nwk_command_header(parent, 1, secure);
+        let command = NwkCommand::EndDeviceTimeoutRequest(EndDeviceTimeoutRequest {
+            requested_timeout: self.nib().end_device_timeout_default(),
+            end_device_configuration: 0,
+        });
+        let len = self.build_nwk_command_frame(header, command)?;
+        self.transmit_nwk_frame(parent, len).await?;
+        Ok(parent)
+    }
+
+    /// Handle an end device timeout response from the parent
+    /// (§3.6.10.2).
+    pub(super) fn end_device_timeout_response_indication(
+        &mut self,
+        response: &EndDeviceTimeoutResponse,
+    ) {
+        if response.status != status::SUCCESS {
+            log::warn!(
+                "[NLME] parent rejected end device timeout: status 0x{:02x}",
+                response.status
+            );
+            return;
+        }
+        log::debug!(
+            "[NLME] end device timeout accepted, parent information 0x{:02x}",
+            response.parent_information
+        );
+        self.nib()
+            .set_parent_information(response.parent_information);
+        self.keepalive_due = self.keepalive_period();
+    }
+
+    /// Milliseconds between two keepalives of an end device.
+    fn keepalive_period(&self) -> u32 {
+        let timeout =
+            end_device_timeout_seconds(self.nib().end_device_timeout_default()).unwrap_or(10);
+        timeout.saturating_mul(1000) / KEEPALIVES_PER_TIMEOUT
+    }
+
+    /// Note a MAC data poll sent to the parent, which keeps the end device
+    /// alive if the parent supports it.
+    pub(super) fn data_poll_sent(&mut self) {
+        if self.nib().parent_information() & parent_information::MAC_DATA_POLL_KEEPALIVE != 0 {
+            self.keepalive_due = self.keepalive_period();
+        }
+    }
+
+    /// Keep the end device alive on its parent if there was no other
+    /// keepalive for a while (§3.6.10.3).
+    ///
+    /// An end device timeout request is preferred over a MAC data poll as
+    /// it does not fetch pending data the next higher layer is waiting for.
+    pub(super) async fn keepalive_tick(&mut self, elapsed_ms: u32) {
+        let parent_information = self.nib().parent_information();
+        // nothing was negotiated with a parent
+        if self.is_router() || self.nib().network_address() == 0xffff || parent_information == 0 {
+            return;
+        }
+        self.keepalive_due = self.keepalive_due.saturating_sub(elapsed_ms);
+        if self.keepalive_due > 0 {
+            return;
+        }
+        self.keepalive_due = self.keepalive_period();
+
+        let result =
+            if parent_information & parent_information::END_DEVICE_TIMEOUT_REQUEST_KEEPALIVE != 0 {
+                self.send_end_device_timeout_request().await.map(drop)
+            } else {
+                self.keepalive_poll().await
+            };
+        if let Err(e) = result {
+            log::debug!("[NLME] failed to send keepalive: {e}");
+        }
+    }
+
+    /// Send a MAC data poll to the parent as keepalive.
+    async fn keepalive_poll(&mut self) -> Result<(), NetworkError> {
+        let mut buf = [0u8; 128];
+        match self.poll_nwk_data_request(&mut buf).await {
+            Ok((frame, lqi)) => {
+                if let Some(indication) = self.polled_frame_indication(lqi, frame).await? {
+                    log::debug!("[NLME] {indication:?}");
+                }
+                Ok(())
+            }
+            Err(NetworkError::MacError(MacError::NoData)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Handle an end device timeout request from a child (§3.6.10.3).
+    ///
+    /// A valid timeout is stored in the neighbor table, the request also
+    /// counts as keepalive.
+    pub(super) async fn end_device_timeout_request_indication(
+        &mut self,
+        source: Address,
+        header: &NwkHeader<'_>,
+        request: &EndDeviceTimeoutRequest,
+    ) -> Result<(), NetworkError> {
+        let Address::Short(_, sender) = source else {
+            return Ok(());
+        };
+        let child = header.source;
+        if !self.is_router() || child.0 != sender.0 {
+            return Ok(());
+        }
+
+        let timeout = end_device_timeout_seconds(request.requested_timeout);
+        let mut table = self.nib().neighbor_table();
+        let Some(neighbor) = table
+            .iter_mut()
+            .find(|n| n.network_address == child && is_end_device_child(n))
+        else {
+            log::debug!(
+                "[NLME] end device timeout request from unknown child 0x{:04x}",
+                child.0
+            );
+            return Ok(());
+        };
+        if let Some(timeout) = timeout {
+            neighbor.device_timeout = timeout;
+            neighbor.end_device_configuration = u16::from(request.end_device_configuration);
+        }
+        neighbor.timeout_counter = neighbor.device_timeout;
+        neighbor.keepalive_received = true;
+        self.nib().set_neighbor_table(table);
+
+        let status = if timeout.is_some() {
+            status::SUCCESS
+        } else {
+            status::INCORRECT_VALUE
+        };
+        log::debug!(
+            "[NLME] end device timeout request from 0x{:04x}: {} -> status 0x{status:02x}",
+            child.0,
+            request.requested_timeout
+        );
+
+        let secure = self.nwk_security_enabled();
+        let header = self.nwk_command_header(child, 1, secure);
+        let command = NwkCommand::EndDeviceTimeoutResponse(EndDeviceTimeoutResponse {
+            status,
+            parent_information: PARENT_INFORMATION,
+        });
+        let len = self.build_nwk_command_frame(header, command)?;
+        self.transmit_nwk_frame(child, len).await
+    }
+
+    /// Handle a MAC data poll from `source`, a keepalive if it is an end
+    /// device child (§3.6.10.3).
+    pub(super) fn poll_indication(&self, source: Address) {
+        let Address::Short(_, source) = source else {
+            return;
+        };
+        let mut table = self.nib().neighbor_table();
+        if let Some(neighbor) = table
+            .iter_mut()
+            .find(|n| n.network_address.0 == source.0 && is_end_device_child(n))
+        {
+            neighbor.timeout_counter = neighbor.device_timeout;
+            neighbor.keepalive_received = true;
+            self.nib().set_neighbor_table(table);
+        }
+    }
+
+    /// Count down the timeouts of the end device children and remove the
+    /// ones which expired (§3.6.10.3).
+    ///
+    /// Expired children are asked to leave and rejoin, they are no longer
+    /// known to this device.
+    pub(super) async fn end_device_timeout_tick(&mut self, elapsed_ms: u32) {
+        if !self.is_router() {
+            return;
+        }
+        self.end_device_timeout_elapsed_ms += elapsed_ms;
+        let seconds = self.end_device_timeout_elapsed_ms / 1000;
+        if seconds == 0 {
+            return;
+        }
+        self.end_device_timeout_elapsed_ms %= 1000;
+
+        let mut expired: Vec<ShortAddress, MAX_NEIGHBOR_TABLE> = Vec::new();
+        let mut table = self.nib().neighbor_table();
+        if !table
+            .iter()
+            .any(|n| is_end_device_child(n) && n.device_timeout > 0)
+        {
+            return;
+        }
+        table.retain_mut(|n| {
+            if !is_end_device_child(n) || n.device_timeout == 0 {
+                return true;
+            }
+            n.timeout_counter = n.timeout_counter.saturating_sub(seconds);
+            if n.timeout_counter > 0 {
+                return true;
+            }
+            log::debug!("[NLME] end device 0x{:04x} timed out", n.network_address.0);
+            let _ = expired.push(n.network_address);
+            false
+        });
+        self.nib().set_neighbor_table(table);
+        if expired.is_empty() {
+            return;
+        }
+
+        for child in expired {
+            let options = LeaveOptions(0).set_request(true).set_rejoin(true);
+            if let Err(e) = self.send_leave(child, options).await {
+                log::debug!("[NLME] failed to send leave to 0x{:04x}: {e}", child.0);
+            }
+        }
+        // capacity may have changed
+        self.update_beacon_payload();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use zigbee_mac::mlme::MacIndication;
+
+    use super::*;
+    use crate::nwk::frame::command::network_update::NetworkUpdate;
+    use crate::nwk::nib::MAX_END_DEVICE_TIMEOUT;
+    use crate::nwk::nib::NWK_COORDINATOR_ADDRESS;
+    use crate::nwk::nlme::tests::MockMlme;
+    use crate::nwk::nlme::tests::OWN;
+    use crate::nwk::nlme::tests::PAN_ID;
+    use crate::nwk::nlme::tests::block_on;
+    use crate::nwk::nlme::tests::command_frame;
+    use crate::nwk::nlme::tests::expect_receive;
+    use crate::nwk::nlme::tests::mac_short;
+    use crate::nwk::nlme::tests::make_end_device;
+    use crate::nwk::nlme::tests::make_neighbor;
+    use crate::nwk::nlme::tests::make_router;
+    use crate::nwk::nlme::tests::parse_command;
+
+    const CHILD: u16 = 0x4444;
+    const PARENT: u16 = NWK_COORDINATOR_ADDRESS;
+
+    fn make_parent(mac: MockMlme) -> Nlme<MockMlme> {
+        let nlme = make_router(mac);
+        let mut child = make_neighbor(PAN_ID, CHILD, 0, 0xff, 1);
+        child.device_type = DeviceType::EndDevice;
+        child.relationship = relationship::CHILD;
+        child.rx_on_when_idle = true;
+        child.device_timeout = 10;
+        child.timeout_counter = 10;
+        let mut table = nlme.nib().neighbor_table();
+        table.push(child).unwrap();
+        nlme.nib().set_neighbor_table(table);
+        nlme
+    }
+
+    fn child(nlme: &Nlme<MockMlme>) -> Option<(u32, u32)> {
+        nlme.nib()
+            .neighbor_table()
+            .iter()
+            .find(|n| n.network_address == ShortAddress(CHILD))
+            .map(|n| (n.device_timeout, n.timeout_counter))
+    }
+
+    fn expect_request(nlme: &mut Nlme<MockMlme>, requested_timeout: u8) {
+        let frame = command_frame(
+            nlme,
+            CHILD,
+            OWN,
+            None,
+            false,
+            NwkCommand::EndDeviceTimeoutRequest(EndDeviceTimeoutRequest {
+                requested_timeout,
+                end_device_configuration: 0,
+            }),
+        );
+        expect_receive(nlme, CHILD, OWN, 0xff, frame);
+    }
+
+    fn expect_response(mac: &mut MockMlme, expected: u8) {
+        mac.expect_transmit_data()
+            .withf(move |dest, payload| {
+                let NwkCommand::EndDeviceTimeoutResponse(response) = parse_command(payload).1
+                else {
+                    return false;
+                };
+                *dest == mac_short(CHILD)
+                    && response.status == expected
+                    && response.parent_information == PARENT_INFORMATION
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+    }
+
+    #[test]
+    fn parent_accepts_requested_timeout() {
+        let mut mac = MockMlme::new();
+        expect_response(&mut mac, status::SUCCESS);
+        let mut nlme = make_parent(mac);
+        expect_request(&mut nlme, 3);
+
+        assert!(block_on(nlme.receive()).unwrap().is_none());
+
+        // 2^3 minutes
+        assert_eq!(child(&nlme), Some((480, 480)));
+    }
+
+    #[test]
+    fn parent_rejects_invalid_timeout() {
+        let mut mac = MockMlme::new();
+        expect_response(&mut mac, status::INCORRECT_VALUE);
+        let mut nlme = make_parent(mac);
+        expect_request(&mut nlme, MAX_END_DEVICE_TIMEOUT + 1);
+
+        block_on(nlme.receive()).unwrap();
+
+        assert_eq!(child(&nlme), Some((10, 10)));
+    }
+
+    #[test]
+    fn data_poll_keeps_child_alive() {
+        let mut nlme = make_parent(MockMlme::new());
+        block_on(nlme.tick(9000));
+        assert_eq!(child(&nlme), Some((10, 1)));
+        nlme.mac.expect_receive().times(1).returning(|_| {
+            Ok(MacIndication::Poll {
+                source: mac_short(CHILD),
+            })
+        });
+
+        block_on(nlme.receive()).unwrap();
+
+        assert_eq!(child(&nlme), Some((10, 10)));
+    }
+
+    #[test]
+    fn expired_child_is_asked_to_leave() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                let NwkCommand::Leave(leave) = parse_command(payload).1 else {
+                    return false;
+                };
+                *dest == mac_short(CHILD)
+                    && leave.command_options.request()
+                    && leave.command_options.rejoin()
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mac.expect_set_beacon_payload().times(1).return_const(());
+        let mut nlme = make_parent(mac);
+
+        block_on(nlme.tick(9999));
+        assert!(child(&nlme).is_some());
+        block_on(nlme.tick(1));
+
+        assert_eq!(child(&nlme), None);
+    }
+
+    #[test]
+    fn end_device_negotiates_timeout() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                let NwkCommand::EndDeviceTimeoutRequest(request) = parse_command(payload).1 else {
+                    return false;
+                };
+                *dest == mac_short(PARENT) && request.requested_timeout == 0x08
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_end_device(mac);
+        let frame = command_frame(
+            &mut nlme,
+            PARENT,
+            OWN,
+            None,
+            false,
+            NwkCommand::EndDeviceTimeoutResponse(EndDeviceTimeoutResponse {
+                status: status::SUCCESS,
+                parent_information: parent_information::END_DEVICE_TIMEOUT_REQUEST_KEEPALIVE,
+            }),
+        );
+        let mut polls = 0;
+        nlme.mac
+            .expect_poll_data()
+            .times(2)
+            .returning(move |_, buf| {
+                polls += 1;
+                if polls == 1 {
+                    return Err(MacError::NoData);
+                }
+                buf[..frame.len()].copy_from_slice(&frame);
+                Ok((frame.len(), 0xff, false))
+            });
+
+        block_on(nlme.end_device_timeout_request()).unwrap();
+
+        assert_eq!(
+            nlme.nib().parent_information(),
+            parent_information::END_DEVICE_TIMEOUT_REQUEST_KEEPALIVE
+        );
+        // a quarter of 2^8 minutes
+        assert_eq!(nlme.keepalive_due, 3_840_000);
+    }
+
+    /// Network update the network manager sends to move the network.
+    fn network_update_frame(nlme: &mut Nlme<MockMlme>) -> std::vec::Vec<u8> {
+        command_frame(
+            nlme,
+            PARENT,
+            OWN,
+            None,
+            false,
+            NwkCommand::NetworkUpdate(NetworkUpdate {
+                update_id: 1,
+                channel: 20,
+                pan_id: PAN_ID,
+                network_address: PARENT,
+            }),
+        )
+    }
+
+    #[test]
+    fn frame_polled_before_timeout_response_is_processed() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data().times(1).returning(|_, _| Ok(()));
+        mac.expect_set_beacon_payload().return_const(());
+        let mut nlme = make_end_device(mac);
+        let update = network_update_frame(&mut nlme);
+        let response = command_frame(
+            &mut nlme,
+            PARENT,
+            OWN,
+            None,
+            false,
+            NwkCommand::EndDeviceTimeoutResponse(EndDeviceTimeoutResponse {
+                status: status::SUCCESS,
+                parent_information: PARENT_INFORMATION,
+            }),
+        );
+        let mut frames = [update, response].into_iter();
+        nlme.mac
+            .expect_poll_data()
+            .times(2)
+            .returning(move |_, buf| {
+                let frame = frames.next().unwrap();
+                buf[..frame.len()].copy_from_slice(&frame);
+                Ok((frame.len(), 0xff, true))
+            });
+
+        block_on(nlme.end_device_timeout_request()).unwrap();
+
+        assert_eq!(nlme.nib().update_id(), 1);
+        assert_eq!(nlme.nib().parent_information(), PARENT_INFORMATION);
+    }
+
+    #[test]
+    fn end_device_without_response_from_parent() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data().times(1).returning(|_, _| Ok(()));
+        mac.expect_poll_data()
+            .times(usize::from(END_DEVICE_TIMEOUT_RESPONSE_POLLS))
+            .returning(|_, _| Err(MacError::NoData));
+        let mut nlme = make_end_device(mac);
+
+        assert!(block_on(nlme.end_device_timeout_request()).is_err());
+        assert_eq!(nlme.nib().parent_information(), 0);
+    }
+
+    #[test]
+    fn end_device_sends_keepalive() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                *dest == mac_short(PARENT)
+                    && matches!(
+                        parse_command(payload).1,
+                        NwkCommand::EndDeviceTimeoutRequest(_)
+                    )
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_end_device(mac);
+        nlme.nib().set_end_device_timeout_default(0);
+        nlme.end_device_timeout_response_indication(&EndDeviceTimeoutResponse {
+            status: status::SUCCESS,
+            parent_information: PARENT_INFORMATION,
+        });
+
+        // a quarter of 10 seconds
+        block_on(nlme.tick(2499));
+        block_on(nlme.tick(1));
+    }
+
+    #[test]
+    fn frame_polled_with_keepalive_is_processed() {
+        let mut mac = MockMlme::new();
+        // carries nwkUpdateId
+        mac.expect_set_beacon_payload().return_const(());
+        let mut nlme = make_end_device(mac);
+        let frame = network_update_frame(&mut nlme);
+        nlme.mac
+            .expect_poll_data()
+            .withf(|dest, _| *dest == mac_short(PARENT))
+            .times(1)
+            .returning(move |_, buf| {
+                buf[..frame.len()].copy_from_slice(&frame);
+                Ok((frame.len(), 0xff, false))
+            });
+        nlme.nib().set_end_device_timeout_default(0);
+        nlme.end_device_timeout_response_indication(&EndDeviceTimeoutResponse {
+            status: status::SUCCESS,
+            parent_information: parent_information::MAC_DATA_POLL_KEEPALIVE,
+        });
+
+        block_on(nlme.tick(2500));
+
+        assert_eq!(nlme.nib().update_id(), 1);
+    }
+
+    #[test]
+    fn data_poll_postpones_keepalive() {
+        let mut mac = MockMlme::new();
+        mac.expect_poll_data()
+            .times(1)
+            .returning(|_, _| Err(MacError::NoData));
+        let mut nlme = make_end_device(mac);
+        nlme.nib().set_end_device_timeout_default(0);
+        nlme.end_device_timeout_response_indication(&EndDeviceTimeoutResponse {
+            status: status::SUCCESS,
+            parent_information: parent_information::MAC_DATA_POLL_KEEPALIVE,
+        });
+        block_on(nlme.tick(2000));
+
+        let mut buf = [0u8; 128];
+        assert!(block_on(nlme.poll_nwk_data(&mut buf, 1)).is_err());
+
+        assert_eq!(nlme.keepalive_due, 2500);
+    }
+}
@@ -0,0 +1,595 @@
+//! Frame relaying
+//!
+//! Routers forward unicast frames for other devices to a neighbor, along
+//! the route table or up and down the tree (§3.6.3.3).
+
+use byte::BytesExt;
+use zigbee_mac::mlme::Mlme;
+use zigbee_types::ShortAddress;
+
+use super::NetworkError;
+use super::Nlme;
+use super::management::NlmeNwkStatusIndication;
+use crate::nwk::frame::Frame as NwkFrame;
+use crate::nwk::frame::command::Command as NwkCommand;
+use crate::nwk::frame::command::network_status::NetworkStatus;
+use crate::nwk::frame::command::network_status::NetworkStatusCode;
+use crate::nwk::frame::frame_control::DiscoverRoute;
+use crate::nwk::nib::MAX_ROUTE_RECORD_RELAYS;
+use crate::nwk::nib::addr_alloc;
+use crate::nwk::nib::broadcast_address;
+use crate::nwk::nib::relationship;
+use crate::security::SecurityContext;
+
+impl<M> Nlme<M>
+where
+    M: Mlme,
+{
+    /// Whether `frame` is a unicast for another device which this device
+    /// has to relay.
+    pub(super) fn is_relayed(&self, frame: &NwkFrame<'_>) -> bool {
+        let header = match frame {
+            NwkFrame::Data(frame) => &frame.header,
+            NwkFrame::NwkCommand(frame) => &frame.header,
+            NwkFrame::Reserved(_) | NwkFrame::InterPan(_) => return false,
+        };
+        self.is_router()
+            && !header.frame_control.multicast_flag()
+            && header.destination.0 < broadcast_address::MIN
+            && header.destination.0 != self.nib().network_address()
+    }
+
+    /// Relay a unicast frame received for another device (§3.6.3.3).
+    ///
+    /// The radius is decremented and the frame is secured again by this
+    /// device. Without a route a discovery is started if the frame allows
+    /// it, otherwise the originator is told that there is no route.
+    pub(super) async fn relay_frame(&mut self, frame: NwkFrame<'_>) -> Result<(), NetworkError> {
+        let header = match &frame {
+            NwkFrame::Data(frame) => &frame.header,
+            NwkFrame::NwkCommand(frame) => &frame.header,
+            NwkFrame::Reserved(_) | NwkFrame::InterPan(_) => return Ok(()),
+        };
+        let (source, destination) = (header.source, header.destination);
+        let discover_route = header.frame_control.discover_route() == DiscoverRoute::Enable;
+        let source_routed = header.source_route_subframe.is_some();
+        if header.radius <= 1 {
+            log::debug!(
+                "[NLME] radius exhausted, dropping frame for 0x{:04x}",
+                destination.0
+            );
+            return Ok(());
+        }
+        if source_routed {
+            return self.relay_source_routed_frame(frame).await;
+        }
+
+        let len = self.build_relayed_frame(frame)?;
+        let (next_hop, link_failure) = if let Some(next_hop) = self.next_hop(destination) {
+            (next_hop, NetworkStatusCode::NonTreeLinkFailure)
+        } else if let Some(next_hop) = self.tree_next_hop(destination) {
+            (next_hop, NetworkStatusCode::TreeLinkFailure)
+        } else {
+            if discover_route
+                && self
+                    .route_data_frame(source, destination, len)
+                    .await
+                    .is_ok()
+            {
+                return Ok(());
+            }
+            self.report_network_status(source, NetworkStatusCode::NoRouteAvailable, destination)
+                .await;
+            return Ok(());
+        };
+
+        match self.transmit_nwk_frame(next_hop, len).await {
+            Ok(()) => {}
+            Err(NetworkError::NoIndirectCapacity) => {
+                self.report_network_status(
+                    source,
+                    NetworkStatusCode::NoIndirectCapacity,
+                    destination,
+                )
+                .await;
+            }
+            Err(e) => {
+                log::debug!(
+                    "[NLME] relaying to 0x{:04x} via 0x{:04x} failed: {e}",
+                    destination.0,
+                    next_hop.0
+                );
+                self.remove_route(destination);
+                self.report_network_status(source, link_failure, destination)
+                    .await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Serialize `frame` with a decremented radius into `self.buf`.
+    ///
+    /// Route records get this device appended to their relay list.
+    pub(super) fn build_relayed_frame(
+        &mut self,
+        frame: NwkFrame<'_>,
+    ) -> Result<usize, NetworkError> {
+        match frame {
+            NwkFrame::Data(mut data) => {
+                data.header.radius -= 1;
+                if data.header.frame_control.security_flag() {
+                    let cx = SecurityContext::new(&self.nib, &self.aib);
+                    return Ok(cx.encrypt_nwk_frame_in_place(NwkFrame::Data(data), &mut self.buf)?);
+                }
+                let offset = &mut 0;
+                self.buf.write_with(offset, data.header, ())?;
+                let end = *offset + data.payload.len();
+                self.buf
+                    .get_mut(*offset..end)
+                    .ok_or(NetworkError::InvalidFrame)?
+                    .copy_from_slice(data.payload);
+                Ok(end)
+            }
+            NwkFrame::NwkCommand(mut command) => {
+                command.header.radius -= 1;
+                if let NwkCommand::RouteRecord(record) = &command.command {
+                    let mut relays = [0; MAX_ROUTE_RECORD_RELAYS * 2];
+                    let record = self.append_relay(record, &mut relays)?;
+                    return self
+                        .build_nwk_command_frame(command.header, NwkCommand::RouteRecord(record));
+                }
+                self.build_nwk_command_frame(command.header, command.command)
+            }
+            NwkFrame::Reserved(_) | NwkFrame::InterPan(_) => Err(NetworkError::InvalidFrame),
+        }
+    }
+
+    /// Send a network status command about `destination` to `originator`,
+    /// failures are only logged.
+    pub(super) async fn report_network_status(
+        &mut self,
+        originator: ShortAddress,
+        status_code: NetworkStatusCode,
+        destination: ShortAddress,
+    ) {
+        if let Err(e) = self
+            .send_network_status(originator, status_code, destination)
+            .await
+        {
+            log::debug!(
+                "[NLME] failed to send network status to 0x{:04x}: {e}",
+                originator.0
+            );
+        }
+    }
+
+    async fn send_network_status(
+        &mut self,
+        originator: ShortAddress,
+        status_code: NetworkStatusCode,
+        destination: ShortAddress,
+    ) -> Result<(), NetworkError> {
+        let Some(next_hop) = self
+            .next_hop(originator)
+            .or_else(|| self.tree_next_hop(originator))
+        else {
+            log::debug!(
+                "[NLME] no route to 0x{:04x} for network status",
+                originator.0
+            );
+            return Ok(());
+        };
+
+        let secure = self.nwk_security_enabled();
+        let radius = self.nib().max_depth().saturating_mul(2);
+        let header = self.nwk_command_header(originator, radius, secure);
+        let status = NetworkStatus {
+            status_code,
+            destination_address: destination,
+        };
+        let len = self.build_nwk_command_frame(header, NwkCommand::NetworkStatus(status))?;
+        self.transmit_nwk_frame(next_hop, len).await
+    }
+
+    /// Handle a network status command addressed to this device.
+    ///
+    /// Routes reported broken are removed from the route table.
+    pub(super) fn network_status_indication(
+        &self,
+        status: &NetworkStatus,
+    ) -> NlmeNwkStatusIndication {
+        if matches!(
+            status.status_code,
+            NetworkStatusCode::NoRouteAvailable
+                | NetworkStatusCode::TreeLinkFailure
+                | NetworkStatusCode::NonTreeLinkFailure
+        ) {
+            self.remove_route(status.destination_address);
+        }
+        NlmeNwkStatusIndication {
+            network_address: status.destination_address,
+            status: status.status_code,
+        }
+    }
+
+    /// Next hop towards `destination` in the address tree (§3.6.3.3.1).
+    ///
+    /// Only available with distributed address assignment and
+    /// nwkUseTreeRouting set.
+    pub(super) fn tree_next_hop(&self, destination: ShortAddress) -> Option<ShortAddress> {
+        let nib = self.nib();
+        if !nib.use_tree_routing() || nib.addr_alloc() != addr_alloc::DISTRIBUTED {
+            return None;
+        }
+
+        let own = u32::from(nib.network_address());
+        let dest = u32::from(destination.0);
+        let is_descendant =
+            self.depth == 0 || (own < dest && dest < own + self.cskip(self.depth - 1));
+        if !is_descendant {
+            return nib
+                .neighbor_table()
+                .iter()
+                .find(|n| n.relationship == relationship::PARENT)
+                .map(|n| n.network_address);
+        }
+
+        let cskip = self.cskip(self.depth);
+        if cskip == 0 || dest > own + u32::from(nib.max_routers()) * cskip {
+            // end device child
+            return Some(destination);
+        }
+        let next_hop = own + 1 + (dest - (own + 1)) / cskip * cskip;
+        u16::try_from(next_hop).ok().map(ShortAddress)
+    }
+
+    /// Size of the address sub-block of a router at `depth` (§3.6.1.6).
+    fn cskip(&self, depth: u8) -> u32 {
+        let nib = self.nib();
+        let (cm, rm, lm) = (
+            i64::from(nib.max_children()),
+            i64::from(nib.max_routers()),
+            nib.max_depth(),
+        );
+        if depth >= lm {
+            return 0;
+        }
+        let exponent = u32::from(lm - depth - 1);
+        let cskip = if rm == 1 {
+            1 + cm * i64::from(exponent)
+        } else {
+            let Some(power) = rm.checked_pow(exponent) else {
+                return u32::MAX;
+            };
+            (1 + cm - rm - cm * power) / (1 - rm)
+        };
+        u32::try_from(cskip).unwrap_or(u32::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byte::TryRead;
+    use zigbee_mac::mlme::MacError;
+
+    use super::*;
+    use crate::aps::aib::Aib;
+    use crate::nwk::frame::DataFrame as NwkDataFrame;
+    use crate::nwk::frame::frame_control::FrameControl as NwkFrameControl;
+    use crate::nwk::frame::frame_control::FrameType as NwkFrameType;
+    use crate::nwk::frame::header::Header as NwkHeader;
+    use crate::nwk::nib::NWK_COORDINATOR_ADDRESS;
+    use crate::nwk::nib::Nib;
+    use crate::nwk::nib::NwkRoute;
+    use crate::nwk::nib::RouteStatus;
+    use crate::nwk::nlme::NwkIndication;
+    use crate::nwk::nlme::tests::MockMlme;
+    use crate::nwk::nlme::tests::OWN;
+    use crate::nwk::nlme::tests::PAN_ID;
+    use crate::nwk::nlme::tests::block_on;
+    use crate::nwk::nlme::tests::expect_receive;
+    use crate::nwk::nlme::tests::mac_short;
+    use crate::nwk::nlme::tests::make_neighbor;
+    use crate::nwk::nlme::tests::make_nlme;
+    use crate::nwk::nlme::tests::make_router;
+
+    const ORIGINATOR: u16 = 0x2222;
+    const NEXT_HOP: u16 = 0x3333;
+    const DESTINATION: u16 = 0x4444;
+
+    fn make_forwarder(mac: MockMlme) -> Nlme<MockMlme> {
+        let nlme = make_router(mac);
+        add_sibling(&nlme, ORIGINATOR);
+        nlme
+    }
+
+    fn add_sibling(nlme: &Nlme<MockMlme>, address: u16) {
+        let mut neighbor = make_neighbor(PAN_ID, address, 0, 255, 1);
+        neighbor.relationship = relationship::SIBLING;
+        let mut table = nlme.nib().neighbor_table();
+        table.push(neighbor).unwrap();
+        nlme.nib().set_neighbor_table(table);
+    }
+
+    fn data_frame(radius: u8, discover_route: DiscoverRoute) -> std::vec::Vec<u8> {
+        let header = NwkHeader {
+            frame_control: NwkFrameControl(0)
+                .set_frame_type(NwkFrameType::Data)
+                .set_protocol_version(2)
+                .set_discover_route(discover_route),
+            destination: ShortAddress(DESTINATION),
+            source: ShortAddress(ORIGINATOR),
+            radius,
+            sequence_number: 0x42,
+            destination_ieee: None,
+            source_ieee: None,
+            multicast_control: None,
+            source_route_subframe: None,
+        };
+        let mut buf = [0u8; 64];
+        let offset = &mut 0;
+        buf.write_with(offset, header, ()).unwrap();
+        buf[*offset..*offset + 5].copy_from_slice(b"hello");
+        buf[..*offset + 5].to_vec()
+    }
+
+    fn is_relayed_data(payload: &[u8]) -> bool {
+        let (nib, aib) = (Nib::default(), Aib::default());
+        let cx = SecurityContext::new(&nib, &aib);
+        let mut payload = payload.to_vec();
+        matches!(
+            cx.decrypt_nwk_frame_in_place(&mut payload),
+            Ok(NwkFrame::Data(NwkDataFrame { header, payload }))
+                if header.radius == 4
+                    && header.source == ShortAddress(ORIGINATOR)
+                    && header.sequence_number == 0x42
+                    && payload == b"hello"
+        )
+    }
+
+    fn is_network_status(payload: &[u8], status_code: NetworkStatusCode) -> bool {
+        let (header, len) = NwkHeader::try_read(payload, ()).unwrap();
+        let (command, _) = NwkCommand::try_read(&payload[len..], ()).unwrap();
+        header.destination == ShortAddress(ORIGINATOR)
+            && matches!(command, NwkCommand::NetworkStatus(s)
+                if s.status_code == status_code
+                    && s.destination_address == ShortAddress(DESTINATION))
+    }
+
+    fn add_route(nlme: &Nlme<MockMlme>) {
+        let mut table = nlme.nib().route_table();
+        table
+            .push(NwkRoute::new(
+                ShortAddress(DESTINATION),
+                ShortAddress(NEXT_HOP),
+                RouteStatus::Active,
+            ))
+            .unwrap();
+        nlme.nib().set_route_table(table);
+    }
+
+    #[test]
+    fn relays_along_route() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| *dest == mac_short(NEXT_HOP) && is_relayed_data(payload))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_forwarder(mac);
+        add_route(&nlme);
+        expect_receive(
+            &mut nlme,
+            ORIGINATOR,
+            OWN,
+            255,
+            data_frame(5, DiscoverRoute::Suppress),
+        );
+
+        let indication = block_on(nlme.receive()).unwrap();
+
+        assert!(indication.is_none());
+    }
+
+    #[test]
+    fn relays_to_neighbor() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| *dest == mac_short(DESTINATION) && is_relayed_data(payload))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_forwarder(mac);
+        add_sibling(&nlme, DESTINATION);
+        expect_receive(
+            &mut nlme,
+            ORIGINATOR,
+            OWN,
+            255,
+            data_frame(5, DiscoverRoute::Suppress),
+        );
+
+        block_on(nlme.receive()).unwrap();
+    }
+
+    #[test]
+    fn drops_frame_with_exhausted_radius() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data().never();
+        let mut nlme = make_forwarder(mac);
+        add_route(&nlme);
+        expect_receive(
+            &mut nlme,
+            ORIGINATOR,
+            OWN,
+            255,
+            data_frame(1, DiscoverRoute::Suppress),
+        );
+
+        block_on(nlme.receive()).unwrap();
+    }
+
+    #[test]
+    fn end_device_does_not_relay() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data().never();
+        let mut nlme = make_nlme(mac);
+        nlme.nib().set_network_address(OWN);
+        expect_receive(
+            &mut nlme,
+            ORIGINATOR,
+            OWN,
+            255,
+            data_frame(5, DiscoverRoute::Suppress),
+        );
+
+        block_on(nlme.receive()).unwrap();
+    }
+
+    #[test]
+    fn reports_missing_route_to_originator() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                *dest == mac_short(ORIGINATOR)
+                    && is_network_status(payload, NetworkStatusCode::NoRouteAvailable)
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_forwarder(mac);
+        expect_receive(
+            &mut nlme,
+            ORIGINATOR,
+            OWN,
+            255,
+            data_frame(5, DiscoverRoute::Suppress),
+        );
+
+        block_on(nlme.receive()).unwrap();
+    }
+
+    #[test]
+    fn discovers_route_for_relayed_frame() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                let (header, len) = NwkHeader::try_read(payload, ()).unwrap();
+                let (command, _) = NwkCommand::try_read(&payload[len..], ()).unwrap();
+                *dest == mac_short(broadcast_address::ALL_DEVICES)
+                    && header.source == ShortAddress(OWN)
+                    && matches!(command, NwkCommand::RouteRequest(_))
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_forwarder(mac);
+        expect_receive(
+            &mut nlme,
+            ORIGINATOR,
+            OWN,
+            255,
+            data_frame(5, DiscoverRoute::Enable),
+        );
+
+        block_on(nlme.receive()).unwrap();
+
+        // the originator learns about the failed discovery
+        nlme.mac
+            .expect_transmit_data()
+            .withf(|dest, payload| {
+                *dest == mac_short(ORIGINATOR)
+                    && is_network_status(payload, NetworkStatusCode::NoRouteAvailable)
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        nlme.mac.expect_transmit_data().returning(|_, _| Ok(()));
+        block_on(nlme.tick(u32::MAX));
+    }
+
+    #[test]
+    fn reports_link_failure() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, _| *dest == mac_short(NEXT_HOP))
+            .times(1)
+            .returning(|_, _| Err(MacError::NoAck));
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                *dest == mac_short(ORIGINATOR)
+                    && is_network_status(payload, NetworkStatusCode::NonTreeLinkFailure)
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_forwarder(mac);
+        add_route(&nlme);
+        expect_receive(
+            &mut nlme,
+            ORIGINATOR,
+            OWN,
+            255,
+            data_frame(5, DiscoverRoute::Suppress),
+        );
+
+        block_on(nlme.receive()).unwrap();
+
+        assert!(nlme.nib().route_table().is_empty());
+    }
+
+    #[test]
+    fn network_status_removes_route() {
+        let mut nlme = make_forwarder(MockMlme::new());
+        add_route(&nlme);
+        let header = nlme.nwk_command_header(ShortAddress(OWN), 5, false);
+        let status = NetworkStatus {
+            status_code: NetworkStatusCode::NoRouteAvailable,
+            destination_address: ShortAddress(DESTINATION),
+        };
+        let len = nlme
+            .build_nwk_command_frame(header, NwkCommand::NetworkStatus(status))
+            .unwrap();
+        let frame = nlme.buf[..len].to_vec();
+        expect_receive(&mut nlme, ORIGINATOR, OWN, 255, frame);
+
+        let indication = block_on(nlme.receive()).unwrap();
+
+        let Some(NwkIndication::NwkStatus(indication)) = indication else {
+            unreachable!("expected NLME-NWK-STATUS.indication");
+        };
+        assert_eq!(indication.network_address, ShortAddress(DESTINATION));
+        assert_eq!(indication.status, NetworkStatusCode::NoRouteAvailable);
+        assert!(nlme.nib().route_table().is_empty());
+    }
+
+    #[test]
+    fn tree_routing() {
+        let mut nlme = make_nlme(MockMlme::new());
+        // Cm = 4, Rm = 2, Lm = 3
+        nlme.nib().set_max_children(4);
+        nlme.nib().set_max_routers(2);
+        nlme.nib().set_max_depth(3);
+        nlme.nib().set_addr_alloc(addr_alloc::DISTRIBUTED);
+        assert_eq!(nlme.cskip(0), 13);
+        assert_eq!(nlme.cskip(1), 5);
+        assert_eq!(nlme.cskip(2), 1);
+        assert_eq!(nlme.cskip(3), 0);
+
+        // coordinator: routers at 1 and 14, end devices at 27 and 28
+        nlme.nib().set_network_address(NWK_COORDINATOR_ADDRESS);
+        assert_eq!(nlme.tree_next_hop(ShortAddress(5)), Some(ShortAddress(1)));
+        assert_eq!(nlme.tree_next_hop(ShortAddress(20)), Some(ShortAddress(14)));
+        assert_eq!(nlme.tree_next_hop(ShortAddress(27)), Some(ShortAddress(27)));
+
+        // router 14 at depth 1 owns 15..=26, its parent is the coordinator
+        nlme.nib().set_network_address(14);
+        nlme.depth = 1;
+        let mut parent = make_neighbor(PAN_ID, NWK_COORDINATOR_ADDRESS, 0, 255, 0);
+        parent.relationship = relationship::PARENT;
+        let mut table = nlme.nib().neighbor_table();
+        table.push(parent).unwrap();
+        nlme.nib().set_neighbor_table(table);
+        assert_eq!(nlme.tree_next_hop(ShortAddress(16)), Some(ShortAddress(15)));
+        assert_eq!(nlme.tree_next_hop(ShortAddress(21)), Some(ShortAddress(20)));
+        assert_eq!(nlme.tree_next_hop(ShortAddress(25)), Some(ShortAddress(25)));
+        assert_eq!(nlme.tree_next_hop(ShortAddress(2)), Some(ShortAddress(0)));
+
+        nlme.nib().set_use_tree_routing(false);
+        assert_eq!(nlme.tree_next_hop(ShortAddress(16)), None);
+    }
+}
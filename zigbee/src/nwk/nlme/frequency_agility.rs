@@ -0,0 +1,598 @@
+//! Frequency agility
+//!
+//! Devices count their unicast transmissions and failures. On interference
+//! on the current channel they scan the energy of all channels and leave the
+//! report to the ZDO, which sends it to the network manager (nwkManagerAddr)
+//! as a Mgmt_NWK_Update_notify. Once enough devices complained, the network
+//! manager picks the quietest channel from an energy detect scan and broadcasts
+//! a network update with a new nwkUpdateId. Every device moves to the new
+//! channel after nwkNetworkBroadcastDeliveryTime (Annex E). PAN identifier
+//! updates are announced the same way.
+
+use heapless::Vec;
+use zigbee_mac::mlme::Mlme;
+use zigbee_mac::mlme::ScanType;
+use zigbee_types::ShortAddress;
+
+use super::NetworkError;
+use super::Nlme;
+use super::management::InterferenceReport;
+use super::management::NwkStatus;
+use crate::nwk::frame::command::Command as NwkCommand;
+use crate::nwk::frame::command::network_report::NetworkReport;
+use crate::nwk::frame::command::network_report::report_type;
+use crate::nwk::frame::command::network_update::NetworkUpdate;
+use crate::nwk::frame::header::Header as NwkHeader;
+use crate::nwk::nib::MAX_PAN_ID;
+use crate::nwk::nib::broadcast_address;
+use crate::nwk::nib::octets_to_ms;
+
+/// Unicast transmissions before the failure rate is evaluated.
+const TX_TOTAL_THRESHOLD: u16 = 20;
+/// Failure rate in percent above which the channel is considered
+/// congested.
+const TX_FAILURE_THRESHOLD: u32 = 25;
+/// Channels of the 2.4 GHz band.
+const CHANNELS: core::ops::Range<u8> = 11..27;
+/// Scan duration of the energy detect scan for a new channel.
+const ED_SCAN_DURATION: u8 = 3;
+/// Minimum time between two channel changes by the network manager.
+const CHANNEL_CHANGE_HOLDOFF_MS: u32 = 60 * 60 * 1000;
+
+// implementation specific
+
+/// Devices which have to report interference before the network manager
+/// changes the channel, including the network manager itself.
+const INTERFERENCE_REPORTERS: usize = 2;
+
+/// Frequency agility state of a device.
+pub(super) struct FrequencyAgility {
+    /// Devices which reported interference since the last channel change,
+    /// only used by the network manager.
+    reporters: Vec<ShortAddress, INTERFERENCE_REPORTERS>,
+    /// Milliseconds until the network manager may change the channel again.
+    holdoff: u32,
+    /// Network update announced but not yet applied.
+    pub(super) pending_update: Option<PendingUpdate>,
+    /// Interference detected on the current channel, not yet taken by the
+    /// ZDO.
+    pending_report: Option<InterferenceReport>,
+}
+
+/// Channel and PAN identifier a device moves to once the network update was
+/// delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct PendingUpdate {
+    pub(super) channel: u8,
+    pub(super) pan_id: u16,
+    /// Milliseconds until the update is applied.
+    due: u32,
+}
+
+impl FrequencyAgility {
+    pub(super) const fn new() -> Self {
+        Self {
+            reporters: Vec::new(),
+            holdoff: 0,
+            pending_update: None,
+            pending_report: None,
+        }
+    }
+}
+
+impl<M> Nlme<M>
+where
+    M: Mlme,
+{
+    /// Whether this device is the network manager (nwkManagerAddr).
+    pub(super) fn is_network_manager(&self) -> bool {
+        let nib = self.nib();
+        nib.network_address() != 0xffff && nib.network_address() == nib.manager_addr()
+    }
+
+    /// Move the network to `channel` (Annex E).
+    ///
+    /// Only the network manager may change the channel. The network update
+    /// is broadcast with an incremented nwkUpdateId and all devices switch
+    /// after nwkNetworkBroadcastDeliveryTime.
+    pub async fn change_channel(&mut self, channel: u8) -> NwkStatus {
+        if !self.is_network_manager() {
+            return NwkStatus::InvalidRequest;
+        }
+        if !CHANNELS.contains(&channel) {
+            return NwkStatus::InvalidParameter;
+        }
+        let pan_id = self.nib().panid();
+        if let Err(e) = self.broadcast_network_update(channel, pan_id).await {
+            log::debug!("[NLME] failed to broadcast network update: {e}");
+            return NwkStatus::MacError;
+        }
+        NwkStatus::Success
+    }
+
+    /// Broadcast a network update moving the network to `channel` and
+    /// `pan_id` with the next nwkUpdateId.
+    pub(super) async fn broadcast_network_update(
+        &mut self,
+        channel: u8,
+        pan_id: u16,
+    ) -> Result<(), NetworkError> {
+        let nib = self.nib();
+        let update_id = nib.update_id().wrapping_add(1);
+        nib.set_update_id(update_id);
+        let update = NetworkUpdate {
+            update_id,
+            channel,
+            pan_id,
+            network_address: nib.network_address(),
+        };
+        log::debug!(
+            "[NLME] moving network to channel {channel}, PAN 0x{pan_id:04x}, update id {update_id}"
+        );
+
+        let secure = self.nwk_security_enabled();
+        let radius = self.nib().max_depth().saturating_mul(2);
+        let header =
+            self.nwk_command_header(ShortAddress(broadcast_address::ALL_DEVICES), radius, secure);
+        let len = self.build_nwk_command_frame(header, NwkCommand::NetworkUpdate(update))?;
+        self.originate_broadcast(len).await?;
+        if channel != self.channel {
+            self.frequency_agility.holdoff = CHANNEL_CHANGE_HOLDOFF_MS;
+            self.frequency_agility.reporters.clear();
+        }
+        self.schedule_network_update(channel, pan_id);
+        Ok(())
+    }
+
+    /// Move to `channel` and `pan_id` once the announcing broadcast was
+    /// delivered.
+    fn schedule_network_update(&mut self, channel: u8, pan_id: u16) {
+        let due = octets_to_ms(self.nib().network_broadcast_delivery_time()).max(1);
+        self.frequency_agility.pending_update = Some(PendingUpdate {
+            channel,
+            pan_id,
+            due,
+        });
+        self.update_beacon_payload();
+    }
+
+    /// Handle a network update command (Annex E).
+    ///
+    /// Updates with an older nwkUpdateId, not sent by the network manager or
+    /// without NWK security in a secured network are ignored.
+    pub(super) fn network_update_indication(
+        &mut self,
+        header: &NwkHeader<'_>,
+        update: &NetworkUpdate,
+    ) {
+        if self.nwk_security_enabled() && !header.frame_control.security_flag() {
+            log::debug!(
+                "[NLME] dropping unsecured network update from 0x{:04x}",
+                header.source.0
+            );
+            return;
+        }
+        let current = self.nib().update_id();
+        // nwkUpdateId is a modular counter
+        let newer = update.update_id.wrapping_sub(current).cast_signed() > 0;
+        if !newer
+            || update.network_address != self.nib().manager_addr()
+            || !CHANNELS.contains(&update.channel)
+            || update.pan_id > MAX_PAN_ID
+        {
+            log::debug!(
+                "[NLME] ignoring network update {} for channel {}",
+                update.update_id,
+                update.channel
+            );
+            return;
+        }
+        self.nib().set_update_id(update.update_id);
+        self.schedule_network_update(update.channel, update.pan_id);
+    }
+
+    fn apply_network_update(&mut self, update: PendingUpdate) {
+        if update.channel != self.channel {
+            log::debug!("[NLME] switching to channel {}", update.channel);
+            self.mac.set_channel(update.channel);
+            self.channel = update.channel;
+        }
+        if update.pan_id != self.nib().panid() {
+            self.set_network_pan_id(update.pan_id);
+        }
+    }
+
+    /// Count a unicast transmission to `next_hop` in nwkTxTotal and the
+    /// transmit failures of the neighbor.
+    pub(super) fn record_transmission(&self, next_hop: ShortAddress, success: bool) {
+        let nib = self.nib();
+        nib.set_tx_total(nib.tx_total().saturating_add(1));
+        if success {
+            return;
+        }
+        let mut table = nib.neighbor_table();
+        if let Some(neighbor) = table.iter_mut().find(|n| n.network_address == next_hop) {
+            neighbor.transmit_failure = neighbor.transmit_failure.saturating_add(1);
+            nib.set_neighbor_table(table);
+        }
+    }
+
+    /// Transmissions and failures if the failures exceed the threshold, the
+    /// counters are reset once nwkTxTotal reaches [`TX_TOTAL_THRESHOLD`].
+    fn evaluate_interference(&self) -> Option<(u16, u16)> {
+        let nib = self.nib();
+        let tx_total = nib.tx_total();
+        if tx_total < TX_TOTAL_THRESHOLD {
+            return None;
+        }
+        let mut table = nib.neighbor_table();
+        let failures: u32 = table.iter().map(|n| u32::from(n.transmit_failure)).sum();
+        for neighbor in table.iter_mut() {
+            neighbor.transmit_failure = 0;
+        }
+        nib.set_neighbor_table(table);
+        nib.set_tx_total(0);
+        (failures * 100 > u32::from(tx_total) * TX_FAILURE_THRESHOLD)
+            .then(|| (tx_total, u16::try_from(failures).unwrap_or(u16::MAX)))
+    }
+
+    /// Advance the frequency agility timers by `elapsed_ms`.
+    pub(super) async fn frequency_agility_tick(&mut self, elapsed_ms: u32) {
+        let state = &mut self.frequency_agility;
+        state.holdoff = state.holdoff.saturating_sub(elapsed_ms);
+        if let Some(update) = &mut state.pending_update {
+            update.due = update.due.saturating_sub(elapsed_ms);
+            if update.due == 0 {
+                let update = *update;
+                state.pending_update = None;
+                self.apply_network_update(update);
+            }
+        }
+
+        if self.nib().network_address() == 0xffff {
+            return;
+        }
+        let Some((total_transmissions, transmission_failures)) = self.evaluate_interference()
+        else {
+            return;
+        };
+        log::debug!("[NLME] interference on channel {}", self.channel);
+        let result = if self.is_network_manager() {
+            let own = ShortAddress(self.nib().network_address());
+            self.interference_report(own).await
+        } else {
+            self.scan_interference(total_transmissions, transmission_failures)
+                .await
+        };
+        if let Err(e) = result {
+            log::debug!("[NLME] failed to report interference: {e}");
+        }
+    }
+
+    /// Scan the energy of all channels for the report to the network
+    /// manager.
+    async fn scan_interference(
+        &mut self,
+        total_transmissions: u16,
+        transmission_failures: u16,
+    ) -> Result<(), NetworkError> {
+        let scan = self
+            .mac
+            .scan_network(ScanType::Ed, CHANNELS, ED_SCAN_DURATION)
+            .await?;
+        let mut report = InterferenceReport {
+            scanned_channels: 0,
+            total_transmissions,
+            transmission_failures,
+            energy_values: Vec::new(),
+        };
+        for ed in &scan.energy_detect_list {
+            report.scanned_channels |= 1 << ed.channel;
+            let _ = report.energy_values.push(ed.energy);
+        }
+        self.frequency_agility.pending_report = Some(report);
+        Ok(())
+    }
+
+    /// Take the interference detected by [`Nlme::tick`], which the ZDO
+    /// reports to the network manager with a Mgmt_NWK_Update_notify
+    /// (Annex E).
+    pub fn take_interference_report(&mut self) -> Option<InterferenceReport> {
+        self.frequency_agility.pending_report.take()
+    }
+
+    /// Send `report` to the network manager.
+    pub(super) async fn send_network_report(
+        &mut self,
+        report: NetworkReport<'_>,
+    ) -> Result<(), NetworkError> {
+        let manager = ShortAddress(self.nib().manager_addr());
+        let next_hop = if self.is_router() {
+            self.next_hop(manager)
+                .or_else(|| self.tree_next_hop(manager))
+        } else {
+            self.parent_network_address().ok()
+        };
+        let Some(next_hop) = next_hop else {
+            log::debug!("[NLME] no route to the network manager");
+            return Ok(());
+        };
+
+        let secure = self.nwk_security_enabled();
+        let radius = self.nib().max_depth().saturating_mul(2);
+        let header = self.nwk_command_header(manager, radius, secure);
+        let len = self.build_nwk_command_frame(header, NwkCommand::NetworkReport(report))?;
+        self.transmit_nwk_frame(next_hop, len).await
+    }
+
+    /// Handle a network report command addressed to this device.
+    pub(super) async fn network_report_indication(
+        &mut self,
+        header: &NwkHeader<'_>,
+        report: &NetworkReport<'_>,
+    ) -> Result<(), NetworkError> {
+        if self.nwk_security_enabled() && !header.frame_control.security_flag() {
+            log::debug!(
+                "[NLME] dropping unsecured network report from 0x{:04x}",
+                header.source.0
+            );
+            return Ok(());
+        }
+        match report.report_type {
+            report_type::PAN_IDENTIFIER_CONFLICT => self.resolve_pan_id_conflict().await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Collect an interference report from `reporter`, the network manager
+    /// moves the network once enough devices reported.
+    pub(crate) async fn interference_report(
+        &mut self,
+        reporter: ShortAddress,
+    ) -> Result<(), NetworkError> {
+        if !self.is_network_manager()
+            || self.frequency_agility.holdoff > 0
+            || self.frequency_agility.pending_update.is_some()
+        {
+            return Ok(());
+        }
+        let reporters = &mut self.frequency_agility.reporters;
+        if !reporters.contains(&reporter) {
+            let _ = reporters.push(reporter);
+        }
+        if !reporters.is_full() {
+            return Ok(());
+        }
+
+        let scan = self
+            .mac
+            .scan_network(ScanType::Ed, CHANNELS, ED_SCAN_DURATION)
+            .await?;
+        let current = self.channel;
+        let Some(best) = scan
+            .energy_detect_list
+            .iter()
+            .filter(|ed| ed.channel != current)
+            .min_by_key(|ed| ed.energy)
+        else {
+            return Ok(());
+        };
+        let channel = best.channel;
+        let pan_id = self.nib().panid();
+        self.broadcast_network_update(channel, pan_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use zigbee_mac::mlme::ScanResult;
+
+    use super::*;
+    use crate::nwk::nlme::tests::MANAGER;
+    use crate::nwk::nlme::tests::MockMlme;
+    use crate::nwk::nlme::tests::OWN;
+    use crate::nwk::nlme::tests::PAN_ID;
+    use crate::nwk::nlme::tests::block_on;
+    use crate::nwk::nlme::tests::command_header;
+    use crate::nwk::nlme::tests::energy_detect_list;
+    use crate::nwk::nlme::tests::install_network_key;
+    use crate::nwk::nlme::tests::mac_short;
+    use crate::nwk::nlme::tests::make_device;
+    use crate::nwk::nlme::tests::parse_command;
+
+    const ROUTER: u16 = OWN;
+    const CHANNEL: u8 = 11;
+
+    fn expect_network_update(mac: &mut MockMlme, channel: u8, update_id: u8) {
+        mac.expect_transmit_data()
+            .withf(move |dest, payload| {
+                let (_, NwkCommand::NetworkUpdate(update)) = parse_command(payload) else {
+                    return false;
+                };
+                *dest == mac_short(broadcast_address::ALL_DEVICES)
+                    && update.channel == channel
+                    && update.update_id == update_id
+                    && update.pan_id == PAN_ID
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mac.expect_set_beacon_payload().return_const(());
+    }
+
+    fn delivery_time(nlme: &Nlme<MockMlme>) -> u32 {
+        octets_to_ms(nlme.nib().network_broadcast_delivery_time())
+    }
+
+    #[test]
+    fn manager_moves_network() {
+        let mut mac = MockMlme::new();
+        expect_network_update(&mut mac, 20, 1);
+        mac.expect_set_channel()
+            .withf(|channel| *channel == 20)
+            .times(1)
+            .return_const(());
+        let mut nlme = make_device(mac, MANAGER, 0, CHANNEL);
+
+        assert_eq!(block_on(nlme.change_channel(20)), NwkStatus::Success);
+        assert_eq!(nlme.nib().update_id(), 1);
+
+        // the broadcast is delivered on the old channel first
+        nlme.frequency_agility.holdoff = 0;
+        let delay = delivery_time(&nlme);
+        block_on(nlme.frequency_agility_tick(delay - 1));
+        assert_eq!(nlme.channel, 11);
+        block_on(nlme.frequency_agility_tick(1));
+        assert_eq!(nlme.channel, 20);
+    }
+
+    #[test]
+    fn only_manager_changes_channel() {
+        let mut nlme = make_device(MockMlme::new(), ROUTER, 0, CHANNEL);
+        assert_eq!(block_on(nlme.change_channel(20)), NwkStatus::InvalidRequest);
+
+        nlme.nib().set_manager_addr(ROUTER);
+        assert_eq!(
+            block_on(nlme.change_channel(27)),
+            NwkStatus::InvalidParameter
+        );
+    }
+
+    #[test]
+    fn follows_newer_network_update() {
+        let mut mac = MockMlme::new();
+        mac.expect_set_beacon_payload().return_const(());
+        mac.expect_set_channel()
+            .withf(|channel| *channel == 15)
+            .times(1)
+            .return_const(());
+        let mut nlme = make_device(mac, ROUTER, 0, CHANNEL);
+        nlme.nib().set_update_id(0xff);
+
+        // stale and foreign updates are ignored
+        for (update_id, network_address) in [(0xfe, MANAGER), (0x00, 0x2222)] {
+            nlme.network_update_indication(
+                &command_header(MANAGER, broadcast_address::ALL_DEVICES, false),
+                &NetworkUpdate {
+                    update_id,
+                    channel: 20,
+                    pan_id: PAN_ID,
+                    network_address,
+                },
+            );
+        }
+        assert!(nlme.frequency_agility.pending_update.is_none());
+
+        // update ids wrap around
+        nlme.network_update_indication(
+            &command_header(MANAGER, broadcast_address::ALL_DEVICES, false),
+            &NetworkUpdate {
+                update_id: 0x00,
+                channel: 15,
+                pan_id: PAN_ID,
+                network_address: MANAGER,
+            },
+        );
+        assert_eq!(nlme.nib().update_id(), 0x00);
+        let delay = delivery_time(&nlme);
+        block_on(nlme.frequency_agility_tick(delay));
+        assert_eq!(nlme.channel, 15);
+    }
+
+    #[test]
+    fn unsecured_network_update_ignored_in_secured_network() {
+        let mut nlme = make_device(MockMlme::new(), ROUTER, 0, CHANNEL);
+        install_network_key(&nlme);
+        let update = NetworkUpdate {
+            update_id: 1,
+            channel: 20,
+            pan_id: PAN_ID,
+            network_address: MANAGER,
+        };
+
+        let header = command_header(MANAGER, broadcast_address::ALL_DEVICES, false);
+        nlme.network_update_indication(&header, &update);
+        block_on(nlme.frequency_agility_tick(delivery_time(&nlme)));
+
+        assert!(nlme.frequency_agility.pending_update.is_none());
+        assert_eq!(nlme.nib().update_id(), 0);
+        assert_eq!(nlme.channel, 11);
+    }
+
+    #[test]
+    fn router_reports_interference() {
+        let mut mac = MockMlme::new();
+        mac.expect_scan_network()
+            .withf(|ty, channels, _| *ty == ScanType::Ed && *channels == CHANNELS)
+            .times(1)
+            .returning(|ty, _, _| {
+                Ok(ScanResult {
+                    scan_type: ty,
+                    pan_descriptor: zigbee_mac::mlme::PanDescriptorList::new(),
+                    energy_detect_list: energy_detect_list(&[(11, 0xc0), (12, 0x10)]),
+                    coordinator_realignment: None,
+                })
+            });
+        let mut nlme = make_device(mac, ROUTER, 0, CHANNEL);
+
+        // failures below the threshold only reset the counters
+        for success in [false; 5].into_iter().chain([true; 15]) {
+            nlme.record_transmission(ShortAddress(MANAGER), success);
+        }
+        block_on(nlme.frequency_agility_tick(0));
+        assert_eq!(nlme.nib().tx_total(), 0);
+        assert!(nlme.take_interference_report().is_none());
+
+        for success in [false; 6].into_iter().chain([true; 14]) {
+            nlme.record_transmission(ShortAddress(MANAGER), success);
+        }
+        block_on(nlme.frequency_agility_tick(0));
+        assert!(
+            nlme.nib()
+                .neighbor_table()
+                .iter()
+                .all(|n| n.transmit_failure == 0)
+        );
+
+        let report = nlme.take_interference_report().unwrap();
+        assert_eq!(report.scanned_channels, 0x0000_1800);
+        assert_eq!(report.total_transmissions, 20);
+        assert_eq!(report.transmission_failures, 6);
+        assert_eq!(&report.energy_values[..], &[0xc0, 0x10]);
+        assert!(nlme.take_interference_report().is_none());
+    }
+
+    #[test]
+    fn manager_moves_to_quietest_channel_after_reports() {
+        let mut mac = MockMlme::new();
+        mac.expect_scan_network()
+            .withf(|ty, _, _| *ty == ScanType::Ed)
+            .times(1)
+            .returning(|ty, _, _| {
+                Ok(ScanResult {
+                    scan_type: ty,
+                    pan_descriptor: zigbee_mac::mlme::PanDescriptorList::new(),
+                    energy_detect_list: energy_detect_list(&[
+                        (11, 0x00),
+                        (15, 0x40),
+                        (20, 0x10),
+                        (25, 0xa0),
+                    ]),
+                    coordinator_realignment: None,
+                })
+            });
+        expect_network_update(&mut mac, 20, 1);
+        let mut nlme = make_device(mac, MANAGER, 0, CHANNEL);
+
+        // a single device is not enough, repeated reports do not count
+        block_on(nlme.interference_report(ShortAddress(ROUTER))).unwrap();
+        block_on(nlme.interference_report(ShortAddress(ROUTER))).unwrap();
+        assert!(nlme.frequency_agility.pending_update.is_none());
+
+        block_on(nlme.interference_report(ShortAddress(MANAGER))).unwrap();
+        assert_eq!(nlme.frequency_agility.pending_update.unwrap().channel, 20);
+
+        // no further change during the hold-off
+        block_on(nlme.interference_report(ShortAddress(0x2222))).unwrap();
+        assert!(nlme.frequency_agility.reporters.is_empty());
+    }
+}
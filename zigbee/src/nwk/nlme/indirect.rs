@@ -0,0 +1,446 @@
+//! Indirect transmission
+//!
+//! End device children with the receiver off when idle cannot be reached
+//! directly. Their parent buffers the frames for them until they poll with
+//! a MAC data request, the frame pending bit tells the child whether more
+//! frames are waiting. Frames which were not polled within
+//! nwkTransactionPersistenceTime are dropped and the sender is told
+//! (§3.6.3.3).
+
+use byte::TryRead;
+use heapless::Vec;
+use zigbee_mac::Address;
+use zigbee_mac::mlme::Mlme;
+use zigbee_types::ShortAddress;
+
+use super::NetworkError;
+use super::Nlme;
+use super::end_device_timeout::is_end_device_child;
+use crate::nwk::frame::command::network_status::NetworkStatusCode;
+use crate::nwk::frame::frame_control::FrameType;
+use crate::nwk::frame::header::Header as NwkHeader;
+use crate::nwk::nib::superframes_to_ms;
+use crate::nwk::nlde::NldeDataConfirm;
+use crate::nwk::nlde::NldeDataStatus;
+
+// implementation specific
+
+/// Frames buffered for all children together.
+pub(super) const MAX_INDIRECT_FRAMES: usize = 4;
+/// Frames buffered for a single child.
+const MAX_INDIRECT_FRAMES_PER_CHILD: usize = 2;
+/// Longest buffered NWK frame.
+const MAX_INDIRECT_FRAME_LEN: usize = 128;
+
+/// NWK frame waiting for a poll of `child`.
+struct IndirectFrame {
+    child: ShortAddress,
+    /// Milliseconds until the frame expires.
+    remaining: u32,
+    len: usize,
+    frame: [u8; MAX_INDIRECT_FRAME_LEN],
+}
+
+/// Frames buffered for sleepy end device children, oldest first.
+pub(super) struct IndirectQueue {
+    frames: Vec<IndirectFrame, MAX_INDIRECT_FRAMES>,
+}
+
+impl IndirectQueue {
+    pub(super) const fn new() -> Self {
+        Self { frames: Vec::new() }
+    }
+
+    fn queued(&self, child: ShortAddress) -> usize {
+        self.frames.iter().filter(|f| f.child == child).count()
+    }
+}
+
+impl<M> Nlme<M>
+where
+    M: Mlme,
+{
+    /// Whether `address` is an end device child with the receiver off when
+    /// idle.
+    pub(super) fn is_sleepy_child(&self, address: ShortAddress) -> bool {
+        self.nib()
+            .neighbor_table()
+            .iter()
+            .any(|n| n.network_address == address && !n.rx_on_when_idle && is_end_device_child(n))
+    }
+
+    /// Buffer the first `len` bytes of `self.buf` until `child` polls for
+    /// them.
+    pub(super) fn queue_indirect(
+        &mut self,
+        child: ShortAddress,
+        len: usize,
+    ) -> Result<(), NetworkError> {
+        if len > MAX_INDIRECT_FRAME_LEN
+            || self.indirect.frames.is_full()
+            || self.indirect.queued(child) >= MAX_INDIRECT_FRAMES_PER_CHILD
+        {
+            log::debug!("[NLME] no indirect capacity for 0x{:04x}", child.0);
+            return Err(NetworkError::NoIndirectCapacity);
+        }
+        let mut frame = IndirectFrame {
+            child,
+            remaining: superframes_to_ms(u32::from(self.nib().transaction_persistence_time())),
+            len,
+            frame: [0u8; MAX_INDIRECT_FRAME_LEN],
+        };
+        frame.frame[..len].copy_from_slice(&self.buf[..len]);
+        // capacity was checked above
+        let _ = self.indirect.frames.push(frame);
+        let dest = self.mac_address(child);
+        self.mac.set_frame_pending(dest, true);
+        Ok(())
+    }
+
+    /// Drop all buffered frames.
+    pub(super) fn clear_indirect(&mut self) {
+        while let Some(frame) = self.indirect.frames.pop() {
+            if self.indirect.queued(frame.child) == 0 {
+                let dest = self.mac_address(frame.child);
+                self.mac.set_frame_pending(dest, false);
+            }
+        }
+    }
+
+    /// Release the oldest frame buffered for `source` which polled with a
+    /// MAC data request.
+    ///
+    /// A frame which could not be transmitted stays buffered for the next
+    /// poll.
+    pub(super) async fn indirect_poll_indication(&mut self, source: Address) {
+        let Address::Short(_, source) = source else {
+            return;
+        };
+        let child = ShortAddress(source.0);
+        let Some(index) = self.indirect.frames.iter().position(|f| f.child == child) else {
+            return;
+        };
+        let frame_pending = self.indirect.queued(child) > 1;
+        let dest = self.mac_address(child);
+        let frame = &self.indirect.frames[index];
+        let result = self
+            .mac
+            .transmit_indirect(dest, &frame.frame[..frame.len], frame_pending)
+            .await;
+        self.record_transmission(child, result.is_ok());
+        match result {
+            Ok(()) => {
+                self.indirect.frames.remove(index);
+                if !frame_pending {
+                    self.mac.set_frame_pending(dest, false);
+                }
+            }
+            Err(e) => log::debug!(
+                "[NLME] indirect transmission to 0x{:04x} failed: {e}",
+                child.0
+            ),
+        }
+    }
+
+    /// Drop the buffered frames which expired.
+    ///
+    /// The originator of a relayed data frame gets a network status, data
+    /// frames of this device are confirmed to the next higher layer.
+    pub(super) async fn indirect_tick(
+        &mut self,
+        elapsed_ms: u32,
+    ) -> Vec<NldeDataConfirm, MAX_INDIRECT_FRAMES> {
+        let mut confirms = Vec::new();
+        for frame in &mut self.indirect.frames {
+            frame.remaining = frame.remaining.saturating_sub(elapsed_ms);
+        }
+        while let Some(index) = self.indirect.frames.iter().position(|f| f.remaining == 0) {
+            let frame = self.indirect.frames.remove(index);
+            log::debug!(
+                "[NLME] indirect transaction for 0x{:04x} expired",
+                frame.child.0
+            );
+            if self.indirect.queued(frame.child) == 0 {
+                let dest = self.mac_address(frame.child);
+                self.mac.set_frame_pending(dest, false);
+            }
+            let Ok((header, _)) = NwkHeader::try_read(&frame.frame[..frame.len], ()) else {
+                continue;
+            };
+            if header.frame_control.frame_type() != FrameType::Data {
+                continue;
+            }
+            if header.source.0 == self.nib().network_address() {
+                // at most one confirm per buffered frame
+                let _ = confirms.push(NldeDataConfirm {
+                    destination: header.destination,
+                    status: NldeDataStatus::TransactionExpired,
+                });
+            } else {
+                self.report_network_status(
+                    header.source,
+                    NetworkStatusCode::IndirectTransactionExpiry,
+                    header.destination,
+                )
+                .await;
+            }
+        }
+        confirms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byte::BytesExt;
+    use zigbee_mac::mlme::MacIndication;
+
+    use super::*;
+    use crate::nwk::frame::command::Command as NwkCommand;
+    use crate::nwk::frame::command::leave::CommandOptions as LeaveOptions;
+    use crate::nwk::frame::frame_control::FrameControl as NwkFrameControl;
+    use crate::nwk::nib::DeviceType;
+    use crate::nwk::nib::relationship;
+    use crate::nwk::nlme::NwkIndication;
+    use crate::nwk::nlme::management::NlmeLeaveIndication;
+    use crate::nwk::nlme::tests::MockMlme;
+    use crate::nwk::nlme::tests::OWN;
+    use crate::nwk::nlme::tests::PAN_ID;
+    use crate::nwk::nlme::tests::block_on;
+    use crate::nwk::nlme::tests::mac_short;
+    use crate::nwk::nlme::tests::make_neighbor;
+    use crate::nwk::nlme::tests::make_nlme;
+    use crate::nwk::nlme::tests::make_router;
+
+    const SIBLING: u16 = 0x2222;
+    const CHILD: u16 = 0x4444;
+    /// nwkTransactionPersistenceTime of 0x01f4 superframes.
+    const PERSISTENCE_MS: u32 = 7680;
+
+    fn make_parent(mac: MockMlme) -> Nlme<MockMlme> {
+        let nlme = make_router(mac);
+        let mut child = make_neighbor(PAN_ID, CHILD, 0, 0xff, 1);
+        child.device_type = DeviceType::EndDevice;
+        child.relationship = relationship::CHILD;
+        let mut sibling = make_neighbor(PAN_ID, SIBLING, 0, 0xff, 1);
+        sibling.relationship = relationship::SIBLING;
+        let mut table = nlme.nib().neighbor_table();
+        table.push(child).unwrap();
+        table.push(sibling).unwrap();
+        nlme.nib().set_neighbor_table(table);
+        nlme
+    }
+
+    fn expect_poll(nlme: &mut Nlme<MockMlme>) {
+        nlme.mac.expect_receive().times(1).returning(|_| {
+            Ok(MacIndication::Poll {
+                source: mac_short(CHILD),
+            })
+        });
+    }
+
+    fn expect_indirect(mac: &mut MockMlme, payload: &'static [u8], frame_pending: bool) {
+        mac.expect_transmit_indirect()
+            .withf(move |dest, frame, pending| {
+                *dest == mac_short(CHILD) && frame.ends_with(payload) && *pending == frame_pending
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+    }
+
+    fn expect_frame_pending(mac: &mut MockMlme, frame_pending: bool, times: usize) {
+        mac.expect_set_frame_pending()
+            .withf(move |dest, pending| *dest == mac_short(CHILD) && *pending == frame_pending)
+            .times(times)
+            .return_const(());
+    }
+
+    #[test]
+    fn polls_release_frames_in_order() {
+        let mut mac = MockMlme::new();
+        let mut seq = mockall::Sequence::new();
+        mac.expect_transmit_indirect()
+            .withf(|_, frame, pending| frame.ends_with(b"first") && *pending)
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+        mac.expect_transmit_indirect()
+            .withf(|_, frame, pending| frame.ends_with(b"second") && !*pending)
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Ok(()));
+        // cleared once the last frame is sent
+        expect_frame_pending(&mut mac, true, 2);
+        expect_frame_pending(&mut mac, false, 1);
+        let mut nlme = make_parent(mac);
+
+        block_on(nlme.send_data(ShortAddress(CHILD), false, b"first")).unwrap();
+        block_on(nlme.send_data(ShortAddress(CHILD), false, b"second")).unwrap();
+        for _ in 0..3 {
+            expect_poll(&mut nlme);
+            block_on(nlme.receive()).unwrap();
+        }
+    }
+
+    #[test]
+    fn failed_transmission_stays_buffered() {
+        let mut mac = MockMlme::new();
+        let mut seq = mockall::Sequence::new();
+        mac.expect_transmit_indirect()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _, _| Err(zigbee_mac::mlme::MacError::NoAck));
+        expect_frame_pending(&mut mac, true, 1);
+        expect_frame_pending(&mut mac, false, 1);
+        let mut nlme = make_parent(mac);
+        expect_indirect(&mut nlme.mac, b"data", false);
+
+        block_on(nlme.send_data(ShortAddress(CHILD), false, b"data")).unwrap();
+        expect_poll(&mut nlme);
+        block_on(nlme.receive()).unwrap();
+        expect_poll(&mut nlme);
+        block_on(nlme.receive()).unwrap();
+    }
+
+    #[test]
+    fn queue_is_bounded_per_child() {
+        let mut mac = MockMlme::new();
+        expect_frame_pending(&mut mac, true, MAX_INDIRECT_FRAMES_PER_CHILD);
+        let mut nlme = make_parent(mac);
+
+        for _ in 0..MAX_INDIRECT_FRAMES_PER_CHILD {
+            block_on(nlme.send_data(ShortAddress(CHILD), false, b"data")).unwrap();
+        }
+        let result = block_on(nlme.send_data(ShortAddress(CHILD), false, b"data"));
+        assert!(matches!(result, Err(NetworkError::NoIndirectCapacity)));
+    }
+
+    #[test]
+    fn expired_frame_is_confirmed() {
+        let mut mac = MockMlme::new();
+        expect_frame_pending(&mut mac, true, 1);
+        expect_frame_pending(&mut mac, false, 1);
+        let mut nlme = make_parent(mac);
+        nlme.link_status_due = u32::MAX;
+        block_on(nlme.send_data(ShortAddress(CHILD), false, b"data")).unwrap();
+
+        assert!(block_on(nlme.tick(PERSISTENCE_MS - 1)).is_empty());
+        let confirms = block_on(nlme.tick(1));
+        assert_eq!(
+            &confirms[..],
+            &[NldeDataConfirm {
+                destination: ShortAddress(CHILD),
+                status: NldeDataStatus::TransactionExpired,
+            }]
+        );
+
+        // nothing left for the child
+        expect_poll(&mut nlme);
+        block_on(nlme.receive()).unwrap();
+    }
+
+    #[test]
+    fn expired_relayed_frame_is_reported_to_originator() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                let (header, len) = NwkHeader::try_read(payload, ()).unwrap();
+                let Ok((NwkCommand::NetworkStatus(status), _)) =
+                    NwkCommand::try_read(&payload[len..], ())
+                else {
+                    return false;
+                };
+                *dest == mac_short(SIBLING)
+                    && header.destination == ShortAddress(SIBLING)
+                    && status.status_code == NetworkStatusCode::IndirectTransactionExpiry
+                    && status.destination_address == ShortAddress(CHILD)
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        expect_frame_pending(&mut mac, true, 1);
+        expect_frame_pending(&mut mac, false, 1);
+        let mut nlme = make_parent(mac);
+        nlme.link_status_due = u32::MAX;
+
+        let header = NwkHeader {
+            frame_control: NwkFrameControl(0).set_protocol_version(2),
+            destination: ShortAddress(CHILD),
+            source: ShortAddress(SIBLING),
+            radius: 5,
+            sequence_number: 1,
+            destination_ieee: None,
+            source_ieee: None,
+            multicast_control: None,
+            source_route_subframe: None,
+        };
+        let mut frame = [0u8; 32];
+        let offset = &mut 0;
+        frame.write_with(offset, header, ()).unwrap();
+        frame.write_with(offset, &b"data"[..], ()).unwrap();
+        let len = *offset;
+        nlme.mac.expect_receive().times(1).returning(move |buf| {
+            buf[..len].copy_from_slice(&frame[..len]);
+            Ok(MacIndication::Data {
+                source: mac_short(SIBLING),
+                destination: mac_short(OWN),
+                len,
+                lqi: 0xff,
+            })
+        });
+        block_on(nlme.receive()).unwrap();
+
+        assert!(block_on(nlme.tick(PERSISTENCE_MS)).is_empty());
+    }
+
+    #[test]
+    fn sleepy_child_polls_leave_request() {
+        let frame = std::sync::Arc::new(std::sync::Mutex::new(std::vec::Vec::new()));
+        let mut mac = MockMlme::new();
+        let sent = frame.clone();
+        mac.expect_transmit_indirect()
+            .times(1)
+            .returning(move |_, payload, _| {
+                sent.lock().unwrap().extend_from_slice(payload);
+                Ok(())
+            });
+        expect_frame_pending(&mut mac, true, 1);
+        expect_frame_pending(&mut mac, false, 1);
+        let mut parent = make_parent(mac);
+        let options = LeaveOptions(0).set_request(true);
+        block_on(parent.send_leave(ShortAddress(CHILD), options)).unwrap();
+        expect_poll(&mut parent);
+        block_on(parent.receive()).unwrap();
+
+        let mut mac = MockMlme::new();
+        mac.expect_poll_data()
+            .withf(|dest, _| *dest == mac_short(OWN))
+            .times(1)
+            .returning(move |_, buf| {
+                let frame = frame.lock().unwrap();
+                buf[..frame.len()].copy_from_slice(&frame);
+                Ok((frame.len(), 0xff, false))
+            });
+        // leave announcement
+        mac.expect_transmit_data().times(1).returning(|_, _| Ok(()));
+        mac.expect_set_association_permit().return_const(());
+        mac.expect_set_short_address().return_const(());
+        let mut child = make_nlme(mac);
+        child.nib().set_network_address(CHILD);
+        child.nib().set_panid(PAN_ID);
+        let mut entry = make_neighbor(PAN_ID, OWN, 0, 0xff, 0);
+        entry.relationship = relationship::PARENT;
+        let mut table = child.nib().neighbor_table();
+        table.push(entry).unwrap();
+        child.nib().set_neighbor_table(table);
+
+        let confirm = block_on(child.poll_tick(0)).unwrap();
+
+        assert!(matches!(
+            confirm.indication,
+            Some(NwkIndication::Leave(NlmeLeaveIndication {
+                device_address: None,
+                rejoin: false,
+            }))
+        ));
+        assert_eq!(child.nib().network_address(), 0xffff);
+    }
+}
@@ -0,0 +1,641 @@
+//! Leaving a network
+//!
+//! A device leaves on its own through NLME-LEAVE.request or when asked to
+//! by a leave request command, and announces this to its neighbors.
+//! Parents may remove their children the same way (§3.6.1.10).
+
+use heapless::Vec;
+use zigbee_mac::mlme::Mlme;
+use zigbee_types::IeeeAddress;
+use zigbee_types::ShortAddress;
+use zigbee_types::StorageVec;
+
+use super::NetworkError;
+use super::Nlme;
+use super::PermitJoining;
+use super::broadcast;
+use super::frequency_agility;
+use super::management::NlmeLeaveConfirm;
+use super::management::NlmeLeaveIndication;
+use super::management::NlmeLeaveRequest;
+use super::management::NwkStatus;
+use super::routing;
+use crate::nwk::frame::command::Command as NwkCommand;
+use crate::nwk::frame::command::leave::CommandOptions as LeaveOptions;
+use crate::nwk::frame::command::leave::Leave;
+use crate::nwk::frame::header::Header as NwkHeader;
+use crate::nwk::nib::MAX_NEIGHBOR_TABLE;
+use crate::nwk::nib::NWK_COORDINATOR_ADDRESS;
+use crate::nwk::nib::broadcast_address;
+use crate::nwk::nib::relationship;
+
+/// Scan duration of the rejoin after a leave request with rejoin.
+const REJOIN_SCAN_DURATION: u8 = 3;
+
+impl<M> Nlme<M>
+where
+    M: Mlme,
+{
+    /// 3.2.2.18
+    ///
+    /// Without a device address this device leaves the network, with
+    /// `rejoin` the network identifiers and keys are kept so that the next
+    /// higher layer can rejoin. Otherwise the child is asked to leave and
+    /// removed.
+    // §3.6.1.10.1, §3.6.1.10.2
+    pub async fn leave(&mut self, request: NlmeLeaveRequest) -> NlmeLeaveConfirm {
+        let confirm = |status| NlmeLeaveConfirm {
+            status,
+            device_address: request.device_address,
+        };
+        if self.nib().network_address() == 0xffff {
+            return confirm(NwkStatus::InvalidRequest);
+        }
+
+        let Some(device_address) = request.device_address else {
+            if let Err(e) = self
+                .leave_network(request.remove_children, request.rejoin)
+                .await
+            {
+                log::debug!("[NLME] failed to announce leave: {e}");
+            }
+            return confirm(NwkStatus::Success);
+        };
+
+        let Some(child) = self.child_address(device_address) else {
+            return confirm(NwkStatus::UnknownDevice);
+        };
+        let options = LeaveOptions(0)
+            .set_request(true)
+            .set_rejoin(request.rejoin)
+            .set_remove_children(request.remove_children);
+        let status = match self.send_leave(child, options).await {
+            Ok(()) => NwkStatus::Success,
+            Err(e) => {
+                log::debug!(
+                    "[NLME] failed to send leave request to 0x{:04x}: {e}",
+                    child.0
+                );
+                NwkStatus::MacError
+            }
+        };
+        log::debug!("[NLME] removed child {device_address:?}");
+        self.remove_neighbor(child);
+        // capacity may have changed
+        self.update_beacon_payload();
+
+        confirm(status)
+    }
+
+    /// Handle a leave command received from a neighbor (§3.6.1.10.3).
+    ///
+    /// A leave request for this device is honored when nwkLeaveRequestAllowed
+    /// and, without rejoin, nwkLeaveRequestWithoutRejoinAllowed permit it; a
+    /// leave with rejoin triggers a rejoin. Otherwise a neighbor announced
+    /// that it left and is removed from the neighbor table.
+    pub(super) async fn leave_indication(
+        &mut self,
+        header: &NwkHeader<'_>,
+        leave: &Leave,
+    ) -> Result<Option<NlmeLeaveIndication>, NetworkError> {
+        if self.nwk_security_enabled() && !header.frame_control.security_flag() {
+            log::debug!(
+                "[NLME] dropping unsecured leave from 0x{:04x}",
+                header.source.0
+            );
+            return Ok(None);
+        }
+        let options = leave.command_options;
+        if !options.request() {
+            return Ok(self.neighbor_left(header.source, header.source_ieee, options.rejoin()));
+        }
+
+        // only unicast requests from the parent or the Trust Center
+        let own_address = self.nib().network_address();
+        if header.destination.0 != own_address
+            || !(header.source.0 == NWK_COORDINATOR_ADDRESS
+                || self.parent_network_address().ok() == Some(header.source))
+        {
+            log::debug!(
+                "[NLME] dropping leave request from 0x{:04x}",
+                header.source.0
+            );
+            return Ok(None);
+        }
+        let nib = self.nib();
+        if !nib.leave_request_allowed()
+            || (!options.rejoin() && !nib.leave_request_without_rejoin_allowed())
+        {
+            log::debug!(
+                "[NLME] leave request not allowed (rejoin: {})",
+                options.rejoin()
+            );
+            return Ok(None);
+        }
+
+        log::debug!(
+            "[NLME] asked to leave by 0x{:04x} (rejoin: {})",
+            header.source.0,
+            options.rejoin()
+        );
+        if let Err(e) = self
+            .leave_network(options.remove_children(), options.rejoin())
+            .await
+        {
+            log::debug!("[NLME] failed to announce leave: {e}");
+        }
+        if options.rejoin() {
+            let channel = self.channel;
+            let secure = self.nwk_security_enabled();
+            let confirm = self
+                .rejoin(channel..channel + 1, REJOIN_SCAN_DURATION, secure)
+                .await;
+            log::debug!("[NLME] rejoin after leave: {:?}", confirm.status);
+        }
+
+        Ok(Some(NlmeLeaveIndication {
+            device_address: None,
+            rejoin: options.rejoin(),
+        }))
+    }
+
+    /// Forget the neighbor `source` which announced that it left.
+    fn neighbor_left(
+        &mut self,
+        source: ShortAddress,
+        source_ieee: Option<IeeeAddress>,
+        rejoin: bool,
+    ) -> Option<NlmeLeaveIndication> {
+        let was_child = self.nib().neighbor_table().iter().any(|n| {
+            n.network_address == source
+                && (n.relationship == relationship::CHILD
+                    || n.relationship == relationship::UNAUTHENTICATED_CHILD)
+        });
+        self.remove_neighbor(source);
+        if was_child {
+            self.update_beacon_payload();
+        }
+        let device_address = source_ieee.or_else(|| {
+            self.nib()
+                .address_map()
+                .iter()
+                .find(|e| e.network_address == source)
+                .map(|e| e.ieee_address)
+        })?;
+        log::debug!("[NLME] {device_address:?} left the network (rejoin: {rejoin})");
+
+        Some(NlmeLeaveIndication {
+            device_address: Some(device_address),
+            rejoin,
+        })
+    }
+
+    /// Ask the children to leave if requested, announce the leave to the
+    /// neighbors and forget the network (§3.6.1.10.1).
+    async fn leave_network(
+        &mut self,
+        remove_children: bool,
+        rejoin: bool,
+    ) -> Result<(), NetworkError> {
+        if remove_children {
+            let children: Vec<ShortAddress, MAX_NEIGHBOR_TABLE> = self
+                .nib()
+                .neighbor_table()
+                .iter()
+                .filter(|n| {
+                    n.relationship == relationship::CHILD
+                        || n.relationship == relationship::UNAUTHENTICATED_CHILD
+                })
+                .map(|n| n.network_address)
+                .collect();
+            for child in children {
+                let options = LeaveOptions(0).set_request(true);
+                if let Err(e) = self.send_leave(child, options).await {
+                    log::debug!(
+                        "[NLME] failed to send leave request to 0x{:04x}: {e}",
+                        child.0
+                    );
+                }
+            }
+        }
+
+        let options = LeaveOptions(0)
+            .set_rejoin(rejoin)
+            .set_remove_children(remove_children);
+        let result = self
+            .send_leave(ShortAddress(broadcast_address::RX_ON_WHEN_IDLE), options)
+            .await;
+        self.clear_network_state(rejoin);
+        result
+    }
+
+    /// Forget the network this device was part of (§3.6.1.10.2).
+    ///
+    /// With `rejoin` the network address, the network identifiers and the
+    /// keys are kept for a secured rejoin.
+    fn clear_network_state(&mut self, rejoin: bool) {
+        self.set_permit_joining(PermitJoining::Closed);
+        self.routing = routing::Routing::new();
+        self.broadcasts = broadcast::Broadcasts::new();
+        self.frequency_agility = frequency_agility::FrequencyAgility::new();
+        self.clear_indirect();
+        self.poll_control.reset();
+        self.link_status_due = 0;
+        self.keepalive_due = 0;
+        self.end_device_timeout_elapsed_ms = 0;
+        self.pan_id_conflict_holdoff = 0;
+        self.depth = 0;
+
+        let nib = self.nib();
+        nib.set_neighbor_table(StorageVec::new());
+        nib.set_route_table(StorageVec::new());
+        nib.set_route_record_table(StorageVec::new());
+        nib.set_broadcast_transaction_table(StorageVec::new());
+        nib.set_address_map(StorageVec::new());
+        nib.set_parent_information(0);
+        if rejoin {
+            return;
+        }
+
+        nib.set_network_address(0xffff);
+        nib.set_panid(0xffff);
+        nib.set_extended_panid(0);
+        nib.set_update_id(0);
+        nib.set_security_material_set(StorageVec::new());
+        nib.set_active_key_seq_number(0);
+        let aib = self.aib();
+        aib.set_device_key_pair_set(StorageVec::new());
+        aib.set_trust_center_address(IeeeAddress(0xffff_ffff_ffff_ffff));
+        self.mac.set_short_address(ShortAddress(0xffff));
+        self.channel = 0;
+    }
+
+    /// Send a leave command to `destination` (§3.4.4).
+    pub(super) async fn send_leave(
+        &mut self,
+        destination: ShortAddress,
+        options: LeaveOptions,
+    ) -> Result<(), NetworkError> {
+        let secure = self.nwk_security_enabled();
+        let mut header = self.nwk_command_header(destination, 1, secure);
+        header.frame_control = header.frame_control.set_source_ieee_flag(true);
+        header.source_ieee = Some(self.nib().ieee_address());
+        let command = NwkCommand::Leave(Leave {
+            command_options: options,
+        });
+        let len = self.build_nwk_command_frame(header, command)?;
+        let next_hop = if Self::is_broadcast(destination) {
+            ShortAddress(broadcast_address::ALL_DEVICES)
+        } else {
+            destination
+        };
+        self.transmit_nwk_frame(next_hop, len).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byte::TryRead;
+    use zigbee_mac::mlme::MacError;
+    use zigbee_mac::mlme::MacIndication;
+    use zigbee_mac::mlme::ScanType;
+
+    use super::*;
+    use crate::nwk::nib::DeviceType;
+    use crate::nwk::nlme::NwkIndication;
+    use crate::nwk::nlme::tests::MockMlme;
+    use crate::nwk::nlme::tests::OWN;
+    use crate::nwk::nlme::tests::PAN_ID;
+    use crate::nwk::nlme::tests::block_on;
+    use crate::nwk::nlme::tests::expect_command_frame;
+    use crate::nwk::nlme::tests::install_network_key;
+    use crate::nwk::nlme::tests::mac_short;
+    use crate::nwk::nlme::tests::make_neighbor;
+    use crate::nwk::nlme::tests::make_nlme;
+
+    const EPID: u64 = 0xDEAD;
+    const PARENT: u16 = NWK_COORDINATOR_ADDRESS;
+    const CHILD: u16 = 0x4444;
+    const CHILD_IEEE: IeeeAddress = IeeeAddress(0x0102_0304_0506_0708);
+
+    fn make_joined(mac: MockMlme, neighbor: u16, rel: u8) -> Nlme<MockMlme> {
+        let mut nlme = make_nlme(mac);
+        nlme.nib().set_network_address(OWN);
+        nlme.nib().set_panid(PAN_ID);
+        nlme.nib().set_extended_panid(EPID);
+        nlme.channel = 15;
+        let mut entry = make_neighbor(PAN_ID, neighbor, 0, 0xff, 0);
+        entry.relationship = rel;
+        if rel == relationship::CHILD {
+            entry.device_type = DeviceType::EndDevice;
+            entry.rx_on_when_idle = true;
+        }
+        let mut table = nlme.nib().neighbor_table();
+        table.push(entry).unwrap();
+        nlme.nib().set_neighbor_table(table);
+        nlme
+    }
+
+    /// A router with an end device child.
+    fn make_parent(mut mac: MockMlme) -> Nlme<MockMlme> {
+        mac.expect_set_beacon_payload().return_const(());
+        let nlme = make_joined(mac, CHILD, relationship::CHILD);
+        let cap = nlme.nib().capability_information().set_device_type(true);
+        nlme.nib().set_capability_information(cap);
+        nlme.update_address_map(CHILD_IEEE, ShortAddress(CHILD));
+        nlme
+    }
+
+    fn parse_leave(payload: &[u8]) -> Option<(NwkHeader<'_>, LeaveOptions)> {
+        let (header, len) = NwkHeader::try_read(payload, ()).ok()?;
+        match NwkCommand::try_read(&payload[len..], ()).ok()?.0 {
+            NwkCommand::Leave(leave) => Some((header, leave.command_options)),
+            _ => None,
+        }
+    }
+
+    fn expect_leave_announcement(mac: &mut MockMlme, rejoin: bool) {
+        mac.expect_transmit_data()
+            .withf(move |dest, payload| {
+                let Some((header, options)) = parse_leave(payload) else {
+                    return false;
+                };
+                *dest == mac_short(broadcast_address::ALL_DEVICES)
+                    && header.destination == ShortAddress(broadcast_address::RX_ON_WHEN_IDLE)
+                    && header.source_ieee.is_some()
+                    && !options.request()
+                    && options.rejoin() == rejoin
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mac.expect_set_association_permit().return_const(());
+    }
+
+    fn expect_leave_frame(
+        nlme: &mut Nlme<MockMlme>,
+        source: u16,
+        destination: u16,
+        options: LeaveOptions,
+    ) {
+        let mut header = nlme.nwk_command_header(ShortAddress(destination), 1, false);
+        header.source = ShortAddress(source);
+        header.frame_control = header.frame_control.set_source_ieee_flag(true);
+        header.source_ieee = Some(CHILD_IEEE);
+        let len = nlme
+            .build_nwk_command_frame(
+                header,
+                NwkCommand::Leave(Leave {
+                    command_options: options,
+                }),
+            )
+            .unwrap();
+        let frame = nlme.buf[..len].to_vec();
+        nlme.mac.expect_receive().times(1).returning(move |buf| {
+            buf[..frame.len()].copy_from_slice(&frame);
+            Ok(MacIndication::Data {
+                source: mac_short(source),
+                destination: mac_short(destination),
+                len: frame.len(),
+                lqi: 0xff,
+            })
+        });
+    }
+
+    fn leave_request(device_address: Option<IeeeAddress>, rejoin: bool) -> NlmeLeaveRequest {
+        NlmeLeaveRequest {
+            device_address,
+            remove_children: false,
+            rejoin,
+        }
+    }
+
+    #[test]
+    fn leave_removes_child() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                let Some((header, options)) = parse_leave(payload) else {
+                    return false;
+                };
+                *dest == mac_short(CHILD)
+                    && header.destination == ShortAddress(CHILD)
+                    && options.request()
+                    && !options.rejoin()
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_parent(mac);
+
+        let confirm = block_on(nlme.leave(leave_request(Some(CHILD_IEEE), false)));
+
+        assert_eq!(confirm.status, NwkStatus::Success);
+        assert!(nlme.nib().neighbor_table().is_empty());
+    }
+
+    #[test]
+    fn leave_unknown_child() {
+        let mut nlme = make_parent(MockMlme::new());
+
+        let confirm = block_on(nlme.leave(leave_request(Some(IeeeAddress(0x42)), false)));
+
+        assert_eq!(confirm.status, NwkStatus::UnknownDevice);
+        assert_eq!(nlme.nib().neighbor_table().len(), 1);
+    }
+
+    #[test]
+    fn leave_rejected_when_not_joined() {
+        let mut nlme = make_nlme(MockMlme::new());
+
+        let confirm = block_on(nlme.leave(leave_request(None, false)));
+
+        assert_eq!(confirm.status, NwkStatus::InvalidRequest);
+    }
+
+    #[test]
+    fn self_leave_clears_network_state() {
+        let mut mac = MockMlme::new();
+        expect_leave_announcement(&mut mac, false);
+        mac.expect_set_short_address()
+            .withf(|address| *address == ShortAddress(0xffff))
+            .times(1)
+            .return_const(());
+        let mut nlme = make_joined(mac, PARENT, relationship::PARENT);
+
+        let confirm = block_on(nlme.leave(leave_request(None, false)));
+
+        assert_eq!(confirm.status, NwkStatus::Success);
+        assert_eq!(nlme.nib().network_address(), 0xffff);
+        assert_eq!(nlme.nib().panid(), 0xffff);
+        assert_eq!(nlme.nib().extended_panid(), 0);
+        assert!(nlme.nib().neighbor_table().is_empty());
+    }
+
+    #[test]
+    fn self_leave_drops_buffered_frames_and_fast_polling() {
+        let mut mac = MockMlme::new();
+        expect_leave_announcement(&mut mac, false);
+        mac.expect_set_short_address().return_const(());
+        let mut seq = mockall::Sequence::new();
+        for pending in [true, false] {
+            mac.expect_set_frame_pending()
+                .withf(move |dest, p| *dest == mac_short(CHILD) && *p == pending)
+                .times(1)
+                .in_sequence(&mut seq)
+                .return_const(());
+        }
+        let mut nlme = make_parent(mac);
+        let mut table = nlme.nib().neighbor_table();
+        table[0].rx_on_when_idle = false;
+        nlme.nib().set_neighbor_table(table);
+        block_on(nlme.send_data(ShortAddress(CHILD), false, b"data")).unwrap();
+        nlme.start_fast_poll();
+
+        block_on(nlme.leave(leave_request(None, false)));
+
+        assert!(!nlme.is_fast_polling());
+    }
+
+    #[test]
+    fn self_leave_with_rejoin_keeps_network() {
+        let mut mac = MockMlme::new();
+        expect_leave_announcement(&mut mac, true);
+        let mut nlme = make_joined(mac, PARENT, relationship::PARENT);
+
+        block_on(nlme.leave(leave_request(None, true)));
+
+        assert_eq!(nlme.nib().network_address(), OWN);
+        assert_eq!(nlme.nib().extended_panid(), EPID);
+        assert!(nlme.nib().neighbor_table().is_empty());
+    }
+
+    #[test]
+    fn leave_request_from_parent() {
+        let mut mac = MockMlme::new();
+        expect_leave_announcement(&mut mac, false);
+        mac.expect_set_short_address().return_const(());
+        let mut nlme = make_joined(mac, PARENT, relationship::PARENT);
+        expect_leave_frame(&mut nlme, PARENT, OWN, LeaveOptions(0).set_request(true));
+
+        let indication = block_on(nlme.receive()).unwrap();
+
+        assert!(matches!(
+            indication,
+            Some(NwkIndication::Leave(NlmeLeaveIndication {
+                device_address: None,
+                rejoin: false,
+            }))
+        ));
+        assert_eq!(nlme.nib().network_address(), 0xffff);
+    }
+
+    #[test]
+    fn secured_leave_request_from_parent() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data().times(1).returning(|_, _| Ok(()));
+        mac.expect_set_association_permit().return_const(());
+        mac.expect_set_short_address().return_const(());
+        let mut nlme = make_joined(mac, PARENT, relationship::PARENT);
+        install_network_key(&nlme);
+        let leave = Leave {
+            command_options: LeaveOptions(0).set_request(true),
+        };
+        expect_command_frame(&mut nlme, PARENT, NwkCommand::Leave(leave), true);
+
+        let indication = block_on(nlme.receive()).unwrap();
+
+        assert!(matches!(indication, Some(NwkIndication::Leave(_))));
+        assert_eq!(nlme.nib().network_address(), 0xffff);
+    }
+
+    #[test]
+    fn unsecured_leave_request_dropped_in_secured_network() {
+        let mut nlme = make_joined(MockMlme::new(), PARENT, relationship::PARENT);
+        install_network_key(&nlme);
+        let mut header = nlme.nwk_command_header(ShortAddress(OWN), 1, false);
+        header.source = ShortAddress(PARENT);
+        let leave = Leave {
+            command_options: LeaveOptions(0).set_request(true),
+        };
+
+        let indication = block_on(nlme.leave_indication(&header, &leave)).unwrap();
+
+        assert!(indication.is_none());
+        assert_eq!(nlme.nib().network_address(), OWN);
+    }
+
+    #[test]
+    fn leave_request_without_rejoin_not_allowed() {
+        let mut nlme = make_joined(MockMlme::new(), PARENT, relationship::PARENT);
+        nlme.nib().set_leave_request_without_rejoin_allowed(false);
+        expect_leave_frame(&mut nlme, PARENT, OWN, LeaveOptions(0).set_request(true));
+
+        assert!(block_on(nlme.receive()).unwrap().is_none());
+        assert_eq!(nlme.nib().network_address(), OWN);
+    }
+
+    #[test]
+    fn leave_request_not_allowed() {
+        let mut nlme = make_joined(MockMlme::new(), PARENT, relationship::PARENT);
+        nlme.nib().set_leave_request_allowed(false);
+        let options = LeaveOptions(0).set_request(true).set_rejoin(true);
+        expect_leave_frame(&mut nlme, PARENT, OWN, options);
+
+        assert!(block_on(nlme.receive()).unwrap().is_none());
+        assert_eq!(nlme.nib().neighbor_table().len(), 1);
+    }
+
+    #[test]
+    fn leave_request_from_other_device_dropped() {
+        let mut nlme = make_joined(MockMlme::new(), PARENT, relationship::PARENT);
+        expect_leave_frame(&mut nlme, 0x2222, OWN, LeaveOptions(0).set_request(true));
+
+        assert!(block_on(nlme.receive()).unwrap().is_none());
+        assert_eq!(nlme.nib().network_address(), OWN);
+    }
+
+    #[test]
+    fn leave_request_with_rejoin_rejoins() {
+        let mut mac = MockMlme::new();
+        expect_leave_announcement(&mut mac, true);
+        mac.expect_scan_network()
+            .withf(|ty, channels, _| *ty == ScanType::Active && *channels == (15..16))
+            .times(1)
+            .returning(|_, _, _| Err(MacError::NoBeacon));
+        let mut nlme = make_joined(mac, PARENT, relationship::PARENT);
+        let options = LeaveOptions(0).set_request(true).set_rejoin(true);
+        expect_leave_frame(&mut nlme, PARENT, OWN, options);
+
+        let indication = block_on(nlme.receive()).unwrap();
+
+        assert!(matches!(
+            indication,
+            Some(NwkIndication::Leave(NlmeLeaveIndication {
+                device_address: None,
+                rejoin: true,
+            }))
+        ));
+        assert_eq!(nlme.nib().extended_panid(), EPID);
+    }
+
+    #[test]
+    fn child_announces_leave() {
+        let mut nlme = make_parent(MockMlme::new());
+        expect_leave_frame(
+            &mut nlme,
+            CHILD,
+            broadcast_address::RX_ON_WHEN_IDLE,
+            LeaveOptions(0),
+        );
+
+        let indication = block_on(nlme.receive()).unwrap();
+
+        assert!(matches!(
+            indication,
+            Some(NwkIndication::Leave(NlmeLeaveIndication {
+                device_address: Some(CHILD_IEEE),
+                rejoin: false,
+            }))
+        ));
+        assert!(nlme.nib().neighbor_table().is_empty());
+    }
+}
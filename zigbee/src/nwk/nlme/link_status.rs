@@ -0,0 +1,427 @@
+//! Link status
+//!
+//! Routers broadcast the cost of the links to their neighboring routers
+//! every nwkLinkStatusPeriod. A link status tells the receiver the cost of
+//! the link in the other direction and that the sender is still around,
+//! routers which fall silent are dropped from the neighbor table (§3.6.3.4).
+
+use heapless::Vec;
+use zigbee_mac::Address;
+use zigbee_mac::mlme::Mlme;
+use zigbee_types::IeeeAddress;
+use zigbee_types::ShortAddress;
+use zigbee_types::TypeArrayRef;
+
+use super::NetworkError;
+use super::Nlme;
+use crate::nwk::frame::command::Command as NwkCommand;
+use crate::nwk::frame::command::link_status::CommandOptions as LinkStatusOptions;
+use crate::nwk::frame::command::link_status::LinkStatus;
+use crate::nwk::frame::command::link_status::LinkStatusEntry;
+use crate::nwk::frame::header::Header as NwkHeader;
+use crate::nwk::nib::DeviceType;
+use crate::nwk::nib::MAX_NEIGHBOR_TABLE;
+use crate::nwk::nib::NwkNeighbor;
+use crate::nwk::nib::broadcast_address;
+use crate::nwk::nib::link_cost_from_lqi;
+use crate::nwk::nib::relationship;
+
+/// MAC payload of a broadcast data frame, aMaxPHYPacketSize less the MAC
+/// header and FCS.
+const MAX_MAC_PAYLOAD: usize = 127 - 9 - 2;
+
+/// NWK header, auxiliary security header with source address, MIC, command
+/// identifier and command options of a link status.
+const LINK_STATUS_OVERHEAD: usize = 8 + 14 + 4 + 2;
+
+/// Entries per link status frame, each entry taking 3 octets.
+const MAX_LINK_STATUS_ENTRIES: usize = (MAX_MAC_PAYLOAD - LINK_STATUS_OVERHEAD) / 3;
+
+/// Whether `neighbor` is a router of this network.
+fn is_router_neighbor(neighbor: &NwkNeighbor) -> bool {
+    !matches!(neighbor.device_type, DeviceType::EndDevice)
+        && matches!(
+            neighbor.relationship,
+            relationship::PARENT | relationship::CHILD | relationship::SIBLING
+        )
+}
+
+impl<M> Nlme<M>
+where
+    M: Mlme,
+{
+    /// Age the neighboring routers and broadcast a link status every
+    /// nwkLinkStatusPeriod seconds.
+    pub(super) async fn link_status_tick(&mut self, elapsed_ms: u32) {
+        let period = self.nib().link_status_period();
+        // a period of 0 disables the link status
+        if !self.is_router() || period == 0 {
+            return;
+        }
+        self.link_status_due = self.link_status_due.saturating_sub(elapsed_ms);
+        if self.link_status_due > 0 {
+            return;
+        }
+        let jitter = self.broadcast_jitter();
+        self.link_status_due = (u32::from(period) * 1000).saturating_sub(jitter);
+
+        self.age_router_neighbors();
+        if let Err(e) = self.send_link_status().await {
+            log::debug!("[NLME] failed to send link status: {e}");
+        }
+    }
+
+    /// Count another link status period for all neighboring routers.
+    ///
+    /// Siblings older than nwkRouterAgeLimit are removed, the outgoing cost
+    /// of an aged parent or child is no longer known.
+    fn age_router_neighbors(&self) {
+        let age_limit = self.nib().router_age_limit();
+        let mut table = self.nib().neighbor_table();
+        table.retain_mut(|n| {
+            if !is_router_neighbor(n) {
+                return true;
+            }
+            n.age = n.age.saturating_add(1);
+            if n.age <= age_limit {
+                return true;
+            }
+            n.outgoing_cost = 0;
+            if n.relationship != relationship::SIBLING {
+                return true;
+            }
+            log::debug!("[NLME] router 0x{:04x} aged out", n.network_address.0);
+            false
+        });
+        self.nib().set_neighbor_table(table);
+    }
+
+    /// Broadcast the link costs to all neighboring routers, split over
+    /// several frames if necessary (§3.6.3.4.1).
+    async fn send_link_status(&mut self) -> Result<(), NetworkError> {
+        let mut entries: Vec<LinkStatusEntry, MAX_NEIGHBOR_TABLE> = self
+            .nib()
+            .neighbor_table()
+            .iter()
+            .filter(|n| is_router_neighbor(n))
+            .map(|n| {
+                LinkStatusEntry::new(
+                    n.network_address,
+                    link_cost_from_lqi(n.lqi),
+                    n.outgoing_cost,
+                )
+            })
+            .collect();
+        if entries.is_empty() {
+            return Ok(());
+        }
+        entries.sort_unstable_by_key(|e| ({ e.neighbor_address }).0);
+
+        let secure = self.nwk_security_enabled();
+        let frames = entries.chunks(MAX_LINK_STATUS_ENTRIES).count();
+        for (idx, chunk) in entries.chunks(MAX_LINK_STATUS_ENTRIES).enumerate() {
+            let command_options = LinkStatusOptions(0)
+                .set_entry_count(u8::try_from(chunk.len()).unwrap_or(0))
+                .set_first_frame(idx == 0)
+                .set_last_frame(idx == frames - 1);
+            let status = LinkStatus {
+                command_options,
+                entries: TypeArrayRef(chunk),
+            };
+            let header =
+                self.nwk_command_header(ShortAddress(broadcast_address::ROUTERS), 1, secure);
+            let len = self.build_nwk_command_frame(header, NwkCommand::LinkStatus(status))?;
+            self.transmit_nwk_frame(ShortAddress(broadcast_address::ALL_DEVICES), len)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Handle a link status received from a neighboring router
+    /// (§3.6.3.4.2).
+    ///
+    /// The neighbor becomes a sibling if it is not known yet, its age is
+    /// reset and the outgoing cost is taken from the entry of this device.
+    pub(super) fn link_status_indication(
+        &self,
+        source: Address,
+        lqi: u8,
+        header: &NwkHeader<'_>,
+        status: &LinkStatus<'_>,
+    ) {
+        let Address::Short(_, sender) = source else {
+            return;
+        };
+        let sender = ShortAddress(sender.0);
+        if !self.is_router() || header.source != sender {
+            return;
+        }
+        let own_address = self.nib().network_address();
+        let outgoing_cost = status
+            .entries
+            .iter()
+            .find(|e| ({ e.neighbor_address }).0 == own_address)
+            .map_or(0, LinkStatusEntry::incoming_cost);
+
+        let mut table = self.nib().neighbor_table();
+        if let Some(neighbor) = table.iter_mut().find(|n| n.network_address == sender) {
+            if matches!(neighbor.device_type, DeviceType::EndDevice) {
+                return;
+            }
+            if neighbor.relationship == relationship::NONE {
+                neighbor.relationship = relationship::SIBLING;
+            }
+            neighbor.lqi = lqi;
+            neighbor.outgoing_cost = outgoing_cost;
+            neighbor.age = 0;
+        } else if table
+            .push(NwkNeighbor {
+                network_address: sender,
+                device_type: DeviceType::Router,
+                rx_on_when_idle: true,
+                end_device_configuration: 0,
+                timeout_counter: 0,
+                device_timeout: 0,
+                relationship: relationship::SIBLING,
+                transmit_failure: 0,
+                lqi,
+                outgoing_cost,
+                age: 0,
+                keepalive_received: false,
+                extended_pan_id: IeeeAddress(self.nib().extended_panid()),
+                logical_channel: self.channel,
+                depth: 0,
+                permit_joining: false,
+                potential_parent: 0,
+                router_capacity: false,
+                end_device_capacity: false,
+                update_id: self.nib().update_id(),
+                pan_id: self.nib().panid(),
+            })
+            .is_err()
+        {
+            log::debug!(
+                "[NLME] neighbor table full, ignoring router 0x{:04x}",
+                sender.0
+            );
+            return;
+        }
+        self.nib().set_neighbor_table(table);
+    }
+
+    /// Cost of the link to the neighbor `neighbor` for a frame received
+    /// with `lqi` (§3.6.3.1).
+    ///
+    /// With nwkSymLink the worse of the incoming and the reported outgoing
+    /// cost is used, as the route is used in both directions.
+    pub(super) fn link_cost(&self, neighbor: ShortAddress, lqi: u8) -> u8 {
+        let incoming_cost = link_cost_from_lqi(lqi);
+        if !self.nib().sym_link() {
+            return incoming_cost;
+        }
+        self.nib()
+            .neighbor_table()
+            .iter()
+            .find(|n| n.network_address == neighbor)
+            .map_or(incoming_cost, |n| incoming_cost.max(n.outgoing_cost))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byte::TryRead;
+
+    use super::*;
+    use crate::nwk::nlme::tests::MockMlme;
+    use crate::nwk::nlme::tests::OWN;
+    use crate::nwk::nlme::tests::PAN_ID;
+    use crate::nwk::nlme::tests::block_on;
+    use crate::nwk::nlme::tests::expect_receive;
+    use crate::nwk::nlme::tests::mac_short;
+    use crate::nwk::nlme::tests::make_neighbor;
+    use crate::nwk::nlme::tests::make_router;
+
+    const NEIGHBOR: u16 = 0x2222;
+    const OTHER_NEIGHBOR: u16 = 0x3333;
+    const CHILD: u16 = 0x4444;
+
+    fn add_neighbor(nlme: &Nlme<MockMlme>, address: u16, device_type: DeviceType, rel: u8) {
+        let mut neighbor = make_neighbor(PAN_ID, address, 0, 0xff, 1);
+        neighbor.device_type = device_type;
+        neighbor.relationship = rel;
+        let mut table = nlme.nib().neighbor_table();
+        table.push(neighbor).unwrap();
+        nlme.nib().set_neighbor_table(table);
+    }
+
+    fn neighbor(nlme: &Nlme<MockMlme>, address: u16) -> Option<(u8, u8, u8)> {
+        nlme.nib()
+            .neighbor_table()
+            .iter()
+            .find(|n| n.network_address == ShortAddress(address))
+            .map(|n| (n.relationship, n.outgoing_cost, n.age))
+    }
+
+    fn link_status_frame(
+        nlme: &mut Nlme<MockMlme>,
+        source: u16,
+        entries: &[LinkStatusEntry],
+    ) -> std::vec::Vec<u8> {
+        let mut header =
+            nlme.nwk_command_header(ShortAddress(broadcast_address::ROUTERS), 1, false);
+        header.source = ShortAddress(source);
+        let status = LinkStatus {
+            command_options: LinkStatusOptions(0)
+                .set_entry_count(u8::try_from(entries.len()).unwrap())
+                .set_first_frame(true)
+                .set_last_frame(true),
+            entries: TypeArrayRef(entries),
+        };
+        let len = nlme
+            .build_nwk_command_frame(header, NwkCommand::LinkStatus(status))
+            .unwrap();
+        nlme.buf[..len].to_vec()
+    }
+
+    #[test]
+    fn router_broadcasts_link_status() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                let (header, len) = NwkHeader::try_read(payload, ()).unwrap();
+                let Ok((NwkCommand::LinkStatus(status), _)) =
+                    NwkCommand::try_read(&payload[len..], ())
+                else {
+                    return false;
+                };
+                let addresses: std::vec::Vec<u16> = status
+                    .entries
+                    .iter()
+                    .map(|e| ({ e.neighbor_address }).0)
+                    .collect();
+                *dest == mac_short(broadcast_address::ALL_DEVICES)
+                    && header.destination == ShortAddress(broadcast_address::ROUTERS)
+                    && header.radius == 1
+                    && status.command_options.first_frame()
+                    && status.command_options.last_frame()
+                    && addresses == [NEIGHBOR, OTHER_NEIGHBOR]
+                    && status.entries[1].incoming_cost() == 1
+                    && status.entries[1].outgoing_cost() == 3
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_router(mac);
+        add_neighbor(
+            &nlme,
+            OTHER_NEIGHBOR,
+            DeviceType::Router,
+            relationship::SIBLING,
+        );
+        add_neighbor(&nlme, NEIGHBOR, DeviceType::Router, relationship::PARENT);
+        add_neighbor(&nlme, CHILD, DeviceType::EndDevice, relationship::CHILD);
+        let mut table = nlme.nib().neighbor_table();
+        table[0].outgoing_cost = 3;
+        nlme.nib().set_neighbor_table(table);
+
+        block_on(nlme.tick(1));
+        // nothing more until the next period
+        block_on(nlme.tick(1000));
+    }
+
+    #[test]
+    fn link_status_updates_outgoing_cost() {
+        let mut nlme = make_router(MockMlme::new());
+        add_neighbor(&nlme, NEIGHBOR, DeviceType::Router, relationship::NONE);
+        let mut table = nlme.nib().neighbor_table();
+        table[0].age = 2;
+        nlme.nib().set_neighbor_table(table);
+        let entries = [
+            LinkStatusEntry::new(ShortAddress(OWN), 3, 1),
+            LinkStatusEntry::new(ShortAddress(OTHER_NEIGHBOR), 1, 1),
+        ];
+        let frame = link_status_frame(&mut nlme, NEIGHBOR, &entries);
+        expect_receive(
+            &mut nlme,
+            NEIGHBOR,
+            broadcast_address::ALL_DEVICES,
+            0xff,
+            frame,
+        );
+
+        assert!(block_on(nlme.receive()).unwrap().is_none());
+
+        assert_eq!(
+            neighbor(&nlme, NEIGHBOR),
+            Some((relationship::SIBLING, 3, 0))
+        );
+    }
+
+    #[test]
+    fn unknown_router_becomes_sibling() {
+        let mut nlme = make_router(MockMlme::new());
+        let frame = link_status_frame(&mut nlme, NEIGHBOR, &[]);
+        expect_receive(
+            &mut nlme,
+            NEIGHBOR,
+            broadcast_address::ALL_DEVICES,
+            0xff,
+            frame,
+        );
+
+        block_on(nlme.receive()).unwrap();
+
+        assert_eq!(
+            neighbor(&nlme, NEIGHBOR),
+            Some((relationship::SIBLING, 0, 0))
+        );
+    }
+
+    #[test]
+    fn silent_routers_age_out() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data().returning(|_, _| Ok(()));
+        let mut nlme = make_router(mac);
+        add_neighbor(&nlme, NEIGHBOR, DeviceType::Router, relationship::PARENT);
+        add_neighbor(
+            &nlme,
+            OTHER_NEIGHBOR,
+            DeviceType::Router,
+            relationship::SIBLING,
+        );
+        let mut table = nlme.nib().neighbor_table();
+        table[0].outgoing_cost = 1;
+        nlme.nib().set_neighbor_table(table);
+        let period = u32::from(nlme.nib().link_status_period()) * 1000;
+        let age_limit = nlme.nib().router_age_limit();
+
+        for _ in 0..age_limit {
+            block_on(nlme.tick(period));
+        }
+        assert_eq!(
+            neighbor(&nlme, OTHER_NEIGHBOR),
+            Some((relationship::SIBLING, 0, age_limit))
+        );
+        block_on(nlme.tick(period));
+
+        assert_eq!(neighbor(&nlme, OTHER_NEIGHBOR), None);
+        // the parent is kept, but the link cost is unknown
+        assert_eq!(
+            neighbor(&nlme, NEIGHBOR),
+            Some((relationship::PARENT, 0, age_limit + 1))
+        );
+    }
+
+    #[test]
+    fn symmetric_link_cost() {
+        let nlme = make_router(MockMlme::new());
+        add_neighbor(&nlme, NEIGHBOR, DeviceType::Router, relationship::SIBLING);
+        let mut table = nlme.nib().neighbor_table();
+        table[0].outgoing_cost = 5;
+        nlme.nib().set_neighbor_table(table);
+
+        assert_eq!(nlme.link_cost(ShortAddress(NEIGHBOR), 0xff), 1);
+        nlme.nib().set_sym_link(true);
+        assert_eq!(nlme.link_cost(ShortAddress(NEIGHBOR), 0xff), 5);
+        assert_eq!(nlme.link_cost(ShortAddress(OTHER_NEIGHBOR), 0xff), 1);
+    }
+}
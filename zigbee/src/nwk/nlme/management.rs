@@ -6,10 +6,12 @@ use alloc::vec::Vec;
 
 use zigbee_mac::BeaconOrder;
 use zigbee_mac::SuperframeOrder;
+use zigbee_mac::mlme::EnergyDetectList;
 use zigbee_mac::mlme::PanDescriptor;
 use zigbee_types::IeeeAddress;
 use zigbee_types::ShortAddress;
 
+use crate::nwk::frame::command::network_status::NetworkStatusCode;
 use crate::nwk::nib::CapabilityInformation;
 
 /// 3.2.2.4 - NLME-NETWORK-DISCOVERY.confirm
@@ -75,9 +77,26 @@ impl From<PanDescriptor> for NetworkDescriptor {
 }
 
 /// 3.2.2.5 - NLME-NETWORK-FORMATION.request
-pub struct NlmeNetworkFormationRequest {}
+///
+/// See Table 3-8 for the full list of parameters.
+pub struct NlmeNetworkFormationRequest {
+    /// Channels to scan for a free channel and PAN identifier.
+    pub scan_channels: core::ops::Range<u8>,
+    /// Time spent scanning each channel (IEEE 802.15.4 scan duration).
+    pub scan_duration: u8,
+    pub beacon_order: BeaconOrder,
+    pub superframe_order: SuperframeOrder,
+    pub battery_life_extension: bool,
+    /// Form a distributed security network without a Trust Center.
+    pub distributed_network: bool,
+    /// Network address to use when `distributed_network` is set.
+    pub distributed_network_address: ShortAddress,
+}
 /// 3.2.2.6 - NLME-NETWORK-FORMATION.confirm
-pub struct NlmeNetworkFormationConfirm {}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NlmeNetworkFormationConfirm {
+    pub status: NwkStatus,
+}
 
 /// 3.2.2.7 - NLME-PERMIT-JOINING.request
 pub struct NlmePermitJoiningRequest {
@@ -89,13 +108,30 @@ pub struct NlmePermitJoiningConfirm {
     pub status: NlmeJoinStatus,
 }
 /// 3.2.2.9 - NLME-START-ROUTER.request
-pub struct NlmeStartRouterRequest {}
+pub struct NlmeStartRouterRequest {
+    pub beacon_order: BeaconOrder,
+    pub superframe_order: SuperframeOrder,
+    pub battery_life_extension: bool,
+}
 /// 3.2.2.10 - NLME-START-ROUTER.confirm
-pub struct NlmeStartRouterConfirm {}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NlmeStartRouterConfirm {
+    pub status: NwkStatus,
+}
 /// 3.2.2.11 - NLME-ED-SCAN.request
-pub struct NlmeEdScanRequest {}
+pub struct NlmeEdScanRequest {
+    /// Channels on which to measure the energy.
+    pub scan_channels: core::ops::Range<u8>,
+    /// Time spent measuring each channel (IEEE 802.15.4 scan duration).
+    pub scan_duration: u8,
+}
 /// 3.2.2.12 - NLME-ED-SCAN.confirm
-pub struct NlmeEdScanConfirm {}
+#[derive(Debug)]
+pub struct NlmeEdScanConfirm {
+    pub status: NwkStatus,
+    /// Peak energy measured on each scanned channel.
+    pub energy_detect_list: EnergyDetectList,
+}
 /// Method used to join or rejoin a network (Table 3-21).
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RejoinNetwork {
@@ -115,17 +151,22 @@ pub enum RejoinNetwork {
 pub struct NlmeJoinRequest {
     pub extended_pan_id: IeeeAddress,
     pub rejoin_network: RejoinNetwork,
+    /// Channels scanned for the parent by an orphan join.
+    pub scan_channels: core::ops::Range<u8>,
+    /// Time spent scanning each channel (IEEE 802.15.4 scan duration).
+    pub scan_duration: u8,
     /// Capability information bitmap (Table 3-62).
     pub capability_information: CapabilityInformation,
     pub security_enabled: bool,
 }
 /// 3.2.2.14 - NLME-JOIN.indication
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct NlmeJoinIndication {
-    pub(crate) network_address: u16,
-    pub(crate) extended_address: u64,
-    pub(crate) capability_information: u8,
-    pub(crate) rejoin_network: RejoinNetwork,
-    pub(crate) secure_rejoin: bool,
+    pub network_address: ShortAddress,
+    pub extended_address: IeeeAddress,
+    pub capability_information: CapabilityInformation,
+    pub rejoin_network: RejoinNetwork,
+    pub secure_rejoin: bool,
 }
 /// 3.2.2.15 - NLME-JOIN.confirm
 #[derive(Debug)]
@@ -160,19 +201,186 @@ pub enum NlmeJoinStatus {
     // TODO: add more from 3.2.2.13.3
 }
 
+/// NWK layer status values (Table 3-75).
+///
+/// Returned by NLME confirm primitives that are not specific to joining.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NwkStatus {
+    /// The request was executed successfully.
+    Success,
+    /// An invalid or out-of-range parameter was passed.
+    InvalidParameter,
+    /// The request cannot be handled in the current state of the device.
+    InvalidRequest,
+    /// The request is not permitted by the NLME.
+    NotPermitted,
+    /// A network could not be started (e.g. no free channel or PAN ID).
+    StartupFailure,
+    /// The device is not in the neighbor table of this device.
+    UnknownDevice,
+    /// The MAC sub-layer failed to execute the request.
+    MacError,
+    /// A route discovery could not be started or a frame could not be
+    /// routed, see the accompanying [`NetworkStatusCode`].
+    RouteError,
+    /// The attribute identifier is not known or not accessible by
+    /// identifier.
+    UnsupportedAttribute,
+    /// The attribute is maintained by the NWK layer and cannot be written.
+    ReadOnly,
+}
+
 /// 3.2.2.16 - NLME-DIRECT-JOIN.request
 pub struct NlmeDirectJoinRequest {}
 /// 3.2.2.17 - NLME-DIRECT-JOIN.confirm
 pub struct NlmeDirectJoinConfirm {}
 
 /// 3.2.2.18 - NLME-LEAVE.request
-pub struct NlmeLeaveRequest {}
+pub struct NlmeLeaveRequest {
+    /// Child to remove, `None` for this device to leave the network.
+    pub device_address: Option<IeeeAddress>,
+    /// Ask the children of the leaving device to leave as well.
+    pub remove_children: bool,
+    /// The leaving device rejoins the network afterwards.
+    pub rejoin: bool,
+}
 /// 3.2.2.19 - NLME-LEAVE.indication
-pub struct NlmeLeaveIndication {}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NlmeLeaveIndication {
+    /// Device which left, `None` if this device was asked to leave.
+    pub device_address: Option<IeeeAddress>,
+    pub rejoin: bool,
+}
 /// 3.2.2.20 - NLME-LEAVE.confirm
-pub struct NlmeLeaveConfirm {}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NlmeLeaveConfirm {
+    pub status: NwkStatus,
+    pub device_address: Option<IeeeAddress>,
+}
+
+/// Value of a NIB or AIB attribute read or written by identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttributeValue {
+    Bool(bool),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+}
+
+impl AttributeValue {
+    pub(crate) const fn as_bool(self) -> Option<bool> {
+        match self {
+            Self::Bool(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub(crate) const fn as_u8(self) -> Option<u8> {
+        match self {
+            Self::U8(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub(crate) const fn as_u16(self) -> Option<u16> {
+        match self {
+            Self::U16(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub(crate) const fn as_u32(self) -> Option<u32> {
+        match self {
+            Self::U32(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub(crate) const fn as_u64(self) -> Option<u64> {
+        match self {
+            Self::U64(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// 3.2.2.27 - NLME-GET.confirm
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NlmeGetConfirm {
+    pub status: NwkStatus,
+    /// Identifier of the attribute, see [`crate::nwk::nib::attribute_id`].
+    pub attribute: u8,
+    /// Value of the attribute if the status is [`NwkStatus::Success`].
+    pub value: Option<AttributeValue>,
+}
+
+/// 3.2.2.28 - NLME-SET.request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NlmeSetRequest {
+    /// Identifier of the attribute, see [`crate::nwk::nib::attribute_id`].
+    pub attribute: u8,
+    pub value: AttributeValue,
+}
+/// 3.2.2.29 - NLME-SET.confirm
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NlmeSetConfirm {
+    pub status: NwkStatus,
+    pub attribute: u8,
+}
+
+/// 3.2.2.30 - NLME-NWK-STATUS.indication
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NlmeNwkStatusIndication {
+    /// Device the status refers to.
+    pub network_address: ShortAddress,
+    pub status: NetworkStatusCode,
+}
+
+/// Addressing mode of an NLME-ROUTE-DISCOVERY.request (Table 3-46).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteDiscoveryAddressMode {
+    /// Many-to-one route discovery from a concentrator (0x00).
+    NoAddress,
+    /// Discovery of a multicast group (0x01).
+    Group,
+    /// Discovery of a route to a single device (0x02).
+    Network,
+}
+
+/// 3.2.2.31 - NLME-ROUTE-DISCOVERY.request
+pub struct NlmeRouteDiscoveryRequest {
+    pub destination_address_mode: RouteDiscoveryAddressMode,
+    pub destination_address: ShortAddress,
+    /// Number of hops the route request travels, 0 for twice nwkMaxDepth.
+    pub radius: u8,
+    /// Only meaningful for many-to-one discovery: the concentrator does not
+    /// store source routes.
+    pub no_route_cache: bool,
+}
+/// 3.2.2.32 - NLME-ROUTE-DISCOVERY.confirm
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NlmeRouteDiscoveryConfirm {
+    pub status: NwkStatus,
+    /// Reason of a [`NwkStatus::RouteError`].
+    pub network_status_code: Option<NetworkStatusCode>,
+}
 
 /// 3.2.2.21 - NLME-RESET.request
 pub struct NlmeResetRequest {}
 /// 3.2.2.22 - NLME-RESET.confirm
 pub struct NlmeResetConfirm {}
+
+/// Interference a device found on the current channel (Annex E), reported
+/// to the network manager with a Mgmt_NWK_Update_notify by the ZDO.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterferenceReport {
+    /// Channels of the energy detect scan, bit `n` for channel `n`.
+    pub scanned_channels: u32,
+    /// Unicast transmissions counted in nwkTxTotal.
+    pub total_transmissions: u16,
+    /// Transmissions of those which failed.
+    pub transmission_failures: u16,
+    /// Energy measured on each scanned channel, in channel order.
+    pub energy_values: heapless::Vec<u8, { zigbee_mac::mlme::MAX_IEEE802154_CHANNELS }>,
+}
@@ -17,50 +17,92 @@ use core::slice;
 
 use byte::BytesExt;
 use byte::TryRead;
+use indirect::MAX_INDIRECT_FRAMES;
 use management::NlmeEdScanConfirm;
 use management::NlmeEdScanRequest;
 use management::NlmeJoinConfirm;
+use management::NlmeJoinIndication;
 use management::NlmeJoinRequest;
 use management::NlmeJoinStatus;
+use management::NlmeLeaveIndication;
 use management::NlmeNetworkDiscoveryConfirm;
 use management::NlmeNetworkFormationConfirm;
 use management::NlmeNetworkFormationRequest;
+use management::NlmeNwkStatusIndication;
 use management::NlmePermitJoiningConfirm;
 use management::NlmePermitJoiningRequest;
 use management::NlmeStartRouterConfirm;
 use management::NlmeStartRouterRequest;
+use management::NwkStatus;
 use management::RejoinNetwork;
 use thiserror::Error;
 use zigbee_mac::Address;
 use zigbee_mac::AssociationStatus;
 use zigbee_mac::MacShortAddress;
 use zigbee_mac::PanId;
+use zigbee_mac::mlme::EnergyDetect;
+use zigbee_mac::mlme::EnergyDetectList;
 use zigbee_mac::mlme::MacError;
+use zigbee_mac::mlme::MacIndication;
 use zigbee_mac::mlme::Mlme;
+use zigbee_mac::mlme::PanDescriptor;
 use zigbee_mac::mlme::ScanType;
+use zigbee_mac::mlme::StackProfile;
+use zigbee_mac::mlme::StartRequest;
+use zigbee_mac::mlme::ZigbeeBeacon;
+use zigbee_types::ByteArray;
 use zigbee_types::IeeeAddress;
 use zigbee_types::ShortAddress;
 use zigbee_types::StorageVec;
 
+use crate::aps::aib::Aib;
+use crate::aps::aib::AibStorage;
+use crate::nwk::frame::CommandFrame as NwkCommandFrame;
 use crate::nwk::frame::DataFrame as NwkDataFrame;
 use crate::nwk::frame::Frame as NwkFrame;
+use crate::nwk::frame::command::Command as NwkCommand;
+use crate::nwk::frame::command::network_status::NetworkStatusCode;
 use crate::nwk::frame::frame_control::DiscoverRoute;
 use crate::nwk::frame::frame_control::FrameControl as NwkFrameControl;
 use crate::nwk::frame::frame_control::FrameType as NwkFrameType;
 use crate::nwk::frame::header::Header as NwkHeader;
+use crate::nwk::frame::header::MulticastControl;
+use crate::nwk::frame::header::SourceRouteSubframe;
 use crate::nwk::nib::CapabilityInformation;
 use crate::nwk::nib::DeviceType;
 use crate::nwk::nib::MAX_PARENT_LINK_COST;
 use crate::nwk::nib::NWK_COORDINATOR_ADDRESS;
+use crate::nwk::nib::NWKC_COORDINATOR_CAPABLE;
+use crate::nwk::nib::NWKC_PROTOCOL_VERSION;
 use crate::nwk::nib::Nib;
 use crate::nwk::nib::NibStorage;
 use crate::nwk::nib::NwkNeighbor;
 use crate::nwk::nib::link_cost_from_lqi;
 use crate::nwk::nib::relationship;
+use crate::nwk::nlde::NldeDataConfirm;
+use crate::nwk::nlde::NldeDataIndication;
+use crate::nwk::nlde::NldeDestination;
 use crate::security::SecurityContext;
 
+mod address_conflict;
+mod association;
+mod attributes;
+mod broadcast;
+mod end_device_timeout;
+mod forwarding;
+mod frequency_agility;
+mod indirect;
+mod leave;
+mod link_status;
 /// Network management entity
 pub mod management;
+mod multicast;
+mod orphan;
+mod pan_id_conflict;
+pub mod poll_control;
+mod rejoin;
+mod routing;
+mod source_routing;
 
 #[derive(Debug, Error)]
 pub enum NetworkError {
@@ -74,6 +116,10 @@ pub enum NetworkError {
     ParseError,
     #[error("invalid frame")]
     InvalidFrame,
+    #[error("no routing capacity")]
+    NoRoutingCapacity,
+    #[error("no indirect capacity")]
+    NoIndirectCapacity,
     #[error("security error: {0}")]
     SecurityError(#[from] crate::security::SecurityError),
 }
@@ -84,6 +130,23 @@ impl From<byte::Error> for NetworkError {
     }
 }
 
+/// Highest measured energy on a channel which is considered acceptable for
+/// forming a network, about 20 dB above the receiver sensitivity.
+const MAX_FORMATION_ENERGY: u8 = 0x80;
+
+/// Indication for the next higher layer, returned by [`Nlme::receive`].
+#[derive(Debug)]
+pub enum NwkIndication {
+    /// A device joined this device as a child (NLME-JOIN.indication).
+    Join(NlmeJoinIndication),
+    /// A network status command was received (NLME-NWK-STATUS.indication).
+    NwkStatus(NlmeNwkStatusIndication),
+    /// This device or a neighbor left the network (NLME-LEAVE.indication).
+    Leave(NlmeLeaveIndication),
+    /// A data frame for this device was received (NLDE-DATA.indication).
+    Data(NldeDataIndication),
+}
+
 /// Network Layer Management Entity (§3.2.2).
 ///
 /// Provides the management service access point (NLME-SAP) that allows
@@ -91,8 +154,48 @@ impl From<byte::Error> for NetworkError {
 /// discovery, formation, joining, rejoining, data transmission, etc.
 pub struct Nlme<M> {
     mac: M,
+    nib: Nib<NibStorage>,
+    /// The AIB is kept next to the NIB as NWK security and multicast need
+    /// both.
+    aib: Aib<AibStorage>,
     nwk_seq: u8,
     buf: [u8; 256],
+    /// State of the pseudo-random generator, see [`Nlme::next_random`].
+    rng: u32,
+    /// Tree depth of this device, 0 for the coordinator.
+    depth: u8,
+    /// Logical channel of the network this device formed or joined.
+    channel: u8,
+    /// Whether this device currently accepts joining devices.
+    permit_joining: PermitJoining,
+    /// Route discovery state, only used by routers.
+    routing: routing::Routing,
+    broadcasts: broadcast::Broadcasts,
+    frequency_agility: frequency_agility::FrequencyAgility,
+    /// Frames buffered for sleepy end device children.
+    indirect: indirect::IndirectQueue,
+    /// Milliseconds until the next link status, see
+    /// [`Nlme::link_status_tick`].
+    link_status_due: u32,
+    /// Milliseconds until the next keepalive of an end device, see
+    /// [`Nlme::keepalive_tick`].
+    keepalive_due: u32,
+    /// Milliseconds not yet counted against the timeouts of the end device
+    /// children.
+    end_device_timeout_elapsed_ms: u32,
+    /// Milliseconds until another PAN identifier conflict is reported.
+    pan_id_conflict_holdoff: u32,
+    /// Data polls of a sleepy end device.
+    poll_control: poll_control::PollControl,
+}
+
+/// Join permission set by NLME-PERMIT-JOINING (§3.6.1.9).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PermitJoining {
+    Closed,
+    Open,
+    /// Open for the remaining number of milliseconds.
+    For(u32),
 }
 
 impl<M> Nlme<M>
@@ -100,11 +203,282 @@ where
     M: Mlme,
 {
     pub fn new(mac: M) -> Self {
+        // seed from the extended address so that devices started at the
+        // same time still pick different PAN IDs and addresses
+        let ext = mac.extended_address().0;
+        let rng = ((ext ^ (ext >> 32)) & 0xffff_ffff) as u32 | 1;
         Self {
             mac,
+            nib: Nib::default(),
+            aib: Aib::default(),
             nwk_seq: 0,
             buf: [0u8; 256],
+            rng,
+            depth: 0,
+            channel: 0,
+            permit_joining: PermitJoining::Closed,
+            routing: routing::Routing::new(),
+            broadcasts: broadcast::Broadcasts::new(),
+            frequency_agility: frequency_agility::FrequencyAgility::new(),
+            indirect: indirect::IndirectQueue::new(),
+            link_status_due: 0,
+            keepalive_due: 0,
+            end_device_timeout_elapsed_ms: 0,
+            pan_id_conflict_holdoff: 0,
+            poll_control: poll_control::PollControl::new(),
+        }
+    }
+
+    /// Advance the NWK layer timers by `elapsed_ms` milliseconds.
+    ///
+    /// Must be called periodically by the next higher layer. Returns the
+    /// NLDE-DATA.confirm of the frames buffered for sleepy children which
+    /// expired.
+    pub async fn tick(
+        &mut self,
+        elapsed_ms: u32,
+    ) -> heapless::Vec<NldeDataConfirm, MAX_INDIRECT_FRAMES> {
+        if let PermitJoining::For(remaining) = self.permit_joining {
+            match remaining.saturating_sub(elapsed_ms) {
+                0 => self.set_permit_joining(PermitJoining::Closed),
+                remaining => self.permit_joining = PermitJoining::For(remaining),
+            }
+        }
+        self.routing_tick(elapsed_ms).await;
+        self.broadcast_tick(elapsed_ms).await;
+        self.link_status_tick(elapsed_ms).await;
+        self.end_device_timeout_tick(elapsed_ms).await;
+        self.keepalive_tick(elapsed_ms).await;
+        self.frequency_agility_tick(elapsed_ms).await;
+        self.pan_id_conflict_tick(elapsed_ms);
+        self.indirect_tick(elapsed_ms).await
+    }
+
+    /// Wait for the next inbound MAC frame and process it.
+    ///
+    /// Returns the resulting indication for the next higher layer, if any.
+    pub async fn receive(&mut self) -> Result<Option<NwkIndication>, NetworkError> {
+        let mut buf = [0u8; 128];
+        match self.mac.receive(&mut buf).await? {
+            MacIndication::Associate {
+                device_address,
+                capability_information,
+            } => Ok(self
+                .associate_indication(device_address, capability_information)
+                .await?
+                .map(NwkIndication::Join)),
+            MacIndication::Orphan { orphan_address } => {
+                self.orphan_indication(orphan_address).await?;
+                Ok(None)
+            }
+            MacIndication::Poll { source } => {
+                self.poll_indication(source);
+                self.indirect_poll_indication(source).await;
+                Ok(None)
+            }
+            MacIndication::Beacon { pan_descriptor } => {
+                self.check_pan_id_conflict(slice::from_ref(&pan_descriptor))
+                    .await;
+                Ok(None)
+            }
+            MacIndication::Data {
+                source, len, lqi, ..
+            } => {
+                let cx = self.security_context();
+                let frame = match cx.decrypt_nwk_frame_in_place(&mut buf[..len]) {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        log::debug!("[NLME] dropping frame from {source:?}: {e}");
+                        return Ok(None);
+                    }
+                };
+                if self.rejects_unsecured(&frame) {
+                    log::debug!("[NLME] dropping unsecured frame from {source:?}");
+                    return Ok(None);
+                }
+                if frame.header().frame_control.security_flag() {
+                    self.authenticate_child(source);
+                }
+                if let Some(indication) = self.frame_address_conflict(&frame).await? {
+                    return Ok(Some(NwkIndication::NwkStatus(indication)));
+                }
+                if self.is_relayed(&frame) {
+                    self.relay_frame(frame).await?;
+                    return Ok(None);
+                }
+                // route requests are relayed by route discovery
+                let is_route_request = matches!(
+                    frame,
+                    NwkFrame::NwkCommand(NwkCommandFrame {
+                        command: NwkCommand::RouteRequest(_),
+                        ..
+                    })
+                );
+                if frame.header().frame_control.multicast_flag() {
+                    if !self.multicast_indication(source, &frame)? {
+                        return Ok(None);
+                    }
+                } else if Self::is_broadcast(frame.header().destination)
+                    && !is_route_request
+                    && !self.broadcast_indication(source, &frame)?
+                {
+                    return Ok(None);
+                }
+                match frame {
+                    NwkFrame::NwkCommand(frame) => {
+                        self.command_indication(source, lqi, frame).await
+                    }
+                    NwkFrame::Data(frame) => {
+                        Ok(self.data_indication(lqi, &frame).map(NwkIndication::Data))
+                    }
+                    NwkFrame::Reserved(_) | NwkFrame::InterPan(_) => {
+                        log::debug!("[NLME] dropping frame from {source:?}");
+                        Ok(None)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Pass a data frame for this device to the next higher layer
+    /// (NLDE-DATA.indication, §3.6.2.3).
+    ///
+    /// Broadcast and multicast frames were filtered before. Unicast frames
+    /// for other devices, which end devices can overhear, are dropped.
+    fn data_indication(&self, lqi: u8, frame: &NwkDataFrame<'_>) -> Option<NldeDataIndication> {
+        let header = &frame.header;
+        let destination = if header.frame_control.multicast_flag() {
+            NldeDestination::Group(header.destination.0)
+        } else if Self::is_broadcast(header.destination)
+            || header.destination.0 == self.nib().network_address()
+        {
+            NldeDestination::Network(header.destination)
+        } else {
+            log::debug!(
+                "[NLME] dropping data frame for 0x{:04x}",
+                header.destination.0
+            );
+            return None;
+        };
+        let Ok(nsdu) = heapless::Vec::from_slice(frame.payload) else {
+            log::debug!("[NLME] dropping oversized data frame");
+            return None;
+        };
+        Some(NldeDataIndication {
+            destination,
+            source: header.source,
+            nsdu,
+            link_quality: lqi,
+            security_use: header.frame_control.security_flag(),
+        })
+    }
+
+    /// Process an NWK command frame addressed to this device.
+    async fn command_indication(
+        &mut self,
+        source: Address,
+        lqi: u8,
+        frame: NwkCommandFrame<'_>,
+    ) -> Result<Option<NwkIndication>, NetworkError> {
+        let NwkCommandFrame { header, command } = frame;
+        match command {
+            NwkCommand::RejoinRequest(request) => {
+                let secure = header.frame_control.security_flag();
+                let (nwk_source, source_ieee) = (header.source, header.source_ieee);
+                Ok(self
+                    .rejoin_indication(nwk_source, source_ieee, secure, request)
+                    .await?
+                    .map(NwkIndication::Join))
+            }
+            NwkCommand::RouteRequest(request) => {
+                self.route_request_indication(source, lqi, &header, request)
+                    .await?;
+                Ok(None)
+            }
+            NwkCommand::RouteReply(reply) => {
+                for (address, ieee_address) in [
+                    (reply.originator_address, reply.originator_ieee_address),
+                    (reply.responder_address, reply.responder_ieee_address),
+                ] {
+                    if let Some(ieee_address) = ieee_address
+                        && let Some(indication) =
+                            self.check_address_conflict(address, ieee_address).await?
+                    {
+                        return Ok(Some(NwkIndication::NwkStatus(indication)));
+                    }
+                }
+                self.route_reply_indication(source, lqi, reply).await?;
+                Ok(None)
+            }
+            NwkCommand::RejoinResponse(response) => Ok(self
+                .rejoin_response_indication(&header, &response)
+                .map(NwkIndication::NwkStatus)),
+            NwkCommand::RouteRecord(record) => {
+                self.route_record_indication(header.source, &record);
+                Ok(None)
+            }
+            NwkCommand::Leave(leave) => Ok(self
+                .leave_indication(&header, &leave)
+                .await?
+                .map(NwkIndication::Leave)),
+            NwkCommand::LinkStatus(status) => {
+                self.link_status_indication(source, lqi, &header, &status);
+                Ok(None)
+            }
+            NwkCommand::EndDeviceTimeoutRequest(request) => {
+                self.end_device_timeout_request_indication(source, &header, &request)
+                    .await?;
+                Ok(None)
+            }
+            NwkCommand::EndDeviceTimeoutResponse(response) => {
+                self.end_device_timeout_response_indication(&response);
+                Ok(None)
+            }
+            NwkCommand::NetworkReport(report) => {
+                self.network_report_indication(&header, &report).await?;
+                Ok(None)
+            }
+            NwkCommand::NetworkUpdate(update) => {
+                self.network_update_indication(&header, &update);
+                Ok(None)
+            }
+            NwkCommand::NetworkStatus(status)
+                if status.status_code == NetworkStatusCode::AddressConflict =>
+            {
+                Ok(self
+                    .address_conflict_indication(status.destination_address)
+                    .await?
+                    .map(NwkIndication::NwkStatus))
+            }
+            NwkCommand::NetworkStatus(status) => Ok(Some(NwkIndication::NwkStatus(
+                self.network_status_indication(&status),
+            ))),
+            _ => {
+                log::debug!("[NLME] dropping command from {source:?}");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Whether this device is the coordinator or a router of a network.
+    fn is_router(&self) -> bool {
+        let network_address = self.nib().network_address();
+        network_address == NWK_COORDINATOR_ADDRESS
+            || (network_address != 0xffff && self.nib().capability_information().device_type())
+    }
+
+    /// Whether this device currently accepts joining devices.
+    pub fn is_joining_permitted(&self) -> bool {
+        self.permit_joining != PermitJoining::Closed
+    }
+
+    fn set_permit_joining(&mut self, permit_joining: PermitJoining) {
+        if permit_joining == PermitJoining::Closed && self.permit_joining != PermitJoining::Closed {
+            log::debug!("[NLME] permit joining closed");
         }
+        self.permit_joining = permit_joining;
+        self.mac
+            .set_association_permit(permit_joining != PermitJoining::Closed);
     }
 
     fn next_nwk_seq(&mut self) -> u8 {
@@ -112,39 +486,55 @@ where
         self.nwk_seq
     }
 
+    /// Pseudo-random number generator (xorshift32) used wherever the
+    /// specification asks for a random value: PAN IDs, stochastic
+    /// addresses and jitter.
+    fn next_random(&mut self) -> u32 {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng = x;
+        x
+    }
+
     /// Build a NWK data frame and write it into `self.buf`.
     ///
     /// When `secure` is true the frame is encrypted with the active
-    /// network key. Returns the total frame length.
+    /// network key. Frames with a `multicast_control` field are addressed
+    /// to the group `destination`. Returns the total frame length.
     fn build_nwk_data_frame(
         &mut self,
         destination: ShortAddress,
         secure: bool,
         payload: &[u8],
+        source_route_subframe: Option<SourceRouteSubframe<'_>>,
+        multicast_control: Option<MulticastControl>,
     ) -> Result<usize, NetworkError> {
-        let nib = self.nib();
         let frame_control = NwkFrameControl(0)
             .set_frame_type(NwkFrameType::Data)
             .set_protocol_version(2)
             .set_discover_route(DiscoverRoute::Suppress)
-            .set_security_flag(secure);
+            .set_security_flag(secure)
+            .set_multicast_flag(multicast_control.is_some())
+            .set_source_flag(source_route_subframe.is_some());
 
         let seq = self.next_nwk_seq();
         let header = NwkHeader {
             frame_control,
             destination,
-            source: ShortAddress(nib.network_address()),
+            source: ShortAddress(self.nib().network_address()),
             radius: 30,
             sequence_number: seq,
             destination_ieee: None,
             source_ieee: None,
-            multicast_control: None,
-            source_route_subframe: None,
+            multicast_control,
+            source_route_subframe,
         };
 
         if secure {
             let nwk_frame = NwkFrame::Data(NwkDataFrame { header, payload });
-            let cx = SecurityContext::get();
+            let cx = SecurityContext::new(&self.nib, &self.aib);
             let len = cx.encrypt_nwk_frame_in_place(nwk_frame, &mut self.buf)?;
             Ok(len)
         } else {
@@ -157,9 +547,116 @@ where
         }
     }
 
-    /// Returns a reference to the global NIB singleton.
-    pub fn nib(&self) -> &'static Nib<NibStorage> {
-        crate::nwk::nib::get_ref()
+    /// Whether NWK frames are secured, i.e. a network key is installed.
+    fn nwk_security_enabled(&self) -> bool {
+        !self.nib().security_material_set().is_empty()
+    }
+
+    /// Whether `frame` is dropped for lacking NWK security.
+    ///
+    /// Once a network key is installed only rejoin requests and responses
+    /// may be sent without NWK security (§4.3.1.2).
+    fn rejects_unsecured(&self, frame: &NwkFrame<'_>) -> bool {
+        self.nwk_security_enabled()
+            && !frame.header().frame_control.security_flag()
+            && !matches!(
+                frame,
+                NwkFrame::NwkCommand(NwkCommandFrame {
+                    command: NwkCommand::RejoinRequest(_) | NwkCommand::RejoinResponse(_),
+                    ..
+                })
+            )
+    }
+
+    /// Frame control of an NWK command frame (§3.4).
+    fn nwk_command_frame_control(secure: bool) -> NwkFrameControl {
+        NwkFrameControl(0)
+            .set_frame_type(NwkFrameType::NwkCommand)
+            .set_protocol_version(NWKC_PROTOCOL_VERSION)
+            .set_discover_route(DiscoverRoute::Suppress)
+            .set_security_flag(secure)
+    }
+
+    /// NWK header for a command frame originated by this device (§3.4).
+    fn nwk_command_header(
+        &mut self,
+        destination: ShortAddress,
+        radius: u8,
+        secure: bool,
+    ) -> NwkHeader<'static> {
+        NwkHeader {
+            frame_control: Self::nwk_command_frame_control(secure),
+            destination,
+            source: ShortAddress(self.nib().network_address()),
+            radius,
+            sequence_number: self.next_nwk_seq(),
+            destination_ieee: None,
+            source_ieee: None,
+            multicast_control: None,
+            source_route_subframe: None,
+        }
+    }
+
+    /// Serialize an NWK command frame into `self.buf`, encrypted with the
+    /// active network key when the security flag of `header` is set.
+    fn build_nwk_command_frame(
+        &mut self,
+        header: NwkHeader<'_>,
+        command: NwkCommand<'_>,
+    ) -> Result<usize, NetworkError> {
+        if header.frame_control.security_flag() {
+            let nwk_frame = NwkFrame::NwkCommand(NwkCommandFrame { header, command });
+            let cx = SecurityContext::new(&self.nib, &self.aib);
+            let len = cx.encrypt_nwk_frame_in_place(nwk_frame, &mut self.buf)?;
+            Ok(len)
+        } else {
+            let offset = &mut 0;
+            self.buf.write_with(offset, header, ())?;
+            self.buf.write_with(offset, command, ())?;
+            Ok(*offset)
+        }
+    }
+
+    /// MAC address of the neighbor `address` on the current PAN.
+    fn mac_address(&self, address: ShortAddress) -> Address {
+        Address::Short(PanId(self.nib().panid()), MacShortAddress(address.0))
+    }
+
+    /// Transmit the first `len` bytes of `self.buf` to the neighbor
+    /// `next_hop`, or as MAC broadcast for 0xffff.
+    ///
+    /// Frames for sleepy end device children are buffered until the child
+    /// polls for them.
+    async fn transmit_nwk_frame(
+        &mut self,
+        next_hop: ShortAddress,
+        len: usize,
+    ) -> Result<(), NetworkError> {
+        if self.is_sleepy_child(next_hop) {
+            return self.queue_indirect(next_hop, len);
+        }
+        let dest = self.mac_address(next_hop);
+        let result = self.mac.transmit_data(dest, &self.buf[..len]).await;
+        if !Self::is_broadcast(next_hop) {
+            self.record_transmission(next_hop, result.is_ok());
+        }
+        result?;
+        Ok(())
+    }
+
+    /// Returns the NIB of this stack.
+    pub fn nib(&self) -> &Nib<NibStorage> {
+        &self.nib
+    }
+
+    /// Returns the AIB of this stack.
+    pub fn aib(&self) -> &Aib<AibStorage> {
+        &self.aib
+    }
+
+    /// Returns the security context over the NIB and AIB of this stack.
+    pub fn security_context(&self) -> SecurityContext<'_> {
+        SecurityContext::new(&self.nib, &self.aib)
     }
 
     /// Select parent candidates from the neighbor table (§3.6.1.4.1.1).
@@ -245,35 +742,68 @@ where
         }
     }
 
-    /// Find the parent's MAC address from the neighbor table.
-    fn parent_address(&self) -> Result<Address, NetworkError> {
-        let table = self.nib().neighbor_table();
-        let parent = table
+    /// Find the parent's network address from the neighbor table.
+    fn parent_network_address(&self) -> Result<ShortAddress, NetworkError> {
+        self.nib()
+            .neighbor_table()
             .iter()
             .find(|n| n.relationship == relationship::PARENT)
-            .ok_or(NetworkError::NotJoined)?;
-        let addr = Address::Short(
-            PanId(self.nib().panid()),
-            MacShortAddress(parent.network_address.0),
-        );
-        Ok(addr)
+            .map(|n| n.network_address)
+            .ok_or(NetworkError::NotJoined)
+    }
+
+    /// Find the parent's MAC address from the neighbor table.
+    fn parent_address(&self) -> Result<Address, NetworkError> {
+        let parent = self.parent_network_address()?;
+        Ok(self.mac_address(parent))
     }
 
+    /// Poll the parent once, returns the received frame and its link
+    /// quality.
     async fn poll_nwk_data_request<'a>(
         &mut self,
         buf: &'a mut [u8],
-    ) -> Result<NwkDataFrame<'a>, NetworkError> {
+    ) -> Result<(NwkFrame<'a>, u8), NetworkError> {
         let coord_addr = self.parent_address()?;
-        let (len, _lqi) = self.mac.poll_data(coord_addr, buf).await?;
+        let result = self.mac.poll_data(coord_addr, buf).await;
+        if matches!(result, Ok(_) | Err(MacError::NoData)) {
+            self.data_poll_sent();
+        }
+        self.poll_control.frame_pending = matches!(result, Ok((_, _, true)));
+        let (len, lqi, _) = result?;
 
-        let cx = SecurityContext::get();
+        let cx = self.security_context();
         let nwk_frame = cx.decrypt_nwk_frame_in_place(&mut buf[..len])?;
-
-        let NwkFrame::Data(data_frame) = nwk_frame else {
+        if self.rejects_unsecured(&nwk_frame) {
             return Err(NetworkError::InvalidFrame);
-        };
+        }
+        // multicast for a group this device is not a member of
+        if let NwkFrame::Data(data_frame) = &nwk_frame
+            && data_frame.header.frame_control.multicast_flag()
+            && !self.is_group_member(data_frame.header.destination.0)
+        {
+            return Err(NetworkError::MacError(MacError::NoData));
+        }
 
-        Ok(data_frame)
+        Ok((nwk_frame, lqi))
+    }
+
+    /// Process a frame polled from the parent like a received one.
+    ///
+    /// Returns the resulting indication for the next higher layer, if any.
+    async fn polled_frame_indication(
+        &mut self,
+        lqi: u8,
+        frame: NwkFrame<'_>,
+    ) -> Result<Option<NwkIndication>, NetworkError> {
+        match frame {
+            NwkFrame::Data(frame) => Ok(self.data_indication(lqi, &frame).map(NwkIndication::Data)),
+            NwkFrame::NwkCommand(frame) => {
+                let parent = self.parent_address()?;
+                self.command_indication(parent, lqi, frame).await
+            }
+            NwkFrame::Reserved(_) | NwkFrame::InterPan(_) => Err(NetworkError::InvalidFrame),
+        }
     }
 
     /// 3.2.2.3
@@ -286,6 +816,8 @@ where
             .mac
             .scan_network(ScanType::Active, channels, duration)
             .await?;
+        self.check_pan_id_conflict(&scan_result.pan_descriptor)
+            .await;
 
         // Populate the neighbor table with mandatory fields (Table 3-63)
         // and optional discovery-time fields (Table 3-64).
@@ -302,6 +834,8 @@ where
                     },
                     rx_on_when_idle: false,
                     end_device_configuration: 0,
+                    timeout_counter: 0,
+                    device_timeout: 0,
                     relationship: relationship::NONE,
                     transmit_failure: 0,
                     lqi: pd.link_quality,
@@ -342,36 +876,279 @@ where
     }
 
     /// 3.2.2.5
-    #[allow(clippy::unused_async)]
+    // §3.6.1.1
     pub async fn network_formation(
-        &self,
-        _request: NlmeNetworkFormationRequest,
+        &mut self,
+        request: NlmeNetworkFormationRequest,
     ) -> NlmeNetworkFormationConfirm {
-        todo!()
+        let confirm = |status| NlmeNetworkFormationConfirm { status };
+
+        // Only a coordinator capable device which is not already part of a
+        // network may form one.
+        if !NWKC_COORDINATOR_CAPABLE || self.nib().network_address() != 0xffff {
+            return confirm(NwkStatus::InvalidRequest);
+        }
+
+        // Measure the energy on the requested channels to avoid noisy ones.
+        // Without a measurement the choice rests on the active scan alone.
+        let energy_detect_list = self
+            .ed_scan(NlmeEdScanRequest {
+                scan_channels: request.scan_channels.clone(),
+                scan_duration: request.scan_duration,
+            })
+            .await
+            .energy_detect_list;
+
+        // Active scan to find the networks already operating on the
+        // requested channels.
+        let pan_descriptors = match self
+            .mac
+            .scan_network(
+                ScanType::Active,
+                request.scan_channels.clone(),
+                request.scan_duration,
+            )
+            .await
+        {
+            Ok(scan_result) => scan_result.pan_descriptor,
+            Err(MacError::NoBeacon) => zigbee_mac::mlme::PanDescriptorList::new(),
+            Err(_) => return confirm(NwkStatus::StartupFailure),
+        };
+
+        let Some(channel) = Self::select_formation_channel(
+            request.scan_channels,
+            &energy_detect_list,
+            &pan_descriptors,
+        ) else {
+            return confirm(NwkStatus::StartupFailure);
+        };
+
+        // nwkExtendedPANId defaults to the extended address of the
+        // coordinator and must not collide with a network already heard.
+        let ieee_address = self.mac.extended_address();
+        let extended_pan_id = match self.nib().extended_panid() {
+            0 => ieee_address.0,
+            epid => epid,
+        };
+        if pan_descriptors
+            .iter()
+            .any(|pd| pd.zigbee_beacon.extended_pan_id.0 == extended_pan_id)
+        {
+            return confirm(NwkStatus::StartupFailure);
+        }
+
+        let pan_id = self.select_pan_id(&pan_descriptors);
+        let network_address = if request.distributed_network {
+            request.distributed_network_address.0
+        } else {
+            NWK_COORDINATOR_ADDRESS
+        };
+
+        let nib = &self.nib;
+        nib.set_ieee_address(ieee_address);
+        nib.set_extended_panid(extended_pan_id);
+        nib.set_update_id(0);
+        self.depth = 0;
+        self.channel = channel;
+
+        let start = StartRequest {
+            pan_id: PanId(pan_id),
+            channel,
+            short_address: ShortAddress(network_address),
+            pan_coordinator: true,
+            beacon_order: request.beacon_order,
+            superframe_order: request.superframe_order,
+            battery_life_extension: request.battery_life_extension,
+            beacon_payload: self.beacon_payload(),
+        };
+        if self.mac.start(start).await.is_err() {
+            nib.set_extended_panid(0);
+            return confirm(NwkStatus::StartupFailure);
+        }
+
+        nib.set_panid(pan_id);
+        nib.set_network_address(network_address);
+        log::debug!(
+            "[NLME] formed network on channel {channel}, PAN ID 0x{pan_id:04x}, EPID 0x{extended_pan_id:016x}"
+        );
+
+        confirm(NwkStatus::Success)
+    }
+
+    /// Pick the channel with the fewest networks heard during the formation
+    /// scan (§3.6.1.1).
+    ///
+    /// Channels measuring more than [`MAX_FORMATION_ENERGY`] are only used
+    /// when no quieter one is available. Remaining ties are resolved in
+    /// favour of the lower energy, then the lower channel.
+    fn select_formation_channel(
+        channels: core::ops::Range<u8>,
+        energy_detect_list: &[EnergyDetect],
+        pan_descriptors: &[PanDescriptor],
+    ) -> Option<u8> {
+        channels
+            .filter(|&c| (c as usize) < zigbee_mac::mlme::MAX_IEEE802154_CHANNELS)
+            .min_by_key(|&c| {
+                let energy = energy_detect_list
+                    .iter()
+                    .find(|ed| ed.channel == c)
+                    .map_or(0, |ed| ed.energy);
+                let networks = pan_descriptors.iter().filter(|pd| pd.channel == c).count();
+                (energy > MAX_FORMATION_ENERGY, networks, energy)
+            })
+    }
+
+    /// Pick a random PAN identifier (≤ 0x3fff) not used by any of the
+    /// networks heard during a scan (§3.6.1.1).
+    fn select_pan_id(&mut self, pan_descriptors: &[PanDescriptor]) -> u16 {
+        loop {
+            let pan_id = (self.next_random() & 0x3fff) as u16;
+            if !pan_descriptors.iter().any(|pd| pd.coord_pan_id.0 == pan_id) {
+                return pan_id;
+            }
+        }
+    }
+
+    /// Build the NWK beacon payload advertised by this device (§3.6.7).
+    fn beacon_payload(&self) -> ZigbeeBeacon {
+        let nib = self.nib();
+        let stack_profile = StackProfile(0)
+            .set_stack_profile(nib.stack_profile())
+            .set_protocol_version(NWKC_PROTOCOL_VERSION)
+            .set_router_capacity(self.router_capacity())
+            .set_device_depth(self.depth)
+            .set_end_device_capacity(self.end_device_capacity());
+
+        ZigbeeBeacon {
+            protocol_id: 0x00,
+            stack_profile,
+            extended_pan_id: IeeeAddress(nib.extended_panid()),
+            tx_offset: ByteArray([0xff; 3]),
+            update_id: nib.update_id(),
+        }
+    }
+
+    /// Advertise the current capacity in outgoing beacons.
+    fn update_beacon_payload(&mut self) {
+        let payload = self.beacon_payload();
+        self.mac.set_beacon_payload(payload);
+    }
+
+    /// Number of children and router children in the neighbor table.
+    fn child_count(&self) -> (usize, usize) {
+        let table = self.nib().neighbor_table();
+        let children = table.iter().filter(|n| {
+            n.relationship == relationship::CHILD
+                || n.relationship == relationship::UNAUTHENTICATED_CHILD
+        });
+        children.fold((0, 0), |(all, routers), n| {
+            (
+                all + 1,
+                routers + usize::from(matches!(n.device_type, DeviceType::Router)),
+            )
+        })
+    }
+
+    /// Whether another router may join as a child of this device.
+    fn router_capacity(&self) -> bool {
+        let (children, routers) = self.child_count();
+        children < self.nib().max_children() as usize && routers < self.nib().max_routers() as usize
+    }
+
+    /// Whether another end device may join as a child of this device.
+    fn end_device_capacity(&self) -> bool {
+        let (children, _) = self.child_count();
+        children < self.nib().max_children() as usize
     }
 
     /// 3.2.2.7
-    // figure 3-39
-    #[allow(clippy::unused_async, clippy::unused_async_trait_impl)]
+    // figure 3-39, §3.6.1.9
+    #[allow(clippy::unused_async)]
     pub async fn permit_joining(
-        &self,
-        _request: NlmePermitJoiningRequest,
+        &mut self,
+        request: NlmePermitJoiningRequest,
     ) -> NlmePermitJoiningConfirm {
+        // Only the coordinator and routers accept joining devices.
+        if !self.is_router() {
+            return NlmePermitJoiningConfirm {
+                status: NlmeJoinStatus::InvalidRequest,
+            };
+        }
+
+        let permit_joining = match request.permit_duration {
+            0x00 => PermitJoining::Closed,
+            0xff => PermitJoining::Open,
+            seconds => PermitJoining::For(u32::from(seconds) * 1000),
+        };
+        log::debug!("[NLME] permit joining: {permit_joining:?}");
+        self.set_permit_joining(permit_joining);
+
         NlmePermitJoiningConfirm {
-            status: NlmeJoinStatus::InvalidRequest,
+            status: NlmeJoinStatus::Success,
         }
     }
 
     /// 3.2.2.9
-    #[allow(clippy::unused_async)]
-    pub async fn start_router(&self, _request: NlmeStartRouterRequest) -> NlmeStartRouterConfirm {
-        todo!()
+    // §3.6.4.1
+    pub async fn start_router(
+        &mut self,
+        request: NlmeStartRouterRequest,
+    ) -> NlmeStartRouterConfirm {
+        let confirm = |status| NlmeStartRouterConfirm { status };
+
+        // Only a router which has joined a network may start a superframe;
+        // the coordinator already did so during formation.
+        let nib = self.nib();
+        let network_address = nib.network_address();
+        if network_address == 0xffff
+            || network_address == NWK_COORDINATOR_ADDRESS
+            || !nib.capability_information().device_type()
+        {
+            return confirm(NwkStatus::InvalidRequest);
+        }
+
+        let start = StartRequest {
+            pan_id: PanId(nib.panid()),
+            channel: self.channel,
+            short_address: ShortAddress(network_address),
+            pan_coordinator: false,
+            beacon_order: request.beacon_order,
+            superframe_order: request.superframe_order,
+            battery_life_extension: request.battery_life_extension,
+            beacon_payload: self.beacon_payload(),
+        };
+        if let Err(e) = self.mac.start(start).await {
+            log::warn!("[NLME] MLME-START failed: {e}");
+            return confirm(NwkStatus::MacError);
+        }
+
+        log::debug!(
+            "[NLME] router started on channel {}, depth {}",
+            self.channel,
+            self.depth
+        );
+        confirm(NwkStatus::Success)
     }
 
     /// 3.2.2.11
-    #[allow(clippy::unused_async)]
-    pub async fn ed_scan(&self, _request: NlmeEdScanRequest) -> NlmeEdScanConfirm {
-        todo!()
+    pub async fn ed_scan(&mut self, request: NlmeEdScanRequest) -> NlmeEdScanConfirm {
+        match self
+            .mac
+            .scan_network(ScanType::Ed, request.scan_channels, request.scan_duration)
+            .await
+        {
+            Ok(scan_result) => NlmeEdScanConfirm {
+                status: NwkStatus::Success,
+                energy_detect_list: scan_result.energy_detect_list,
+            },
+            Err(e) => {
+                log::debug!("[NLME] energy detect scan failed: {e}");
+                NlmeEdScanConfirm {
+                    status: NwkStatus::MacError,
+                    energy_detect_list: EnergyDetectList::new(),
+                }
+            }
+        }
     }
 
     /// 3.2.2.13
@@ -387,11 +1164,12 @@ where
 
         // --- Validate the request (§3.2.2.13.3) ---
 
-        // Only MAC association is handled here.
-        // Orphan (0x01), NWK rejoin (0x02), and channel change (0x03) are not yet
-        // implemented.
-        if request.rejoin_network != RejoinNetwork::Association {
-            return fail(NlmeJoinStatus::InvalidRequest);
+        // Channel change (0x03) is not yet implemented.
+        match request.rejoin_network {
+            RejoinNetwork::Association => {}
+            RejoinNetwork::Orphan => return self.orphan_join(request).await,
+            RejoinNetwork::NwkRejoin => return self.nwk_rejoin(request).await,
+            RejoinNetwork::ChannelChange => return fail(NlmeJoinStatus::InvalidRequest),
         }
 
         // A device already joined must not re-associate (§3.6.1.4.1.1).
@@ -446,36 +1224,7 @@ where
                             self.nib().set_extended_panid(request.extended_pan_id.0);
                             self.nib().set_panid(pan_id.0);
 
-                            // Read parent fields before the clearing loop
-                            // zeroes them (§3.6.1.4.1.1).
-                            let parent_update_id =
-                                self.nib().neighbor_table()[candidate_idx].update_id;
-                            let parent_channel =
-                                self.nib().neighbor_table()[candidate_idx].logical_channel;
-                            self.nib().set_update_id(parent_update_id);
-
-                            // Update the neighbor table: set the relationship
-                            // field to 0x00 (parent) and clear optional
-                            // Table 3-64 fields on all entries (they should
-                            // not be retained after joining).
-                            let mut table = self.nib().neighbor_table();
-                            table[candidate_idx].relationship = relationship::PARENT;
-                            for neighbor in table.iter_mut() {
-                                neighbor.extended_pan_id = IeeeAddress(0);
-                                neighbor.logical_channel = 0;
-                                neighbor.depth = 0;
-                                neighbor.permit_joining = false;
-                                neighbor.potential_parent = 0;
-                                neighbor.router_capacity = false;
-                                neighbor.end_device_capacity = false;
-                                neighbor.update_id = 0;
-                                neighbor.pan_id = 0xffff;
-                            }
-                            // Discard entries not on the chosen network
-                            // (they are no longer relevant).
-                            // TODO: retain only entries belonging to the
-                            // joined network.
-                            self.nib().set_neighbor_table(table);
+                            let parent_channel = self.adopt_parent(candidate_idx);
 
                             return NlmeJoinConfirm {
                                 status: NlmeJoinStatus::Success,
@@ -519,12 +1268,73 @@ where
         fail(last_status)
     }
 
-    /// Convenience wrapper — issues a join with `RejoinNetwork::NwkRejoin`
-    /// (§3.2.2.13).
-    #[allow(clippy::unused_async)]
-    pub async fn rejoin(&mut self) -> NlmeJoinConfirm {
-        // TODO: implement NWK rejoin procedure (§3.6.1.4.2)
-        todo!()
+    /// Record the neighbor at `parent_idx` as parent after a successful join
+    /// and take over its depth, channel and update id (§3.6.1.4.1.1).
+    ///
+    /// Returns the logical channel of the parent.
+    fn adopt_parent(&mut self, parent_idx: usize) -> u8 {
+        let mut table = self.nib().neighbor_table();
+        let parent = &table[parent_idx];
+        self.nib().set_update_id(parent.update_id);
+        self.depth = parent.depth.saturating_add(1);
+        self.channel = parent.logical_channel;
+
+        // Set the relationship to parent and clear the optional Table 3-64
+        // fields on all entries, they are not retained after joining.
+        table[parent_idx].relationship = relationship::PARENT;
+        for neighbor in table.iter_mut() {
+            neighbor.extended_pan_id = IeeeAddress(0);
+            neighbor.logical_channel = 0;
+            neighbor.depth = 0;
+            neighbor.permit_joining = false;
+            neighbor.potential_parent = 0;
+            neighbor.router_capacity = false;
+            neighbor.end_device_capacity = false;
+            neighbor.update_id = 0;
+            neighbor.pan_id = 0xffff;
+        }
+        // TODO: retain only entries belonging to the joined network.
+        self.nib().set_neighbor_table(table);
+
+        self.channel
+    }
+
+    /// Convenience wrapper — discovers the network on `channels` and issues
+    /// a join with `RejoinNetwork::NwkRejoin` (§3.2.2.13).
+    ///
+    /// The extended PAN id and capabilities are taken from the NIB. With
+    /// `secure` unset an unsecured Trust Center rejoin is performed.
+    pub async fn rejoin(
+        &mut self,
+        channels: core::ops::Range<u8>,
+        scan_duration: u8,
+        secure: bool,
+    ) -> NlmeJoinConfirm {
+        let extended_pan_id = IeeeAddress(self.nib().extended_panid());
+        if let Err(e) = self
+            .network_discovery(channels.clone(), scan_duration)
+            .await
+        {
+            log::debug!("[NLME] rejoin discovery failed: {e}");
+            return NlmeJoinConfirm {
+                status: NlmeJoinStatus::NoNetworks,
+                network_address: ShortAddress(0xffff),
+                extended_pan_id,
+                channel: 0,
+                enhanced_beacon_type: false,
+                mac_interface_index: 0u8,
+            };
+        }
+
+        self.join(NlmeJoinRequest {
+            extended_pan_id,
+            rejoin_network: RejoinNetwork::NwkRejoin,
+            scan_channels: channels,
+            scan_duration,
+            capability_information: self.nib().capability_information(),
+            security_enabled: secure,
+        })
+        .await
     }
 
     /// Poll the coordinator for pending data, strip the NWK header, and
@@ -540,9 +1350,15 @@ where
             // &mut buf is still guaranteed within 'a
             let buf = unsafe { slice::from_raw_parts_mut(buf.as_mut_ptr(), buf.len()) };
             match self.poll_nwk_data_request(buf).await {
-                Ok(data_frame) => {
+                Ok((NwkFrame::Data(data_frame), _lqi)) => {
                     return Ok(data_frame);
                 }
+                // e.g. the answer to a keepalive, poll again for data
+                Ok((frame, lqi)) => {
+                    if let Some(indication) = self.polled_frame_indication(lqi, frame).await? {
+                        log::debug!("[NLME] {indication:?}");
+                    }
+                }
                 Err(NetworkError::MacError(MacError::NoData)) => (),
                 Err(e) => return Err(e),
             }
@@ -553,8 +1369,9 @@ where
 
     /// Broadcast an NWK data frame (§3.6.5).
     ///
-    /// Wraps `payload` in a NWK header addressed to `destination` and
-    /// transmits it as a MAC broadcast.
+    /// Wraps `payload` in a NWK header addressed to the broadcast address
+    /// `destination` and transmits it as a MAC broadcast. Routers repeat the
+    /// broadcast until all neighboring routers relayed it.
     ///
     /// When `secure` is true the NWK frame is encrypted with the
     /// active network key.
@@ -564,19 +1381,20 @@ where
         secure: bool,
         payload: &[u8],
     ) -> Result<(), NetworkError> {
-        let panid = self.nib().panid();
-        let total_len = self.build_nwk_data_frame(destination, secure, payload)?;
-        let mac_dest = Address::Short(PanId(panid), MacShortAddress(destination.0));
-        self.mac
-            .transmit_data(mac_dest, &self.buf[..total_len])
-            .await?;
-        Ok(())
+        if !Self::is_broadcast(destination) {
+            return Err(NetworkError::InvalidFrame);
+        }
+        let total_len = self.build_nwk_data_frame(destination, secure, payload, None, None)?;
+        self.originate_broadcast(total_len).await
     }
 
     /// Send an NWK data frame to a specific destination (§3.6.3).
     ///
     /// Wraps `payload` in a NWK header addressed to `destination` and
-    /// transmits it via the parent (for end devices) or directly.
+    /// transmits it via the parent (for end devices), directly to a
+    /// neighbor, along a route or, from a concentrator, along the source
+    /// route of a route record. Routers without a route hold the frame back
+    /// and discover one first.
     ///
     /// When `secure` is true the NWK frame is encrypted with the
     /// active network key.
@@ -586,31 +1404,65 @@ where
         secure: bool,
         payload: &[u8],
     ) -> Result<(), NetworkError> {
-        let total_len = self.build_nwk_data_frame(destination, secure, payload)?;
-        // end devices route via parent
-        let mac_dest = self.parent_address()?;
-        self.mac
-            .transmit_data(mac_dest, &self.buf[..total_len])
-            .await?;
-        Ok(())
+        if !self.is_router() {
+            // end devices route via parent
+            let total_len = self.build_nwk_data_frame(destination, secure, payload, None, None)?;
+            let mac_dest = self.parent_address()?;
+            self.mac
+                .transmit_data(mac_dest, &self.buf[..total_len])
+                .await?;
+            return Ok(());
+        }
+
+        if let Some(next_hop) = self.next_hop(destination) {
+            self.send_route_record_if_required(destination, next_hop)
+                .await;
+            let total_len = self.build_nwk_data_frame(destination, secure, payload, None, None)?;
+            self.transmit_nwk_frame(next_hop, total_len).await
+        } else if let Some(source_route) = self.source_route(destination) {
+            let total_len = self.build_nwk_data_frame(
+                destination,
+                secure,
+                payload,
+                source_route.subframe(),
+                None,
+            )?;
+            self.transmit_nwk_frame(source_route.next_hop(destination), total_len)
+                .await
+        } else {
+            let total_len = self.build_nwk_data_frame(destination, secure, payload, None, None)?;
+            let source = ShortAddress(self.nib().network_address());
+            self.route_data_frame(source, destination, total_len).await
+        }
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use core::future::Future;
 
     use zigbee_mac::AssociationStatus;
+    use zigbee_mac::BeaconOrder;
+    use zigbee_mac::SuperframeOrder;
+    use zigbee_mac::SuperframeSpecification;
     use zigbee_mac::mlme::AssociationResponse;
     use zigbee_mac::mlme::MacError;
+    use zigbee_mac::mlme::MacIndication;
     use zigbee_mac::mlme::ScanResult;
     use zigbee_mac::mlme::ScanType;
 
     use super::*;
-    use crate::nwk::nib::NibStorage;
-
-    // tests share a global NIB singleton — serialize access
-    static TEST_MUTEX: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    use crate::nwk::frame::command::leave::CommandOptions as LeaveOptions;
+    use crate::nwk::frame::command::leave::Leave;
+    use crate::nwk::frame::command::network_update::NetworkUpdate;
+
+    const TEST_EXTENDED_ADDRESS: u64 = 0x0011_2233_4455_6677;
+    /// PAN ID of the network the test devices are joined to.
+    pub(crate) const PAN_ID: u16 = 0x1234;
+    /// Network address of the router built by [`make_router`].
+    pub(crate) const OWN: u16 = 0x1111;
+    /// Network address of the network manager built by [`make_device`].
+    pub(crate) const MANAGER: u16 = NWK_COORDINATOR_ADDRESS;
 
     // -------------------------------------------------------------------
     // Minimal async block_on — the mock futures resolve immediately so a
@@ -618,7 +1470,7 @@ mod tests {
     // -------------------------------------------------------------------
 
     #[allow(clippy::panic)]
-    fn block_on<F: Future>(f: F) -> F::Output {
+    pub(crate) fn block_on<F: Future>(f: F) -> F::Output {
         use core::pin::pin;
         use core::task::Context;
         use core::task::Poll;
@@ -643,7 +1495,7 @@ mod tests {
     }
 
     mockall::mock! {
-        Mlme {}
+        pub(crate) Mlme {}
         impl Mlme for Mlme {
             async fn scan_network(
                 &mut self,
@@ -661,12 +1513,39 @@ mod tests {
                 &mut self,
                 coord_address: Address,
                 buf: &mut [u8],
-            ) -> Result<(usize, u8), MacError>;
+            ) -> Result<(usize, u8, bool), MacError>;
             async fn transmit_data(
                 &mut self,
                 dest: Address,
                 payload: &[u8],
             ) -> Result<(), MacError>;
+            async fn transmit_indirect(
+                &mut self,
+                dest: Address,
+                payload: &[u8],
+                frame_pending: bool,
+            ) -> Result<(), MacError>;
+            fn set_frame_pending(&mut self, dest: Address, pending: bool);
+            async fn start(&mut self, request: StartRequest) -> Result<(), MacError>;
+            fn set_association_permit(&mut self, permit: bool);
+            fn set_channel(&mut self, channel: u8);
+            fn set_pan_id(&mut self, pan_id: PanId);
+            fn set_short_address(&mut self, short_address: ShortAddress);
+            fn set_beacon_payload(&mut self, payload: ZigbeeBeacon);
+            async fn receive(&mut self, buf: &mut [u8]) -> Result<MacIndication, MacError>;
+            async fn associate_response(
+                &mut self,
+                device_address: IeeeAddress,
+                association_address: ShortAddress,
+                status: AssociationStatus,
+            ) -> Result<(), MacError>;
+            async fn orphan_response(
+                &mut self,
+                orphan_address: IeeeAddress,
+                short_address: ShortAddress,
+                associated_member: bool,
+            ) -> Result<(), MacError>;
+            fn extended_address(&self) -> IeeeAddress;
         }
     }
 
@@ -675,7 +1554,13 @@ mod tests {
     // -------------------------------------------------------------------
 
     /// Create a default `NwkNeighbor` pre-filled for parent selection.
-    fn make_neighbor(pan_id: u16, short_addr: u16, epid: u64, lqi: u8, depth: u8) -> NwkNeighbor {
+    pub(crate) fn make_neighbor(
+        pan_id: u16,
+        short_addr: u16,
+        epid: u64,
+        lqi: u8,
+        depth: u8,
+    ) -> NwkNeighbor {
         NwkNeighbor {
             network_address: ShortAddress(short_addr),
             device_type: if short_addr == 0 {
@@ -685,6 +1570,8 @@ mod tests {
             },
             rx_on_when_idle: false,
             end_device_configuration: 0,
+            timeout_counter: 0,
+            device_timeout: 0,
             relationship: 0x03,
             transmit_failure: 0,
             lqi,
@@ -703,37 +1590,184 @@ mod tests {
         }
     }
 
-    fn make_nlme(mac: MockMlme) -> (std::sync::MutexGuard<'static, ()>, Nlme<MockMlme>) {
-        let guard = TEST_MUTEX.lock().unwrap_or_else(|e| e.into_inner());
-        use crate::nwk::nib;
-        nib::try_init(NibStorage::default());
-        nib::reset();
-        (guard, Nlme::new(mac))
+    pub(crate) fn make_nlme(mut mac: MockMlme) -> Nlme<MockMlme> {
+        mac.expect_extended_address()
+            .return_const(IeeeAddress(TEST_EXTENDED_ADDRESS));
+        Nlme::new(mac)
+    }
+
+    /// A router joined to [`PAN_ID`] as [`OWN`], without neighbors.
+    pub(crate) fn make_router(mac: MockMlme) -> Nlme<MockMlme> {
+        let nlme = make_nlme(mac);
+        nlme.nib().set_network_address(OWN);
+        nlme.nib().set_panid(PAN_ID);
+        let cap = nlme.nib().capability_information().set_device_type(true);
+        nlme.nib().set_capability_information(cap);
+        nlme
+    }
+
+    /// A router on `channel` of the network `epid`, either [`OWN`] or the
+    /// network manager [`MANAGER`], with the other one as sibling.
+    pub(crate) fn make_device(
+        mac: MockMlme,
+        address: u16,
+        epid: u64,
+        channel: u8,
+    ) -> Nlme<MockMlme> {
+        let mut nlme = make_router(mac);
+        nlme.nib().set_network_address(address);
+        nlme.nib().set_extended_panid(epid);
+        nlme.channel = channel;
+        let other = if address == MANAGER { OWN } else { MANAGER };
+        let mut neighbor = make_neighbor(PAN_ID, other, epid, 0xff, 1);
+        neighbor.relationship = relationship::SIBLING;
+        let mut table = nlme.nib().neighbor_table();
+        table.push(neighbor).unwrap();
+        nlme.nib().set_neighbor_table(table);
+        nlme
+    }
+
+    /// An end device joined to [`PAN_ID`] as [`OWN`], with the coordinator
+    /// as parent.
+    pub(crate) fn make_end_device(mac: MockMlme) -> Nlme<MockMlme> {
+        let nlme = make_nlme(mac);
+        nlme.nib().set_network_address(OWN);
+        nlme.nib().set_panid(PAN_ID);
+        let mut parent = make_neighbor(PAN_ID, NWK_COORDINATOR_ADDRESS, 0, 0xff, 0);
+        parent.relationship = relationship::PARENT;
+        let mut table = nlme.nib().neighbor_table();
+        table.push(parent).unwrap();
+        nlme.nib().set_neighbor_table(table);
+        nlme
+    }
+
+    /// MAC address of the device with network address `address` in
+    /// [`PAN_ID`].
+    pub(crate) fn mac_short(address: u16) -> Address {
+        Address::Short(PanId(PAN_ID), MacShortAddress(address))
+    }
+
+    /// Install a network key, securing the NWK frames of `nlme`.
+    pub(crate) fn install_network_key(nlme: &Nlme<MockMlme>) {
+        nlme.security_context()
+            .install_network_key(0, ByteArray([0xab; 16]), 0x01);
+    }
+
+    /// Header of an NWK command frame from `source` to `destination`.
+    pub(crate) fn command_header(
+        source: u16,
+        destination: u16,
+        secure: bool,
+    ) -> NwkHeader<'static> {
+        NwkHeader {
+            frame_control: Nlme::<MockMlme>::nwk_command_frame_control(secure),
+            destination: ShortAddress(destination),
+            source: ShortAddress(source),
+            radius: 1,
+            sequence_number: 1,
+            destination_ieee: None,
+            source_ieee: None,
+            multicast_control: None,
+            source_route_subframe: None,
+        }
+    }
+
+    /// Serialize an NWK command frame from `source` to `destination`.
+    pub(crate) fn command_frame(
+        nlme: &mut Nlme<MockMlme>,
+        source: u16,
+        destination: u16,
+        source_ieee: Option<IeeeAddress>,
+        secure: bool,
+        command: NwkCommand<'_>,
+    ) -> std::vec::Vec<u8> {
+        let mut header = nlme.nwk_command_header(ShortAddress(destination), 1, secure);
+        header.source = ShortAddress(source);
+        if let Some(source_ieee) = source_ieee {
+            header.frame_control = header.frame_control.set_source_ieee_flag(true);
+            header.source_ieee = Some(source_ieee);
+        }
+        let len = nlme.build_nwk_command_frame(header, command).unwrap();
+        nlme.buf[..len].to_vec()
+    }
+
+    /// Parse the NWK header and command of a transmitted command frame.
+    pub(crate) fn parse_command(payload: &[u8]) -> (NwkHeader<'_>, NwkCommand<'_>) {
+        let (header, len) = NwkHeader::try_read(payload, ()).unwrap();
+        let (command, _) = NwkCommand::try_read(&payload[len..], ()).unwrap();
+        (header, command)
+    }
+
+    /// Expect `frame` from the neighbor `source` to the MAC address
+    /// `destination` to be received next.
+    pub(crate) fn expect_receive(
+        nlme: &mut Nlme<MockMlme>,
+        source: u16,
+        destination: u16,
+        lqi: u8,
+        frame: std::vec::Vec<u8>,
+    ) {
+        nlme.mac.expect_receive().times(1).returning(move |buf| {
+            buf[..frame.len()].copy_from_slice(&frame);
+            Ok(MacIndication::Data {
+                source: mac_short(source),
+                destination: mac_short(destination),
+                len: frame.len(),
+                lqi,
+            })
+        });
+    }
+
+    /// Expect an NWK command frame from `source` to this device to be
+    /// received next.
+    pub(crate) fn expect_command_frame(
+        nlme: &mut Nlme<MockMlme>,
+        source: u16,
+        command: NwkCommand<'_>,
+        secure: bool,
+    ) {
+        let destination = nlme.nib().network_address();
+        let frame = command_frame(nlme, source, destination, None, secure, command);
+        expect_receive(nlme, source, destination, 0xff, frame);
     }
 
     fn default_join_request(epid: u64) -> NlmeJoinRequest {
         NlmeJoinRequest {
             extended_pan_id: IeeeAddress(epid),
             rejoin_network: RejoinNetwork::Association,
+            scan_channels: 11..27,
+            scan_duration: 3,
             capability_information: CapabilityInformation(0x80),
             security_enabled: false,
         }
     }
 
+    #[test]
+    fn stacks_have_independent_information_bases() {
+        let first = make_nlme(MockMlme::new());
+        let second = make_nlme(MockMlme::new());
+
+        first.nib().set_network_address(0x1234);
+        first.aib().set_non_member_radius(0x05);
+
+        assert_eq!(second.nib().network_address(), 0xffff);
+        assert_eq!(second.aib().non_member_radius(), 0x02);
+    }
+
     // -------------------------------------------------------------------
     // select_parent_candidates tests
     // -------------------------------------------------------------------
 
     #[test]
     fn select_parent_no_neighbors() {
-        let (_guard, nlme) = make_nlme(MockMlme::new());
+        let nlme = make_nlme(MockMlme::new());
         let candidates = nlme.select_parent_candidates(IeeeAddress(0x1234), false);
         assert!(candidates.is_empty());
     }
 
     #[test]
     fn select_parent_filters_by_extended_pan_id() {
-        let (_guard, nlme) = make_nlme(MockMlme::new());
+        let nlme = make_nlme(MockMlme::new());
 
         let mut table = StorageVec::new();
         // neighbor on the correct network
@@ -753,7 +1787,7 @@ mod tests {
 
     #[test]
     fn select_parent_filters_by_link_cost() {
-        let (_guard, nlme) = make_nlme(MockMlme::new());
+        let nlme = make_nlme(MockMlme::new());
 
         let mut table = StorageVec::new();
         // good LQI => low cost => eligible
@@ -773,7 +1807,7 @@ mod tests {
 
     #[test]
     fn select_parent_filters_by_end_device_capacity() {
-        let (_guard, nlme) = make_nlme(MockMlme::new());
+        let nlme = make_nlme(MockMlme::new());
 
         let mut table = StorageVec::new();
         let mut n = make_neighbor(0xAAAA, 0x0000, 0x1234, 200, 0);
@@ -787,7 +1821,7 @@ mod tests {
 
     #[test]
     fn select_parent_filters_by_router_capacity() {
-        let (_guard, nlme) = make_nlme(MockMlme::new());
+        let nlme = make_nlme(MockMlme::new());
 
         let mut table = StorageVec::new();
         let mut n = make_neighbor(0xAAAA, 0x0000, 0x1234, 200, 0);
@@ -801,7 +1835,7 @@ mod tests {
 
     #[test]
     fn select_parent_sorts_by_depth_for_stack_profile_1() {
-        let (_guard, nlme) = make_nlme(MockMlme::new());
+        let nlme = make_nlme(MockMlme::new());
         nlme.nib().set_stack_profile(1);
 
         let mut table = StorageVec::new();
@@ -826,7 +1860,7 @@ mod tests {
 
     #[test]
     fn select_parent_filters_not_permitting_join() {
-        let (_guard, nlme) = make_nlme(MockMlme::new());
+        let nlme = make_nlme(MockMlme::new());
 
         let mut table = StorageVec::new();
         let mut n = make_neighbor(0xAAAA, 0x0000, 0x1234, 200, 0);
@@ -840,7 +1874,7 @@ mod tests {
 
     #[test]
     fn select_parent_filters_non_potential_parent() {
-        let (_guard, nlme) = make_nlme(MockMlme::new());
+        let nlme = make_nlme(MockMlme::new());
 
         let mut table = StorageVec::new();
         let mut n = make_neighbor(0xAAAA, 0x0000, 0x1234, 200, 0);
@@ -854,7 +1888,7 @@ mod tests {
 
     #[test]
     fn select_parent_prefers_most_recent_update_id() {
-        let (_guard, nlme) = make_nlme(MockMlme::new());
+        let nlme = make_nlme(MockMlme::new());
 
         let mut table = StorageVec::new();
         let mut n1 = make_neighbor(0xAAAA, 0x0000, 0x1234, 200, 0);
@@ -885,7 +1919,7 @@ mod tests {
             })
         });
 
-        let (_guard, mut nlme) = make_nlme(mac);
+        let mut nlme = make_nlme(mac);
 
         let mut table = StorageVec::new();
         table
@@ -920,7 +1954,7 @@ mod tests {
             })
         });
 
-        let (_guard, mut nlme) = make_nlme(mac);
+        let mut nlme = make_nlme(mac);
 
         let mut n = make_neighbor(0xAAAA, 0x0000, 0xDEAD, 200, 0);
         n.update_id = 7;
@@ -936,7 +1970,7 @@ mod tests {
     #[test]
     fn join_fails_when_no_candidates() {
         let mac = MockMlme::new();
-        let (_guard, mut nlme) = make_nlme(mac);
+        let mut nlme = make_nlme(mac);
         nlme.nib().set_neighbor_table(StorageVec::new());
         let confirm = block_on(nlme.join(default_join_request(0xDEAD)));
         assert_eq!(confirm.status, NlmeJoinStatus::NotPermitted);
@@ -945,7 +1979,7 @@ mod tests {
     #[test]
     fn join_fails_when_already_joined() {
         let mac = MockMlme::new();
-        let (_guard, mut nlme) = make_nlme(mac);
+        let mut nlme = make_nlme(mac);
         nlme.nib().set_network_address(0x0001);
 
         let confirm = block_on(nlme.join(default_join_request(0xDEAD)));
@@ -977,7 +2011,7 @@ mod tests {
                 })
             });
 
-        let (_guard, mut nlme) = make_nlme(mac);
+        let mut nlme = make_nlme(mac);
 
         let mut table = StorageVec::new();
         table
@@ -1008,7 +2042,7 @@ mod tests {
             })
         });
 
-        let (_guard, mut nlme) = make_nlme(mac);
+        let mut nlme = make_nlme(mac);
 
         let mut table = StorageVec::new();
         table
@@ -1027,7 +2061,7 @@ mod tests {
         mac.expect_associate()
             .returning(|_, _, _| Err(MacError::NoAck));
 
-        let (_guard, mut nlme) = make_nlme(mac);
+        let mut nlme = make_nlme(mac);
 
         let mut table = StorageVec::new();
         table
@@ -1042,12 +2076,476 @@ mod tests {
     #[test]
     fn join_invalid_rejoin_network() {
         let mac = MockMlme::new();
-        let (_guard, mut nlme) = make_nlme(mac);
+        let mut nlme = make_nlme(mac);
 
         let mut req = default_join_request(0xDEAD);
-        req.rejoin_network = RejoinNetwork::Orphan;
+        req.rejoin_network = RejoinNetwork::ChannelChange;
 
         let confirm = block_on(nlme.join(req));
         assert_eq!(confirm.status, NlmeJoinStatus::InvalidRequest);
     }
+
+    // -------------------------------------------------------------------
+    // network_formation() tests
+    // -------------------------------------------------------------------
+
+    fn default_formation_request() -> NlmeNetworkFormationRequest {
+        NlmeNetworkFormationRequest {
+            scan_channels: 11..14,
+            scan_duration: 3,
+            beacon_order: BeaconOrder::OnDemand,
+            superframe_order: SuperframeOrder::Inactive,
+            battery_life_extension: false,
+            distributed_network: false,
+            distributed_network_address: ShortAddress(0x0000),
+        }
+    }
+
+    pub(crate) fn make_pan_descriptor(channel: u8, pan_id: u16, epid: u64) -> PanDescriptor {
+        let beacon = ZigbeeBeacon {
+            protocol_id: 0,
+            stack_profile: StackProfile(0),
+            extended_pan_id: IeeeAddress(epid),
+            tx_offset: ByteArray([0xff; 3]),
+            update_id: 0,
+        };
+        PanDescriptor::new(
+            channel,
+            Address::Short(PanId(pan_id), MacShortAddress(0x0000)),
+            SuperframeSpecification {
+                beacon_order: BeaconOrder::OnDemand,
+                superframe_order: SuperframeOrder::Inactive,
+                final_cap_slot: 15,
+                battery_life_extension: false,
+                pan_coordinator: true,
+                association_permit: true,
+            },
+            200,
+            false,
+            beacon,
+        )
+    }
+
+    #[test]
+    fn formation_picks_quietest_channel() {
+        let mut mac = MockMlme::new();
+        mac.expect_scan_network().returning(|ty, _, _| {
+            let mut pan_descriptor = zigbee_mac::mlme::PanDescriptorList::new();
+            for (channel, pan_id) in [(11, 0x1111), (11, 0x2222), (12, 0x3333)] {
+                let _ = pan_descriptor.push(make_pan_descriptor(channel, pan_id, 0xBEEF));
+            }
+            Ok(ScanResult {
+                scan_type: ty,
+                pan_descriptor,
+                energy_detect_list: EnergyDetectList::new(),
+                coordinator_realignment: None,
+            })
+        });
+        mac.expect_start()
+            .withf(|req| {
+                req.channel == 13
+                    && req.pan_coordinator
+                    && req.short_address.0 == 0x0000
+                    && req.pan_id.0 <= 0x3fff
+                    && req.beacon_payload.extended_pan_id.0 == TEST_EXTENDED_ADDRESS
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let mut nlme = make_nlme(mac);
+        let confirm = block_on(nlme.network_formation(default_formation_request()));
+
+        assert_eq!(confirm.status, NwkStatus::Success);
+        assert_eq!(nlme.nib().network_address(), 0x0000);
+        assert_eq!(nlme.nib().extended_panid(), TEST_EXTENDED_ADDRESS);
+        assert_eq!(nlme.nib().ieee_address().0, TEST_EXTENDED_ADDRESS);
+        assert!(nlme.nib().panid() <= 0x3fff);
+        assert!(![0x1111, 0x2222, 0x3333].contains(&nlme.nib().panid()));
+    }
+
+    pub(crate) fn energy_detect_list(energies: &[(u8, u8)]) -> EnergyDetectList {
+        let mut list = EnergyDetectList::new();
+        for &(channel, energy) in energies {
+            let _ = list.push(EnergyDetect { channel, energy });
+        }
+        list
+    }
+
+    #[test]
+    fn formation_avoids_noisy_channel() {
+        let mut mac = MockMlme::new();
+        mac.expect_scan_network().returning(|ty, _, _| {
+            let mut pan_descriptor = zigbee_mac::mlme::PanDescriptorList::new();
+            if ty == ScanType::Active {
+                let _ = pan_descriptor.push(make_pan_descriptor(12, 0x1111, 0xBEEF));
+            }
+            Ok(ScanResult {
+                scan_type: ty,
+                pan_descriptor,
+                energy_detect_list: energy_detect_list(&[(11, 0xf0), (12, 0x20), (13, 0x60)]),
+                coordinator_realignment: None,
+            })
+        });
+        mac.expect_start()
+            .withf(|req| req.channel == 13)
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let mut nlme = make_nlme(mac);
+        let confirm = block_on(nlme.network_formation(default_formation_request()));
+
+        assert_eq!(confirm.status, NwkStatus::Success);
+    }
+
+    #[test]
+    fn formation_without_beacons_uses_first_channel() {
+        let mut mac = MockMlme::new();
+        mac.expect_scan_network()
+            .returning(|_, _, _| Err(MacError::NoBeacon));
+        mac.expect_start()
+            .withf(|req| req.channel == 11)
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let mut nlme = make_nlme(mac);
+        nlme.nib().set_extended_panid(0xCAFE);
+        let confirm = block_on(nlme.network_formation(default_formation_request()));
+
+        assert_eq!(confirm.status, NwkStatus::Success);
+        assert_eq!(nlme.nib().extended_panid(), 0xCAFE);
+    }
+
+    #[test]
+    fn formation_fails_on_extended_pan_id_conflict() {
+        let mut mac = MockMlme::new();
+        mac.expect_scan_network().returning(|ty, _, _| {
+            let mut pan_descriptor = zigbee_mac::mlme::PanDescriptorList::new();
+            let _ = pan_descriptor.push(make_pan_descriptor(11, 0x1111, TEST_EXTENDED_ADDRESS));
+            Ok(ScanResult {
+                scan_type: ty,
+                pan_descriptor,
+                energy_detect_list: EnergyDetectList::new(),
+                coordinator_realignment: None,
+            })
+        });
+        mac.expect_start().never();
+
+        let mut nlme = make_nlme(mac);
+        let confirm = block_on(nlme.network_formation(default_formation_request()));
+
+        assert_eq!(confirm.status, NwkStatus::StartupFailure);
+        assert_eq!(nlme.nib().network_address(), 0xffff);
+    }
+
+    #[test]
+    fn formation_fails_when_already_joined() {
+        let mut mac = MockMlme::new();
+        mac.expect_scan_network().never();
+
+        let mut nlme = make_nlme(mac);
+        nlme.nib().set_network_address(0x0001);
+        let confirm = block_on(nlme.network_formation(default_formation_request()));
+
+        assert_eq!(confirm.status, NwkStatus::InvalidRequest);
+    }
+
+    #[test]
+    fn formation_reports_start_failure() {
+        let mut mac = MockMlme::new();
+        mac.expect_scan_network()
+            .returning(|_, _, _| Err(MacError::NoBeacon));
+        mac.expect_start().returning(|_| Err(MacError::NoAck));
+
+        let mut nlme = make_nlme(mac);
+        let confirm = block_on(nlme.network_formation(default_formation_request()));
+
+        assert_eq!(confirm.status, NwkStatus::StartupFailure);
+        assert_eq!(nlme.nib().network_address(), 0xffff);
+        assert_eq!(nlme.nib().extended_panid(), 0);
+    }
+
+    // -------------------------------------------------------------------
+    // ed_scan() tests
+    // -------------------------------------------------------------------
+
+    #[test]
+    fn ed_scan_reports_energy_per_channel() {
+        let mut mac = MockMlme::new();
+        mac.expect_scan_network()
+            .withf(|ty, channels, duration| {
+                *ty == ScanType::Ed && *channels == (11..13) && *duration == 4
+            })
+            .times(1)
+            .returning(|ty, _, _| {
+                Ok(ScanResult {
+                    scan_type: ty,
+                    pan_descriptor: zigbee_mac::mlme::PanDescriptorList::new(),
+                    energy_detect_list: energy_detect_list(&[(11, 0x10), (12, 0xa0)]),
+                    coordinator_realignment: None,
+                })
+            });
+
+        let mut nlme = make_nlme(mac);
+        let confirm = block_on(nlme.ed_scan(NlmeEdScanRequest {
+            scan_channels: 11..13,
+            scan_duration: 4,
+        }));
+
+        assert_eq!(confirm.status, NwkStatus::Success);
+        assert_eq!(
+            confirm.energy_detect_list.as_slice(),
+            &[
+                EnergyDetect {
+                    channel: 11,
+                    energy: 0x10
+                },
+                EnergyDetect {
+                    channel: 12,
+                    energy: 0xa0
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn ed_scan_reports_mac_failure() {
+        let mut mac = MockMlme::new();
+        mac.expect_scan_network()
+            .returning(|_, _, _| Err(MacError::InvalidScanParams));
+
+        let mut nlme = make_nlme(mac);
+        let confirm = block_on(nlme.ed_scan(NlmeEdScanRequest {
+            scan_channels: 11..27,
+            scan_duration: 3,
+        }));
+
+        assert_eq!(confirm.status, NwkStatus::MacError);
+        assert!(confirm.energy_detect_list.is_empty());
+    }
+
+    // -------------------------------------------------------------------
+    // start_router() tests
+    // -------------------------------------------------------------------
+
+    fn default_start_router_request() -> NlmeStartRouterRequest {
+        NlmeStartRouterRequest {
+            beacon_order: BeaconOrder::OnDemand,
+            superframe_order: SuperframeOrder::Inactive,
+            battery_life_extension: false,
+        }
+    }
+
+    #[test]
+    fn start_router_after_join_advertises_depth_and_capacity() {
+        let mut mac = MockMlme::new();
+        mac.expect_associate().returning(|_, _, _| {
+            Ok(AssociationResponse {
+                device_address: IeeeAddress(0),
+                association_address: ShortAddress(0x1234),
+                status: AssociationStatus::Successful,
+            })
+        });
+        mac.expect_start()
+            .withf(|req| {
+                let profile = req.beacon_payload.stack_profile;
+                req.channel == 11
+                    && req.pan_id.0 == 0xAAAA
+                    && req.short_address.0 == 0x1234
+                    && !req.pan_coordinator
+                    && profile.device_depth() == 3
+                    && profile.router_capacity()
+                    && profile.end_device_capacity()
+                    && req.beacon_payload.extended_pan_id.0 == 0xDEAD
+                    && req.beacon_payload.update_id == 4
+            })
+            .times(1)
+            .returning(|_| Ok(()));
+
+        let mut nlme = make_nlme(mac);
+
+        let mut parent = make_neighbor(0xAAAA, 0x0001, 0xDEAD, 200, 2);
+        parent.update_id = 4;
+        let mut table = StorageVec::new();
+        table.push(parent).unwrap();
+        nlme.nib().set_neighbor_table(table);
+
+        let mut request = default_join_request(0xDEAD);
+        request.capability_information = CapabilityInformation(0x82);
+        let confirm = block_on(nlme.join(request));
+        assert_eq!(confirm.status, NlmeJoinStatus::Success);
+
+        let confirm = block_on(nlme.start_router(default_start_router_request()));
+        assert_eq!(confirm.status, NwkStatus::Success);
+    }
+
+    #[test]
+    fn start_router_rejected_when_not_joined() {
+        let mut mac = MockMlme::new();
+        mac.expect_start().never();
+
+        let mut nlme = make_nlme(mac);
+        nlme.nib()
+            .set_capability_information(CapabilityInformation(0x82));
+
+        let confirm = block_on(nlme.start_router(default_start_router_request()));
+        assert_eq!(confirm.status, NwkStatus::InvalidRequest);
+    }
+
+    #[test]
+    fn start_router_rejected_for_end_device() {
+        let mut mac = MockMlme::new();
+        mac.expect_start().never();
+
+        let mut nlme = make_nlme(mac);
+        nlme.nib().set_network_address(0x1234);
+        nlme.nib()
+            .set_capability_information(CapabilityInformation(0x80));
+
+        let confirm = block_on(nlme.start_router(default_start_router_request()));
+        assert_eq!(confirm.status, NwkStatus::InvalidRequest);
+    }
+
+    #[test]
+    fn start_router_reports_mac_failure() {
+        let mut mac = MockMlme::new();
+        mac.expect_start().returning(|_| Err(MacError::NoAck));
+
+        let mut nlme = make_nlme(mac);
+        nlme.nib().set_network_address(0x1234);
+        nlme.nib()
+            .set_capability_information(CapabilityInformation(0x82));
+
+        let confirm = block_on(nlme.start_router(default_start_router_request()));
+        assert_eq!(confirm.status, NwkStatus::MacError);
+    }
+
+    // -------------------------------------------------------------------
+    // permit_joining() tests
+    // -------------------------------------------------------------------
+
+    fn permit_joining_request(permit_duration: u8) -> NlmePermitJoiningRequest {
+        NlmePermitJoiningRequest { permit_duration }
+    }
+
+    #[test]
+    fn permit_joining_counts_down_and_closes() {
+        let mut mac = MockMlme::new();
+        let mut seq = mockall::Sequence::new();
+        mac.expect_set_association_permit()
+            .with(mockall::predicate::eq(true))
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_const(());
+        mac.expect_set_association_permit()
+            .with(mockall::predicate::eq(false))
+            .times(1)
+            .in_sequence(&mut seq)
+            .return_const(());
+
+        let mut nlme = make_nlme(mac);
+        nlme.nib().set_network_address(NWK_COORDINATOR_ADDRESS);
+
+        let confirm = block_on(nlme.permit_joining(permit_joining_request(3)));
+        assert_eq!(confirm.status, NlmeJoinStatus::Success);
+        assert!(nlme.is_joining_permitted());
+
+        block_on(nlme.tick(2_000));
+        assert!(nlme.is_joining_permitted());
+
+        block_on(nlme.tick(1_000));
+        assert!(!nlme.is_joining_permitted());
+
+        // closed stays closed without touching the MAC again
+        block_on(nlme.tick(1_000));
+        assert!(!nlme.is_joining_permitted());
+    }
+
+    #[test]
+    fn permit_joining_0xff_stays_open() {
+        let mut mac = MockMlme::new();
+        mac.expect_set_association_permit()
+            .with(mockall::predicate::eq(true))
+            .times(1)
+            .return_const(());
+
+        let mut nlme = make_nlme(mac);
+        nlme.nib().set_network_address(NWK_COORDINATOR_ADDRESS);
+
+        let confirm = block_on(nlme.permit_joining(permit_joining_request(0xff)));
+        assert_eq!(confirm.status, NlmeJoinStatus::Success);
+
+        block_on(nlme.tick(u32::MAX));
+        assert!(nlme.is_joining_permitted());
+    }
+
+    #[test]
+    fn permit_joining_0x00_closes() {
+        let mut mac = MockMlme::new();
+        mac.expect_set_association_permit().return_const(());
+
+        let mut nlme = make_nlme(mac);
+        nlme.nib().set_network_address(0x1234);
+        nlme.nib()
+            .set_capability_information(CapabilityInformation(0x82));
+
+        block_on(nlme.permit_joining(permit_joining_request(0xff)));
+        let confirm = block_on(nlme.permit_joining(permit_joining_request(0x00)));
+        assert_eq!(confirm.status, NlmeJoinStatus::Success);
+        assert!(!nlme.is_joining_permitted());
+    }
+
+    #[test]
+    fn permit_joining_rejected_on_end_device() {
+        let mut mac = MockMlme::new();
+        mac.expect_set_association_permit().never();
+
+        let mut nlme = make_nlme(mac);
+        nlme.nib().set_network_address(0x1234);
+        nlme.nib()
+            .set_capability_information(CapabilityInformation(0x80));
+
+        let confirm = block_on(nlme.permit_joining(permit_joining_request(60)));
+        assert_eq!(confirm.status, NlmeJoinStatus::InvalidRequest);
+        assert!(!nlme.is_joining_permitted());
+    }
+
+    // 4.3.1.2
+    #[test]
+    fn unsecured_commands_are_ignored_in_secured_network() {
+        let mut nlme = make_router(MockMlme::new());
+        let mut parent = make_neighbor(PAN_ID, NWK_COORDINATOR_ADDRESS, 0, 0xff, 0);
+        parent.relationship = relationship::PARENT;
+        let mut table = nlme.nib().neighbor_table();
+        table.push(parent).unwrap();
+        nlme.nib().set_neighbor_table(table);
+        install_network_key(&nlme);
+        let leave = Leave {
+            command_options: LeaveOptions(0).set_request(true),
+        };
+        let update = NetworkUpdate {
+            update_id: 1,
+            channel: 20,
+            pan_id: PAN_ID,
+            network_address: NWK_COORDINATOR_ADDRESS,
+        };
+        expect_command_frame(
+            &mut nlme,
+            NWK_COORDINATOR_ADDRESS,
+            NwkCommand::Leave(leave),
+            false,
+        );
+        expect_command_frame(
+            &mut nlme,
+            NWK_COORDINATOR_ADDRESS,
+            NwkCommand::NetworkUpdate(update),
+            false,
+        );
+
+        assert!(block_on(nlme.receive()).unwrap().is_none());
+        assert!(block_on(nlme.receive()).unwrap().is_none());
+
+        assert_eq!(nlme.nib().network_address(), OWN);
+        assert_eq!(nlme.nib().update_id(), 0);
+        assert!(nlme.frequency_agility.pending_update.is_none());
+    }
 }
@@ -0,0 +1,327 @@
+//! NWK multicast
+//!
+//! Multicast frames are addressed to a group ID and carry a multicast control
+//! field. Members of the group deliver the frame and relay it in member mode.
+//! Other routers relay it only while the non-member radius allows. Devices
+//! which are not a member of the group send in non-member mode and the first
+//! member on the way turns the frame into a member mode frame (§3.6.6).
+
+use zigbee_mac::Address;
+use zigbee_mac::mlme::Mlme;
+use zigbee_types::ShortAddress;
+
+use super::NetworkError;
+use super::Nlme;
+use crate::nwk::frame::Frame as NwkFrame;
+use crate::nwk::frame::header::MulticastControl;
+use crate::nwk::frame::header::MulticastMode;
+use crate::nwk::nib::broadcast_address;
+
+impl<M> Nlme<M>
+where
+    M: Mlme,
+{
+    /// Whether `group` is in the group ID table of this device.
+    pub(super) fn is_group_member(&self, group: u16) -> bool {
+        self.nib().group_idtable().contains(&group)
+    }
+
+    /// Send an NWK data frame to the group `group` (§3.6.6.1).
+    ///
+    /// Members of the group send in member mode, other devices in
+    /// non-member mode with the non-member radius of the AIB. End devices
+    /// hand the frame to their parent. Without `nwkUseMulticast` the frame is
+    /// broadcast to all routers instead and the group is left to the APS.
+    ///
+    /// When `secure` is true the NWK frame is encrypted with the
+    /// active network key.
+    pub async fn multicast_data(
+        &mut self,
+        group: u16,
+        secure: bool,
+        payload: &[u8],
+    ) -> Result<(), NetworkError> {
+        if !self.nib().use_multicast() {
+            let destination = ShortAddress(broadcast_address::ROUTERS);
+            return self.broadcast_data(destination, secure, payload).await;
+        }
+
+        let mode = if self.is_group_member(group) {
+            MulticastMode::Member
+        } else {
+            MulticastMode::NonMember
+        };
+        let radius = self
+            .aib()
+            .non_member_radius()
+            .min(MulticastControl::INFINITE_NON_MEMBER_RADIUS);
+        let control = MulticastControl::new(mode, radius, radius);
+        let total_len =
+            self.build_nwk_data_frame(ShortAddress(group), secure, payload, None, Some(control))?;
+
+        if self.is_router() {
+            self.originate_broadcast(total_len).await
+        } else {
+            let parent = self.parent_address()?;
+            self.mac
+                .transmit_data(parent, &self.buf[..total_len])
+                .await?;
+            Ok(())
+        }
+    }
+
+    /// Process a multicast `frame` received from the neighbor `source`
+    /// (§3.6.6.2).
+    ///
+    /// Routers relay the frame: group members in member mode with the
+    /// non-member radius reset to its maximum, other routers while the
+    /// non-member radius is not used up. Returns whether the frame is
+    /// delivered to this device.
+    pub(super) fn multicast_indication(
+        &mut self,
+        source: Address,
+        frame: &NwkFrame<'_>,
+    ) -> Result<bool, NetworkError> {
+        let header = frame.header();
+        let (group, control) = (header.destination.0, header.multicast_control);
+        let Some(control) = control else {
+            return Err(NetworkError::InvalidFrame);
+        };
+        if !self.record_broadcast(source, frame) {
+            return Ok(false);
+        }
+
+        let member = self.is_group_member(group);
+        let relayed_control = if member {
+            Some(
+                control
+                    .set_mode(MulticastMode::Member)
+                    .set_non_member_radius(control.max_non_member_radius()),
+            )
+        } else {
+            match control.non_member_radius() {
+                0 => None,
+                MulticastControl::INFINITE_NON_MEMBER_RADIUS => Some(control),
+                radius => Some(control.set_non_member_radius(radius - 1)),
+            }
+        };
+
+        if self.is_router()
+            && let Some(relayed_control) = relayed_control
+        {
+            let mut frame = frame.clone();
+            frame.header_mut().multicast_control = Some(relayed_control);
+            self.relay_broadcast(source, frame)?;
+        }
+        Ok(member)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byte::BytesExt;
+    use byte::TryRead;
+    use zigbee_types::StorageVec;
+
+    use super::*;
+    use crate::nwk::frame::frame_control::FrameControl as NwkFrameControl;
+    use crate::nwk::frame::header::Header as NwkHeader;
+    use crate::nwk::nib::NWKC_MAX_BROADCAST_JITTER;
+    use crate::nwk::nib::octets_to_ms;
+    use crate::nwk::nib::relationship;
+    use crate::nwk::nlde::NldeDestination;
+    use crate::nwk::nlme::NwkIndication;
+    use crate::nwk::nlme::tests::MockMlme;
+    use crate::nwk::nlme::tests::OWN;
+    use crate::nwk::nlme::tests::PAN_ID;
+    use crate::nwk::nlme::tests::block_on;
+    use crate::nwk::nlme::tests::expect_receive;
+    use crate::nwk::nlme::tests::mac_short;
+    use crate::nwk::nlme::tests::make_neighbor;
+    use crate::nwk::nlme::tests::make_nlme;
+    use crate::nwk::nlme::tests::make_router;
+
+    const NEIGHBOR: u16 = 0x2222;
+    const ORIGINATOR: u16 = 0x4444;
+    const GROUP: u16 = 0x0042;
+
+    fn make_group_router(mac: MockMlme, member: bool) -> Nlme<MockMlme> {
+        let mut nlme = make_router(mac);
+        // no link status during the tests
+        nlme.link_status_due = u32::MAX;
+        let mut neighbor = make_neighbor(PAN_ID, NEIGHBOR, 0, 0xff, 1);
+        neighbor.relationship = relationship::SIBLING;
+        let mut table = nlme.nib().neighbor_table();
+        table.push(neighbor).unwrap();
+        nlme.nib().set_neighbor_table(table);
+        if member {
+            join_group(&nlme);
+        }
+        nlme
+    }
+
+    fn join_group(nlme: &Nlme<MockMlme>) {
+        let mut groups = StorageVec::new();
+        groups.push(GROUP).unwrap();
+        nlme.nib().set_group_idtable(groups);
+    }
+
+    fn multicast_frame(control: MulticastControl) -> std::vec::Vec<u8> {
+        let header = NwkHeader {
+            frame_control: NwkFrameControl(0)
+                .set_protocol_version(2)
+                .set_multicast_flag(true),
+            destination: ShortAddress(GROUP),
+            source: ShortAddress(ORIGINATOR),
+            radius: 5,
+            sequence_number: 7,
+            destination_ieee: None,
+            source_ieee: None,
+            multicast_control: Some(control),
+            source_route_subframe: None,
+        };
+        let mut buf = [0u8; 32];
+        let offset = &mut 0;
+        buf.write_with(offset, header, ()).unwrap();
+        buf.write_with(offset, &[1u8, 2, 3][..], ()).unwrap();
+        buf[..*offset].to_vec()
+    }
+
+    fn expect_multicast(mac: &mut MockMlme, dest: Address, control: MulticastControl) {
+        mac.expect_transmit_data()
+            .withf(move |d, payload| {
+                let (header, _) = NwkHeader::try_read(payload, ()).unwrap();
+                *d == dest
+                    && header.frame_control.multicast_flag()
+                    && header.destination == ShortAddress(GROUP)
+                    && header.multicast_control == Some(control)
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+    }
+
+    /// Receive `control` from a neighbor and let the relay jitter pass.
+    fn receive_multicast(nlme: &mut Nlme<MockMlme>, control: MulticastControl) -> bool {
+        let frame = multicast_frame(control);
+        let (header, len) = NwkHeader::try_read(&frame, ()).unwrap();
+        let nwk_frame = NwkFrame::Data(crate::nwk::frame::DataFrame {
+            header,
+            payload: &frame[len..],
+        });
+        let delivered = nlme
+            .multicast_indication(mac_short(NEIGHBOR), &nwk_frame)
+            .unwrap();
+        block_on(nlme.tick(octets_to_ms(NWKC_MAX_BROADCAST_JITTER)));
+        delivered
+    }
+
+    #[test]
+    fn member_originates_member_mode() {
+        let mut mac = MockMlme::new();
+        expect_multicast(
+            &mut mac,
+            mac_short(broadcast_address::ALL_DEVICES),
+            MulticastControl::new(MulticastMode::Member, 2, 2),
+        );
+        let mut nlme = make_group_router(mac, true);
+
+        block_on(nlme.multicast_data(GROUP, false, &[1, 2, 3])).unwrap();
+    }
+
+    #[test]
+    fn end_device_sends_non_member_mode_to_parent() {
+        let mut mac = MockMlme::new();
+        expect_multicast(
+            &mut mac,
+            mac_short(0x0000),
+            MulticastControl::new(MulticastMode::NonMember, 2, 2),
+        );
+        let mut nlme = make_nlme(mac);
+        nlme.nib().set_network_address(OWN);
+        nlme.nib().set_panid(PAN_ID);
+        let mut parent = make_neighbor(PAN_ID, 0x0000, 0, 0xff, 0);
+        parent.relationship = relationship::PARENT;
+        let mut table = nlme.nib().neighbor_table();
+        table.push(parent).unwrap();
+        nlme.nib().set_neighbor_table(table);
+
+        block_on(nlme.multicast_data(GROUP, false, &[1, 2, 3])).unwrap();
+    }
+
+    #[test]
+    fn member_relays_non_member_mode_as_member_mode() {
+        let mut mac = MockMlme::new();
+        expect_multicast(
+            &mut mac,
+            mac_short(broadcast_address::ALL_DEVICES),
+            MulticastControl::new(MulticastMode::Member, 3, 3),
+        );
+        let mut nlme = make_group_router(mac, true);
+
+        let control = MulticastControl::new(MulticastMode::NonMember, 1, 3);
+        assert!(receive_multicast(&mut nlme, control));
+    }
+
+    #[test]
+    fn non_member_relays_within_non_member_radius() {
+        let mut mac = MockMlme::new();
+        expect_multicast(
+            &mut mac,
+            mac_short(broadcast_address::ALL_DEVICES),
+            MulticastControl::new(MulticastMode::Member, 1, 3),
+        );
+        let mut nlme = make_group_router(mac, false);
+
+        let control = MulticastControl::new(MulticastMode::Member, 2, 3);
+        assert!(!receive_multicast(&mut nlme, control));
+    }
+
+    #[test]
+    fn non_member_drops_at_zero_non_member_radius() {
+        let mut nlme = make_group_router(MockMlme::new(), false);
+
+        let control = MulticastControl::new(MulticastMode::Member, 0, 3);
+        assert!(!receive_multicast(&mut nlme, control));
+    }
+
+    #[test]
+    fn duplicate_multicast_is_not_delivered_twice() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data().returning(|_, _| Ok(()));
+        let mut nlme = make_group_router(mac, true);
+        let control = MulticastControl::new(MulticastMode::Member, 2, 2);
+
+        expect_receive(
+            &mut nlme,
+            NEIGHBOR,
+            broadcast_address::ALL_DEVICES,
+            0xff,
+            multicast_frame(control),
+        );
+        let Some(NwkIndication::Data(indication)) = block_on(nlme.receive()).unwrap() else {
+            unreachable!("expected a data indication");
+        };
+        assert_eq!(indication.destination, NldeDestination::Group(GROUP));
+        assert_eq!(indication.source, ShortAddress(ORIGINATOR));
+        assert_eq!(&indication.nsdu[..], &[1, 2, 3]);
+        assert!(!receive_multicast(&mut nlme, control));
+    }
+
+    #[test]
+    fn multicast_falls_back_to_broadcast() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                let (header, _) = NwkHeader::try_read(payload, ()).unwrap();
+                *dest == mac_short(broadcast_address::ALL_DEVICES)
+                    && !header.frame_control.multicast_flag()
+                    && header.destination == ShortAddress(broadcast_address::ROUTERS)
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_group_router(mac, true);
+        nlme.nib().set_use_multicast(false);
+
+        block_on(nlme.multicast_data(GROUP, false, &[1, 2, 3])).unwrap();
+    }
+}
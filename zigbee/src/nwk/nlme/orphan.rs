@@ -0,0 +1,400 @@
+//! Orphan procedure
+//!
+//! A child which lost contact with its parent finds it again through an
+//! orphan scan, the parent answers with a coordinator realignment
+//! (§3.6.1.4.3).
+
+use zigbee_mac::mlme::MacError;
+use zigbee_mac::mlme::Mlme;
+use zigbee_mac::mlme::ScanType;
+use zigbee_types::IeeeAddress;
+use zigbee_types::ShortAddress;
+
+use super::NetworkError;
+use super::Nlme;
+use super::management::NlmeJoinConfirm;
+use super::management::NlmeJoinRequest;
+use super::management::NlmeJoinStatus;
+use crate::nwk::nib::DeviceType;
+use crate::nwk::nib::NWK_COORDINATOR_ADDRESS;
+use crate::nwk::nib::NwkNeighbor;
+use crate::nwk::nib::relationship;
+
+impl<M> Nlme<M>
+where
+    M: Mlme,
+{
+    /// NLME-JOIN.request with `RejoinNetwork::Orphan` (§3.6.1.4.3.1).
+    ///
+    /// The device rejoins the network it belonged to, a realigning parent
+    /// known to be of another network is rejected. The tree depth is taken
+    /// over from the parent when it is known.
+    pub(super) async fn orphan_join(&mut self, request: NlmeJoinRequest) -> NlmeJoinConfirm {
+        let fail = |status| NlmeJoinConfirm {
+            status,
+            network_address: ShortAddress(0xffff),
+            extended_pan_id: request.extended_pan_id,
+            channel: 0,
+            enhanced_beacon_type: false,
+            mac_interface_index: 0u8,
+        };
+
+        let stored = self.nib().extended_panid();
+        if stored != 0 && stored != request.extended_pan_id.0 {
+            log::debug!("[NLME] orphan rejoin requested for another network");
+            return fail(NlmeJoinStatus::InvalidRequest);
+        }
+
+        let scan_result = match self
+            .mac
+            .scan_network(
+                ScanType::Orphan,
+                request.scan_channels.clone(),
+                request.scan_duration,
+            )
+            .await
+        {
+            Ok(scan_result) => scan_result,
+            Err(MacError::NoBeacon) => return fail(NlmeJoinStatus::NoNetworks),
+            Err(e) => {
+                log::debug!("[NLME] orphan scan failed: {e}");
+                return fail(NlmeJoinStatus::MacError);
+            }
+        };
+        let Some(realignment) = scan_result.coordinator_realignment else {
+            return fail(NlmeJoinStatus::NoNetworks);
+        };
+
+        let network_address = ShortAddress(realignment.device_address.0);
+        let parent_address = ShortAddress(realignment.coordinator_address.0);
+        // the realignment does not carry the extended PAN ID, only a parent
+        // known from a network discovery can be checked
+        let known_parent = self
+            .nib()
+            .neighbor_table()
+            .iter()
+            .find(|n| n.network_address == parent_address && n.extended_pan_id.0 != 0)
+            .map(|n| (n.extended_pan_id, n.depth));
+        if let Some((extended_pan_id, _)) = known_parent
+            && extended_pan_id != request.extended_pan_id
+        {
+            log::debug!(
+                "[NLME] orphan realigned by 0x{:04x} of another network",
+                parent_address.0
+            );
+            return fail(NlmeJoinStatus::NoNetworks);
+        }
+        if parent_address.0 == NWK_COORDINATOR_ADDRESS {
+            self.depth = 1;
+        } else if let Some((_, depth)) = known_parent {
+            self.depth = depth.saturating_add(1);
+        }
+
+        self.nib()
+            .set_capability_information(request.capability_information);
+        self.nib().set_ieee_address(self.mac.extended_address());
+        self.nib().set_network_address(network_address.0);
+        self.nib().set_panid(realignment.pan_id.0);
+        self.nib().set_extended_panid(request.extended_pan_id.0);
+        self.channel = realignment.channel;
+        self.set_parent(parent_address);
+        log::debug!(
+            "[NLME] orphan rejoined via 0x{:04x}, address 0x{:04x}",
+            parent_address.0,
+            network_address.0
+        );
+
+        NlmeJoinConfirm {
+            status: NlmeJoinStatus::Success,
+            network_address,
+            extended_pan_id: request.extended_pan_id,
+            channel: realignment.channel,
+            enhanced_beacon_type: false,
+            mac_interface_index: 0u8,
+        }
+    }
+
+    /// Record `parent_address` as parent in the neighbor table, promoting a
+    /// known neighbor or replacing the address of the known parent.
+    fn set_parent(&self, parent_address: ShortAddress) {
+        let mut table = self.nib().neighbor_table();
+        if let Some(neighbor) = table
+            .iter_mut()
+            .find(|n| n.network_address == parent_address)
+        {
+            neighbor.relationship = relationship::PARENT;
+            table.retain(|n| {
+                n.relationship != relationship::PARENT || n.network_address == parent_address
+            });
+        } else if let Some(parent) = table
+            .iter_mut()
+            .find(|n| n.relationship == relationship::PARENT)
+        {
+            parent.network_address = parent_address;
+        } else {
+            let parent = NwkNeighbor {
+                network_address: parent_address,
+                device_type: if parent_address.0 == NWK_COORDINATOR_ADDRESS {
+                    DeviceType::Coordinator
+                } else {
+                    DeviceType::Router
+                },
+                rx_on_when_idle: true,
+                end_device_configuration: 0,
+                timeout_counter: 0,
+                device_timeout: 0,
+                relationship: relationship::PARENT,
+                transmit_failure: 0,
+                lqi: 0,
+                outgoing_cost: 0,
+                age: 0,
+                keepalive_received: false,
+                extended_pan_id: IeeeAddress(0),
+                logical_channel: 0,
+                depth: 0,
+                permit_joining: false,
+                potential_parent: 0,
+                router_capacity: false,
+                end_device_capacity: false,
+                update_id: 0,
+                pan_id: 0xffff,
+            };
+            if table.push(parent).is_err() {
+                log::warn!("[NLME] neighbor table full, parent not recorded");
+                return;
+            }
+        }
+        self.nib().set_neighbor_table(table);
+    }
+
+    /// Handle an MLME-ORPHAN.indication (§3.6.1.4.3.2).
+    ///
+    /// Known children are realigned with the network address they had,
+    /// notifications of other devices are ignored.
+    pub(super) async fn orphan_indication(
+        &mut self,
+        orphan_address: IeeeAddress,
+    ) -> Result<(), NetworkError> {
+        if !self.is_router() {
+            return Ok(());
+        }
+        let Some(network_address) = self.child_address(orphan_address) else {
+            log::debug!("[NLME] ignoring orphan notification from {orphan_address:?}");
+            return Ok(());
+        };
+
+        log::debug!(
+            "[NLME] realigning orphaned child {orphan_address:?} at 0x{:04x}",
+            network_address.0
+        );
+        self.mac
+            .orphan_response(orphan_address, network_address, true)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use zigbee_mac::CoordinatorRealignmentData;
+    use zigbee_mac::MacShortAddress;
+    use zigbee_mac::PanId;
+    use zigbee_mac::mlme::EnergyDetectList;
+    use zigbee_mac::mlme::MacIndication;
+    use zigbee_mac::mlme::PanDescriptorList;
+    use zigbee_mac::mlme::ScanResult;
+
+    use super::*;
+    use crate::nwk::nib::CapabilityInformation;
+    use crate::nwk::nlme::management::RejoinNetwork;
+    use crate::nwk::nlme::tests::MockMlme;
+    use crate::nwk::nlme::tests::block_on;
+    use crate::nwk::nlme::tests::make_neighbor;
+    use crate::nwk::nlme::tests::make_nlme;
+
+    const EPID: u64 = 0xDEAD;
+    const CHILD: IeeeAddress = IeeeAddress(0x0102_0304_0506_0708);
+
+    fn orphan_request() -> NlmeJoinRequest {
+        NlmeJoinRequest {
+            extended_pan_id: IeeeAddress(EPID),
+            rejoin_network: RejoinNetwork::Orphan,
+            scan_channels: 11..27,
+            scan_duration: 3,
+            capability_information: CapabilityInformation(0x80),
+            security_enabled: false,
+        }
+    }
+
+    fn expect_realignment(mac: &mut MockMlme, coordinator_address: u16) {
+        mac.expect_scan_network()
+            .withf(|ty, channels, _| *ty == ScanType::Orphan && *channels == (11..27))
+            .times(1)
+            .returning(move |ty, _, _| {
+                Ok(ScanResult {
+                    scan_type: ty,
+                    pan_descriptor: PanDescriptorList::new(),
+                    energy_detect_list: EnergyDetectList::new(),
+                    coordinator_realignment: Some(CoordinatorRealignmentData {
+                        pan_id: PanId(0x1234),
+                        coordinator_address: MacShortAddress(coordinator_address),
+                        channel: 15,
+                        device_address: MacShortAddress(0x5555),
+                        channel_page: None,
+                    }),
+                })
+            });
+    }
+
+    #[test]
+    fn orphan_join() {
+        let mut mac = MockMlme::new();
+        expect_realignment(&mut mac, 0x0000);
+        let mut nlme = make_nlme(mac);
+
+        let confirm = block_on(nlme.join(orphan_request()));
+
+        assert_eq!(confirm.status, NlmeJoinStatus::Success);
+        assert_eq!(confirm.network_address, ShortAddress(0x5555));
+        assert_eq!(confirm.channel, 15);
+        assert_eq!(nlme.nib().network_address(), 0x5555);
+        assert_eq!(nlme.nib().panid(), 0x1234);
+        assert_eq!(nlme.nib().extended_panid(), EPID);
+        assert_eq!(nlme.depth, 1);
+        let table = nlme.nib().neighbor_table();
+        assert_eq!(table.len(), 1);
+        assert_eq!(table[0].network_address, ShortAddress(0x0000));
+        assert_eq!(table[0].relationship, relationship::PARENT);
+    }
+
+    #[test]
+    fn orphan_join_updates_known_parent() {
+        let mut mac = MockMlme::new();
+        expect_realignment(&mut mac, 0x2222);
+        let mut nlme = make_nlme(mac);
+        let mut parent = make_neighbor(0x1234, 0x1111, EPID, 200, 1);
+        parent.relationship = relationship::PARENT;
+        let mut table = nlme.nib().neighbor_table();
+        table.push(parent).unwrap();
+        nlme.nib().set_neighbor_table(table);
+
+        let confirm = block_on(nlme.join(orphan_request()));
+
+        assert_eq!(confirm.status, NlmeJoinStatus::Success);
+        let table = nlme.nib().neighbor_table();
+        assert_eq!(table.len(), 1);
+        assert_eq!(table[0].network_address, ShortAddress(0x2222));
+    }
+
+    #[test]
+    fn orphan_join_takes_over_depth_of_known_parent() {
+        let mut mac = MockMlme::new();
+        expect_realignment(&mut mac, 0x2222);
+        let mut nlme = make_nlme(mac);
+        nlme.nib().set_extended_panid(EPID);
+        let mut table = nlme.nib().neighbor_table();
+        table
+            .push(make_neighbor(0x1234, 0x2222, EPID, 200, 2))
+            .unwrap();
+        nlme.nib().set_neighbor_table(table);
+
+        let confirm = block_on(nlme.join(orphan_request()));
+
+        assert_eq!(confirm.status, NlmeJoinStatus::Success);
+        assert_eq!(nlme.depth, 3);
+        let table = nlme.nib().neighbor_table();
+        assert_eq!(table.len(), 1);
+        assert_eq!(table[0].relationship, relationship::PARENT);
+    }
+
+    #[test]
+    fn orphan_join_rejects_parent_of_other_network() {
+        let mut mac = MockMlme::new();
+        expect_realignment(&mut mac, 0x2222);
+        let mut nlme = make_nlme(mac);
+        let mut table = nlme.nib().neighbor_table();
+        table
+            .push(make_neighbor(0x1234, 0x2222, 0xBEEF, 200, 2))
+            .unwrap();
+        nlme.nib().set_neighbor_table(table);
+
+        let confirm = block_on(nlme.join(orphan_request()));
+
+        assert_eq!(confirm.status, NlmeJoinStatus::NoNetworks);
+        assert_eq!(nlme.nib().network_address(), 0xffff);
+        assert_eq!(nlme.nib().extended_panid(), 0);
+    }
+
+    #[test]
+    fn orphan_join_for_other_network_is_invalid() {
+        let mut mac = MockMlme::new();
+        mac.expect_scan_network().never();
+        let mut nlme = make_nlme(mac);
+        nlme.nib().set_extended_panid(0xBEEF);
+
+        let confirm = block_on(nlme.join(orphan_request()));
+
+        assert_eq!(confirm.status, NlmeJoinStatus::InvalidRequest);
+    }
+
+    #[test]
+    fn orphan_join_without_parent() {
+        let mut mac = MockMlme::new();
+        mac.expect_scan_network()
+            .times(1)
+            .returning(|_, _, _| Err(MacError::NoBeacon));
+        let mut nlme = make_nlme(mac);
+
+        let confirm = block_on(nlme.join(orphan_request()));
+
+        assert_eq!(confirm.status, NlmeJoinStatus::NoNetworks);
+        assert_eq!(nlme.nib().network_address(), 0xffff);
+    }
+
+    #[test]
+    fn parent_realigns_known_child() {
+        let mut mac = MockMlme::new();
+        mac.expect_receive().times(1).returning(|_| {
+            Ok(MacIndication::Orphan {
+                orphan_address: CHILD,
+            })
+        });
+        mac.expect_orphan_response()
+            .withf(|orphan, address, member| {
+                *orphan == CHILD && *address == ShortAddress(0x4242) && *member
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        let mut nlme = make_nlme(mac);
+        nlme.nib().set_network_address(NWK_COORDINATOR_ADDRESS);
+        nlme.add_child(
+            CHILD,
+            ShortAddress(0x4242),
+            CapabilityInformation(0x80),
+            relationship::CHILD,
+        )
+        .unwrap();
+
+        let indication = block_on(nlme.receive()).unwrap();
+
+        assert!(indication.is_none());
+    }
+
+    #[test]
+    fn parent_ignores_unknown_orphan() {
+        let mut mac = MockMlme::new();
+        mac.expect_receive().times(1).returning(|_| {
+            Ok(MacIndication::Orphan {
+                orphan_address: CHILD,
+            })
+        });
+        mac.expect_orphan_response().never();
+        let mut nlme = make_nlme(mac);
+        nlme.nib().set_network_address(NWK_COORDINATOR_ADDRESS);
+
+        let indication = block_on(nlme.receive()).unwrap();
+
+        assert!(indication.is_none());
+    }
+}
@@ -0,0 +1,327 @@
+//! PAN identifier conflict detection and resolution
+//!
+//! A conflict exists when a beacon of another network, with the same PAN
+//! identifier but a different extended PAN identifier, is heard on the
+//! channel of this network. Devices report it to the network manager with
+//! a network report, the network manager picks a new PAN identifier and
+//! announces it with a network update (§3.6.1.13).
+
+use heapless::Vec;
+use zigbee_mac::Address;
+use zigbee_mac::PanId;
+use zigbee_mac::mlme::MacError;
+use zigbee_mac::mlme::Mlme;
+use zigbee_mac::mlme::PanDescriptor;
+use zigbee_mac::mlme::ScanType;
+use zigbee_types::TypeArrayRef;
+
+use super::NetworkError;
+use super::Nlme;
+use crate::nwk::frame::command::network_report::DeviceListEntry;
+use crate::nwk::frame::command::network_report::NetworkReport;
+use crate::nwk::frame::command::network_report::report_type;
+use crate::nwk::nib::NWK_COORDINATOR_ADDRESS;
+
+/// Scan duration of the active scan for the PAN identifiers in use.
+const ACTIVE_SCAN_DURATION: u8 = 3;
+
+// implementation specific
+
+/// Conflicting devices listed in a network report.
+const MAX_REPORTED_DEVICES: usize = 4;
+/// Minimum time between two conflict reports of a device.
+const PAN_ID_CONFLICT_REPORT_INTERVAL_MS: u32 = 60 * 1000;
+
+impl<M> Nlme<M>
+where
+    M: Mlme,
+{
+    /// Whether `pan_descriptor` belongs to another network using the PAN
+    /// identifier of this network.
+    fn is_pan_id_conflict(&self, pan_descriptor: &PanDescriptor) -> bool {
+        let nib = self.nib();
+        nib.network_address() != 0xffff
+            && pan_descriptor.channel == self.channel
+            && pan_descriptor.coord_pan_id.0 == nib.panid()
+            && pan_descriptor.zigbee_beacon.extended_pan_id.0 != nib.extended_panid()
+    }
+
+    /// Look for conflicts among the beacons heard in a scan or received
+    /// outside of one, and report them to the network manager.
+    pub(super) async fn check_pan_id_conflict(&mut self, pan_descriptors: &[PanDescriptor]) {
+        let devices: Vec<DeviceListEntry, MAX_REPORTED_DEVICES> = pan_descriptors
+            .iter()
+            .filter(|pd| self.is_pan_id_conflict(pd))
+            .filter_map(|pd| match pd.coord_address {
+                Address::Short(_, address) => Some(DeviceListEntry {
+                    device_address: address.0,
+                    device_type: u8::from(address.0 != NWK_COORDINATOR_ADDRESS),
+                }),
+                Address::Extended(_, _) => None,
+            })
+            .take(MAX_REPORTED_DEVICES)
+            .collect();
+        if devices.is_empty() || self.pan_id_conflict_holdoff > 0 {
+            return;
+        }
+        log::warn!(
+            "[NLME] PAN identifier 0x{:04x} used by another network",
+            self.nib().panid()
+        );
+        self.pan_id_conflict_holdoff = PAN_ID_CONFLICT_REPORT_INTERVAL_MS;
+
+        let result = if self.is_network_manager() {
+            self.resolve_pan_id_conflict().await
+        } else {
+            let report = NetworkReport {
+                report_type: report_type::PAN_IDENTIFIER_CONFLICT,
+                device_count: u8::try_from(devices.len()).unwrap_or(u8::MAX),
+                device_list: TypeArrayRef(&devices),
+            };
+            self.send_network_report(report).await
+        };
+        if let Err(e) = result {
+            log::debug!("[NLME] failed to handle PAN identifier conflict: {e}");
+        }
+    }
+
+    /// Move the network to a PAN identifier not heard on the channel of the
+    /// network, only done by the network manager (§3.6.1.13.2).
+    pub(super) async fn resolve_pan_id_conflict(&mut self) -> Result<(), NetworkError> {
+        if !self.is_network_manager() || self.frequency_agility.pending_update.is_some() {
+            return Ok(());
+        }
+        let channel = self.channel;
+        let pan_descriptors = match self
+            .mac
+            .scan_network(ScanType::Active, channel..channel + 1, ACTIVE_SCAN_DURATION)
+            .await
+        {
+            Ok(scan_result) => scan_result.pan_descriptor,
+            Err(MacError::NoBeacon) => zigbee_mac::mlme::PanDescriptorList::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let current = self.nib().panid();
+        let pan_id = loop {
+            let pan_id = self.select_pan_id(&pan_descriptors);
+            if pan_id != current {
+                break pan_id;
+            }
+        };
+        self.broadcast_network_update(channel, pan_id).await
+    }
+
+    /// Switch this device to `pan_id`.
+    pub(super) fn set_network_pan_id(&mut self, pan_id: u16) {
+        let nib = self.nib();
+        let previous = nib.panid();
+        log::debug!("[NLME] changing PAN identifier 0x{previous:04x} -> 0x{pan_id:04x}");
+        nib.set_panid(pan_id);
+        let mut table = nib.neighbor_table();
+        for neighbor in table.iter_mut().filter(|n| n.pan_id == previous) {
+            neighbor.pan_id = pan_id;
+        }
+        nib.set_neighbor_table(table);
+        self.mac.set_pan_id(PanId(pan_id));
+    }
+
+    /// Advance the conflict report hold-off by `elapsed_ms`.
+    pub(super) fn pan_id_conflict_tick(&mut self, elapsed_ms: u32) {
+        self.pan_id_conflict_holdoff = self.pan_id_conflict_holdoff.saturating_sub(elapsed_ms);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use zigbee_mac::mlme::MacIndication;
+    use zigbee_mac::mlme::ScanResult;
+    use zigbee_types::ShortAddress;
+
+    use super::*;
+    use crate::nwk::frame::command::Command as NwkCommand;
+    use crate::nwk::frame::command::network_update::NetworkUpdate;
+    use crate::nwk::nib::MAX_PAN_ID;
+    use crate::nwk::nib::broadcast_address;
+    use crate::nwk::nib::octets_to_ms;
+    use crate::nwk::nlme::tests::MANAGER;
+    use crate::nwk::nlme::tests::MockMlme;
+    use crate::nwk::nlme::tests::OWN;
+    use crate::nwk::nlme::tests::PAN_ID;
+    use crate::nwk::nlme::tests::block_on;
+    use crate::nwk::nlme::tests::command_header;
+    use crate::nwk::nlme::tests::install_network_key;
+    use crate::nwk::nlme::tests::mac_short;
+    use crate::nwk::nlme::tests::make_device;
+    use crate::nwk::nlme::tests::make_pan_descriptor;
+    use crate::nwk::nlme::tests::parse_command;
+
+    const EPID: u64 = 0x00aa_bbcc_ddee_ff00;
+    const CHANNEL: u8 = 15;
+    const ROUTER: u16 = OWN;
+
+    fn expect_beacon(nlme: &mut Nlme<MockMlme>, channel: u8, epid: u64) {
+        nlme.mac.expect_receive().times(1).returning(move |_| {
+            Ok(MacIndication::Beacon {
+                pan_descriptor: make_pan_descriptor(channel, PAN_ID, epid),
+            })
+        });
+    }
+
+    #[test]
+    fn router_reports_conflicting_beacon() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                let (header, NwkCommand::NetworkReport(report)) = parse_command(payload) else {
+                    return false;
+                };
+                *dest == mac_short(MANAGER)
+                    && header.destination.0 == MANAGER
+                    && report.report_type == report_type::PAN_IDENTIFIER_CONFLICT
+                    && report.device_count == 1
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_device(mac, ROUTER, EPID, CHANNEL);
+
+        expect_beacon(&mut nlme, CHANNEL, 0x1122);
+        assert!(block_on(nlme.receive()).unwrap().is_none());
+
+        // repeated beacons are not reported again right away
+        expect_beacon(&mut nlme, CHANNEL, 0x1122);
+        assert!(block_on(nlme.receive()).unwrap().is_none());
+    }
+
+    #[test]
+    fn ignores_own_network_and_other_channels() {
+        let mut nlme = make_device(MockMlme::new(), ROUTER, EPID, CHANNEL);
+
+        for (channel, epid) in [(CHANNEL, EPID), (CHANNEL + 1, 0x1122)] {
+            expect_beacon(&mut nlme, channel, epid);
+            assert!(block_on(nlme.receive()).unwrap().is_none());
+        }
+        assert_eq!(nlme.pan_id_conflict_holdoff, 0);
+    }
+
+    #[test]
+    fn manager_moves_to_unused_pan_id() {
+        let mut mac = MockMlme::new();
+        mac.expect_scan_network()
+            .withf(|ty, channels, _| *ty == ScanType::Active && *channels == (CHANNEL..CHANNEL + 1))
+            .times(1)
+            .returning(|ty, _, _| {
+                let mut pan_descriptor = zigbee_mac::mlme::PanDescriptorList::new();
+                let _ = pan_descriptor.push(make_pan_descriptor(CHANNEL, PAN_ID, 0x1122));
+                Ok(ScanResult {
+                    scan_type: ty,
+                    pan_descriptor,
+                    energy_detect_list: zigbee_mac::mlme::EnergyDetectList::new(),
+                    coordinator_realignment: None,
+                })
+            });
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                let (_, NwkCommand::NetworkUpdate(update)) = parse_command(payload) else {
+                    return false;
+                };
+                *dest == mac_short(broadcast_address::ALL_DEVICES)
+                    && update.channel == CHANNEL
+                    && update.pan_id != PAN_ID
+                    && update.pan_id <= MAX_PAN_ID
+                    && update.update_id == 1
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mac.expect_set_beacon_payload().return_const(());
+        mac.expect_set_pan_id()
+            .withf(|pan_id| pan_id.0 != PAN_ID)
+            .times(1)
+            .return_const(());
+        let mut nlme = make_device(mac, MANAGER, EPID, CHANNEL);
+
+        let report = NetworkReport {
+            report_type: report_type::PAN_IDENTIFIER_CONFLICT,
+            device_count: 0,
+            device_list: TypeArrayRef(&[]),
+        };
+        let mut header = nlme.nwk_command_header(ShortAddress(MANAGER), 1, false);
+        header.source = ShortAddress(ROUTER);
+        block_on(nlme.network_report_indication(&header, &report)).unwrap();
+
+        let delay = octets_to_ms(nlme.nib().network_broadcast_delivery_time());
+        block_on(nlme.frequency_agility_tick(delay));
+        let pan_id = nlme.nib().panid();
+        assert_ne!(pan_id, PAN_ID);
+        assert!(
+            nlme.nib()
+                .neighbor_table()
+                .iter()
+                .all(|n| n.pan_id == pan_id)
+        );
+        assert_eq!(nlme.channel, CHANNEL);
+    }
+
+    #[test]
+    fn device_follows_pan_id_update() {
+        let mut mac = MockMlme::new();
+        mac.expect_set_beacon_payload().return_const(());
+        mac.expect_set_pan_id()
+            .withf(|pan_id| pan_id.0 == 0x0777)
+            .times(1)
+            .return_const(());
+        let mut nlme = make_device(mac, ROUTER, EPID, CHANNEL);
+
+        nlme.network_update_indication(
+            &command_header(MANAGER, broadcast_address::ALL_DEVICES, false),
+            &NetworkUpdate {
+                update_id: 1,
+                channel: CHANNEL,
+                pan_id: 0x0777,
+                network_address: MANAGER,
+            },
+        );
+        assert_eq!(nlme.nib().panid(), PAN_ID);
+
+        let delay = octets_to_ms(nlme.nib().network_broadcast_delivery_time());
+        block_on(nlme.frequency_agility_tick(delay));
+        assert_eq!(nlme.nib().panid(), 0x0777);
+        assert_eq!(nlme.nib().update_id(), 1);
+    }
+
+    #[test]
+    fn unsecured_pan_id_update_ignored_in_secured_network() {
+        let mut nlme = make_device(MockMlme::new(), ROUTER, EPID, CHANNEL);
+        install_network_key(&nlme);
+        let update = NetworkUpdate {
+            update_id: 1,
+            channel: CHANNEL,
+            pan_id: 0x0777,
+            network_address: MANAGER,
+        };
+
+        let header = command_header(MANAGER, broadcast_address::ALL_DEVICES, false);
+        nlme.network_update_indication(&header, &update);
+        let delay = octets_to_ms(nlme.nib().network_broadcast_delivery_time());
+        block_on(nlme.frequency_agility_tick(delay));
+
+        assert_eq!(nlme.nib().panid(), PAN_ID);
+        assert_eq!(nlme.nib().update_id(), 0);
+    }
+
+    #[test]
+    fn unsecured_conflict_report_ignored_in_secured_network() {
+        let mut nlme = make_device(MockMlme::new(), MANAGER, EPID, CHANNEL);
+        install_network_key(&nlme);
+        let report = NetworkReport {
+            report_type: report_type::PAN_IDENTIFIER_CONFLICT,
+            device_count: 0,
+            device_list: TypeArrayRef(&[]),
+        };
+
+        let header = command_header(ROUTER, MANAGER, false);
+        block_on(nlme.network_report_indication(&header, &report)).unwrap();
+
+        assert!(nlme.frequency_agility.pending_update.is_none());
+        assert_eq!(nlme.nib().update_id(), 0);
+    }
+}
@@ -0,0 +1,346 @@
+//! Poll control of sleepy end devices
+//!
+//! End devices with the receiver off when idle fetch the frames buffered by
+//! their parent with MAC data polls (§3.6.2). They poll every long poll
+//! interval and switch to the short poll interval while the next higher
+//! layer waits for the answer to a transaction or the parent has more
+//! frames pending. The radio may sleep between two polls.
+
+use zigbee_mac::mlme::MacError;
+use zigbee_mac::mlme::Mlme;
+
+use super::NetworkError;
+use super::Nlme;
+use super::NwkIndication;
+use super::management::NwkStatus;
+
+/// Poll intervals of a sleepy end device, the defaults are the ones of the
+/// ZCL Poll Control cluster.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollIntervals {
+    /// Milliseconds between two polls while idle.
+    pub long_poll_interval_ms: u32,
+    /// Milliseconds between two polls while fast polling.
+    pub short_poll_interval_ms: u32,
+    /// Milliseconds after which fast polling for an outstanding
+    /// transaction ends, even if the transaction was not finished.
+    pub fast_poll_timeout_ms: u32,
+}
+
+impl Default for PollIntervals {
+    fn default() -> Self {
+        Self {
+            long_poll_interval_ms: 5_000,
+            short_poll_interval_ms: 500,
+            fast_poll_timeout_ms: 10_000,
+        }
+    }
+}
+
+/// Result of [`Nlme::poll_tick`].
+#[derive(Debug)]
+pub struct PollConfirm {
+    /// Indication for the frame fetched from the parent, if any.
+    pub indication: Option<NwkIndication>,
+    /// Milliseconds the radio may sleep until the next poll is due, `None`
+    /// if the receiver has to stay on.
+    pub sleep_ms: Option<u32>,
+}
+
+/// Poll state of an end device.
+pub(super) struct PollControl {
+    intervals: PollIntervals,
+    /// Transactions waiting for an answer.
+    transactions: u8,
+    /// Milliseconds until fast polling for the transactions ends.
+    fast_poll_remaining: u32,
+    /// Whether the parent set the MAC frame pending bit of the frame
+    /// delivered with the last poll, i.e. has more frames buffered.
+    pub(super) frame_pending: bool,
+    /// Milliseconds until the next poll.
+    poll_due: u32,
+}
+
+impl PollControl {
+    pub(super) fn new() -> Self {
+        Self {
+            intervals: PollIntervals::default(),
+            transactions: 0,
+            fast_poll_remaining: 0,
+            frame_pending: false,
+            poll_due: 0,
+        }
+    }
+
+    /// Forget the outstanding transactions, the configured poll intervals
+    /// are kept.
+    pub(super) fn reset(&mut self) {
+        *self = Self {
+            intervals: self.intervals,
+            ..Self::new()
+        };
+    }
+
+    fn is_fast_polling(&self) -> bool {
+        self.transactions > 0 || self.frame_pending
+    }
+
+    /// Milliseconds until the next poll after a poll.
+    fn interval(&self) -> u32 {
+        if self.is_fast_polling() {
+            self.intervals.short_poll_interval_ms
+        } else {
+            self.intervals.long_poll_interval_ms
+        }
+    }
+}
+
+impl<M> Nlme<M>
+where
+    M: Mlme,
+{
+    /// Configure the poll intervals of this end device.
+    ///
+    /// The short poll interval must not be zero or longer than the long
+    /// poll interval.
+    pub fn set_poll_intervals(&mut self, intervals: PollIntervals) -> NwkStatus {
+        if intervals.short_poll_interval_ms == 0
+            || intervals.short_poll_interval_ms > intervals.long_poll_interval_ms
+        {
+            return NwkStatus::InvalidParameter;
+        }
+        let poll = &mut self.poll_control;
+        poll.intervals = intervals;
+        poll.poll_due = poll.poll_due.min(poll.interval());
+        NwkStatus::Success
+    }
+
+    /// Poll at the short poll interval until the answer to a transaction
+    /// was received, see [`Nlme::stop_fast_poll`].
+    ///
+    /// The APSME calls this for acknowledged transmissions, the application
+    /// for requests of its own, e.g. ZCL requests awaiting a response.
+    pub fn start_fast_poll(&mut self) {
+        let poll = &mut self.poll_control;
+        poll.transactions = poll.transactions.saturating_add(1);
+        poll.fast_poll_remaining = poll.intervals.fast_poll_timeout_ms;
+        poll.poll_due = poll.poll_due.min(poll.intervals.short_poll_interval_ms);
+    }
+
+    /// The answer to a transaction started with [`Nlme::start_fast_poll`]
+    /// was received.
+    pub fn stop_fast_poll(&mut self) {
+        let poll = &mut self.poll_control;
+        poll.transactions = poll.transactions.saturating_sub(1);
+    }
+
+    /// Whether this device currently polls at the short poll interval.
+    pub fn is_fast_polling(&self) -> bool {
+        self.poll_control.is_fast_polling()
+    }
+
+    /// Whether this device has to poll its parent for data.
+    fn is_sleepy(&self) -> bool {
+        !self.is_router() && !self.nib().capability_information().receiver_on_when_idle()
+    }
+
+    /// Advance the poll timer by `elapsed_ms` milliseconds and poll the
+    /// parent when due.
+    ///
+    /// Must be called periodically by the next higher layer of a sleepy end
+    /// device, at the latest after the returned sleep time.
+    pub async fn poll_tick(&mut self, elapsed_ms: u32) -> Result<PollConfirm, NetworkError> {
+        if !self.is_sleepy() {
+            return Ok(PollConfirm {
+                indication: None,
+                sleep_ms: None,
+            });
+        }
+        let poll = &mut self.poll_control;
+        if poll.transactions > 0 {
+            poll.fast_poll_remaining = poll.fast_poll_remaining.saturating_sub(elapsed_ms);
+            if poll.fast_poll_remaining == 0 {
+                log::debug!("[NLME] fast poll timeout");
+                poll.transactions = 0;
+            }
+        }
+        poll.poll_due = poll.poll_due.saturating_sub(elapsed_ms);
+        if poll.poll_due > 0 {
+            return Ok(PollConfirm {
+                indication: None,
+                sleep_ms: Some(poll.poll_due),
+            });
+        }
+
+        let mut buf = [0u8; 128];
+        let result = match self.poll_nwk_data_request(&mut buf).await {
+            Ok((frame, lqi)) => self.polled_frame_indication(lqi, frame).await,
+            Err(NetworkError::MacError(MacError::NoData)) => Ok(None),
+            Err(e) => Err(e),
+        };
+        let poll = &mut self.poll_control;
+        poll.poll_due = poll.interval();
+        Ok(PollConfirm {
+            indication: result?,
+            sleep_ms: Some(poll.poll_due),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byte::BytesExt;
+    use zigbee_mac::Address;
+    use zigbee_mac::MacShortAddress;
+    use zigbee_mac::PanId;
+    use zigbee_types::ShortAddress;
+
+    use super::*;
+    use crate::nwk::frame::frame_control::FrameControl as NwkFrameControl;
+    use crate::nwk::frame::header::Header as NwkHeader;
+    use crate::nwk::nlme::tests::MockMlme;
+    use crate::nwk::nlme::tests::OWN;
+    use crate::nwk::nlme::tests::PAN_ID;
+    use crate::nwk::nlme::tests::block_on;
+    use crate::nwk::nlme::tests::make_end_device;
+
+    const PARENT: u16 = 0x0000;
+
+    fn expect_polls(mac: &mut MockMlme, times: usize) {
+        mac.expect_poll_data()
+            .withf(|dest, _| *dest == Address::Short(PanId(PAN_ID), MacShortAddress(PARENT)))
+            .times(times)
+            .returning(|_, _| Err(MacError::NoData));
+    }
+
+    #[test]
+    fn polls_at_long_poll_interval() {
+        let mut mac = MockMlme::new();
+        expect_polls(&mut mac, 2);
+        let mut nlme = make_end_device(mac);
+
+        let confirm = block_on(nlme.poll_tick(0)).unwrap();
+        assert!(confirm.indication.is_none());
+        assert_eq!(confirm.sleep_ms, Some(5_000));
+        let confirm = block_on(nlme.poll_tick(4_000)).unwrap();
+        assert_eq!(confirm.sleep_ms, Some(1_000));
+        let confirm = block_on(nlme.poll_tick(1_000)).unwrap();
+        assert_eq!(confirm.sleep_ms, Some(5_000));
+    }
+
+    #[test]
+    fn outstanding_transaction_polls_fast() {
+        let mut mac = MockMlme::new();
+        expect_polls(&mut mac, 3);
+        let mut nlme = make_end_device(mac);
+        block_on(nlme.poll_tick(0)).unwrap();
+
+        nlme.start_fast_poll();
+        assert!(nlme.is_fast_polling());
+        let confirm = block_on(nlme.poll_tick(500)).unwrap();
+        assert_eq!(confirm.sleep_ms, Some(500));
+
+        nlme.stop_fast_poll();
+        assert!(!nlme.is_fast_polling());
+        let confirm = block_on(nlme.poll_tick(500)).unwrap();
+        assert_eq!(confirm.sleep_ms, Some(5_000));
+    }
+
+    #[test]
+    fn fast_poll_times_out() {
+        let mut mac = MockMlme::new();
+        expect_polls(&mut mac, 1);
+        let mut nlme = make_end_device(mac);
+        nlme.start_fast_poll();
+
+        let confirm = block_on(nlme.poll_tick(10_000)).unwrap();
+        assert!(!nlme.is_fast_polling());
+        assert_eq!(confirm.sleep_ms, Some(5_000));
+    }
+
+    /// Write a data frame from the parent into `buf`, returns its length.
+    fn write_data_frame(buf: &mut [u8]) -> usize {
+        let header = NwkHeader {
+            frame_control: NwkFrameControl(0).set_protocol_version(2),
+            destination: ShortAddress(OWN),
+            source: ShortAddress(PARENT),
+            radius: 1,
+            sequence_number: 1,
+            destination_ieee: None,
+            source_ieee: None,
+            multicast_control: None,
+            source_route_subframe: None,
+        };
+        let offset = &mut 0;
+        buf.write_with(offset, header, ()).unwrap();
+        buf.write_with(offset, &[1u8, 2][..], ()).unwrap();
+        *offset
+    }
+
+    #[test]
+    fn pending_frames_are_polled_fast() {
+        let mut mac = MockMlme::new();
+        let mut seq = mockall::Sequence::new();
+        mac.expect_poll_data()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, buf| Ok((write_data_frame(buf), 0xc0, true)));
+        mac.expect_poll_data()
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _| Err(MacError::NoData));
+        let mut nlme = make_end_device(mac);
+
+        let confirm = block_on(nlme.poll_tick(0)).unwrap();
+        let Some(NwkIndication::Data(indication)) = confirm.indication else {
+            unreachable!("expected a data indication");
+        };
+        assert_eq!(indication.source, ShortAddress(PARENT));
+        assert_eq!(indication.link_quality, 0xc0);
+        assert_eq!(&indication.nsdu[..], &[1, 2]);
+        assert_eq!(confirm.sleep_ms, Some(500));
+
+        let confirm = block_on(nlme.poll_tick(500)).unwrap();
+        assert!(confirm.indication.is_none());
+        assert_eq!(confirm.sleep_ms, Some(5_000));
+    }
+
+    #[test]
+    fn last_pending_frame_returns_to_long_poll() {
+        let mut mac = MockMlme::new();
+        mac.expect_poll_data()
+            .times(1)
+            .returning(|_, buf| Ok((write_data_frame(buf), 0xc0, false)));
+        let mut nlme = make_end_device(mac);
+
+        let confirm = block_on(nlme.poll_tick(0)).unwrap();
+
+        assert!(confirm.indication.is_some());
+        assert!(!nlme.is_fast_polling());
+        assert_eq!(confirm.sleep_ms, Some(5_000));
+    }
+
+    #[test]
+    fn routers_do_not_poll() {
+        let mut nlme = make_end_device(MockMlme::new());
+        nlme.nib().set_network_address(PARENT);
+
+        let confirm = block_on(nlme.poll_tick(0)).unwrap();
+        assert!(confirm.sleep_ms.is_none());
+    }
+
+    #[test]
+    fn short_poll_interval_longer_than_long_is_rejected() {
+        let mut nlme = make_end_device(MockMlme::new());
+        let intervals = PollIntervals {
+            long_poll_interval_ms: 1_000,
+            short_poll_interval_ms: 2_000,
+            fast_poll_timeout_ms: 10_000,
+        };
+
+        assert_eq!(
+            nlme.set_poll_intervals(intervals),
+            NwkStatus::InvalidParameter
+        );
+    }
+}
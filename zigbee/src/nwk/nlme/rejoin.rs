@@ -0,0 +1,808 @@
+//! NWK rejoin procedure
+//!
+//! Both the rejoining device and the parent side of the exchange of
+//! rejoin request and rejoin response commands (§3.6.1.4.2).
+
+use zigbee_mac::Address;
+use zigbee_mac::MacShortAddress;
+use zigbee_mac::PanId;
+use zigbee_mac::mlme::MacError;
+use zigbee_mac::mlme::MacIndication;
+use zigbee_mac::mlme::Mlme;
+use zigbee_types::IeeeAddress;
+use zigbee_types::ShortAddress;
+
+use super::NetworkError;
+use super::Nlme;
+use super::management::NlmeJoinConfirm;
+use super::management::NlmeJoinIndication;
+use super::management::NlmeJoinRequest;
+use super::management::NlmeJoinStatus;
+use super::management::RejoinNetwork;
+use crate::nwk::frame::CommandFrame;
+use crate::nwk::frame::Frame;
+use crate::nwk::frame::command::Command;
+use crate::nwk::frame::command::rejoin_request::CapabilityInformation as RejoinCapabilityInformation;
+use crate::nwk::frame::command::rejoin_request::RejoinRequest;
+use crate::nwk::frame::command::rejoin_response::RejoinResponse;
+use crate::nwk::nib::CapabilityInformation;
+use crate::nwk::nib::relationship;
+use crate::security::SecurityContext;
+
+/// Number of data polls for the rejoin response before trying the next
+/// parent candidate.
+const REJOIN_RESPONSE_POLLS: u8 = 3;
+
+/// Number of frames a device with the receiver on waits through for the
+/// rejoin response before trying the next parent candidate.
+const REJOIN_RESPONSE_FRAMES: u8 = 8;
+
+/// Rejoin response status values (§3.4.7.3.2), taken from the MAC
+/// association status values.
+pub(super) mod rejoin_status {
+    pub const SUCCESS: u8 = 0x00;
+    pub const PAN_AT_CAPACITY: u8 = 0x01;
+    pub const PAN_ACCESS_DENIED: u8 = 0x02;
+}
+
+impl<M> Nlme<M>
+where
+    M: Mlme,
+{
+    /// NLME-JOIN.request with `RejoinNetwork::NwkRejoin` (§3.6.1.4.2.1).
+    ///
+    /// With `security_enabled` the rejoin request is secured with the
+    /// current network key, otherwise an unsecured Trust Center rejoin is
+    /// performed. The neighbor table must hold the result of a network
+    /// discovery.
+    pub(super) async fn nwk_rejoin(&mut self, request: NlmeJoinRequest) -> NlmeJoinConfirm {
+        let fail = |status| NlmeJoinConfirm {
+            status,
+            network_address: ShortAddress(0xffff),
+            extended_pan_id: request.extended_pan_id,
+            channel: 0,
+            enhanced_beacon_type: false,
+            mac_interface_index: 0u8,
+        };
+
+        // A secured rejoin needs the current network key.
+        if request.security_enabled && self.nib().security_material_set().is_empty() {
+            return fail(NlmeJoinStatus::InvalidRequest);
+        }
+
+        let join_as_router = request.capability_information.device_type();
+        let candidates = self.select_parent_candidates(request.extended_pan_id, join_as_router);
+        if candidates.is_empty() {
+            return fail(NlmeJoinStatus::NotPermitted);
+        }
+
+        // A failed rejoin leaves the device as it was, so that it can still
+        // rejoin or associate afterwards.
+        let prior_address = self.nib().network_address();
+        let prior_capability_information = self.nib().capability_information();
+
+        self.nib()
+            .set_capability_information(request.capability_information);
+        self.nib().set_ieee_address(self.mac.extended_address());
+        // A device without a network address rejoins with a random one,
+        // the parent replaces it if it conflicts.
+        if self.nib().network_address() == 0xffff {
+            let Some(network_address) = self.allocate_address() else {
+                self.nib()
+                    .set_capability_information(prior_capability_information);
+                return fail(NlmeJoinStatus::InvalidRequest);
+            };
+            self.nib().set_network_address(network_address.0);
+        }
+
+        let mut last_status = NlmeJoinStatus::NotPermitted;
+        for &candidate_idx in &candidates {
+            let table = self.nib().neighbor_table();
+            let neighbor = &table[candidate_idx];
+            let channel = neighbor.logical_channel;
+            let pan_id = neighbor.pan_id;
+            let parent_address = neighbor.network_address;
+            drop(table);
+
+            self.mac.set_channel(channel);
+            self.mac.set_pan_id(PanId(pan_id));
+            self.mac
+                .set_short_address(ShortAddress(self.nib().network_address()));
+
+            let response = match self
+                .rejoin_exchange(parent_address, pan_id, request.security_enabled)
+                .await
+            {
+                Ok(response) => response,
+                Err(e) => {
+                    log::debug!("[NLME] rejoin via 0x{:04x} failed: {e}", parent_address.0);
+                    last_status = NlmeJoinStatus::MacError;
+                    continue;
+                }
+            };
+
+            if response.status != rejoin_status::SUCCESS {
+                let mut table = self.nib().neighbor_table();
+                table[candidate_idx].potential_parent = 0;
+                self.nib().set_neighbor_table(table);
+                last_status = match response.status {
+                    rejoin_status::PAN_AT_CAPACITY => NlmeJoinStatus::PanAtCapacity,
+                    _ => NlmeJoinStatus::PanAccessDenied,
+                };
+                continue;
+            }
+
+            let network_address = response.network_address;
+            self.nib().set_network_address(network_address.0);
+            self.nib().set_extended_panid(request.extended_pan_id.0);
+            self.nib().set_panid(pan_id);
+            self.mac.set_short_address(network_address);
+            let channel = self.adopt_parent(candidate_idx);
+            log::debug!(
+                "[NLME] rejoined via 0x{:04x}, address 0x{:04x}",
+                parent_address.0,
+                network_address.0
+            );
+
+            return NlmeJoinConfirm {
+                status: NlmeJoinStatus::Success,
+                network_address,
+                extended_pan_id: request.extended_pan_id,
+                channel,
+                enhanced_beacon_type: false,
+                mac_interface_index: 0u8,
+            };
+        }
+
+        self.nib().set_network_address(prior_address);
+        self.nib()
+            .set_capability_information(prior_capability_information);
+        self.mac.set_channel(self.channel);
+        self.mac.set_pan_id(PanId(self.nib().panid()));
+        self.mac.set_short_address(ShortAddress(prior_address));
+        fail(last_status)
+    }
+
+    /// Send a rejoin request to `parent` and wait or poll for its rejoin
+    /// response (§3.6.1.4.2.1).
+    async fn rejoin_exchange(
+        &mut self,
+        parent: ShortAddress,
+        pan_id: u16,
+        secure: bool,
+    ) -> Result<RejoinResponse, NetworkError> {
+        let mut header = self.nwk_command_header(parent, 1, secure);
+        header.frame_control = header.frame_control.set_source_ieee_flag(true);
+        header.source_ieee = Some(self.nib().ieee_address());
+        let command = Command::RejoinRequest(RejoinRequest {
+            capability_information: RejoinCapabilityInformation(
+                self.nib().capability_information().0,
+            ),
+        });
+        let len = self.build_nwk_command_frame(header, command)?;
+
+        let dest = Address::Short(PanId(pan_id), MacShortAddress(parent.0));
+        self.mac.transmit_data(dest, &self.buf[..len]).await?;
+
+        // The parent sends the response right away to a device with the
+        // receiver on, and holds it for the poll of any other device.
+        let mut buf = [0u8; 128];
+        if self.nib().capability_information().receiver_on_when_idle() {
+            for _ in 0..REJOIN_RESPONSE_FRAMES {
+                if let MacIndication::Data { len, .. } = self.mac.receive(&mut buf).await?
+                    && let Some(response) = self.rejoin_response(parent, secure, &mut buf[..len])
+                {
+                    return Ok(response);
+                }
+            }
+        } else {
+            for _ in 0..REJOIN_RESPONSE_POLLS {
+                let len = match self.mac.poll_data(dest, &mut buf).await {
+                    Ok((len, ..)) => len,
+                    Err(MacError::NoData) => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                if let Some(response) = self.rejoin_response(parent, secure, &mut buf[..len]) {
+                    return Ok(response);
+                }
+            }
+        }
+
+        Err(MacError::NoData.into())
+    }
+
+    /// Decode `buf` as the rejoin response of `parent`.
+    fn rejoin_response(
+        &self,
+        parent: ShortAddress,
+        secure: bool,
+        buf: &mut [u8],
+    ) -> Option<RejoinResponse> {
+        let cx = self.security_context();
+        let Ok(Frame::NwkCommand(CommandFrame {
+            header,
+            command: Command::RejoinResponse(response),
+        })) = cx.decrypt_nwk_frame_in_place(buf)
+        else {
+            return None;
+        };
+        if header.source != parent {
+            return None;
+        }
+        // the answer to a secured rejoin must be secured as well
+        if secure && !header.frame_control.security_flag() {
+            log::warn!("[NLME] dropping unsecured rejoin response");
+            return None;
+        }
+        Some(response)
+    }
+
+    /// Handle a rejoin request command from the device at `source`
+    /// (§3.6.1.4.2.2).
+    ///
+    /// Returns the NLME-JOIN.indication for the next higher layer when the
+    /// device was admitted.
+    pub(super) async fn rejoin_indication(
+        &mut self,
+        source: ShortAddress,
+        source_ieee: Option<IeeeAddress>,
+        secure: bool,
+        request: RejoinRequest,
+    ) -> Result<Option<NlmeJoinIndication>, NetworkError> {
+        if !self.is_router() {
+            return Ok(None);
+        }
+        // the rejoining device is identified by its IEEE address
+        let Some(device_address) = source_ieee else {
+            return Ok(None);
+        };
+        let capability_information = CapabilityInformation(request.capability_information.0);
+
+        let (status, network_address) = self
+            .admit_rejoining_child(device_address, source, capability_information, secure)
+            .map_or(
+                (rejoin_status::PAN_AT_CAPACITY, ShortAddress(0xffff)),
+                |network_address| (rejoin_status::SUCCESS, network_address),
+            );
+        log::debug!(
+            "[NLME] rejoin request from {device_address:?} (secure: {secure}): status 0x{status:02x}"
+        );
+
+        // §3.6.1.4.2.2: the response goes to the old address of the device
+        self.send_rejoin_response(
+            source,
+            device_address,
+            RejoinResponse {
+                network_address,
+                status,
+            },
+            secure,
+        )
+        .await?;
+
+        if status != rejoin_status::SUCCESS {
+            return Ok(None);
+        }
+        self.update_beacon_payload();
+
+        Ok(Some(NlmeJoinIndication {
+            network_address,
+            extended_address: device_address,
+            capability_information,
+            rejoin_network: RejoinNetwork::NwkRejoin,
+            secure_rejoin: secure,
+        }))
+    }
+
+    /// Check the capacity, assign a network address and add the rejoining
+    /// device `device_address` to the neighbor table.
+    ///
+    /// A known child keeps its entry when it is rejected.
+    fn admit_rejoining_child(
+        &mut self,
+        device_address: IeeeAddress,
+        source: ShortAddress,
+        capability_information: CapabilityInformation,
+        secure: bool,
+    ) -> Option<ShortAddress> {
+        let previous_address = self.child_address(device_address);
+        if !self.has_child_slot(previous_address, capability_information.device_type()) {
+            return None;
+        }
+
+        // keep the current address of the device unless it is in use by
+        // another device
+        let keep_address = previous_address.is_some()
+            || self.lookup_network_address(device_address) == Some(source)
+            || (source.0 <= 0xfff7 && !self.is_address_in_use(source));
+        let network_address = if keep_address {
+            source
+        } else {
+            self.allocate_address()?
+        };
+        if let Some(previous_address) = previous_address {
+            self.remove_neighbor(previous_address);
+        }
+
+        // A secured rejoin proves the device holds the network key.
+        let relationship = if secure || self.nib().security_material_set().is_empty() {
+            relationship::CHILD
+        } else {
+            relationship::UNAUTHENTICATED_CHILD
+        };
+        self.add_child(
+            device_address,
+            network_address,
+            capability_information,
+            relationship,
+        )
+        .ok()?;
+        Some(network_address)
+    }
+
+    /// Send `response` to the child `device_address`, known under the
+    /// network address `destination`.
+    ///
+    /// A sleepy child polls for the response under its old address until it
+    /// adopts the one in `response`.
+    pub(super) async fn send_rejoin_response(
+        &mut self,
+        destination: ShortAddress,
+        device_address: IeeeAddress,
+        response: RejoinResponse,
+        secure: bool,
+    ) -> Result<(), NetworkError> {
+        let new_address = response.network_address;
+        let mut header = self.nwk_command_header(destination, 1, secure);
+        header.frame_control = header
+            .frame_control
+            .set_destination_ieee_flag(true)
+            .set_source_ieee_flag(true);
+        header.destination_ieee = Some(device_address);
+        header.source_ieee = Some(self.nib().ieee_address());
+        let len = self.build_nwk_command_frame(header, Command::RejoinResponse(response))?;
+        if new_address != destination && self.is_sleepy_child(new_address) {
+            return self.queue_indirect(destination, len);
+        }
+        self.transmit_nwk_frame(destination, len).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byte::TryRead;
+    use zigbee_mac::AssociationStatus;
+    use zigbee_mac::mlme::AssociationResponse;
+
+    use super::*;
+    use crate::nwk::frame::header::Header;
+    use crate::nwk::nib::NWK_COORDINATOR_ADDRESS;
+    use crate::nwk::nlme::NwkIndication;
+    use crate::nwk::nlme::tests::MockMlme;
+    use crate::nwk::nlme::tests::PAN_ID;
+    use crate::nwk::nlme::tests::block_on;
+    use crate::nwk::nlme::tests::command_frame;
+    use crate::nwk::nlme::tests::install_network_key;
+    use crate::nwk::nlme::tests::mac_short;
+    use crate::nwk::nlme::tests::make_neighbor;
+    use crate::nwk::nlme::tests::make_nlme;
+
+    const EPID: u64 = 0xDEAD;
+    const CHILD: IeeeAddress = IeeeAddress(0x0102_0304_0506_0708);
+
+    fn rejoin_request(security_enabled: bool) -> NlmeJoinRequest {
+        NlmeJoinRequest {
+            extended_pan_id: IeeeAddress(EPID),
+            rejoin_network: RejoinNetwork::NwkRejoin,
+            scan_channels: 11..27,
+            scan_duration: 3,
+            capability_information: CapabilityInformation(0x80),
+            security_enabled,
+        }
+    }
+
+    fn expect_poll_response(
+        nlme: &mut Nlme<MockMlme>,
+        parent: u16,
+        frame: std::vec::Vec<u8>,
+        times: usize,
+    ) {
+        nlme.mac
+            .expect_poll_data()
+            .withf(move |dest, _| *dest == Address::Short(PanId(PAN_ID), MacShortAddress(parent)))
+            .times(times)
+            .returning(move |_, buf| {
+                buf[..frame.len()].copy_from_slice(&frame);
+                Ok((frame.len(), 0xff, false))
+            });
+    }
+
+    fn response(status: u8, network_address: u16) -> Command<'static> {
+        Command::RejoinResponse(RejoinResponse {
+            network_address: ShortAddress(network_address),
+            status,
+        })
+    }
+
+    /// A device which was joined with address 0x5555 and lost its parent.
+    fn make_orphaned(mut mac: MockMlme) -> Nlme<MockMlme> {
+        mac.expect_set_channel().return_const(());
+        mac.expect_set_pan_id().return_const(());
+        mac.expect_set_short_address().return_const(());
+        let nlme = make_nlme(mac);
+        nlme.nib().set_network_address(0x5555);
+        let mut table = nlme.nib().neighbor_table();
+        table
+            .push(make_neighbor(PAN_ID, 0x0000, EPID, 200, 0))
+            .unwrap();
+        nlme.nib().set_neighbor_table(table);
+        nlme
+    }
+
+    #[test]
+    fn trust_center_rejoin() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                let (header, _) = Header::try_read(payload, ()).unwrap();
+                *dest == Address::Short(PanId(PAN_ID), MacShortAddress(0x0000))
+                    && !header.frame_control.security_flag()
+                    && header.source == ShortAddress(0x5555)
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_orphaned(mac);
+        let frame = command_frame(
+            &mut nlme,
+            0x0000,
+            NWK_COORDINATOR_ADDRESS,
+            None,
+            false,
+            response(rejoin_status::SUCCESS, 0x5555),
+        );
+        expect_poll_response(&mut nlme, 0x0000, frame, 1);
+
+        let confirm = block_on(nlme.join(rejoin_request(false)));
+
+        assert_eq!(confirm.status, NlmeJoinStatus::Success);
+        assert_eq!(confirm.network_address, ShortAddress(0x5555));
+        assert_eq!(confirm.channel, 11);
+        assert_eq!(nlme.nib().panid(), PAN_ID);
+        assert_eq!(nlme.nib().extended_panid(), EPID);
+        assert_eq!(
+            nlme.nib().neighbor_table()[0].relationship,
+            relationship::PARENT
+        );
+    }
+
+    #[test]
+    fn rejoin_with_receiver_on_waits_for_response() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data().times(1).returning(|_, _| Ok(()));
+        mac.expect_poll_data().never();
+        let mut nlme = make_orphaned(mac);
+        let frame = command_frame(
+            &mut nlme,
+            0x0000,
+            NWK_COORDINATOR_ADDRESS,
+            None,
+            false,
+            response(rejoin_status::SUCCESS, 0x5555),
+        );
+        nlme.mac.expect_receive().times(1).returning(move |buf| {
+            buf[..frame.len()].copy_from_slice(&frame);
+            Ok(MacIndication::Data {
+                source: Address::Short(PanId(PAN_ID), MacShortAddress(0x0000)),
+                destination: Address::Short(PanId(PAN_ID), MacShortAddress(0x5555)),
+                len: frame.len(),
+                lqi: 0xff,
+            })
+        });
+        let mut request = rejoin_request(false);
+        request.capability_information = request
+            .capability_information
+            .set_receiver_on_when_idle(true);
+
+        let confirm = block_on(nlme.join(request));
+
+        assert_eq!(confirm.status, NlmeJoinStatus::Success);
+        assert_eq!(confirm.network_address, ShortAddress(0x5555));
+    }
+
+    #[test]
+    fn secured_rejoin() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|_, payload| {
+                let (header, _) = Header::try_read(payload, ()).unwrap();
+                header.frame_control.security_flag() && header.source_ieee.is_some()
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_orphaned(mac);
+        install_network_key(&nlme);
+        let frame = command_frame(
+            &mut nlme,
+            0x0000,
+            NWK_COORDINATOR_ADDRESS,
+            None,
+            true,
+            response(rejoin_status::SUCCESS, 0x6666),
+        );
+        expect_poll_response(&mut nlme, 0x0000, frame, 1);
+
+        let confirm = block_on(nlme.join(rejoin_request(true)));
+
+        assert_eq!(confirm.status, NlmeJoinStatus::Success);
+        assert_eq!(confirm.network_address, ShortAddress(0x6666));
+        assert_eq!(nlme.nib().network_address(), 0x6666);
+    }
+
+    #[test]
+    fn secured_rejoin_ignores_unsecured_response() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data().returning(|_, _| Ok(()));
+        let mut nlme = make_orphaned(mac);
+        install_network_key(&nlme);
+        let frame = command_frame(
+            &mut nlme,
+            0x0000,
+            NWK_COORDINATOR_ADDRESS,
+            None,
+            false,
+            response(rejoin_status::SUCCESS, 0x6666),
+        );
+        expect_poll_response(&mut nlme, 0x0000, frame, REJOIN_RESPONSE_POLLS as usize);
+
+        let confirm = block_on(nlme.join(rejoin_request(true)));
+
+        assert_eq!(confirm.status, NlmeJoinStatus::MacError);
+        assert_eq!(nlme.nib().network_address(), 0x5555);
+    }
+
+    #[test]
+    fn secured_rejoin_without_key() {
+        let mac = MockMlme::new();
+        let mut nlme = make_orphaned(mac);
+
+        let confirm = block_on(nlme.join(rejoin_request(true)));
+
+        assert_eq!(confirm.status, NlmeJoinStatus::InvalidRequest);
+    }
+
+    #[test]
+    fn rejoin_tries_next_parent_on_failure() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data().times(2).returning(|_, _| Ok(()));
+        let mut nlme = make_orphaned(mac);
+        let mut table = nlme.nib().neighbor_table();
+        table
+            .push(make_neighbor(PAN_ID, 0x0001, EPID, 150, 1))
+            .unwrap();
+        nlme.nib().set_neighbor_table(table);
+        let at_capacity = command_frame(
+            &mut nlme,
+            0x0000,
+            NWK_COORDINATOR_ADDRESS,
+            None,
+            false,
+            response(rejoin_status::PAN_AT_CAPACITY, 0xffff),
+        );
+        let success = command_frame(
+            &mut nlme,
+            0x0001,
+            NWK_COORDINATOR_ADDRESS,
+            None,
+            false,
+            response(rejoin_status::SUCCESS, 0x5555),
+        );
+        expect_poll_response(&mut nlme, 0x0000, at_capacity, 1);
+        expect_poll_response(&mut nlme, 0x0001, success, 1);
+
+        let confirm = block_on(nlme.join(rejoin_request(false)));
+
+        assert_eq!(confirm.status, NlmeJoinStatus::Success);
+        let table = nlme.nib().neighbor_table();
+        assert_eq!(table[1].relationship, relationship::PARENT);
+        assert_eq!(table[0].relationship, 0x03);
+    }
+
+    #[test]
+    fn failed_rejoin_then_association_succeeds() {
+        let short_addresses = std::sync::Arc::new(std::sync::Mutex::new(std::vec::Vec::new()));
+        let recorded = short_addresses.clone();
+        let mut mac = MockMlme::new();
+        mac.expect_set_channel().return_const(());
+        mac.expect_set_pan_id().return_const(());
+        mac.expect_set_short_address()
+            .returning(move |address| recorded.lock().unwrap().push(address.0));
+        mac.expect_transmit_data().times(1).returning(|_, _| Ok(()));
+        mac.expect_poll_data()
+            .times(REJOIN_RESPONSE_POLLS as usize)
+            .returning(|_, _| Err(MacError::NoData));
+        mac.expect_associate().times(1).returning(|_, _, _| {
+            Ok(AssociationResponse {
+                device_address: IeeeAddress(0),
+                association_address: ShortAddress(0x4321),
+                status: AssociationStatus::Successful,
+            })
+        });
+        let mut nlme = make_nlme(mac);
+        let mut table = nlme.nib().neighbor_table();
+        table
+            .push(make_neighbor(PAN_ID, 0x0000, EPID, 200, 0))
+            .unwrap();
+        nlme.nib().set_neighbor_table(table);
+
+        let confirm = block_on(nlme.join(rejoin_request(false)));
+
+        assert_eq!(confirm.status, NlmeJoinStatus::MacError);
+        assert_eq!(nlme.nib().network_address(), 0xffff);
+        assert_eq!(nlme.nib().capability_information().0, 0);
+        assert_eq!(short_addresses.lock().unwrap().last(), Some(&0xffff));
+
+        let mut request = rejoin_request(false);
+        request.rejoin_network = RejoinNetwork::Association;
+        let confirm = block_on(nlme.join(request));
+
+        assert_eq!(confirm.status, NlmeJoinStatus::Success);
+        assert_eq!(confirm.network_address, ShortAddress(0x4321));
+        assert_eq!(nlme.nib().network_address(), 0x4321);
+        assert_eq!(nlme.nib().panid(), PAN_ID);
+    }
+
+    /// A coordinator receiving a rejoin request for `source` from `CHILD`.
+    fn make_parent(
+        mut mac: MockMlme,
+        source: u16,
+        secure: bool,
+        capability_information: u8,
+    ) -> Nlme<MockMlme> {
+        mac.expect_set_beacon_payload().return_const(());
+        let mut nlme = make_nlme(mac);
+        nlme.nib().set_network_address(NWK_COORDINATOR_ADDRESS);
+        nlme.nib().set_panid(PAN_ID);
+        nlme.nib()
+            .set_ieee_address(IeeeAddress(0x0011_2233_4455_6677));
+        install_network_key(&nlme);
+        let frame = command_frame(
+            &mut nlme,
+            source,
+            NWK_COORDINATOR_ADDRESS,
+            Some(CHILD),
+            secure,
+            Command::RejoinRequest(RejoinRequest {
+                capability_information: RejoinCapabilityInformation(capability_information),
+            }),
+        );
+        nlme.mac.expect_receive().times(1).returning(move |buf| {
+            buf[..frame.len()].copy_from_slice(&frame);
+            Ok(MacIndication::Data {
+                source: Address::Short(PanId(PAN_ID), MacShortAddress(source)),
+                destination: Address::Short(PanId(PAN_ID), MacShortAddress(0x0000)),
+                len: frame.len(),
+                lqi: 0xff,
+            })
+        });
+        nlme
+    }
+
+    #[test]
+    fn parent_accepts_secured_rejoin() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_indirect()
+            .withf(|dest, payload, _| {
+                let (header, _) = Header::try_read(payload, ()).unwrap();
+                *dest == Address::Short(PanId(PAN_ID), MacShortAddress(0x4242))
+                    && header.destination == ShortAddress(0x4242)
+                    && header.destination_ieee == Some(CHILD)
+                    && header.frame_control.security_flag()
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        mac.expect_set_frame_pending().return_const(());
+        let mut nlme = make_parent(mac, 0x4242, true, 0x80);
+
+        let indication = block_on(nlme.receive()).unwrap();
+        // the sleepy child polls for its rejoin response
+        block_on(nlme.indirect_poll_indication(mac_short(0x4242)));
+
+        let Some(NwkIndication::Join(indication)) = indication else {
+            unreachable!("expected a join indication");
+        };
+        assert_eq!(
+            indication,
+            NlmeJoinIndication {
+                network_address: ShortAddress(0x4242),
+                extended_address: CHILD,
+                capability_information: CapabilityInformation(0x80),
+                rejoin_network: RejoinNetwork::NwkRejoin,
+                secure_rejoin: true,
+            }
+        );
+        let table = nlme.nib().neighbor_table();
+        assert_eq!(table.len(), 1);
+        assert_eq!(table[0].relationship, relationship::CHILD);
+        assert_eq!(
+            nlme.lookup_network_address(CHILD),
+            Some(ShortAddress(0x4242))
+        );
+    }
+
+    #[test]
+    fn parent_accepts_unsecured_rejoin_as_unauthenticated_child() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_indirect()
+            .withf(|_, payload, _| {
+                let (header, _) = Header::try_read(payload, ()).unwrap();
+                !header.frame_control.security_flag()
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        mac.expect_set_frame_pending().return_const(());
+        let mut nlme = make_parent(mac, 0x4242, false, 0x80);
+
+        let indication = block_on(nlme.receive()).unwrap();
+        block_on(nlme.indirect_poll_indication(mac_short(0x4242)));
+
+        assert!(matches!(
+            indication,
+            Some(NwkIndication::Join(NlmeJoinIndication {
+                secure_rejoin: false,
+                ..
+            }))
+        ));
+        let table = nlme.nib().neighbor_table();
+        assert_eq!(table[0].relationship, relationship::UNAUTHENTICATED_CHILD);
+    }
+
+    #[test]
+    fn parent_assigns_new_address_on_conflict() {
+        let mut mac = MockMlme::new();
+        // the child polls for the response under its old address
+        mac.expect_transmit_indirect()
+            .withf(|dest, payload, _| {
+                let (header, _) = Header::try_read(payload, ()).unwrap();
+                *dest == Address::Short(PanId(PAN_ID), MacShortAddress(0x4242))
+                    && header.destination == ShortAddress(0x4242)
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        mac.expect_set_frame_pending().return_const(());
+        let mut nlme = make_parent(mac, 0x4242, true, 0x80);
+        nlme.update_address_map(IeeeAddress(0xAA), ShortAddress(0x4242));
+
+        let Some(NwkIndication::Join(indication)) = block_on(nlme.receive()).unwrap() else {
+            unreachable!("expected a join indication");
+        };
+        block_on(nlme.indirect_poll_indication(mac_short(0x4242)));
+
+        assert_ne!(indication.network_address, ShortAddress(0x4242));
+        assert_eq!(
+            nlme.lookup_network_address(CHILD),
+            Some(indication.network_address)
+        );
+    }
+
+    #[test]
+    fn child_rejoining_as_router_at_capacity_keeps_its_entry() {
+        let mut mac = MockMlme::new();
+        // the PAN at capacity rejoin response
+        mac.expect_set_frame_pending().return_const(());
+        let mut nlme = make_parent(mac, 0x4242, true, 0x82);
+        nlme.add_child(
+            CHILD,
+            ShortAddress(0x4242),
+            CapabilityInformation(0x80),
+            relationship::CHILD,
+        )
+        .unwrap();
+        nlme.nib().set_max_routers(0);
+
+        let indication = block_on(nlme.receive()).unwrap();
+
+        assert!(indication.is_none());
+        assert_eq!(nlme.child_address(CHILD), Some(ShortAddress(0x4242)));
+        assert!(nlme.is_end_device_child(ShortAddress(0x4242)));
+    }
+}
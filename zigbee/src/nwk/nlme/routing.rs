@@ -0,0 +1,1084 @@
+//! Mesh route discovery
+//!
+//! Routers discover routes on demand by broadcasting a route request, the
+//! destination answers with a route reply which travels back along the
+//! cheapest path (§3.6.3.5).
+
+use heapless::Vec;
+use zigbee_mac::Address;
+use zigbee_mac::mlme::Mlme;
+use zigbee_types::ShortAddress;
+
+use super::NetworkError;
+use super::Nlme;
+use super::management::NlmeRouteDiscoveryConfirm;
+use super::management::NlmeRouteDiscoveryRequest;
+use super::management::NwkStatus;
+use super::management::RouteDiscoveryAddressMode;
+use crate::nwk::frame::command::Command as NwkCommand;
+use crate::nwk::frame::command::network_status::NetworkStatusCode;
+use crate::nwk::frame::command::route_reply::CommandOptions as RouteReplyOptions;
+use crate::nwk::frame::command::route_reply::RouteReply;
+use crate::nwk::frame::command::route_request::CommandOptions as RouteRequestOptions;
+use crate::nwk::frame::command::route_request::RouteRequest;
+use crate::nwk::frame::command::route_request::many_to_one;
+use crate::nwk::frame::header::Header as NwkHeader;
+use crate::nwk::nib::DeviceType;
+use crate::nwk::nib::NWKC_INITIAL_RREQ_RETRIES;
+use crate::nwk::nib::NWKC_MAX_RREQ_JITTER;
+use crate::nwk::nib::NWKC_MIN_RREQ_JITTER;
+use crate::nwk::nib::NWKC_ROUTE_DISCOVERY_TIME;
+use crate::nwk::nib::NWKC_RREQ_RETRIES;
+use crate::nwk::nib::NWKC_RREQ_RETRY_INTERVAL;
+use crate::nwk::nib::NwkRoute;
+use crate::nwk::nib::RouteStatus;
+use crate::nwk::nib::broadcast_address;
+use crate::nwk::nib::octets_to_ms;
+use crate::nwk::nib::relationship;
+
+// implementation specific
+
+const MAX_ROUTE_DISCOVERY_TABLE: usize = 4;
+const MAX_PENDING_ROUTE_REQUESTS: usize = 4;
+const MAX_PENDING_FRAMES: usize = 2;
+const MAX_PENDING_FRAME_LEN: usize = 128;
+/// Active routes which were not used for this long are removed from the
+/// route table.
+const ROUTE_EXPIRY_TIME_MS: u32 = 300_000;
+
+/// Route discovery table entry (Table 3-69).
+#[derive(Debug)]
+struct RouteDiscovery {
+    route_request_id: u8,
+    /// Originator of the route request.
+    source_address: ShortAddress,
+    /// Neighbor the cheapest route request was received from, the route
+    /// reply is sent back to it.
+    sender_address: ShortAddress,
+    destination_address: ShortAddress,
+    /// Path cost from the originator to this device.
+    forward_cost: u8,
+    /// Path cost from this device to the destination.
+    residual_cost: u8,
+    /// Remaining lifetime in milliseconds.
+    expiration: u32,
+}
+
+/// A route request waiting for its next broadcast.
+#[derive(Debug, Clone)]
+struct PendingRouteRequest {
+    source: ShortAddress,
+    sequence_number: u8,
+    radius: u8,
+    request: RouteRequest,
+    /// Remaining broadcasts of the route request.
+    transmissions: u8,
+    /// Milliseconds until the next broadcast.
+    due: u32,
+}
+
+/// A data frame waiting for a route to its destination.
+struct PendingFrame {
+    /// Originator of the frame, this device or the source of a relayed
+    /// frame.
+    source: ShortAddress,
+    destination: ShortAddress,
+    len: usize,
+    frame: [u8; MAX_PENDING_FRAME_LEN],
+}
+
+/// Route discovery state of a router.
+pub(super) struct Routing {
+    route_request_id: u8,
+    discovery_table: Vec<RouteDiscovery, MAX_ROUTE_DISCOVERY_TABLE>,
+    pending_requests: Vec<PendingRouteRequest, MAX_PENDING_ROUTE_REQUESTS>,
+    pending_frames: Vec<PendingFrame, MAX_PENDING_FRAMES>,
+    /// Milliseconds until a concentrator sends its next many-to-one route
+    /// request.
+    many_to_one_due: u32,
+}
+
+impl Routing {
+    pub(super) const fn new() -> Self {
+        Self {
+            route_request_id: 0,
+            discovery_table: Vec::new(),
+            pending_requests: Vec::new(),
+            pending_frames: Vec::new(),
+            many_to_one_due: 0,
+        }
+    }
+
+    /// Route discovery table entry of the route request `route_request_id`
+    /// originated by `source`.
+    fn discovery_mut(
+        &mut self,
+        route_request_id: u8,
+        source: ShortAddress,
+    ) -> Option<&mut RouteDiscovery> {
+        self.discovery_table
+            .iter_mut()
+            .find(|e| e.route_request_id == route_request_id && e.source_address == source)
+    }
+}
+
+impl<M> Nlme<M>
+where
+    M: Mlme,
+{
+    /// 3.2.2.31 - NLME-ROUTE-DISCOVERY.request
+    ///
+    /// The confirm reports whether the discovery was started, the route
+    /// becomes active in the route table once a route reply is received.
+    pub async fn route_discovery(
+        &mut self,
+        request: NlmeRouteDiscoveryRequest,
+    ) -> NlmeRouteDiscoveryConfirm {
+        let confirm = |status, network_status_code| NlmeRouteDiscoveryConfirm {
+            status,
+            network_status_code,
+        };
+
+        if !self.is_router() {
+            return confirm(NwkStatus::InvalidRequest, None);
+        }
+        let result = match request.destination_address_mode {
+            RouteDiscoveryAddressMode::NoAddress => {
+                self.start_many_to_one_discovery(request.radius, request.no_route_cache)
+                    .await
+            }
+            RouteDiscoveryAddressMode::Network
+                if request.destination_address.0 < broadcast_address::MIN =>
+            {
+                self.start_route_discovery(request.destination_address, request.radius)
+                    .await
+            }
+            // TODO: multicast route discovery
+            _ => return confirm(NwkStatus::InvalidParameter, None),
+        };
+
+        match result {
+            Ok(()) => confirm(NwkStatus::Success, None),
+            Err(NetworkError::NoRoutingCapacity) => confirm(
+                NwkStatus::RouteError,
+                Some(NetworkStatusCode::NoRoutingCapacity),
+            ),
+            Err(NetworkError::MacError(_)) => confirm(NwkStatus::MacError, None),
+            Err(e) => {
+                log::debug!("[NLME] route discovery failed: {e}");
+                confirm(NwkStatus::RouteError, None)
+            }
+        }
+    }
+
+    /// Next hop towards `destination`: the destination itself when it is a
+    /// neighbor, otherwise the next hop of an active route.
+    pub(super) fn next_hop(&self, destination: ShortAddress) -> Option<ShortAddress> {
+        let is_neighbor = self.nib().neighbor_table().iter().any(|n| {
+            n.network_address == destination
+                && matches!(
+                    n.relationship,
+                    relationship::PARENT
+                        | relationship::CHILD
+                        | relationship::SIBLING
+                        | relationship::UNAUTHENTICATED_CHILD
+                )
+        });
+        if is_neighbor {
+            return Some(destination);
+        }
+
+        let mut table = self.nib().route_table();
+        let route = table
+            .iter_mut()
+            .find(|r| r.destination_address == destination && r.status() == RouteStatus::Active)?;
+        route.age = 0;
+        let next_hop = route.next_hop_address;
+        self.nib().set_route_table(table);
+        Some(next_hop)
+    }
+
+    /// Hold the frame from `source` in `self.buf` until a route to
+    /// `destination` is found, starting a route discovery unless one is
+    /// underway.
+    pub(super) async fn route_data_frame(
+        &mut self,
+        source: ShortAddress,
+        destination: ShortAddress,
+        len: usize,
+    ) -> Result<(), NetworkError> {
+        if self.routing.pending_frames.is_full() || len > MAX_PENDING_FRAME_LEN {
+            return Err(NetworkError::NoRoutingCapacity);
+        }
+        // the route request is built in `self.buf` as well
+        let mut frame = PendingFrame {
+            source,
+            destination,
+            len,
+            frame: [0u8; MAX_PENDING_FRAME_LEN],
+        };
+        frame.frame[..len].copy_from_slice(&self.buf[..len]);
+
+        let underway = self
+            .routing
+            .discovery_table
+            .iter()
+            .any(|e| e.destination_address == destination);
+        if !underway {
+            self.start_route_discovery(destination, 0).await?;
+        }
+        // capacity was checked above
+        let _ = self.routing.pending_frames.push(frame);
+        Ok(())
+    }
+
+    /// Originate a route request for `destination` (§3.6.3.5.1).
+    async fn start_route_discovery(
+        &mut self,
+        destination: ShortAddress,
+        radius: u8,
+    ) -> Result<(), NetworkError> {
+        if self.routing.discovery_table.is_full()
+            || self.routing.pending_requests.is_full()
+            || !self.set_route_underway(destination)
+        {
+            return Err(NetworkError::NoRoutingCapacity);
+        }
+
+        let own_address = ShortAddress(self.nib().network_address());
+        self.routing.route_request_id = self.routing.route_request_id.wrapping_add(1);
+        let route_request_id = self.routing.route_request_id;
+        let _ = self.routing.discovery_table.push(RouteDiscovery {
+            route_request_id,
+            source_address: own_address,
+            sender_address: own_address,
+            destination_address: destination,
+            forward_cost: 0,
+            residual_cost: 0xff,
+            expiration: octets_to_ms(NWKC_ROUTE_DISCOVERY_TIME),
+        });
+
+        let radius = if radius == 0 {
+            self.nib().max_depth().saturating_mul(2)
+        } else {
+            radius
+        };
+        let pending = PendingRouteRequest {
+            source: own_address,
+            sequence_number: self.next_nwk_seq(),
+            radius,
+            request: RouteRequest {
+                command_options: RouteRequestOptions(0),
+                route_request_id,
+                destination_address: destination,
+                path_cost: 0,
+                destination_ieee_address: None,
+            },
+            transmissions: NWKC_INITIAL_RREQ_RETRIES,
+            due: octets_to_ms(NWKC_RREQ_RETRY_INTERVAL),
+        };
+        log::debug!(
+            "[NLME] route discovery {route_request_id} for 0x{:04x}",
+            destination.0
+        );
+        self.send_route_request(&pending).await?;
+        let _ = self.routing.pending_requests.push(pending);
+
+        Ok(())
+    }
+
+    /// Broadcast a many-to-one route request making this device a
+    /// concentrator for all routers within `radius` (§3.6.3.5.1).
+    ///
+    /// With `no_route_cache` the other devices do not send route records.
+    async fn start_many_to_one_discovery(
+        &mut self,
+        radius: u8,
+        no_route_cache: bool,
+    ) -> Result<(), NetworkError> {
+        if self.routing.pending_requests.is_full() {
+            return Err(NetworkError::NoRoutingCapacity);
+        }
+
+        self.routing.route_request_id = self.routing.route_request_id.wrapping_add(1);
+        let route_request_id = self.routing.route_request_id;
+        let radius = if radius == 0 {
+            self.nib().max_depth().saturating_mul(2)
+        } else {
+            radius
+        };
+        let mode = if no_route_cache {
+            many_to_one::NO_ROUTE_CACHE
+        } else {
+            many_to_one::ROUTE_RECORD_TABLE
+        };
+        let pending = PendingRouteRequest {
+            source: ShortAddress(self.nib().network_address()),
+            sequence_number: self.next_nwk_seq(),
+            radius,
+            request: RouteRequest {
+                command_options: RouteRequestOptions(0).set_many_to_one(mode),
+                route_request_id,
+                destination_address: ShortAddress(broadcast_address::ROUTERS),
+                path_cost: 0,
+                destination_ieee_address: None,
+            },
+            transmissions: NWKC_INITIAL_RREQ_RETRIES,
+            due: octets_to_ms(NWKC_RREQ_RETRY_INTERVAL),
+        };
+        log::debug!("[NLME] many-to-one route discovery {route_request_id}");
+        self.send_route_request(&pending).await?;
+        let _ = self.routing.pending_requests.push(pending);
+
+        Ok(())
+    }
+
+    /// Mark the route to `destination` as being discovered, keeping an
+    /// active route until a better one is found.
+    ///
+    /// Returns `false` if the route table is full.
+    fn set_route_underway(&self, destination: ShortAddress) -> bool {
+        let mut table = self.nib().route_table();
+        if table.iter().any(|r| r.destination_address == destination) {
+            for route in table.iter_mut() {
+                if route.destination_address == destination && route.status() != RouteStatus::Active
+                {
+                    route.set_status(RouteStatus::DiscoveryUnderway);
+                }
+            }
+        } else if table
+            .push(NwkRoute::new(
+                destination,
+                ShortAddress(broadcast_address::ALL_DEVICES),
+                RouteStatus::DiscoveryUnderway,
+            ))
+            .is_err()
+        {
+            return false;
+        }
+        self.nib().set_route_table(table);
+        true
+    }
+
+    /// Activate the route to `destination` via `next_hop`.
+    ///
+    /// Returns `false` if the route table is full.
+    fn set_route_active(&self, destination: ShortAddress, next_hop: ShortAddress) -> bool {
+        let mut table = self.nib().route_table();
+        if let Some(route) = table
+            .iter_mut()
+            .find(|r| r.destination_address == destination)
+        {
+            route.next_hop_address = next_hop;
+            route.set_status(RouteStatus::Active);
+            route.age = 0;
+        } else if table
+            .push(NwkRoute::new(destination, next_hop, RouteStatus::Active))
+            .is_err()
+        {
+            return false;
+        }
+        self.nib().set_route_table(table);
+        true
+    }
+
+    /// Forget the route to `destination`.
+    pub(super) fn remove_route(&self, destination: ShortAddress) {
+        let mut table = self.nib().route_table();
+        let len = table.len();
+        table.retain(|r| r.destination_address != destination);
+        if table.len() != len {
+            self.nib().set_route_table(table);
+        }
+    }
+
+    /// Broadcast a route request to all routers.
+    async fn send_route_request(
+        &mut self,
+        pending: &PendingRouteRequest,
+    ) -> Result<(), NetworkError> {
+        let secure = self.nwk_security_enabled();
+        let header = NwkHeader {
+            frame_control: Self::nwk_command_frame_control(secure),
+            destination: ShortAddress(broadcast_address::ROUTERS),
+            source: pending.source,
+            radius: pending.radius,
+            sequence_number: pending.sequence_number,
+            destination_ieee: None,
+            source_ieee: None,
+            multicast_control: None,
+            source_route_subframe: None,
+        };
+        let len = self
+            .build_nwk_command_frame(header, NwkCommand::RouteRequest(pending.request.clone()))?;
+        self.transmit_nwk_frame(ShortAddress(broadcast_address::ALL_DEVICES), len)
+            .await
+    }
+
+    /// Unicast a route reply to the neighbor `next_hop`.
+    async fn send_route_reply(
+        &mut self,
+        next_hop: ShortAddress,
+        reply: RouteReply,
+    ) -> Result<(), NetworkError> {
+        let secure = self.nwk_security_enabled();
+        let radius = self.nib().max_depth().saturating_mul(2);
+        let header = self.nwk_command_header(next_hop, radius, secure);
+        let len = self.build_nwk_command_frame(header, NwkCommand::RouteReply(reply))?;
+        self.transmit_nwk_frame(next_hop, len).await
+    }
+
+    /// Handle a received route request (§3.6.3.5.2).
+    ///
+    /// `source` is the MAC source of the frame, `lqi` the link quality it
+    /// was received with.
+    pub(super) async fn route_request_indication(
+        &mut self,
+        source: Address,
+        lqi: u8,
+        header: &NwkHeader<'_>,
+        mut request: RouteRequest,
+    ) -> Result<(), NetworkError> {
+        let Address::Short(_, sender) = source else {
+            return Ok(());
+        };
+        let sender = ShortAddress(sender.0);
+        let own_address = ShortAddress(self.nib().network_address());
+        if !self.is_router() || header.source == own_address {
+            return Ok(());
+        }
+        if request.command_options.multicast() {
+            log::debug!("[NLME] ignoring multicast route request");
+            return Ok(());
+        }
+
+        let path_cost = request
+            .path_cost
+            .saturating_add(self.link_cost(sender, lqi));
+        let route_request_id = request.route_request_id;
+        let destination = request.destination_address;
+        if let Some(entry) = self.routing.discovery_mut(route_request_id, header.source) {
+            if path_cost >= entry.forward_cost {
+                return Ok(());
+            }
+            entry.forward_cost = path_cost;
+            entry.sender_address = sender;
+        } else if self
+            .routing
+            .discovery_table
+            .push(RouteDiscovery {
+                route_request_id,
+                source_address: header.source,
+                sender_address: sender,
+                destination_address: destination,
+                forward_cost: path_cost,
+                residual_cost: 0xff,
+                expiration: octets_to_ms(NWKC_ROUTE_DISCOVERY_TIME),
+            })
+            .is_err()
+        {
+            log::debug!("[NLME] route discovery table full, dropping route request");
+            return Ok(());
+        }
+
+        let mode = request.command_options.many_to_one();
+        if mode != many_to_one::NONE {
+            // a many-to-one route request is never answered, the route to
+            // the concentrator is known right away
+            self.set_many_to_one_route(header.source, sender, mode);
+            if header.radius <= 1 {
+                return Ok(());
+            }
+        } else if destination == own_address || self.is_end_device_child(destination) {
+            if self.nib().sym_link() {
+                // the route is used in both directions
+                self.set_route_active(header.source, sender);
+            }
+            let reply = RouteReply {
+                command_options: RouteReplyOptions(0),
+                route_request_id,
+                originator_address: header.source,
+                responder_address: destination,
+                path_cost: 0,
+                originator_ieee_address: None,
+                responder_ieee_address: None,
+            };
+            return self.send_route_reply(sender, reply).await;
+        } else if header.radius <= 1 || !self.set_route_underway(destination) {
+            return Ok(());
+        }
+        request.path_cost = path_cost;
+        if let Some(pending) =
+            self.routing.pending_requests.iter_mut().find(|p| {
+                p.source == header.source && p.request.route_request_id == route_request_id
+            })
+        {
+            // relay the cheaper path with the scheduled broadcast
+            pending.request = request;
+            return Ok(());
+        }
+        let due = self.rreq_jitter();
+        let pending = PendingRouteRequest {
+            source: header.source,
+            sequence_number: header.sequence_number,
+            radius: header.radius - 1,
+            request,
+            transmissions: NWKC_RREQ_RETRIES + 1,
+            due,
+        };
+        if self.routing.pending_requests.push(pending).is_err() {
+            log::debug!("[NLME] too many pending route requests, not relaying");
+        }
+
+        Ok(())
+    }
+
+    /// Handle a received route reply (§3.6.3.5.3).
+    pub(super) async fn route_reply_indication(
+        &mut self,
+        source: Address,
+        lqi: u8,
+        mut reply: RouteReply,
+    ) -> Result<(), NetworkError> {
+        let Address::Short(_, sender) = source else {
+            return Ok(());
+        };
+        let sender = ShortAddress(sender.0);
+        if !self.is_router() {
+            return Ok(());
+        }
+
+        let path_cost = reply.path_cost.saturating_add(self.link_cost(sender, lqi));
+        let Some(entry) = self
+            .routing
+            .discovery_mut(reply.route_request_id, reply.originator_address)
+        else {
+            log::debug!(
+                "[NLME] no route discovery {} from 0x{:04x}",
+                reply.route_request_id,
+                reply.originator_address.0
+            );
+            return Ok(());
+        };
+        if path_cost >= entry.residual_cost {
+            return Ok(());
+        }
+        entry.residual_cost = path_cost;
+        let previous_hop = entry.sender_address;
+
+        let responder = reply.responder_address;
+        if !self.set_route_active(responder, sender) {
+            log::warn!(
+                "[NLME] route table full, route to 0x{:04x} lost",
+                responder.0
+            );
+            return Ok(());
+        }
+
+        if reply.originator_address.0 != self.nib().network_address() {
+            if self.nib().sym_link() {
+                self.set_route_active(reply.originator_address, previous_hop);
+            }
+            reply.path_cost = path_cost;
+            return self.send_route_reply(previous_hop, reply).await;
+        }
+
+        log::debug!(
+            "[NLME] route to 0x{:04x} via 0x{:04x}, cost {path_cost}",
+            responder.0,
+            sender.0
+        );
+        let route_request_id = reply.route_request_id;
+        let own_address = reply.originator_address;
+        self.routing
+            .pending_requests
+            .retain(|p| p.source != own_address || p.request.route_request_id != route_request_id);
+        while let Some(idx) = self
+            .routing
+            .pending_frames
+            .iter()
+            .position(|f| f.destination == responder)
+        {
+            let frame = self.routing.pending_frames.swap_remove(idx);
+            self.buf[..frame.len].copy_from_slice(&frame.frame[..frame.len]);
+            self.transmit_nwk_frame(sender, frame.len).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Advance the route discovery timers, broadcast due route requests and
+    /// expire unused routes.
+    pub(super) async fn routing_tick(&mut self, elapsed_ms: u32) {
+        self.concentrator_tick(elapsed_ms).await;
+
+        let mut expired: Vec<ShortAddress, MAX_ROUTE_DISCOVERY_TABLE> = Vec::new();
+        self.routing.discovery_table.retain_mut(|e| {
+            e.expiration = e.expiration.saturating_sub(elapsed_ms);
+            if e.expiration == 0 {
+                let _ = expired.push(e.destination_address);
+            }
+            e.expiration > 0
+        });
+        for destination in expired {
+            self.route_discovery_expired(destination).await;
+        }
+
+        let mut due: Vec<PendingRouteRequest, MAX_PENDING_ROUTE_REQUESTS> = Vec::new();
+        self.routing.pending_requests.retain_mut(|p| {
+            p.due = p.due.saturating_sub(elapsed_ms);
+            if p.due > 0 {
+                return true;
+            }
+            let _ = due.push(p.clone());
+            p.transmissions -= 1;
+            p.due = octets_to_ms(NWKC_RREQ_RETRY_INTERVAL);
+            p.transmissions > 0
+        });
+        for pending in &due {
+            if let Err(e) = self.send_route_request(pending).await {
+                log::debug!("[NLME] failed to broadcast route request: {e}");
+            }
+        }
+
+        let mut table = self.nib().route_table();
+        if table.is_empty() {
+            return;
+        }
+        table.retain_mut(|r| {
+            if r.status() != RouteStatus::Active {
+                return true;
+            }
+            r.age = r.age.saturating_add(elapsed_ms);
+            if r.age < ROUTE_EXPIRY_TIME_MS {
+                return true;
+            }
+            log::debug!("[NLME] route to 0x{:04x} expired", r.destination_address.0);
+            false
+        });
+        self.nib().set_route_table(table);
+    }
+
+    /// Repeat the many-to-one route request of a concentrator every
+    /// nwkConcentratorDiscoveryTime seconds.
+    async fn concentrator_tick(&mut self, elapsed_ms: u32) {
+        let nib = &self.nib;
+        if !nib.is_concentrator() || nib.concentrator_discovery_time() == 0 || !self.is_router() {
+            return;
+        }
+        self.routing.many_to_one_due = self.routing.many_to_one_due.saturating_sub(elapsed_ms);
+        if self.routing.many_to_one_due > 0 {
+            return;
+        }
+        self.routing.many_to_one_due = u32::from(nib.concentrator_discovery_time()) * 1000;
+        if let Err(e) = self
+            .start_many_to_one_discovery(nib.concentrator_radius(), false)
+            .await
+        {
+            log::debug!("[NLME] many-to-one route discovery failed: {e}");
+        }
+    }
+
+    /// Clean up after the last route discovery for `destination` timed out,
+    /// the originators of relayed frames are told that there is no route.
+    async fn route_discovery_expired(&mut self, destination: ShortAddress) {
+        if self
+            .routing
+            .discovery_table
+            .iter()
+            .any(|e| e.destination_address == destination)
+        {
+            return;
+        }
+
+        let mut table = self.nib().route_table();
+        let len = table.len();
+        table.retain(|r| r.destination_address != destination || r.status() == RouteStatus::Active);
+        if table.len() != len {
+            log::debug!("[NLME] route discovery for 0x{:04x} failed", destination.0);
+            self.nib().set_route_table(table);
+        }
+
+        let own_address = ShortAddress(self.nib().network_address());
+        while let Some(idx) = self
+            .routing
+            .pending_frames
+            .iter()
+            .position(|f| f.destination == destination)
+        {
+            let frame = self.routing.pending_frames.swap_remove(idx);
+            if frame.source != own_address {
+                self.report_network_status(
+                    frame.source,
+                    NetworkStatusCode::NoRouteAvailable,
+                    destination,
+                )
+                .await;
+            }
+        }
+    }
+
+    pub(super) fn is_end_device_child(&self, address: ShortAddress) -> bool {
+        self.nib().neighbor_table().iter().any(|n| {
+            n.network_address == address
+                && matches!(n.device_type, DeviceType::EndDevice)
+                && matches!(
+                    n.relationship,
+                    relationship::CHILD | relationship::UNAUTHENTICATED_CHILD
+                )
+        })
+    }
+
+    /// Random route request jitter in milliseconds.
+    fn rreq_jitter(&mut self) -> u32 {
+        let min = octets_to_ms(NWKC_MIN_RREQ_JITTER);
+        let max = octets_to_ms(NWKC_MAX_RREQ_JITTER);
+        min + self.next_random() % (max - min + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byte::TryRead;
+
+    use super::*;
+    use crate::nwk::nlme::tests::MockMlme;
+    use crate::nwk::nlme::tests::OWN;
+    use crate::nwk::nlme::tests::PAN_ID;
+    use crate::nwk::nlme::tests::block_on;
+    use crate::nwk::nlme::tests::expect_receive;
+    use crate::nwk::nlme::tests::mac_short;
+    use crate::nwk::nlme::tests::make_neighbor;
+    use crate::nwk::nlme::tests::make_nlme;
+    use crate::nwk::nlme::tests::make_router;
+    use crate::nwk::nlme::tests::parse_command;
+
+    const NEIGHBOR: u16 = 0x2222;
+    const DESTINATION: u16 = 0x3333;
+    const ORIGINATOR: u16 = 0x4444;
+
+    fn is_route_request(payload: &[u8], source: u16, path_cost: u8) -> bool {
+        let (header, command) = parse_command(payload);
+        matches!(command, NwkCommand::RouteRequest(r)
+            if r.destination_address == ShortAddress(DESTINATION) && r.path_cost == path_cost)
+            && header.destination == ShortAddress(broadcast_address::ROUTERS)
+            && header.source == ShortAddress(source)
+    }
+
+    fn route_request_frame(
+        nlme: &mut Nlme<MockMlme>,
+        destination: u16,
+        radius: u8,
+        path_cost: u8,
+    ) -> std::vec::Vec<u8> {
+        let pending = PendingRouteRequest {
+            source: ShortAddress(ORIGINATOR),
+            sequence_number: 7,
+            radius,
+            request: RouteRequest {
+                command_options: RouteRequestOptions(0),
+                route_request_id: 9,
+                destination_address: ShortAddress(destination),
+                path_cost,
+                destination_ieee_address: None,
+            },
+            transmissions: 1,
+            due: 0,
+        };
+        let header = NwkHeader {
+            frame_control: Nlme::<MockMlme>::nwk_command_frame_control(false),
+            destination: ShortAddress(broadcast_address::ROUTERS),
+            source: pending.source,
+            radius: pending.radius,
+            sequence_number: pending.sequence_number,
+            destination_ieee: None,
+            source_ieee: None,
+            multicast_control: None,
+            source_route_subframe: None,
+        };
+        let len = nlme
+            .build_nwk_command_frame(header, NwkCommand::RouteRequest(pending.request))
+            .unwrap();
+        nlme.buf[..len].to_vec()
+    }
+
+    fn route_reply_frame(
+        nlme: &mut Nlme<MockMlme>,
+        route_request_id: u8,
+        originator: u16,
+        path_cost: u8,
+    ) -> std::vec::Vec<u8> {
+        let header = nlme.nwk_command_header(ShortAddress(OWN), 30, false);
+        let reply = RouteReply {
+            command_options: RouteReplyOptions(0),
+            route_request_id,
+            originator_address: ShortAddress(originator),
+            responder_address: ShortAddress(DESTINATION),
+            path_cost,
+            originator_ieee_address: None,
+            responder_ieee_address: None,
+        };
+        let len = nlme
+            .build_nwk_command_frame(header, NwkCommand::RouteReply(reply))
+            .unwrap();
+        nlme.buf[..len].to_vec()
+    }
+
+    fn route(nlme: &Nlme<MockMlme>, destination: u16) -> Option<(u16, RouteStatus)> {
+        nlme.nib()
+            .route_table()
+            .iter()
+            .find(|r| r.destination_address == ShortAddress(destination))
+            .map(|r| (r.next_hop_address.0, r.status()))
+    }
+
+    fn discovery_request() -> NlmeRouteDiscoveryRequest {
+        NlmeRouteDiscoveryRequest {
+            destination_address_mode: RouteDiscoveryAddressMode::Network,
+            destination_address: ShortAddress(DESTINATION),
+            radius: 0,
+            no_route_cache: false,
+        }
+    }
+
+    #[test]
+    fn route_discovery_broadcasts_and_retries() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                *dest == mac_short(broadcast_address::ALL_DEVICES)
+                    && is_route_request(payload, OWN, 0)
+            })
+            .times(1 + NWKC_INITIAL_RREQ_RETRIES as usize)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_router(mac);
+
+        let confirm = block_on(nlme.route_discovery(discovery_request()));
+
+        assert_eq!(confirm.status, NwkStatus::Success);
+        assert_eq!(
+            route(&nlme, DESTINATION),
+            Some((0xffff, RouteStatus::DiscoveryUnderway))
+        );
+        for _ in 0..=NWKC_INITIAL_RREQ_RETRIES {
+            block_on(nlme.tick(octets_to_ms(NWKC_RREQ_RETRY_INTERVAL)));
+        }
+        assert!(nlme.routing.pending_requests.is_empty());
+    }
+
+    #[test]
+    fn route_discovery_requires_router() {
+        let mut nlme = make_nlme(MockMlme::new());
+        nlme.nib().set_network_address(0x5555);
+
+        let confirm = block_on(nlme.route_discovery(discovery_request()));
+
+        assert_eq!(confirm.status, NwkStatus::InvalidRequest);
+    }
+
+    #[test]
+    fn route_discovery_without_capacity() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data().returning(|_, _| Ok(()));
+        let mut nlme = make_router(mac);
+        let mut table = nlme.nib().route_table();
+        for destination in (0x0100..).take(table.capacity()) {
+            table
+                .push(NwkRoute::new(
+                    ShortAddress(destination),
+                    ShortAddress(NEIGHBOR),
+                    RouteStatus::Active,
+                ))
+                .unwrap();
+        }
+        nlme.nib().set_route_table(table);
+
+        let confirm = block_on(nlme.route_discovery(discovery_request()));
+
+        assert_eq!(confirm.status, NwkStatus::RouteError);
+        assert_eq!(
+            confirm.network_status_code,
+            Some(NetworkStatusCode::NoRoutingCapacity)
+        );
+    }
+
+    #[test]
+    fn relays_route_request_with_accumulated_cost() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                let (header, _) = parse_command(payload);
+                *dest == mac_short(broadcast_address::ALL_DEVICES)
+                    && is_route_request(payload, ORIGINATOR, 3)
+                    && header.radius == 4
+                    && header.sequence_number == 7
+            })
+            .times(1 + NWKC_RREQ_RETRIES as usize)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_router(mac);
+        let frame = route_request_frame(&mut nlme, DESTINATION, 5, 1);
+        expect_receive(&mut nlme, NEIGHBOR, 0xffff, 150, frame);
+
+        block_on(nlme.receive()).unwrap();
+
+        assert_eq!(
+            route(&nlme, DESTINATION),
+            Some((0xffff, RouteStatus::DiscoveryUnderway))
+        );
+        // broadcast after the jitter, then retried
+        block_on(nlme.tick(octets_to_ms(NWKC_MAX_RREQ_JITTER)));
+        for _ in 0..NWKC_RREQ_RETRIES {
+            block_on(nlme.tick(octets_to_ms(NWKC_RREQ_RETRY_INTERVAL)));
+        }
+        assert!(nlme.routing.pending_requests.is_empty());
+    }
+
+    #[test]
+    fn does_not_relay_last_hop() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data().never();
+        let mut nlme = make_router(mac);
+        let frame = route_request_frame(&mut nlme, DESTINATION, 1, 0);
+        expect_receive(&mut nlme, NEIGHBOR, 0xffff, 255, frame);
+
+        block_on(nlme.receive()).unwrap();
+        block_on(nlme.tick(octets_to_ms(NWKC_MAX_RREQ_JITTER)));
+
+        assert!(nlme.routing.pending_requests.is_empty());
+    }
+
+    #[test]
+    fn destination_answers_route_request() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                let (header, command) = parse_command(payload);
+                *dest == mac_short(NEIGHBOR)
+                    && header.destination == ShortAddress(NEIGHBOR)
+                    && matches!(command, NwkCommand::RouteReply(r)
+                        if r.route_request_id == 9
+                            && r.originator_address == ShortAddress(ORIGINATOR)
+                            && r.responder_address == ShortAddress(OWN))
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_router(mac);
+        let frame = route_request_frame(&mut nlme, OWN, 5, 2);
+        expect_receive(&mut nlme, NEIGHBOR, 0xffff, 255, frame);
+
+        block_on(nlme.receive()).unwrap();
+    }
+
+    #[test]
+    fn route_reply_activates_route_and_sends_queued_frame() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| *dest == mac_short(0xffff) && is_route_request(payload, OWN, 0))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                let (header, _) = NwkHeader::try_read(payload, ()).unwrap();
+                *dest == mac_short(NEIGHBOR) && header.destination == ShortAddress(DESTINATION)
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_router(mac);
+
+        block_on(nlme.send_data(ShortAddress(DESTINATION), false, b"hello")).unwrap();
+        let route_request_id = nlme.routing.route_request_id;
+        let frame = route_reply_frame(&mut nlme, route_request_id, OWN, 2);
+        expect_receive(&mut nlme, NEIGHBOR, 0xffff, 255, frame);
+        block_on(nlme.receive()).unwrap();
+
+        assert_eq!(
+            route(&nlme, DESTINATION),
+            Some((NEIGHBOR, RouteStatus::Active))
+        );
+        assert!(nlme.routing.pending_frames.is_empty());
+        assert!(nlme.routing.pending_requests.is_empty());
+        // the unicast to the next hop is counted
+        assert_eq!(nlme.nib().tx_total(), 1);
+    }
+
+    #[test]
+    fn relay_forwards_route_reply_to_originator() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                *dest == mac_short(0xffff) && is_route_request(payload, ORIGINATOR, 1)
+            })
+            .returning(|_, _| Ok(()));
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                let (_, command) = parse_command(payload);
+                *dest == mac_short(ORIGINATOR)
+                    && matches!(command, NwkCommand::RouteReply(r) if r.path_cost == 3)
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_router(mac);
+        let frame = route_request_frame(&mut nlme, DESTINATION, 5, 0);
+        expect_receive(&mut nlme, ORIGINATOR, 0xffff, 255, frame);
+        block_on(nlme.receive()).unwrap();
+
+        let frame = route_reply_frame(&mut nlme, 9, ORIGINATOR, 1);
+        expect_receive(&mut nlme, NEIGHBOR, 0xffff, 150, frame);
+        block_on(nlme.receive()).unwrap();
+
+        assert_eq!(
+            route(&nlme, DESTINATION),
+            Some((NEIGHBOR, RouteStatus::Active))
+        );
+    }
+
+    #[test]
+    fn failed_route_discovery_is_removed() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data().returning(|_, _| Ok(()));
+        let mut nlme = make_router(mac);
+
+        block_on(nlme.send_data(ShortAddress(DESTINATION), false, b"hello")).unwrap();
+        block_on(nlme.tick(octets_to_ms(NWKC_ROUTE_DISCOVERY_TIME)));
+
+        assert_eq!(route(&nlme, DESTINATION), None);
+        assert!(nlme.routing.pending_frames.is_empty());
+        assert!(nlme.routing.discovery_table.is_empty());
+    }
+
+    #[test]
+    fn unused_route_expires() {
+        let mut nlme = make_router(MockMlme::new());
+        assert!(nlme.set_route_active(ShortAddress(DESTINATION), ShortAddress(NEIGHBOR)));
+
+        block_on(nlme.tick(ROUTE_EXPIRY_TIME_MS / 2));
+        assert_eq!(
+            nlme.next_hop(ShortAddress(DESTINATION)),
+            Some(ShortAddress(NEIGHBOR))
+        );
+        block_on(nlme.tick(ROUTE_EXPIRY_TIME_MS / 2));
+        assert!(route(&nlme, DESTINATION).is_some());
+        block_on(nlme.tick(ROUTE_EXPIRY_TIME_MS / 2));
+
+        assert_eq!(route(&nlme, DESTINATION), None);
+    }
+
+    #[test]
+    fn neighbors_are_reached_directly() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, _| *dest == mac_short(NEIGHBOR))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_router(mac);
+        let mut neighbor = make_neighbor(PAN_ID, NEIGHBOR, 0, 255, 1);
+        neighbor.relationship = relationship::SIBLING;
+        let mut table = nlme.nib().neighbor_table();
+        table.push(neighbor).unwrap();
+        nlme.nib().set_neighbor_table(table);
+
+        block_on(nlme.send_data(ShortAddress(NEIGHBOR), false, b"hello")).unwrap();
+
+        assert!(nlme.routing.discovery_table.is_empty());
+    }
+}
@@ -0,0 +1,469 @@
+//! Many-to-one and source routing
+//!
+//! A concentrator announces itself with many-to-one route requests, every
+//! router keeps a route towards it. Devices report their path with a route
+//! record, which the concentrator uses to reach them with a source route
+//! subframe instead of discovering routes (§3.6.3.5.1, §3.6.3.3.2).
+
+use zigbee_mac::mlme::Mlme;
+use zigbee_types::ShortAddress;
+use zigbee_types::StorageVec;
+
+use super::NetworkError;
+use super::Nlme;
+use crate::nwk::frame::Frame as NwkFrame;
+use crate::nwk::frame::command::Command as NwkCommand;
+use crate::nwk::frame::command::network_status::NetworkStatusCode;
+use crate::nwk::frame::command::route_record::RouteRecord;
+use crate::nwk::frame::command::route_request::many_to_one;
+use crate::nwk::frame::header::SourceRouteSubframe;
+use crate::nwk::nib::MAX_ROUTE_RECORD_RELAYS;
+use crate::nwk::nib::NwkRoute;
+use crate::nwk::nib::RouteRecord as RouteRecordEntry;
+use crate::nwk::nib::RouteStatus;
+
+/// Relay list of a route record or source route subframe.
+pub(super) type RelayList = [u8; MAX_ROUTE_RECORD_RELAYS * 2];
+
+/// Path from a concentrator to a device, taken from its route record.
+pub(super) struct SourceRoute {
+    relay_count: u8,
+    relays: RelayList,
+}
+
+impl SourceRoute {
+    /// Source route subframe of a frame originated by the concentrator.
+    pub(super) fn subframe(&self) -> Option<SourceRouteSubframe<'_>> {
+        (self.relay_count > 0).then(|| SourceRouteSubframe {
+            relay_count: self.relay_count,
+            relay_index: self.relay_count - 1,
+            relay_list: &self.relays[..usize::from(self.relay_count) * 2],
+        })
+    }
+
+    /// First hop towards `destination`, the relay closest to the
+    /// concentrator.
+    pub(super) fn next_hop(&self, destination: ShortAddress) -> ShortAddress {
+        self.subframe()
+            .and_then(|subframe| subframe.relays().last())
+            .unwrap_or(destination)
+    }
+}
+
+impl<M> Nlme<M>
+where
+    M: Mlme,
+{
+    /// Store the route to the concentrator `concentrator` learned from a
+    /// many-to-one route request received from `sender`.
+    pub(super) fn set_many_to_one_route(
+        &self,
+        concentrator: ShortAddress,
+        sender: ShortAddress,
+        mode: u8,
+    ) {
+        let mut table = self.nib().route_table();
+        let route = if let Some(route) = table
+            .iter_mut()
+            .find(|r| r.destination_address == concentrator)
+        {
+            route
+        } else {
+            if table
+                .push(NwkRoute::new(concentrator, sender, RouteStatus::Active))
+                .is_err()
+            {
+                log::warn!(
+                    "[NLME] route table full, route to concentrator 0x{:04x} lost",
+                    concentrator.0
+                );
+                return;
+            }
+            let Some(route) = table.last_mut() else {
+                return;
+            };
+            route
+        };
+        route.next_hop_address = sender;
+        route.set_status(RouteStatus::Active);
+        route.age = 0;
+        route.set_many_to_one(true);
+        route.set_no_route_cache(mode == many_to_one::NO_ROUTE_CACHE);
+        route.set_route_record_required(mode == many_to_one::ROUTE_RECORD_TABLE);
+        self.nib().set_route_table(table);
+    }
+
+    /// Send a route record to `destination` via `next_hop` ahead of a data
+    /// frame, if the concentrator asked for one.
+    pub(super) async fn send_route_record_if_required(
+        &mut self,
+        destination: ShortAddress,
+        next_hop: ShortAddress,
+    ) {
+        let mut table = self.nib().route_table();
+        let Some(route) = table
+            .iter_mut()
+            .find(|r| r.destination_address == destination && r.route_record_required())
+        else {
+            return;
+        };
+        route.set_route_record_required(false);
+        self.nib().set_route_table(table);
+
+        if let Err(e) = self.send_route_record(destination, next_hop).await {
+            log::debug!(
+                "[NLME] failed to send route record to 0x{:04x}: {e}",
+                destination.0
+            );
+        }
+    }
+
+    async fn send_route_record(
+        &mut self,
+        concentrator: ShortAddress,
+        next_hop: ShortAddress,
+    ) -> Result<(), NetworkError> {
+        let secure = self.nwk_security_enabled();
+        let radius = self.nib().max_depth().saturating_mul(2);
+        let header = self.nwk_command_header(concentrator, radius, secure);
+        let record = RouteRecord {
+            relay_count: 0,
+            relay_list: &[],
+        };
+        let len = self.build_nwk_command_frame(header, NwkCommand::RouteRecord(record))?;
+        self.transmit_nwk_frame(next_hop, len).await
+    }
+
+    /// Copy of `record` with this device appended to the relay list, for
+    /// relaying a route record towards the concentrator.
+    pub(super) fn append_relay<'a>(
+        &self,
+        record: &RouteRecord<'_>,
+        relays: &'a mut RelayList,
+    ) -> Result<RouteRecord<'a>, NetworkError> {
+        let len = record.relay_list.len();
+        let max_relays = usize::from(self.nib().max_source_route()).min(MAX_ROUTE_RECORD_RELAYS);
+        if usize::from(record.relay_count) >= max_relays || len + 2 > relays.len() {
+            log::debug!("[NLME] route record exceeds nwkMaxSourceRoute");
+            return Err(NetworkError::InvalidFrame);
+        }
+        relays[..len].copy_from_slice(record.relay_list);
+        relays[len..len + 2].copy_from_slice(&self.nib().network_address().to_le_bytes());
+        Ok(RouteRecord {
+            relay_count: record.relay_count + 1,
+            relay_list: &relays[..len + 2],
+        })
+    }
+
+    /// Handle a route record addressed to this device (§3.6.4.5).
+    ///
+    /// A concentrator stores the path to `source`, replacing a previous one
+    /// or the oldest record when the table is full.
+    pub(super) fn route_record_indication(&self, source: ShortAddress, record: &RouteRecord<'_>) {
+        if !self.nib().is_concentrator() {
+            return;
+        }
+        let mut path = StorageVec::new();
+        for relay in record.relays() {
+            if path.push(relay).is_err() {
+                log::debug!("[NLME] route record of 0x{:04x} too long", source.0);
+                return;
+            }
+        }
+
+        let mut table = self.nib().route_record_table();
+        table.retain(|r| r.network_address != source);
+        if table.is_full() {
+            table.remove(0);
+        }
+        let _ = table.push(RouteRecordEntry {
+            network_address: source,
+            relay_count: u16::from(record.relay_count),
+            path,
+        });
+        self.nib().set_route_record_table(table);
+    }
+
+    /// Source route to `destination` from the route record table.
+    pub(super) fn source_route(&self, destination: ShortAddress) -> Option<SourceRoute> {
+        if !self.nib().is_concentrator() {
+            return None;
+        }
+        let table = self.nib().route_record_table();
+        let record = table.iter().find(|r| r.network_address == destination)?;
+        let mut route = SourceRoute {
+            relay_count: 0,
+            relays: [0; MAX_ROUTE_RECORD_RELAYS * 2],
+        };
+        // the path starts at the destination, as the relay list does
+        for (relay, bytes) in record.path.iter().zip(route.relays.chunks_exact_mut(2)) {
+            bytes.copy_from_slice(&relay.0.to_le_bytes());
+            route.relay_count += 1;
+        }
+        Some(route)
+    }
+
+    /// Relay a frame along its source route subframe (§3.6.3.3.2).
+    ///
+    /// The relay index points at this device, the frame is sent to the next
+    /// relay towards the destination or to the destination itself.
+    pub(super) async fn relay_source_routed_frame(
+        &mut self,
+        mut frame: NwkFrame<'_>,
+    ) -> Result<(), NetworkError> {
+        let own_address = ShortAddress(self.nib().network_address());
+        let header = frame.header_mut();
+        let (source, destination) = (header.source, header.destination);
+        let Some(subframe) = header.source_route_subframe.as_mut() else {
+            return Ok(());
+        };
+        if subframe.relays().nth(usize::from(subframe.relay_index)) != Some(own_address) {
+            log::debug!(
+                "[NLME] not the next relay of a source route to 0x{:04x}",
+                destination.0
+            );
+            return Ok(());
+        }
+        let next_hop = if subframe.relay_index == 0 {
+            destination
+        } else {
+            subframe.relay_index -= 1;
+            let next_relay = subframe.relays().nth(usize::from(subframe.relay_index));
+            next_relay.ok_or(NetworkError::InvalidFrame)?
+        };
+
+        let len = self.build_relayed_frame(frame)?;
+        if let Err(e) = self.transmit_nwk_frame(next_hop, len).await {
+            log::debug!(
+                "[NLME] source routing to 0x{:04x} via 0x{:04x} failed: {e}",
+                destination.0,
+                next_hop.0
+            );
+            self.report_network_status(source, NetworkStatusCode::SourceRouteFailure, destination)
+                .await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byte::TryRead;
+
+    use super::*;
+    use crate::nwk::frame::command::route_request::CommandOptions as RouteRequestOptions;
+    use crate::nwk::frame::command::route_request::RouteRequest;
+    use crate::nwk::frame::header::Header as NwkHeader;
+    use crate::nwk::nib::broadcast_address;
+    use crate::nwk::nlme::tests::MockMlme;
+    use crate::nwk::nlme::tests::OWN;
+    use crate::nwk::nlme::tests::block_on;
+    use crate::nwk::nlme::tests::expect_receive;
+    use crate::nwk::nlme::tests::mac_short;
+    use crate::nwk::nlme::tests::make_router;
+
+    const NEIGHBOR: u16 = 0x2222;
+    const RELAY: u16 = 0x3333;
+    const DEVICE: u16 = 0x4444;
+    const CONCENTRATOR: u16 = 0x0000;
+
+    fn parse(payload: &[u8]) -> (NwkHeader<'_>, &[u8]) {
+        let (header, len) = NwkHeader::try_read(payload, ()).unwrap();
+        (header, &payload[len..])
+    }
+
+    fn route_record(payload: &[u8]) -> Option<(u16, std::vec::Vec<ShortAddress>)> {
+        let (header, rest) = parse(payload);
+        match NwkCommand::try_read(rest, ()).ok()?.0 {
+            NwkCommand::RouteRecord(record) => {
+                Some((header.destination.0, record.relays().collect()))
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn concentrator_sends_many_to_one_route_requests() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                let (header, rest) = parse(payload);
+                *dest == mac_short(broadcast_address::ALL_DEVICES)
+                    && header.destination == ShortAddress(broadcast_address::ROUTERS)
+                    && matches!(NwkCommand::try_read(rest, ()).unwrap().0,
+                        NwkCommand::RouteRequest(r)
+                            if r.command_options.many_to_one() == many_to_one::ROUTE_RECORD_TABLE
+                                && r.destination_address == ShortAddress(broadcast_address::ROUTERS))
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_router(mac);
+        nlme.nib().set_is_concentrator(true);
+        nlme.nib().set_concentrator_discovery_time(60);
+
+        block_on(nlme.tick(1));
+    }
+
+    #[test]
+    fn many_to_one_route_request_sets_route_to_concentrator() {
+        let mut nlme = make_router(MockMlme::new());
+        let request = RouteRequest {
+            command_options: RouteRequestOptions(0)
+                .set_many_to_one(many_to_one::ROUTE_RECORD_TABLE),
+            route_request_id: 3,
+            destination_address: ShortAddress(broadcast_address::ROUTERS),
+            path_cost: 1,
+            destination_ieee_address: None,
+        };
+        // a radius of 1 is not relayed any further
+        let mut header =
+            nlme.nwk_command_header(ShortAddress(broadcast_address::ROUTERS), 1, false);
+        header.source = ShortAddress(CONCENTRATOR);
+
+        block_on(nlme.route_request_indication(mac_short(NEIGHBOR), 0xff, &header, request))
+            .unwrap();
+
+        let table = nlme.nib().route_table();
+        let route = table
+            .iter()
+            .find(|r| r.destination_address == ShortAddress(CONCENTRATOR))
+            .unwrap();
+        assert_eq!(route.next_hop_address, ShortAddress(NEIGHBOR));
+        assert_eq!(route.status(), RouteStatus::Active);
+        assert!(route.many_to_one());
+        assert!(route.route_record_required());
+        assert!(!route.no_route_cache());
+    }
+
+    #[test]
+    fn route_record_precedes_first_data_frame() {
+        let mut mac = MockMlme::new();
+        let mut seq = mockall::Sequence::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                *dest == mac_short(NEIGHBOR)
+                    && route_record(payload) == Some((CONCENTRATOR, std::vec::Vec::new()))
+            })
+            .times(1)
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+        mac.expect_transmit_data()
+            .withf(|dest, payload| *dest == mac_short(NEIGHBOR) && route_record(payload).is_none())
+            .times(2)
+            .in_sequence(&mut seq)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_router(mac);
+        nlme.set_many_to_one_route(
+            ShortAddress(CONCENTRATOR),
+            ShortAddress(NEIGHBOR),
+            many_to_one::ROUTE_RECORD_TABLE,
+        );
+
+        block_on(nlme.send_data(ShortAddress(CONCENTRATOR), false, &[1, 2, 3])).unwrap();
+        block_on(nlme.send_data(ShortAddress(CONCENTRATOR), false, &[4, 5, 6])).unwrap();
+    }
+
+    #[test]
+    fn relayed_route_record_gets_own_address() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                *dest == mac_short(NEIGHBOR)
+                    && route_record(payload)
+                        == Some((
+                            CONCENTRATOR,
+                            std::vec![ShortAddress(RELAY), ShortAddress(OWN)],
+                        ))
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_router(mac);
+        nlme.set_many_to_one_route(
+            ShortAddress(CONCENTRATOR),
+            ShortAddress(NEIGHBOR),
+            many_to_one::ROUTE_RECORD_TABLE,
+        );
+        let mut header = nlme.nwk_command_header(ShortAddress(CONCENTRATOR), 5, false);
+        header.source = ShortAddress(DEVICE);
+        let record = RouteRecord {
+            relay_count: 1,
+            relay_list: &RELAY.to_le_bytes(),
+        };
+        let len = nlme
+            .build_nwk_command_frame(header, NwkCommand::RouteRecord(record))
+            .unwrap();
+        let frame = nlme.buf[..len].to_vec();
+        expect_receive(&mut nlme, RELAY, OWN, 0xff, frame);
+
+        assert!(block_on(nlme.receive()).unwrap().is_none());
+    }
+
+    #[test]
+    fn concentrator_source_routes_to_recorded_device() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                let (header, _) = parse(payload);
+                let subframe = header.source_route_subframe.unwrap();
+                *dest == mac_short(RELAY)
+                    && header.frame_control.source_flag()
+                    && header.destination == ShortAddress(DEVICE)
+                    && subframe.relay_index == 1
+                    && subframe
+                        .relays()
+                        .eq([ShortAddress(NEIGHBOR), ShortAddress(RELAY)])
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_router(mac);
+        nlme.nib().set_is_concentrator(true);
+        let record = RouteRecord {
+            relay_count: 2,
+            relay_list: &[0x22, 0x22, 0x33, 0x33],
+        };
+
+        nlme.route_record_indication(ShortAddress(DEVICE), &record);
+        block_on(nlme.send_data(ShortAddress(DEVICE), false, &[1, 2, 3])).unwrap();
+
+        let table = nlme.nib().route_record_table();
+        assert_eq!(table.len(), 1);
+        assert_eq!(table[0].relay_count, 2);
+    }
+
+    #[test]
+    fn relays_source_routed_frame() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, payload| {
+                let (header, rest) = parse(payload);
+                *dest == mac_short(NEIGHBOR)
+                    && header.radius == 4
+                    && header.source_route_subframe.unwrap().relay_index == 0
+                    && rest == [1, 2, 3]
+            })
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_router(mac);
+        let relays = [NEIGHBOR.to_le_bytes(), OWN.to_le_bytes()].concat();
+        let subframe = SourceRouteSubframe {
+            relay_count: 2,
+            relay_index: 1,
+            relay_list: &relays,
+        };
+        let len = nlme
+            .build_nwk_data_frame(
+                ShortAddress(DEVICE),
+                false,
+                &[1, 2, 3],
+                Some(subframe),
+                None,
+            )
+            .unwrap();
+        let mut frame = nlme.buf[..len].to_vec();
+        // radius is at offset 6 of the header
+        frame[6] = 5;
+        expect_receive(&mut nlme, RELAY, OWN, 0xff, frame);
+
+        assert!(block_on(nlme.receive()).unwrap().is_none());
+    }
+}
@@ -27,7 +27,6 @@ use zigbee_types::ByteArray;
 use zigbee_types::ByteArrayRef;
 use zigbee_types::IeeeAddress;
 
-use crate::aps::aib;
 use crate::aps::aib::Aib;
 use crate::aps::aib::AibStorage;
 use crate::aps::aib::DeviceKeyPairDescriptor;
@@ -42,7 +41,6 @@ use crate::aps::frame::header::Header as ApsHeader;
 use crate::aps::types::TxOptions;
 use crate::nwk::frame::Frame as NwkFrame;
 use crate::nwk::frame::header::Header as NwkHeader;
-use crate::nwk::nib;
 use crate::nwk::nib::IncomingFrameCounterDescriptor;
 use crate::nwk::nib::NetworkSecurityMaterialDescriptor;
 use crate::nwk::nib::Nib;
@@ -72,6 +70,8 @@ pub enum SecurityError {
     ParseError(byte::Error),
     #[error("ccm error")]
     CcmError(ccm::Error),
+    #[error("stale frame counter")]
+    StaleFrameCounter,
     #[error("frame security failed")]
     Unspecified,
 }
@@ -95,6 +95,9 @@ impl From<SecurityError> for byte::Error {
             SecurityError::CcmError(_) => Self::BadInput {
                 err: "security: ccm error",
             },
+            SecurityError::StaleFrameCounter => Self::BadInput {
+                err: "security: stale frame counter",
+            },
             SecurityError::Unspecified => Self::BadInput {
                 err: "frame security failed",
             },
@@ -112,12 +115,66 @@ impl<'a> SecurityContext<'a> {
         Self { nib, aib }
     }
 
-    /// Returns the `SecurityContext` referencing the static `NIB` and `AIB`.
-    pub fn get() -> Self {
-        Self {
-            nib: nib::get_ref(),
-            aib: aib::get_ref(),
+    /// Install `key` as the network key with sequence number
+    /// `key_seq_number`.
+    ///
+    /// The first key becomes the active key. Later keys are kept next to the
+    /// active key as the alternate key until a Switch-Key command activates
+    /// them, replacing the previous alternate key (§4.4.10.1).
+    ///
+    /// Installing the same key again keeps its frame counters, so a repeated
+    /// transport key neither reopens the replay window nor makes neighbors
+    /// drop our frames. A new key starts with fresh frame counters.
+    pub fn install_network_key(
+        &self,
+        key_seq_number: u8,
+        key: ByteArray<16>,
+        network_key_type: u8,
+    ) {
+        let mut sec_material_set = self.nib.security_material_set();
+        if sec_material_set
+            .iter()
+            .any(|k| k.key_seq_number == key_seq_number && k.key == key)
+        {
+            return;
+        }
+        let active_key_seq_number = self.nib.active_key_seq_number();
+        // a different key under a known sequence number replaces that key
+        sec_material_set.retain(|k| k.key_seq_number != key_seq_number);
+        let has_active_key = sec_material_set
+            .iter()
+            .any(|k| k.key_seq_number == active_key_seq_number);
+        // only the active key is kept, the new key becomes the alternate key
+        sec_material_set.retain(|k| k.key_seq_number == active_key_seq_number);
+        let _ = sec_material_set.push(NetworkSecurityMaterialDescriptor {
+            key_seq_number,
+            outgoing_frame_counter: 0,
+            incoming_frame_counter_set: zigbee_types::StorageVec::new(),
+            key,
+            network_key_type,
+        });
+        self.nib.set_security_material_set(sec_material_set);
+        if !has_active_key {
+            self.nib.set_active_key_seq_number(key_seq_number);
+        }
+    }
+
+    /// Make the installed network key `key_seq_number` the active key on
+    /// receipt of a Switch-Key command (§4.4.10.4).
+    ///
+    /// The previously active key stays installed, so frames still secured
+    /// with it are accepted until the next key update replaces it.
+    pub fn switch_network_key(&self, key_seq_number: u8) -> Result<(), SecurityError> {
+        if !self
+            .nib
+            .security_material_set()
+            .iter()
+            .any(|k| k.key_seq_number == key_seq_number)
+        {
+            return Err(SecurityError::InvalidKey);
         }
+        self.nib.set_active_key_seq_number(key_seq_number);
+        Ok(())
     }
 
     // section 4.3.1.1
@@ -239,7 +296,7 @@ impl<'a> SecurityContext<'a> {
                 aux_hdr.frame_counter <= inc_frame_counter.incoming_frame_counter
             })
         {
-            return Err(SecurityError::InvalidData);
+            return Err(SecurityError::StaleFrameCounter);
         }
 
         // write back the security level from NIB to aux header
@@ -269,7 +326,7 @@ impl<'a> SecurityContext<'a> {
                 .key_sequence_number
                 .is_some_and(|ksn| ksn == k.key_seq_number)
         }) {
-            record_nwk_incoming_frame_counter(material, source_address, aux_hdr.frame_counter)?;
+            record_nwk_incoming_frame_counter(material, source_address, aux_hdr.frame_counter);
             self.nib.set_security_material_set(sec_material_set);
         }
 
@@ -543,29 +600,25 @@ impl<'a> SecurityContext<'a> {
 }
 
 /// Records `frame_counter` as the most recently accepted incoming counter for
-/// `sender_address`, inserting a new entry when this is the first frame seen
-/// from that sender. Used by the NWK decrypt path for anti-replay tracking.
+/// `sender_address`. Used by the NWK decrypt path for anti-replay tracking.
+///
+/// The set is kept in the order frames were accepted, so when it is full the
+/// sender heard from least recently makes room for a new one.
 fn record_nwk_incoming_frame_counter(
     material: &mut NetworkSecurityMaterialDescriptor,
     sender_address: IeeeAddress,
     frame_counter: u32,
-) -> Result<(), SecurityError> {
-    if let Some(entry) = material
-        .incoming_frame_counter_set
-        .iter_mut()
-        .find(|i| i.sender_address == sender_address)
-    {
-        entry.incoming_frame_counter = frame_counter;
-        Ok(())
-    } else {
-        material
-            .incoming_frame_counter_set
-            .push(IncomingFrameCounterDescriptor {
-                sender_address,
-                incoming_frame_counter: frame_counter,
-            })
-            .map_err(|_| SecurityError::Unspecified)
+) {
+    let set = &mut material.incoming_frame_counter_set;
+    if let Some(index) = set.iter().position(|i| i.sender_address == sender_address) {
+        set.remove(index);
+    } else if set.is_full() {
+        set.remove(0);
     }
+    let _ = set.push(IncomingFrameCounterDescriptor {
+        sender_address,
+        incoming_frame_counter: frame_counter,
+    });
 }
 
 // Figure 4-20
@@ -990,13 +1043,125 @@ mod tests {
         let mut frame_buffer = NWK_FRAME_CMD_BUFFER;
 
         assert!(
-            security_context
-                .decrypt_nwk_frame_in_place(&mut frame_buffer)
-                .is_err(),
+            matches!(
+                security_context.decrypt_nwk_frame_in_place(&mut frame_buffer),
+                Err(SecurityError::StaleFrameCounter)
+            ),
             "replay of an already-accepted NWK frame counter must be rejected"
         );
     }
 
+    #[test]
+    fn incoming_frame_counter_set_evicts_least_recent_sender() {
+        let nib = nib_with_incoming_counter(0);
+        let mut material_set = nib.security_material_set();
+        let material = &mut material_set[0];
+        let capacity = material.incoming_frame_counter_set.capacity();
+        for sender in 1..capacity as u64 {
+            record_nwk_incoming_frame_counter(material, IeeeAddress(sender), 1);
+        }
+        // the fixture's sender is heard from again and moves to the back
+        record_nwk_incoming_frame_counter(material, IeeeAddress(0xa4c1_389c_3830_01e5), 5);
+        assert!(material.incoming_frame_counter_set.is_full());
+
+        record_nwk_incoming_frame_counter(material, IeeeAddress(0xffff), 1);
+
+        let senders: std::vec::Vec<_> = material
+            .incoming_frame_counter_set
+            .iter()
+            .map(|i| i.sender_address)
+            .collect();
+        assert_eq!(senders.len(), capacity);
+        assert!(!senders.contains(&IeeeAddress(1)));
+        assert!(senders.contains(&IeeeAddress(0xa4c1_389c_3830_01e5)));
+        assert!(senders.contains(&IeeeAddress(0xffff)));
+    }
+
+    #[test]
+    fn reinstalling_network_key_keeps_frame_counters() {
+        let nib = nib_with_incoming_counter(1);
+        let aib = setup_aib();
+        let security_context = SecurityContext::new(&nib, &aib);
+
+        security_context.install_network_key(0, ByteArray(NETWORK_KEY), 0x01);
+        let mut frame_buffer = NWK_FRAME_CMD_BUFFER;
+        assert!(matches!(
+            security_context.decrypt_nwk_frame_in_place(&mut frame_buffer),
+            Err(SecurityError::StaleFrameCounter)
+        ));
+        assert_eq!(nib.security_material_set()[0].outgoing_frame_counter, 1);
+
+        security_context.install_network_key(1, ByteArray(NETWORK_KEY), 0x01);
+        let material_set = nib.security_material_set();
+        let material = material_set.iter().find(|k| k.key_seq_number == 1).unwrap();
+        assert_eq!(material.outgoing_frame_counter, 0);
+        assert!(material.incoming_frame_counter_set.is_empty());
+    }
+
+    #[test]
+    fn new_network_key_is_installed_as_alternate_key() {
+        let nib = setup_nib();
+        let aib = setup_aib();
+        let security_context = SecurityContext::new(&nib, &aib);
+
+        security_context.install_network_key(1, ByteArray([0x11; 16]), 0x01);
+        assert_eq!(nib.security_material_set().len(), 2);
+        assert_eq!(nib.active_key_seq_number(), 0);
+
+        // a further key replaces the alternate key, never the active one
+        security_context.install_network_key(2, ByteArray([0x22; 16]), 0x01);
+        let key_seq_numbers: std::vec::Vec<_> = nib
+            .security_material_set()
+            .iter()
+            .map(|k| k.key_seq_number)
+            .collect();
+        assert_eq!(key_seq_numbers, [0, 2]);
+        assert_eq!(nib.active_key_seq_number(), 0);
+    }
+
+    #[test]
+    fn first_network_key_becomes_active() {
+        let nib = Nib::new(NibStorage::default());
+        nib.init();
+        let aib = setup_aib();
+        let security_context = SecurityContext::new(&nib, &aib);
+
+        security_context.install_network_key(5, ByteArray(NETWORK_KEY), 0x01);
+
+        assert_eq!(nib.active_key_seq_number(), 5);
+        assert_eq!(nib.security_material_set().len(), 1);
+    }
+
+    #[test]
+    fn switch_key_keeps_old_key_for_decryption() {
+        let nib = setup_nib();
+        let aib = setup_aib();
+        let security_context = SecurityContext::new(&nib, &aib);
+        security_context.install_network_key(1, ByteArray([0x11; 16]), 0x01);
+
+        assert!(matches!(
+            security_context.switch_network_key(7),
+            Err(SecurityError::InvalidKey)
+        ));
+        security_context.switch_network_key(1).unwrap();
+        assert_eq!(nib.active_key_seq_number(), 1);
+
+        // the fixture is still secured with key 0
+        let mut frame_buffer = NWK_FRAME_CMD_BUFFER;
+        let frame = security_context
+            .decrypt_nwk_frame_in_place(&mut frame_buffer)
+            .unwrap();
+
+        // outgoing frames use the new active key
+        let mut buf = [0u8; 45];
+        security_context
+            .encrypt_nwk_frame_in_place(frame, &mut buf)
+            .unwrap();
+        let (_, nwk_hdr_len) = NwkHeader::try_read(&buf, ()).unwrap();
+        let (aux_hdr, _) = AuxFrameHeader::try_read(&buf[nwk_hdr_len..], ()).unwrap();
+        assert_eq!(aux_hdr.key_sequence_number, Some(1));
+    }
+
     #[test]
     fn decrypt_nwk_frame_accepts_newer_frame_counter() {
         // Last accepted counter is 0; the fixture's counter 1 is strictly
@@ -1103,7 +1268,7 @@ mod tests {
             .unwrap();
 
         let NwkFrame::Data(data_frame) = frame else {
-            panic!("expected data frame");
+            unreachable!("expected data frame");
         };
 
         // payload should be the APS bytes, not the entire buffer
@@ -11,9 +11,9 @@ use crate::zdp::device_annce::CLUSTER_ID;
 pub use crate::zdp::device_annce::DeviceAnnce;
 
 /// ZigBee Device Profile identifier.
-const ZDP_PROFILE_ID: u16 = 0x0000;
+pub(super) const ZDP_PROFILE_ID: u16 = 0x0000;
 /// ZDO endpoint.
-const ZDO_ENDPOINT: u8 = 0x00;
+pub(super) const ZDO_ENDPOINT: u8 = 0x00;
 
 /// Broadcast a ZDO Device_annce (§2.4.3.1.11).
 ///
@@ -0,0 +1,803 @@
+//! Inbound frame dispatcher
+//!
+//! Routers and coordinators keep their receiver on, so frames arrive whenever
+//! a neighbor sends them. The dispatcher takes every frame the NWK layer
+//! passes up (NLDE-DATA.indication), parses the APS frame and hands it to the
+//! APSME, the ZDO on endpoint 0 or the application endpoints (§2.2.4.1.3).
+//! Sleepy end devices fetch their frames with data polls instead.
+
+use byte::BytesExt;
+use heapless::Vec;
+use zigbee_mac::mlme::Mlme;
+use zigbee_types::ShortAddress;
+
+use super::ZigbeeDevice;
+use super::device_annce::ZDO_ENDPOINT;
+use super::device_annce::ZDP_PROFILE_ID;
+use crate::apl::endpoint::BROADCAST_ENDPOINT;
+use crate::apl::endpoint::Endpoint;
+use crate::aps::apsde::ApsdeSapIndication;
+use crate::aps::apsde::SecurityStatus;
+use crate::aps::apsme::ApsFrameIndication;
+use crate::aps::frame::CommandFrame;
+use crate::aps::frame::DataFrame;
+use crate::aps::frame::Frame;
+use crate::aps::frame::command::Command;
+use crate::aps::frame::command::TransportKey;
+use crate::aps::frame::frame_control::DeliveryMode;
+use crate::aps::types::Address;
+use crate::aps::types::DstAddrMode;
+use crate::aps::types::SrcAddrMode;
+use crate::nwk::frame::command::network_status::NetworkStatusCode;
+use crate::nwk::nlde::NldeDataIndication;
+use crate::nwk::nlde::NldeDestination;
+use crate::nwk::nlme::NetworkError;
+use crate::nwk::nlme::Nlme;
+use crate::nwk::nlme::NwkIndication;
+use crate::nwk::nlme::management::NlmeNwkStatusIndication;
+use crate::nwk::nlme::management::NlmePermitJoiningRequest;
+use crate::zdp::device_annce;
+use crate::zdp::device_annce::DeviceAnnce;
+use crate::zdp::mgmt_nwk_update_notify;
+use crate::zdp::mgmt_nwk_update_notify::MgmtNwkUpdateNotify;
+use crate::zdp::mgmt_permit_joining;
+use crate::zdp::mgmt_permit_joining::MgmtPermitJoiningReq;
+
+impl ZigbeeDevice {
+    /// Receive and dispatch inbound frames forever.
+    ///
+    /// Meant to be spawned next to the tasks of the application, which
+    /// receives its data through `endpoints`. Errors of single frames are
+    /// logged and do not stop the dispatcher.
+    pub async fn run<M: Mlme>(
+        &mut self,
+        nlme: &mut Nlme<M>,
+        endpoints: &mut [&mut dyn Endpoint],
+    ) -> ! {
+        loop {
+            if let Err(e) = self.process_indication(nlme, endpoints).await {
+                log::debug!("[ZDO] dropping inbound frame: {e}");
+            }
+        }
+    }
+
+    /// Wait for the next inbound MAC frame and dispatch it.
+    pub async fn process_indication<M: Mlme>(
+        &mut self,
+        nlme: &mut Nlme<M>,
+        endpoints: &mut [&mut dyn Endpoint],
+    ) -> Result<(), NetworkError> {
+        let indication = nlme.receive().await?;
+        self.nwk_indication(nlme, endpoints, indication).await
+    }
+
+    /// Poll the parent of a sleepy end device when due and dispatch the
+    /// received frame.
+    ///
+    /// Returns the milliseconds the radio may sleep, see
+    /// [`Nlme::poll_tick`].
+    pub async fn poll<M: Mlme>(
+        &mut self,
+        nlme: &mut Nlme<M>,
+        endpoints: &mut [&mut dyn Endpoint],
+        elapsed_ms: u32,
+    ) -> Result<Option<u32>, NetworkError> {
+        let confirm = nlme.poll_tick(elapsed_ms).await?;
+        if let Err(e) = self
+            .nwk_indication(nlme, endpoints, confirm.indication)
+            .await
+        {
+            log::debug!("[ZDO] dropping polled frame: {e}");
+        }
+        Ok(confirm.sleep_ms)
+    }
+
+    /// Dispatch an indication of the NWK layer.
+    async fn nwk_indication<M: Mlme>(
+        &mut self,
+        nlme: &mut Nlme<M>,
+        endpoints: &mut [&mut dyn Endpoint],
+        indication: Option<NwkIndication>,
+    ) -> Result<(), NetworkError> {
+        let indication = match indication {
+            Some(NwkIndication::Data(indication)) => self
+                .nlde_data_indication(nlme, endpoints, indication)
+                .await?
+                .map(NwkIndication::NwkStatus),
+            indication => indication,
+        };
+        let Some(indication) = indication else {
+            return Ok(());
+        };
+        log::debug!("[ZDO] {indication:?}");
+        if let NwkIndication::NwkStatus(status) = &indication
+            && status.status == NetworkStatusCode::NetworkAddressUpdate
+            && status.network_address.0 == nlme.nib().network_address()
+        {
+            // neighbors learn the new address of this device from its
+            // announcement (§3.6.1.9.2)
+            let nib = nlme.nib();
+            let annce = DeviceAnnce {
+                nwk_addr: status.network_address,
+                ieee_addr: nib.ieee_address(),
+                capability: nib.capability_information(),
+            };
+            self.device_annce(nlme, annce).await?;
+        }
+        if let NwkIndication::Join(join) = &indication
+            && let Err(e) = self.update_device(nlme, join).await
+        {
+            log::debug!("[ZDO] failed to send Update-Device: {e}");
+        }
+        for endpoint in endpoints.iter_mut() {
+            endpoint.nlme_indication(&indication);
+        }
+        Ok(())
+    }
+
+    /// Dispatch the APS frame carried by a NLDE-DATA.indication.
+    ///
+    /// Returns the NLME-NWK-STATUS.indication of a ZDP frame which changed
+    /// the address of this device.
+    async fn nlde_data_indication<M: Mlme>(
+        &mut self,
+        nlme: &mut Nlme<M>,
+        endpoints: &mut [&mut dyn Endpoint],
+        indication: NldeDataIndication,
+    ) -> Result<Option<NlmeNwkStatusIndication>, NetworkError> {
+        let NldeDataIndication {
+            destination,
+            source,
+            mut nsdu,
+            link_quality,
+            security_use,
+        } = indication;
+        let ApsFrameIndication {
+            frame,
+            secured,
+            key_identifier,
+            source: aps_source,
+        } = self.apsme.frame_indication(nlme, &mut nsdu)?;
+        match frame {
+            Frame::Data(frame) => {
+                let security_status = if secured {
+                    SecurityStatus::SecuredLinkKey
+                } else if security_use {
+                    SecurityStatus::SecuredNwkKey
+                } else {
+                    SecurityStatus::Unsecured
+                };
+                let mut indication = Self::apsde_data_indication(destination, &frame);
+                indication.src_address = Address::Network(source.0);
+                indication.security_status = security_status;
+                indication.link_quality = link_quality;
+
+                if indication.profile_id == ZDP_PROFILE_ID
+                    && indication.dst_endpoint == ZDO_ENDPOINT
+                {
+                    return Self::zdp_indication(nlme, &indication).await;
+                }
+                for endpoint in endpoints.iter_mut().filter(|endpoint| {
+                    indication.dst_endpoint == BROADCAST_ENDPOINT
+                        || indication.dst_endpoint == endpoint.endpoint()
+                }) {
+                    endpoint.data_indication(&indication);
+                }
+                Ok(None)
+            }
+            Frame::ApsCommand(CommandFrame { command, .. }) => {
+                let nwk_source = security_use.then_some(source);
+                self.apsme
+                    .command_indication(nlme, &command, secured, aps_source, nwk_source)?;
+                if let Command::TransportKey(TransportKey::StandardNetworkKey(nwk_key)) = command {
+                    Self::network_key_indication(nlme, &nwk_key, key_identifier, aps_source)?;
+                }
+                Ok(None)
+            }
+            Frame::Acknowledgement(header) => {
+                self.apsme.ack_indication(nlme, &header);
+                Ok(None)
+            }
+        }
+    }
+
+    /// APSDE-DATA.indication for the APS data `frame` (§2.2.4.1.3).
+    ///
+    /// Group addressed frames go to every endpoint, the NWK layer only
+    /// passes up groups in the group ID table.
+    fn apsde_data_indication(
+        destination: NldeDestination,
+        frame: &DataFrame<'_>,
+    ) -> ApsdeSapIndication {
+        let header = &frame.header;
+        let (dst_addr_mode, dst_address) = match (header.frame_control.delivery_mode(), destination)
+        {
+            (DeliveryMode::GroubAddressing, _) => (
+                DstAddrMode::Group,
+                Address::Group(header.group_address.map_or(0, |group| group.0)),
+            ),
+            (_, NldeDestination::Group(group)) => (DstAddrMode::Group, Address::Group(group)),
+            (_, NldeDestination::Network(address)) => {
+                (DstAddrMode::Network, Address::Network(address.0))
+            }
+        };
+        ApsdeSapIndication {
+            dst_addr_mode,
+            dst_address,
+            dst_endpoint: header.destination_endpoint.unwrap_or(BROADCAST_ENDPOINT),
+            src_addr_mode: SrcAddrMode::Short,
+            src_endpoint: header.source_endpoint.unwrap_or_default(),
+            profile_id: header.profile_id.unwrap_or_default(),
+            cluster_id: header.cluster_id.unwrap_or_default(),
+            // the ASDU is never longer than the NSDU it was taken from
+            asdu: Vec::from_slice(frame.payload).unwrap_or_default(),
+            ..ApsdeSapIndication::default()
+        }
+    }
+
+    /// Process a ZDP frame received on the ZDO endpoint (§2.4).
+    async fn zdp_indication<M: Mlme>(
+        nlme: &mut Nlme<M>,
+        indication: &ApsdeSapIndication,
+    ) -> Result<Option<NlmeNwkStatusIndication>, NetworkError> {
+        match indication.cluster_id {
+            device_annce::CLUSTER_ID => {
+                // the payload follows the transaction sequence number
+                let annce: DeviceAnnce = indication.asdu.read_with(&mut 1, ())?;
+                nlme.device_annce_indication(annce.nwk_addr, annce.ieee_addr)
+                    .await
+            }
+            // §2.4.3.3.7: open or close the network for joining devices
+            mgmt_permit_joining::CLUSTER_ID => {
+                let request: MgmtPermitJoiningReq = indication.asdu.read_with(&mut 1, ())?;
+                let confirm = nlme
+                    .permit_joining(NlmePermitJoiningRequest {
+                        permit_duration: request.permit_duration,
+                    })
+                    .await;
+                log::debug!("[ZDO] Mgmt_Permit_Joining_req: {:?}", confirm.status);
+                Ok(None)
+            }
+            // Annex E: a device detected interference on the current channel
+            mgmt_nwk_update_notify::CLUSTER_ID => {
+                let _: MgmtNwkUpdateNotify<'_> = indication.asdu.read_with(&mut 1, ())?;
+                let Address::Network(source) = indication.src_address else {
+                    return Ok(None);
+                };
+                nlme.interference_report(ShortAddress(source)).await?;
+                Ok(None)
+            }
+            cluster_id => {
+                log::debug!("[ZDO] unsupported ZDP cluster 0x{cluster_id:04x}");
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byte::BytesExt;
+    use byte::TryRead;
+    use zigbee_mac::mlme::MacIndication;
+    use zigbee_mac::mlme::ScanResult;
+    use zigbee_mac::mlme::ScanType;
+    use zigbee_types::IeeeAddress;
+    use zigbee_types::ShortAddress;
+
+    use super::*;
+    use crate::Config;
+    use crate::aps::aib::DeviceKeyPairDescriptor;
+    use crate::aps::aib::KeyAttribute;
+    use crate::aps::aib::LinkKeyType;
+    use crate::aps::frame::command::update_device_status;
+    use crate::aps::frame::frame_control::FrameControl;
+    use crate::aps::frame::frame_control::FrameType;
+    use crate::aps::frame::header::Header;
+    use crate::nwk::frame::command::Command as NwkCommand;
+    use crate::nwk::frame::command::network_status::NetworkStatus;
+    use crate::nwk::frame::frame_control::FrameControl as NwkFrameControl;
+    use crate::nwk::frame::header::Header as NwkHeader;
+    use crate::nwk::nib::relationship;
+    use crate::nwk::nlme::management::NlmeNwkStatusIndication;
+    use crate::nwk::nlme::tests::MockMlme;
+    use crate::nwk::nlme::tests::OWN;
+    use crate::nwk::nlme::tests::PAN_ID;
+    use crate::nwk::nlme::tests::block_on;
+    use crate::nwk::nlme::tests::energy_detect_list;
+    use crate::nwk::nlme::tests::expect_command_frame;
+    use crate::nwk::nlme::tests::install_network_key;
+    use crate::nwk::nlme::tests::mac_short;
+    use crate::nwk::nlme::tests::make_neighbor;
+    use crate::nwk::nlme::tests::make_router;
+    use crate::zdp::device_annce::DeviceAnnce;
+
+    const NEIGHBOR: u16 = 0x2222;
+    const NEIGHBOR_IEEE: IeeeAddress = IeeeAddress(0x0000_0000_0000_2222);
+
+    #[derive(Default)]
+    struct TestEndpoint {
+        endpoint: u8,
+        received: std::vec::Vec<ApsdeSapIndication>,
+        statuses: std::vec::Vec<NlmeNwkStatusIndication>,
+    }
+
+    impl Endpoint for TestEndpoint {
+        fn endpoint(&self) -> u8 {
+            self.endpoint
+        }
+
+        fn data_indication(&mut self, indication: &ApsdeSapIndication) {
+            self.received.push(indication.clone());
+        }
+
+        fn nlme_indication(&mut self, indication: &NwkIndication) {
+            if let NwkIndication::NwkStatus(status) = indication {
+                self.statuses.push(status.clone());
+            }
+        }
+    }
+
+    /// NWK frame from the neighbor to this device carrying an APS data
+    /// frame.
+    fn aps_data_frame(
+        dst_endpoint: u8,
+        cluster_id: u16,
+        profile_id: u16,
+        asdu: &[u8],
+    ) -> std::vec::Vec<u8> {
+        let aps_header = Header {
+            frame_control: FrameControl::default()
+                .set_frame_type(FrameType::Data)
+                .set_delivery_mode(DeliveryMode::Unicast),
+            destination_endpoint: Some(dst_endpoint),
+            group_address: None,
+            cluster_id: Some(cluster_id),
+            profile_id: Some(profile_id),
+            source_endpoint: Some(1),
+            counter: 5,
+            extended_header: None,
+        };
+        nwk_frame(aps_header, asdu)
+    }
+
+    /// NWK frame from the neighbor to this device carrying an APS command
+    /// frame without APS security.
+    fn aps_command_frame(command: &[u8]) -> std::vec::Vec<u8> {
+        let aps_header = Header {
+            frame_control: FrameControl::default()
+                .set_frame_type(FrameType::Command)
+                .set_delivery_mode(DeliveryMode::Unicast),
+            destination_endpoint: None,
+            group_address: None,
+            cluster_id: None,
+            profile_id: None,
+            source_endpoint: None,
+            counter: 5,
+            extended_header: None,
+        };
+        nwk_frame(aps_header, command)
+    }
+
+    /// NWK frame from the neighbor to this device carrying the APS
+    /// acknowledgement of the frame with APS counter `counter`.
+    fn aps_ack_frame(counter: u8) -> std::vec::Vec<u8> {
+        let aps_header = Header {
+            frame_control: FrameControl::default()
+                .set_frame_type(FrameType::Acknowledgement)
+                .set_delivery_mode(DeliveryMode::Unicast),
+            destination_endpoint: Some(1),
+            group_address: None,
+            cluster_id: Some(0x0006),
+            profile_id: Some(0x0104),
+            source_endpoint: Some(2),
+            counter,
+            extended_header: None,
+        };
+        nwk_frame(aps_header, &[])
+    }
+
+    fn nwk_frame(aps_header: Header, payload: &[u8]) -> std::vec::Vec<u8> {
+        let nwk_header = NwkHeader {
+            frame_control: NwkFrameControl(0).set_protocol_version(2),
+            destination: ShortAddress(OWN),
+            source: ShortAddress(NEIGHBOR),
+            radius: 1,
+            sequence_number: 3,
+            destination_ieee: None,
+            source_ieee: None,
+            multicast_control: None,
+            source_route_subframe: None,
+        };
+        let mut buf = [0u8; 64];
+        let offset = &mut 0;
+        buf.write_with(offset, nwk_header, ()).unwrap();
+        buf.write_with(offset, aps_header, ()).unwrap();
+        buf.write_with(offset, payload, ()).unwrap();
+        buf[..*offset].to_vec()
+    }
+
+    fn make_receiving_router(mut mac: MockMlme, frame: std::vec::Vec<u8>) -> Nlme<MockMlme> {
+        mac.expect_receive().times(1).returning(move |buf| {
+            buf[..frame.len()].copy_from_slice(&frame);
+            Ok(MacIndication::Data {
+                source: mac_short(NEIGHBOR),
+                destination: mac_short(OWN),
+                len: frame.len(),
+                lqi: 0xff,
+            })
+        });
+        make_router(mac)
+    }
+
+    #[test]
+    fn data_is_delivered_to_destination_endpoint() {
+        let frame = aps_data_frame(2, 0x0006, 0x0104, &[1, 2, 3]);
+        let mut nlme = make_receiving_router(MockMlme::new(), frame);
+        let mut device = ZigbeeDevice::new(Config::default());
+        let mut first = TestEndpoint {
+            endpoint: 1,
+            ..TestEndpoint::default()
+        };
+        let mut second = TestEndpoint {
+            endpoint: 2,
+            ..TestEndpoint::default()
+        };
+
+        block_on(device.process_indication(&mut nlme, &mut [&mut first, &mut second])).unwrap();
+
+        assert!(first.received.is_empty());
+        let [indication] = &second.received[..] else {
+            unreachable!("expected one data indication");
+        };
+        assert_eq!(indication.dst_addr_mode, DstAddrMode::Network);
+        assert_eq!(indication.dst_address, Address::Network(OWN));
+        assert_eq!(indication.src_address, Address::Network(NEIGHBOR));
+        assert_eq!(indication.src_endpoint, 1);
+        assert_eq!(indication.cluster_id, 0x0006);
+        assert_eq!(indication.profile_id, 0x0104);
+        assert_eq!(&indication.asdu[..], &[1, 2, 3]);
+        assert_eq!(indication.security_status, SecurityStatus::Unsecured);
+    }
+
+    #[test]
+    fn aps_ack_ends_fast_polling() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, _| *dest == mac_short(NEIGHBOR))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        let mut nlme = make_receiving_router(mac, aps_ack_frame(1));
+        let mut neighbor = make_neighbor(PAN_ID, NEIGHBOR, 0, 0xff, 1);
+        neighbor.relationship = relationship::SIBLING;
+        let mut table = nlme.nib().neighbor_table();
+        table.push(neighbor).unwrap();
+        nlme.nib().set_neighbor_table(table);
+        let mut device = ZigbeeDevice::new(Config::default());
+        block_on(device.apsme.unicast_data(
+            &mut nlme,
+            ShortAddress(NEIGHBOR),
+            2,
+            0x0006,
+            0x0104,
+            1,
+            true,
+            &[0x01],
+        ))
+        .unwrap();
+        assert!(nlme.is_fast_polling());
+
+        block_on(device.process_indication(&mut nlme, &mut [])).unwrap();
+
+        assert!(!nlme.is_fast_polling());
+    }
+
+    #[test]
+    fn device_annce_is_handled_by_zdo() {
+        let annce = DeviceAnnce {
+            nwk_addr: ShortAddress(NEIGHBOR),
+            ieee_addr: NEIGHBOR_IEEE,
+            capability: crate::nwk::nib::CapabilityInformation(0x8e),
+        };
+        let frame = device_annce_frame(annce);
+        let mut nlme = make_receiving_router(MockMlme::new(), frame);
+        let mut device = ZigbeeDevice::new(Config::default());
+        let mut endpoint = TestEndpoint {
+            endpoint: 1,
+            ..TestEndpoint::default()
+        };
+
+        block_on(device.process_indication(&mut nlme, &mut [&mut endpoint])).unwrap();
+
+        assert!(endpoint.received.is_empty());
+        assert_eq!(
+            nlme.lookup_network_address(NEIGHBOR_IEEE),
+            Some(ShortAddress(NEIGHBOR))
+        );
+    }
+
+    #[test]
+    fn network_status_is_passed_to_endpoints() {
+        let mut nlme = make_router(MockMlme::new());
+        let status = NetworkStatus {
+            status_code: NetworkStatusCode::NoRouteAvailable,
+            destination_address: ShortAddress(0x4444),
+        };
+        expect_command_frame(
+            &mut nlme,
+            NEIGHBOR,
+            NwkCommand::NetworkStatus(status),
+            false,
+        );
+        let mut device = ZigbeeDevice::new(Config::default());
+        let mut first = TestEndpoint {
+            endpoint: 1,
+            ..TestEndpoint::default()
+        };
+        let mut second = TestEndpoint {
+            endpoint: 2,
+            ..TestEndpoint::default()
+        };
+
+        block_on(device.process_indication(&mut nlme, &mut [&mut first, &mut second])).unwrap();
+
+        let expected = NlmeNwkStatusIndication {
+            network_address: ShortAddress(0x4444),
+            status: NetworkStatusCode::NoRouteAvailable,
+        };
+        assert_eq!(first.statuses, second.statuses);
+        assert_eq!(second.statuses, [expected]);
+        assert!(first.received.is_empty());
+    }
+
+    /// NWK frame from the neighbor carrying a Device_annce for `annce`.
+    fn device_annce_frame(annce: DeviceAnnce) -> std::vec::Vec<u8> {
+        let mut zdp = [0u8; 12];
+        let offset = &mut 0;
+        zdp.write(offset, 0x01u8).unwrap();
+        zdp.write_with(offset, annce, ()).unwrap();
+        aps_data_frame(
+            ZDO_ENDPOINT,
+            device_annce::CLUSTER_ID,
+            ZDP_PROFILE_ID,
+            &zdp[..*offset],
+        )
+    }
+
+    #[test]
+    fn new_address_after_conflict_is_announced() {
+        let sent = std::sync::Arc::new(std::sync::Mutex::new(std::vec::Vec::new()));
+        let recorded = sent.clone();
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data().returning(move |_, payload| {
+            recorded.lock().unwrap().push(payload.to_vec());
+            Ok(())
+        });
+        mac.expect_set_short_address().times(1).return_const(());
+        mac.expect_set_beacon_payload().return_const(());
+        // the neighbor announces the address of this router
+        let frame = device_annce_frame(DeviceAnnce {
+            nwk_addr: ShortAddress(OWN),
+            ieee_addr: NEIGHBOR_IEEE,
+            capability: crate::nwk::nib::CapabilityInformation(0x8e),
+        });
+        let mut nlme = make_receiving_router(mac, frame);
+        let mut device = ZigbeeDevice::new(Config::default());
+
+        block_on(device.process_indication(&mut nlme, &mut [])).unwrap();
+
+        let new_address = nlme.nib().network_address();
+        assert_ne!(new_address, OWN);
+        let annce = sent.lock().unwrap().pop().unwrap();
+        // Device_annce payload: NWK address, IEEE address, capability
+        let (nwk_addr, _) = annce.split_at(annce.len() - 9);
+        assert_eq!(nwk_addr[nwk_addr.len() - 2..], new_address.to_le_bytes());
+    }
+
+    // 2.4.3.3.7
+    #[test]
+    fn mgmt_permit_joining_req_opens_the_network() {
+        let mut mac = MockMlme::new();
+        mac.expect_set_association_permit()
+            .withf(|permit| *permit)
+            .times(1)
+            .return_const(());
+        // ZDP sequence number, PermitDuration and TC_Significance
+        let frame = aps_data_frame(
+            ZDO_ENDPOINT,
+            mgmt_permit_joining::CLUSTER_ID,
+            ZDP_PROFILE_ID,
+            &[0x01, 0xb4, 0x01],
+        );
+        let mut nlme = make_receiving_router(mac, frame);
+        let mut device = ZigbeeDevice::new(Config::default());
+        let mut endpoint = TestEndpoint {
+            endpoint: 1,
+            ..TestEndpoint::default()
+        };
+
+        block_on(device.process_indication(&mut nlme, &mut [&mut endpoint])).unwrap();
+
+        assert!(nlme.is_joining_permitted());
+        assert!(endpoint.received.is_empty());
+    }
+
+    // Annex E
+    #[test]
+    fn mgmt_nwk_update_notify_moves_the_network() {
+        let mut mac = MockMlme::new();
+        mac.expect_scan_network()
+            .withf(|ty, _, _| *ty == ScanType::Ed)
+            .times(1)
+            .returning(|ty, _, _| {
+                Ok(ScanResult {
+                    scan_type: ty,
+                    pan_descriptor: zigbee_mac::mlme::PanDescriptorList::new(),
+                    energy_detect_list: energy_detect_list(&[(11, 0xc0), (20, 0x10)]),
+                    coordinator_realignment: None,
+                })
+            });
+        mac.expect_transmit_data()
+            .withf(|dest, _| *dest == mac_short(0xffff))
+            .times(1)
+            .returning(|_, _| Ok(()));
+        mac.expect_set_beacon_payload().return_const(());
+        // ZDP sequence number, Status, ScannedChannels, TotalTransmissions,
+        // TransmissionFailures and the energy of two channels
+        let frame = aps_data_frame(
+            ZDO_ENDPOINT,
+            mgmt_nwk_update_notify::CLUSTER_ID,
+            ZDP_PROFILE_ID,
+            &[
+                0x01, 0x00, 0x00, 0x08, 0x10, 0x00, 0x14, 0x00, 0x06, 0x00, 0x02, 0xc0, 0x10,
+            ],
+        );
+        let mut nlme = make_receiving_router(mac, frame);
+        nlme.nib().set_manager_addr(OWN);
+        // the network manager itself already found interference
+        block_on(nlme.interference_report(ShortAddress(OWN))).unwrap();
+        let mut device = ZigbeeDevice::new(Config::default());
+
+        block_on(device.process_indication(&mut nlme, &mut [])).unwrap();
+
+        assert_eq!(nlme.nib().update_id(), 1);
+    }
+
+    const CHILD: IeeeAddress = IeeeAddress(0x0102_0304_0506_0708);
+    const TRUST_CENTER: IeeeAddress = IeeeAddress(0x00aa_0000_0000_0000);
+
+    /// A router with the coordinator as parent which accepts the association
+    /// of [`CHILD`].
+    fn make_associating_router(mut mac: MockMlme) -> Nlme<MockMlme> {
+        mac.expect_receive().times(1).returning(|_| {
+            Ok(MacIndication::Associate {
+                device_address: CHILD,
+                capability_information: zigbee_mac::CapabilityInformation {
+                    full_function_device: false,
+                    mains_power: false,
+                    idle_receive: false,
+                    frame_protection: false,
+                    allocate_address: true,
+                },
+            })
+        });
+        mac.expect_associate_response()
+            .times(1)
+            .returning(|_, _, _| Ok(()));
+        mac.expect_set_association_permit().return_const(());
+        mac.expect_set_beacon_payload().return_const(());
+        let mut nlme = make_router(mac);
+        let mut parent = make_neighbor(PAN_ID, 0x0000, 0, 0xff, 0);
+        parent.relationship = relationship::PARENT;
+        let mut table = nlme.nib().neighbor_table();
+        table.push(parent).unwrap();
+        nlme.nib().set_neighbor_table(table);
+        install_network_key(&nlme);
+        block_on(nlme.permit_joining(NlmePermitJoiningRequest {
+            permit_duration: 0xff,
+        }));
+        nlme
+    }
+
+    // 4.4.3
+    #[test]
+    fn child_join_is_reported_to_the_trust_center() {
+        let sent = std::sync::Arc::new(std::sync::Mutex::new(std::vec::Vec::new()));
+        let recorded = sent.clone();
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data()
+            .withf(|dest, _| *dest == mac_short(0x0000))
+            .times(1)
+            .returning(move |_, payload| {
+                recorded.lock().unwrap().push(payload.to_vec());
+                Ok(())
+            });
+        let mut nlme = make_associating_router(mac);
+        let aib = nlme.aib();
+        aib.set_trust_center_address(TRUST_CENTER);
+        let mut key_set = aib.device_key_pair_set();
+        key_set
+            .push(DeviceKeyPairDescriptor {
+                device_address: TRUST_CENTER,
+                key_attributes: KeyAttribute::VerifiedKey,
+                link_key: zigbee_types::ByteArray(crate::security::TRUST_CENTER_LINK_KEY),
+                outgoing_frame_counter: 0,
+                incoming_frame_counter: 0,
+                link_key_type: LinkKeyType::UniqueLinkKey,
+            })
+            .unwrap();
+        aib.set_device_key_pair_set(key_set);
+        let mut device = ZigbeeDevice::new(Config::default());
+        let mut endpoint = TestEndpoint::default();
+
+        block_on(device.process_indication(&mut nlme, &mut [&mut endpoint])).unwrap();
+
+        // an APS secured command to the Trust Center on the coordinator
+        let mut frame = sent.lock().unwrap().pop().unwrap();
+        let crate::nwk::frame::Frame::Data(data) = nlme
+            .security_context()
+            .decrypt_nwk_frame_in_place(&mut frame)
+            .unwrap()
+        else {
+            unreachable!("expected a NWK data frame");
+        };
+        assert_eq!(data.header.destination, ShortAddress(0x0000));
+        let (aps_header, _) = Header::try_read(data.payload, ()).unwrap();
+        assert!(aps_header.frame_control.security_flag());
+
+        // the Trust Center decrypts with the same well-known link key
+        let mut aps_frame = data.payload.to_vec();
+        let Frame::ApsCommand(CommandFrame {
+            command: Command::UpdateDevice(update),
+            ..
+        }) = nlme
+            .security_context()
+            .decrypt_aps_frame_in_place(&mut aps_frame)
+            .unwrap()
+        else {
+            unreachable!("expected an Update-Device command");
+        };
+        assert_eq!(update.device_address, CHILD);
+        assert_eq!(
+            update.status,
+            update_device_status::STANDARD_DEVICE_UNSECURED_JOIN
+        );
+    }
+
+    // 4.4.3
+    #[test]
+    fn child_join_without_trust_center_is_not_reported() {
+        let mut mac = MockMlme::new();
+        mac.expect_transmit_data().never();
+        let mut nlme = make_associating_router(mac);
+        let mut device = ZigbeeDevice::new(Config::default());
+        let mut endpoint = TestEndpoint::default();
+
+        block_on(device.process_indication(&mut nlme, &mut [&mut endpoint])).unwrap();
+
+        assert_eq!(nlme.nib().neighbor_table().len(), 2);
+    }
+
+    // 4.4.10.1
+    #[test]
+    fn unsecured_transport_key_is_ignored() {
+        // Transport-Key command carrying a standard network key
+        let transport_key = [
+            0x05, 0x01, 0xab, 0xcd, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0xe5, 0x01, 0x30, 0x38, 0x9c, 0x38, 0xc1, 0xa4, 0xe1,
+            0x52, 0x38, 0x7d, 0xc1, 0x36, 0xce, 0xf4,
+        ];
+        let frame = aps_command_frame(&transport_key);
+        let mut nlme = make_receiving_router(MockMlme::new(), frame);
+        let mut device = ZigbeeDevice::new(Config::default());
+
+        let result = block_on(device.process_indication(&mut nlme, &mut []));
+
+        assert!(matches!(result, Err(NetworkError::NoTransportKey)));
+        assert!(nlme.nib().security_material_set().is_empty());
+        assert_eq!(
+            nlme.aib().trust_center_address(),
+            IeeeAddress(0xffff_ffff_ffff_ffff)
+        );
+    }
+}
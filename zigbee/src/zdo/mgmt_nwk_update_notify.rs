@@ -0,0 +1,53 @@
+//! ZDO Mgmt_NWK_Update_notify (§2.4.4.3.9)
+//!
+//! ZDO reports interference detected by the NWK layer to the network
+//! manager via the APSDE-SAP on endpoint 0.
+
+use byte::BytesExt;
+use zigbee_types::ShortAddress;
+use zigbee_types::TypeArrayRef;
+
+use super::device_annce::ZDO_ENDPOINT;
+use super::device_annce::ZDP_PROFILE_ID;
+use crate::aps::apsme::Apsme;
+use crate::nwk::nlme::NetworkError;
+use crate::nwk::nlme::Nlme;
+use crate::nwk::nlme::management::InterferenceReport;
+use crate::zdp::mgmt_nwk_update_notify::CLUSTER_ID;
+pub use crate::zdp::mgmt_nwk_update_notify::MgmtNwkUpdateNotify;
+
+/// Send `report` to the network manager `manager` as an unsolicited
+/// Mgmt_NWK_Update_notify (§2.4.4.3.9).
+pub async fn unicast<M: zigbee_mac::mlme::Mlme>(
+    nlme: &mut Nlme<M>,
+    apsme: &mut Apsme,
+    zdp_seq: u8,
+    manager: ShortAddress,
+    report: &InterferenceReport,
+) -> Result<(), NetworkError> {
+    let notify = MgmtNwkUpdateNotify {
+        status: 0x00,
+        scanned_channels: report.scanned_channels,
+        total_transmissions: report.total_transmissions,
+        transmission_failures: report.transmission_failures,
+        scanned_channels_list_count: u8::try_from(report.energy_values.len()).unwrap_or(u8::MAX),
+        energy_values: TypeArrayRef(&report.energy_values),
+    };
+    let mut zdp_buf = [0u8; 38];
+    let offset = &mut 0;
+    zdp_buf.write(offset, zdp_seq)?;
+    zdp_buf.write_with(offset, notify, ())?;
+
+    apsme
+        .unicast_data(
+            nlme,
+            manager,
+            ZDO_ENDPOINT,
+            CLUSTER_ID,
+            ZDP_PROFILE_ID,
+            ZDO_ENDPOINT,
+            false,
+            &zdp_buf[..*offset],
+        )
+        .await
+}
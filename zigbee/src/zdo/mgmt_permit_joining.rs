@@ -0,0 +1,41 @@
+//! ZDO Mgmt_Permit_Joining_req broadcast (§2.4.3.3.7)
+//!
+//! ZDO sends Mgmt_Permit_Joining_req via the APSDE-SAP on endpoint 0.
+
+use byte::BytesExt;
+use zigbee_types::ShortAddress;
+
+use super::device_annce::ZDO_ENDPOINT;
+use super::device_annce::ZDP_PROFILE_ID;
+use crate::aps::apsme::Apsme;
+use crate::nwk::nlme::NetworkError;
+use crate::nwk::nlme::Nlme;
+use crate::zdp::mgmt_permit_joining::CLUSTER_ID;
+pub use crate::zdp::mgmt_permit_joining::MgmtPermitJoiningReq;
+
+/// Broadcast a Mgmt_Permit_Joining_req to all routers and the coordinator
+/// (§2.4.3.3.7).
+pub async fn broadcast<M: zigbee_mac::mlme::Mlme>(
+    nlme: &mut Nlme<M>,
+    apsme: &mut Apsme,
+    zdp_seq: u8,
+    request: MgmtPermitJoiningReq,
+) -> Result<(), NetworkError> {
+    let mut zdp_buf = [0u8; 3];
+    let offset = &mut 0;
+    zdp_buf.write(offset, zdp_seq)?;
+    zdp_buf.write_with(offset, request, ())?;
+
+    let routers = ShortAddress(0xFFFC);
+    apsme
+        .broadcast_data(
+            nlme,
+            routers,
+            ZDO_ENDPOINT,
+            CLUSTER_ID,
+            ZDP_PROFILE_ID,
+            ZDO_ENDPOINT,
+            &zdp_buf[..*offset],
+        )
+        .await
+}
@@ -4,23 +4,37 @@ use zigbee_types::ShortAddress;
 
 pub mod config;
 pub mod device_annce;
-use zigbee_types::StorageVec;
+mod dispatch;
+pub mod mgmt_nwk_update_notify;
+pub mod mgmt_permit_joining;
 
 use crate::apl::descriptors::node_descriptor::LogicalType;
-use crate::aps::aib;
 use crate::aps::aib::DeviceKeyPairDescriptor;
 use crate::aps::aib::KeyAttribute;
 use crate::aps::aib::LinkKeyType;
+use crate::aps::apsme::ApsFrameIndication;
 use crate::aps::apsme::Apsme;
+use crate::aps::apsme::ApsmeSap;
+use crate::aps::apsme::basemgt::ApsmeGetConfirm;
+use crate::aps::apsme::basemgt::ApsmeSetConfirm;
+use crate::aps::apsme::basemgt::ApsmeSetRequest;
 use crate::aps::frame::CommandFrame;
 use crate::aps::frame::Frame;
 use crate::aps::frame::command::Command;
+use crate::aps::frame::command::StandardNetworkKeyDescriptor;
 use crate::aps::frame::command::TransportKey;
-use crate::nwk::nib;
-use crate::nwk::nib::NetworkSecurityMaterialDescriptor;
+use crate::aps::frame::command::UpdateDevice;
+use crate::aps::frame::command::update_device_status;
+use crate::nwk::nib::NWK_COORDINATOR_ADDRESS;
 use crate::nwk::nlme::NetworkError;
 use crate::nwk::nlme::Nlme;
+use crate::nwk::nlme::management::NlmeJoinIndication;
+use crate::nwk::nlme::management::RejoinNetwork;
 use crate::security::SecurityContext;
+use crate::security::frame::KeyIdentifier;
+
+/// `apsTrustCenterAddress` before a Trust Center is known.
+const UNKNOWN_TRUST_CENTER: IeeeAddress = IeeeAddress(0xffff_ffff_ffff_ffff);
 
 /// Provides an interface between the application object, the device profile and
 /// the APS.
@@ -59,6 +73,24 @@ impl ZigbeeDevice {
 
     pub fn send_keep_alive(&self) {}
 
+    /// Read an AIB attribute by its identifier (APSME-GET).
+    pub fn apsme_get<M: zigbee_mac::mlme::Mlme>(
+        &self,
+        nlme: &Nlme<M>,
+        attribute: u8,
+    ) -> ApsmeGetConfirm {
+        self.apsme.get(nlme.aib(), attribute)
+    }
+
+    /// Write an AIB attribute by its identifier (APSME-SET).
+    pub fn apsme_set<M: zigbee_mac::mlme::Mlme>(
+        &mut self,
+        nlme: &Nlme<M>,
+        request: ApsmeSetRequest,
+    ) -> ApsmeSetConfirm {
+        self.apsme.set(nlme.aib(), request)
+    }
+
     pub fn send_data(&self, _input: &[u8]) {}
 
     /// 2.1.3.1 - Device Discovery
@@ -95,8 +127,37 @@ impl ZigbeeDevice {
         device_annce::broadcast(nlme, &mut self.apsme, self.zdp_seq, annce).await
     }
 
+    /// Broadcast a ZDO Mgmt_Permit_Joining_req to all routers and the
+    /// coordinator (§2.4.3.3.7).
+    pub async fn mgmt_permit_joining_req<M: zigbee_mac::mlme::Mlme>(
+        &mut self,
+        nlme: &mut Nlme<M>,
+        request: mgmt_permit_joining::MgmtPermitJoiningReq,
+    ) -> Result<(), NetworkError> {
+        self.zdp_seq = self.zdp_seq.wrapping_add(1);
+        mgmt_permit_joining::broadcast(nlme, &mut self.apsme, self.zdp_seq, request).await
+    }
+
+    /// Report interference detected by the NWK layer to the network manager
+    /// with a Mgmt_NWK_Update_notify (§2.4.4.3.9), to be called after
+    /// [`Nlme::tick`].
+    pub async fn report_interference<M: zigbee_mac::mlme::Mlme>(
+        &mut self,
+        nlme: &mut Nlme<M>,
+    ) -> Result<(), NetworkError> {
+        let Some(report) = nlme.take_interference_report() else {
+            return Ok(());
+        };
+        let manager = ShortAddress(nlme.nib().manager_addr());
+        self.zdp_seq = self.zdp_seq.wrapping_add(1);
+        mgmt_nwk_update_notify::unicast(nlme, &mut self.apsme, self.zdp_seq, manager, &report).await
+    }
+
     /// Security Manager: poll for a Transport-Key command and install the
     /// network key and Trust Center link key entry (§4.4.10).
+    ///
+    /// A key update received while joined is kept as the alternate key until
+    /// the Trust Center sends a Switch-Key command.
     pub async fn poll_transport_key<M: zigbee_mac::mlme::Mlme>(
         &mut self,
         nlme: &mut Nlme<M>,
@@ -106,51 +167,24 @@ impl ZigbeeDevice {
 
         // SAFETY: we can safely take a &mut since it references the buf above
         let aps_buf = unsafe { nwk_data.payload_as_mut() };
-        let cx = SecurityContext::get();
-        let aps_frame = cx.decrypt_aps_frame_in_place(aps_buf)?;
+        let ApsFrameIndication {
+            frame,
+            key_identifier,
+            source,
+            ..
+        } = self.apsme.frame_indication(nlme, aps_buf)?;
 
         let Frame::ApsCommand(CommandFrame {
             command: Command::TransportKey(transport_key),
             ..
-        }) = aps_frame
+        }) = frame
         else {
             return Err(NetworkError::NoTransportKey);
         };
 
         match transport_key {
             TransportKey::StandardNetworkKey(nwk_key) => {
-                log::debug!("[ZDO] received network key {:02x?}", nwk_key.key);
-
-                let aib = aib::get_ref();
-                aib.set_trust_center_address(nwk_key.source_address);
-                let mut key_set = aib.device_key_pair_set();
-                if !key_set
-                    .iter()
-                    .any(|k| k.device_address == nwk_key.source_address)
-                {
-                    let _ = key_set.push(DeviceKeyPairDescriptor {
-                        device_address: nwk_key.source_address,
-                        key_attributes: KeyAttribute::ProvisionalKey,
-                        link_key: zigbee_types::ByteArray(crate::security::TRUST_CENTER_LINK_KEY),
-                        outgoing_frame_counter: 0,
-                        incoming_frame_counter: 0,
-                        link_key_type: LinkKeyType::GlobalLinkKey,
-                    });
-                    aib.set_device_key_pair_set(key_set);
-                }
-
-                let nib = nib::get_ref();
-                let mut sec_material = nib.security_material_set();
-                sec_material.clear();
-                let _ = sec_material.push(NetworkSecurityMaterialDescriptor {
-                    key_seq_number: nwk_key.sequence_number,
-                    outgoing_frame_counter: 0,
-                    incoming_frame_counter_set: StorageVec::new(),
-                    key: nwk_key.key,
-                    network_key_type: 0x01,
-                });
-                nib.set_security_material_set(sec_material);
-                nib.set_active_key_seq_number(nwk_key.sequence_number);
+                Self::network_key_indication(nlme, &nwk_key, key_identifier, source)?;
             }
             TransportKey::ApplicationLinkKey(_app_key) => (), // TODO
             TransportKey::TrustCenterLinkKey(_tcl_key) => (), // TODO
@@ -160,6 +194,98 @@ impl ZigbeeDevice {
         Ok(())
     }
 
+    /// Security Manager: install a network key received from the Trust
+    /// Center and its link key entry (§4.4.10.1).
+    ///
+    /// The key is only accepted when the Transport-Key command was secured
+    /// with the key-transport key of the Trust Center link key. The first
+    /// key sets the Trust Center address, later keys have to come from it.
+    fn network_key_indication<M: zigbee_mac::mlme::Mlme>(
+        nlme: &Nlme<M>,
+        nwk_key: &StandardNetworkKeyDescriptor,
+        key_identifier: Option<KeyIdentifier>,
+        source: Option<IeeeAddress>,
+    ) -> Result<(), NetworkError> {
+        let aib = nlme.aib();
+        let (Some(KeyIdentifier::KeyTransport), Some(source)) = (key_identifier, source) else {
+            log::debug!("[ZDO] dropping network key not secured with the key-transport key");
+            return Err(NetworkError::NoTransportKey);
+        };
+        let trust_center_address = aib.trust_center_address();
+        if trust_center_address != UNKNOWN_TRUST_CENTER && source != trust_center_address {
+            log::debug!("[ZDO] dropping network key from {source:?}, not the Trust Center");
+            return Err(NetworkError::NoTransportKey);
+        }
+        log::debug!("[ZDO] received network key {:02x?}", nwk_key.key);
+
+        if trust_center_address == UNKNOWN_TRUST_CENTER {
+            aib.set_trust_center_address(source);
+        }
+        let mut key_set = aib.device_key_pair_set();
+        if !key_set.iter().any(|k| k.device_address == source) {
+            let _ = key_set.push(DeviceKeyPairDescriptor {
+                device_address: source,
+                key_attributes: KeyAttribute::ProvisionalKey,
+                link_key: zigbee_types::ByteArray(crate::security::TRUST_CENTER_LINK_KEY),
+                outgoing_frame_counter: 0,
+                incoming_frame_counter: 0,
+                link_key_type: LinkKeyType::GlobalLinkKey,
+            });
+            aib.set_device_key_pair_set(key_set);
+        }
+
+        let cx = nlme.security_context();
+        cx.install_network_key(nwk_key.sequence_number, nwk_key.key, 0x01);
+        Ok(())
+    }
+
+    /// Security Manager: tell the Trust Center about a device which joined or
+    /// rejoined through this router with an Update-Device command (§4.4.3).
+    ///
+    /// Nothing is sent while the Trust Center is unknown or when this device
+    /// is the Trust Center itself.
+    async fn update_device<M: zigbee_mac::mlme::Mlme>(
+        &mut self,
+        nlme: &mut Nlme<M>,
+        join: &NlmeJoinIndication,
+    ) -> Result<(), NetworkError> {
+        let trust_center_address = nlme.aib().trust_center_address();
+        if trust_center_address == UNKNOWN_TRUST_CENTER
+            || trust_center_address == nlme.nib().ieee_address()
+        {
+            return Ok(());
+        }
+        let status = match join.rejoin_network {
+            RejoinNetwork::NwkRejoin if join.secure_rejoin => {
+                update_device_status::STANDARD_DEVICE_SECURED_REJOIN
+            }
+            RejoinNetwork::NwkRejoin => update_device_status::STANDARD_DEVICE_TRUST_CENTER_REJOIN,
+            _ => update_device_status::STANDARD_DEVICE_UNSECURED_JOIN,
+        };
+        let update = UpdateDevice {
+            device_address: join.extended_address,
+            device_short_address: join.network_address,
+            status,
+        };
+        let destination = nlme
+            .lookup_network_address(trust_center_address)
+            .unwrap_or(ShortAddress(NWK_COORDINATOR_ADDRESS));
+        log::debug!(
+            "[ZDO] updating the Trust Center about {:?} at 0x{:04x}",
+            join.extended_address,
+            join.network_address.0
+        );
+        self.apsme
+            .send_command(
+                nlme,
+                destination,
+                trust_center_address,
+                Command::UpdateDevice(update),
+                true,
+            )
+            .await
+    }
+
     /// Security Manager: build and send an APS command frame (§4.4).
     ///
     /// Delegates to APSME which owns `apsCounter` (§4.4.11). When
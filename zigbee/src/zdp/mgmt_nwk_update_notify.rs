@@ -0,0 +1,33 @@
+//! ZDP Mgmt_NWK_Update_notify frame payload (§2.4.4.3.9)
+//!
+//! Sent to the network manager by a device which detected interference on
+//! the current channel, carrying the transmission counters and an energy
+//! detect scan of the channels.
+
+use zigbee_macros::impl_byte;
+use zigbee_types::TypeArrayCtx;
+use zigbee_types::TypeArrayRef;
+
+/// ZDP Mgmt_NWK_Update_notify cluster identifier.
+pub const CLUSTER_ID: u16 = 0x8038;
+
+impl_byte! {
+    /// ZDP Mgmt_NWK_Update_notify payload (§2.4.4.3.9).
+    #[derive(Debug, Clone)]
+    pub struct MgmtNwkUpdateNotify<'a> {
+        /// ZDP status, always SUCCESS for an unsolicited notification.
+        pub status: u8,
+        /// Channels of the energy detect scan, bit `n` for channel `n`.
+        pub scanned_channels: u32,
+        /// Unicast transmissions since the counters were last reset.
+        pub total_transmissions: u16,
+        /// Failed transmissions since the counters were last reset.
+        pub transmission_failures: u16,
+        /// Number of entries in `energy_values`.
+        pub scanned_channels_list_count: u8,
+        /// Energy measured on each scanned channel.
+        #[ctx = TypeArrayCtx::Len(usize::from(scanned_channels_list_count))]
+        #[ctx_write = ()]
+        pub energy_values: TypeArrayRef<'a, u8>,
+    }
+}
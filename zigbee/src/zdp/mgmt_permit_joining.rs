@@ -0,0 +1,22 @@
+//! ZDP Mgmt_Permit_Joining_req frame payload (§2.4.3.3.7)
+//!
+//! Requests a remote device or all routers and the coordinator to allow or
+//! disallow association for a number of seconds.
+
+use zigbee_macros::impl_byte;
+
+/// ZDP Mgmt_Permit_Joining_req cluster identifier.
+pub const CLUSTER_ID: u16 = 0x0036;
+
+impl_byte! {
+    /// ZDP Mgmt_Permit_Joining_req payload (§2.4.3.3.7).
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct MgmtPermitJoiningReq {
+        /// Time in seconds during which association is permitted, 0x00
+        /// disables it.
+        pub permit_duration: u8,
+        /// Whether the Trust Center is asked to update its policy, always
+        /// 0x01.
+        pub tc_significance: u8,
+    }
+}
@@ -15,3 +15,5 @@
 
 pub mod client_services;
 pub mod device_annce;
+pub mod mgmt_nwk_update_notify;
+pub mod mgmt_permit_joining;